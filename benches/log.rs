@@ -5,13 +5,13 @@ extern crate raft;
 extern crate rand;
 extern crate test;
 
-use std::fs::remove_file;
+use std::fs::remove_dir_all;
 use std::path::Path;
 
 use rand::Rng;
 
 use raft::persistent_log::FsLog;
-use raft::{Log, LogIndex, Term};
+use raft::{EntryKind, Log, LogIndex, Term};
 
 #[bench]
 fn bench_log_control(b: &mut test::Bencher) {
@@ -19,10 +19,10 @@ fn bench_log_control(b: &mut test::Bencher) {
     b.iter(|| {
         let i: u64 = rng.gen();
         let name = format!("/tmp/raft-rs-bench-log-control-{:016x}", i);
-        let filename = Path::new(&name);
-        let log = FsLog::new(&filename).unwrap();
+        let dir = Path::new(&name);
+        let log = FsLog::new(&dir).unwrap();
         let x = log.latest_log_index();
-        remove_file(&filename).expect("Could not remove file");
+        remove_dir_all(&dir).expect("Could not remove directory");
         x
     });
 }
@@ -32,19 +32,19 @@ fn do_bench_append(b: &mut test::Bencher, name: &str, count: usize) {
     let values: Vec<u8> = (0..255).collect();
     let mut entries = vec![];
     for x in 0..count {
-        entries.push((Term::from(0x1234abcd8765fedc), &values[(x % 100)..(x % 100 + 100)]));
+        entries.push((Term::from(0x1234abcd8765fedc), EntryKind::Normal, &values[(x % 100)..(x % 100 + 100)]));
     }
     b.iter(|| {
         let i: u64 = rng.gen();
         let name = format!("/tmp/raft-rs-bench-log-{}-{:016x}", name, i);
-        let filename = Path::new(&name);
-        let mut log = FsLog::new(&filename).unwrap();
+        let dir = Path::new(&name);
+        let mut log = FsLog::new(&dir).unwrap();
         log.append_entries(
             LogIndex::from(1), 
             &entries[..],
         ).expect("appending entries");
         let x = log.latest_log_index();
-        remove_file(&filename).expect("Could not remove file");
+        remove_dir_all(&dir).expect("Could not remove directory");
         x
     });
 }
@@ -55,21 +55,21 @@ fn do_bench_append_then_rewrite(b: &mut test::Bencher, name: &str, count: usize,
     let mut initial_entries = vec![];
     let mut rewrite_entries = vec![];
     for x in 0..count {
-        initial_entries.push((Term::from(0x12), &values[(x % 100)..(x % 100 + 1)]));
+        initial_entries.push((Term::from(0x12), EntryKind::Normal, &values[(x % 100)..(x % 100 + 1)]));
     }
     for x in 0..rewrite {
-        rewrite_entries.push((Term::from(0x30af), &values[((rewrite - x) % 100)..((rewrite - x) % 100 + 1)]));
+        rewrite_entries.push((Term::from(0x30af), EntryKind::Normal, &values[((rewrite - x) % 100)..((rewrite - x) % 100 + 1)]));
     }
     b.iter(|| {
         let i: u64 = rng.gen();
         let name = format!("/tmp/raft-rs-bench-log-{}-{:016x}", name, i);
-        let filename = Path::new(&name);
-        let mut log = FsLog::new(&filename).unwrap();
+        let dir = Path::new(&name);
+        let mut log = FsLog::new(&dir).unwrap();
         log.append_entries(LogIndex::from(1), &initial_entries[..]).expect("append entries");
         log.append_entries(from, &rewrite_entries[..]).expect("rewrite entries");
 
         let x = log.latest_log_index();
-        remove_file(&filename).expect("Could not remove file");
+        remove_dir_all(&dir).expect("Could not remove directory");
         x
     });
 
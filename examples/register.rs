@@ -148,7 +148,8 @@ fn handle_response(response: &[u8]) {
 fn server(args: &Args) {
     // Creating a raft server requires several things:
 
-    // A log implementation, which manages the persistent, replicated log.
+    // A log implementation, which manages the persistent, replicated log. `FsLog` keeps the log
+    // in a directory of its own, which it creates if necessary.
 
     // A state machine implementation. The state machine type must be the same
     // on all nodes.
@@ -157,7 +158,8 @@ fn server(args: &Args) {
     // A unique server id.
     let id = ServerId::from(args.arg_id.unwrap());
 
-    let log = persistent_log::FsLog::new(Path::new(&format!("/tmp/register-raftlog.{}", id.as_u64()))).unwrap();
+    let dir = format!("/tmp/register-raftlog-{}", id.as_u64());
+    let log = persistent_log::FsLog::new(Path::new(&dir)).unwrap();
 
     // A list of peers.
     let mut peers = args.arg_node_id
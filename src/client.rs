@@ -1,115 +1,421 @@
 //! The `Client` allows users of the `raft` library to connect to remote `Server` instances and
 //! issue commands to be applied to the `StateMachine`.
+//!
+//! `Client` sends one request at a time and blocks until it is answered. `AsyncClient` keeps
+//! many requests in flight on a single connection instead, and hands each response to a
+//! callback or a `Response` handle.
+//!
+//! Both connect to the servers over TCP, unless `ClientBuilder::with_transport` gives them
+//! another `Transport`.
 
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt;
-use std::io::Write;
-use std::time::Duration;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
 use std::net::SocketAddr;
-use std::net::TcpStream;
 use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
-use bufstream::BufStream;
-use capnp::serialize;
-use capnp::message::{Allocator, Builder, ReaderOptions};
+use backoff::Backoff;
+use capnp::message::{Allocator, Builder, HeapAllocator, Reader};
+use capnp::serialize::OwnedSegments;
+use mio::{Evented, Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 
-use messages_capnp::{client_response, command_response};
+use messages_capnp::{client_request, client_response, command_response};
 use messages;
+use membership::LearnerStatus;
 use ClientId;
+use Consistency;
 use Result;
 use RaftError;
+use ServerId;
+use transport::{Framed, TcpTransport, Transport, frame};
 
-const CLIENT_TIMEOUT: u64 = 1500;
+/// The token of a connection's stream.
+const STREAM: Token = Token(0);
+/// The token which wakes the reader of a connection once it is shut down.
+const WAKEUP: Token = Token(1);
+
+/// Builds a `Client` or an `AsyncClient` with non-default timeouts, retry policy or transport.
+pub struct ClientBuilder<T = TcpTransport> {
+    cluster: HashSet<SocketAddr>,
+    connect_timeout_millis: u64,
+    request_timeout_millis: u64,
+    deadline_millis: u64,
+    max_retries: u32,
+    backoff_initial_millis: u32,
+    backoff_max_millis: u32,
+    transport: T,
+}
+
+impl ClientBuilder {
+    fn new(cluster: HashSet<SocketAddr>) -> ClientBuilder {
+        ClientBuilder {
+            cluster: cluster,
+            connect_timeout_millis: 1500,
+            request_timeout_millis: 1500,
+            deadline_millis: 10000,
+            max_retries: 10,
+            backoff_initial_millis: 50,
+            backoff_max_millis: 1000,
+            transport: TcpTransport,
+        }
+    }
+}
+
+impl<T> ClientBuilder<T>
+    where T: Transport
+{
+    pub fn finalize(self) -> Client<T> {
+        let id = ClientId::new();
+        Client {
+            id: id,
+            leader_connection: None,
+            cluster: self.cluster,
+            session: id,
+            sequence: 0,
+            connect_timeout: Duration::from_millis(self.connect_timeout_millis),
+            request_timeout: Duration::from_millis(self.request_timeout_millis),
+            deadline: Duration::from_millis(self.deadline_millis),
+            max_retries: self.max_retries,
+            backoff: Backoff::with_duration_range(self.backoff_initial_millis,
+                                                  self.backoff_max_millis),
+            transport: self.transport,
+        }
+    }
+
+    /// Builds an `AsyncClient`. The deadline, retry budget and backoff apply to each request
+    /// separately; the request timeout is not used, since requests are not answered in order.
+    pub fn finalize_async(self) -> AsyncClient<T>
+        where T::Stream: Send + 'static
+    {
+        let id = ClientId::new();
+        let (timer, wakeups) = mpsc::channel();
+        let shared = Shared {
+            id: id,
+            cluster: self.cluster,
+            connection: None,
+            connecting: false,
+            generation: 0,
+            next_request: 1,
+            session: id,
+            sequence: 0,
+            pending: BTreeMap::new(),
+            closed: false,
+            connect_timeout: Duration::from_millis(self.connect_timeout_millis),
+            deadline: Duration::from_millis(self.deadline_millis),
+            max_retries: self.max_retries,
+            backoff: Backoff::with_duration_range(self.backoff_initial_millis,
+                                                  self.backoff_max_millis),
+            timer: timer,
+            transport: self.transport,
+        };
+        let shared = Arc::new(Mutex::new(shared));
+        let handle = shared.clone();
+        thread::spawn(move || expire_requests(handle, wakeups));
+        AsyncClient {
+            id: id,
+            shared: shared,
+        }
+    }
+
+    /// Sets how long the client waits to establish a connection to a server. Defaults to 1.5
+    /// seconds.
+    pub fn with_connect_timeout_millis(mut self, timeout: u64) -> ClientBuilder<T> {
+        self.connect_timeout_millis = timeout;
+        self
+    }
+
+    /// Sets how long the client waits for a server to answer a request before trying again.
+    /// Proposals are only answered once committed, so this should allow for a round of
+    /// replication. Defaults to 1.5 seconds.
+    pub fn with_request_timeout_millis(mut self, timeout: u64) -> ClientBuilder<T> {
+        self.request_timeout_millis = timeout;
+        self
+    }
+
+    /// Sets how long the client keeps trying a request, retries included, before giving up.
+    /// Defaults to 10 seconds.
+    pub fn with_deadline_millis(mut self, deadline: u64) -> ClientBuilder<T> {
+        self.deadline_millis = deadline;
+        self
+    }
+
+    /// Sets how many times the client retries a request after a failed attempt, such as a
+    /// refused connection, a timeout, or a server which does not know the leader. Redirects to
+    /// the leader count as retries, but are followed without waiting. Defaults to 10.
+    pub fn with_max_retries(mut self, retries: u32) -> ClientBuilder<T> {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Sets the range of the randomized exponential backoff between retries. Defaults to 50
+    /// milliseconds initially, growing to at most a second.
+    pub fn with_backoff_millis(mut self, initial: u32, max: u32) -> ClientBuilder<T> {
+        self.backoff_initial_millis = initial;
+        self.backoff_max_millis = max;
+        self
+    }
+
+    /// Sets the transport over which the client connects to the servers. Defaults to
+    /// `TcpTransport`; it must be the kind of transport the servers listen on.
+    pub fn with_transport<U>(self, transport: U) -> ClientBuilder<U>
+        where U: Transport
+    {
+        ClientBuilder {
+            cluster: self.cluster,
+            connect_timeout_millis: self.connect_timeout_millis,
+            request_timeout_millis: self.request_timeout_millis,
+            deadline_millis: self.deadline_millis,
+            max_retries: self.max_retries,
+            backoff_initial_millis: self.backoff_initial_millis,
+            backoff_max_millis: self.backoff_max_millis,
+            transport: transport,
+        }
+    }
+}
 
 /// The representation of a Client connection to the cluster.
-pub struct Client {
+pub struct Client<T = TcpTransport>
+    where T: Transport
+{
     /// The `Uuid` of the client, should be unique in the cluster.
     pub id: ClientId,
     /// The current connection to the current leader.
     /// If it is `None`, there may be no established leader, or a connection
     /// issue.
-    leader_connection: Option<BufStream<TcpStream>>,
+    leader_connection: Option<Connection<T::Stream>>,
     /// A lookup for the cluster's nodes.
     cluster: HashSet<SocketAddr>,
+    /// The ID of the client's session. A new session is started once the session expires.
+    session: ClientId,
+    /// The sequence number of the latest proposal. Retries of a proposal reuse its sequence
+    /// number, so that the cluster applies it only once.
+    sequence: u64,
+    /// How long to wait for a connection to a server.
+    connect_timeout: Duration,
+    /// How long to wait for a server to answer.
+    request_timeout: Duration,
+    /// How long to keep trying a request.
+    deadline: Duration,
+    /// How many times to retry a request.
+    max_retries: u32,
+    /// The wait between retries.
+    backoff: Backoff,
+    /// The transport connections are opened over.
+    transport: T,
 }
 
 impl Client {
-    /// Creates a new client.
+    /// Creates a new client with the default timeouts and retry policy.
     pub fn new(cluster: HashSet<SocketAddr>) -> Client {
-        Client {
-            id: ClientId::new(),
-            leader_connection: None,
-            cluster: cluster,
-        }
+        Client::builder(cluster).finalize()
     }
 
+    /// Returns a builder for a client with non-default timeouts or retry policy.
+    pub fn builder(cluster: HashSet<SocketAddr>) -> ClientBuilder {
+        ClientBuilder::new(cluster)
+    }
+}
+
+impl<T> Client<T>
+    where T: Transport
+{
     /// Proposes an entry to be appended to the replicated log. This will only
-    /// return once the entry has been durably committed.
-    /// Returns `Error` when the entire cluster has an unknown leader. Try proposing again later.
+    /// return once the entry has been durably committed. The entry is applied to the state
+    /// machine once, however many times it is resent while looking for the leader.
+    /// Returns an error when no leader answers within the client's deadline and retry budget;
+    /// `RaftError::maybe_applied` tells whether the proposal may have been applied regardless.
+    /// Returns `RaftError::SessionExpired` if the client's session expired before the proposal
+    /// was applied, in which case the next proposal starts a new session.
     pub fn propose(&mut self, entry: &[u8]) -> Result<Vec<u8>> {
         scoped_trace!("{:?}: propose", self);
-        let mut message = messages::proposal_request(entry);
+        self.sequence += 1;
+        // Every earlier proposal has been answered, or given up on.
+        let mut message = messages::session_proposal_request(entry,
+                                                             self.session,
+                                                             self.sequence,
+                                                             self.sequence);
         self.send_message(&mut message)
     }
 
     /// Queries an entry from the state machine. This is non-mutating and doesn't go through the
-    /// durable log. Like `.propose()` this will only communicate with the leader of the cluster.
+    /// durable log. Like `.propose()` this will only communicate with the leader of the cluster,
+    /// which confirms its leadership with a majority of the cluster before answering, so the
+    /// result reflects every proposal committed before the query was sent.
     pub fn query(&mut self, query: &[u8]) -> Result<Vec<u8>> {
-        scoped_trace!("{:?}: query", self);
-        let mut message = messages::query_request(query);
+        self.query_with(query, Consistency::Linearizable)
+    }
+
+    /// Queries the state machine with the given consistency. Relaxed queries are answered by
+    /// whichever server the client is connected to, without a trip through the leader for
+    /// `Consistency::AnyReplica`, which offloads reads from the leader.
+    pub fn query_with(&mut self, query: &[u8], consistency: Consistency) -> Result<Vec<u8>> {
+        scoped_trace!("{:?}: query with {:?}", self, consistency);
+        let mut message = messages::query_request(query, consistency);
         self.send_message(&mut message)
     }
 
+    /// Adds a server to the cluster configuration. This will only return once the new
+    /// configuration has been durably committed. The server should have been started with
+    /// `ServerBuilder::joining`.
+    /// Returns `RaftError::RequestRejected` if another configuration change is in progress.
+    pub fn add_server(&mut self, id: ServerId, addr: SocketAddr) -> Result<()> {
+        scoped_trace!("{:?}: add_server", self);
+        let mut message = messages::add_server_request(id, &addr, false);
+        try!(self.send_message(&mut message));
+        self.cluster.insert(addr);
+        Ok(())
+    }
+
+    /// Adds a server to the cluster configuration as a non-voting learner, which receives
+    /// entries but does not take part in elections or commitment. This will only return once the
+    /// new configuration has been durably committed.
+    pub fn add_learner(&mut self, id: ServerId, addr: SocketAddr) -> Result<()> {
+        scoped_trace!("{:?}: add_learner", self);
+        let mut message = messages::add_server_request(id, &addr, true);
+        try!(self.send_message(&mut message));
+        self.cluster.insert(addr);
+        Ok(())
+    }
+
+    /// Promotes a learner to a voting member. The leader refuses with
+    /// `RaftError::RequestRejected` unless the learner has caught up; see `.learner_status()`.
+    pub fn promote_server(&mut self, id: ServerId) -> Result<()> {
+        scoped_trace!("{:?}: promote_server", self);
+        let mut message = messages::promote_server_request(id);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Returns how far a learner has caught up with the leader's log.
+    pub fn learner_status(&mut self, id: ServerId) -> Result<LearnerStatus> {
+        scoped_trace!("{:?}: learner_status", self);
+        let mut message = messages::learner_status_request(id);
+        let status = try!(self.send_message(&mut message));
+        LearnerStatus::from_bytes(&status)
+    }
+
+    /// Removes a server from the cluster configuration. This will only return once the new
+    /// configuration has been durably committed.
+    /// Returns `RaftError::RequestRejected` if another configuration change is in progress.
+    pub fn remove_server(&mut self, id: ServerId) -> Result<()> {
+        scoped_trace!("{:?}: remove_server", self);
+        let mut message = messages::remove_server_request(id);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Transfers leadership to the voting member `id`. This returns once the leader has brought
+    /// the target up to date and told it to start an election; new proposals are rejected
+    /// meanwhile.
+    /// Returns `RaftError::RequestRejected` if the target cannot take over, or did not catch up
+    /// within the minimum election timeout.
+    pub fn transfer_leadership(&mut self, id: ServerId) -> Result<()> {
+        scoped_trace!("{:?}: transfer_leadership", self);
+        let mut message = messages::transfer_leadership_request(id);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Connects to a server, and sends the connection preamble.
+    fn connect(&self, addr: SocketAddr, deadline: Instant) -> Result<Connection<T::Stream>> {
+        scoped_debug!("connecting to potential leader {}", addr);
+        let timeout = try!(remaining(deadline, self.connect_timeout));
+        let mut connection = try!(Connection::open(&self.transport, addr, self.id));
+        connection.set_timeout(Some(timeout));
+        try!(connection.wait_written());
+        scoped_debug!("connected");
+        Ok(connection)
+    }
+
+    /// Counts a failed attempt against the retry budget, and waits out the backoff if `wait`
+    /// is set. Returns the error to give up with once the budget or the deadline is exhausted.
+    fn retry(&mut self,
+             retries: &mut u32,
+             deadline: Instant,
+             maybe_applied: bool,
+             wait: bool)
+             -> Result<()> {
+        let now = Instant::now();
+        if now >= deadline || *retries >= self.max_retries {
+            let error = if maybe_applied {
+                RaftError::MaybeApplied
+            } else if now >= deadline {
+                RaftError::DeadlineExceeded
+            } else {
+                RaftError::LeaderSearchExhausted
+            };
+            return Err(error.into());
+        }
+        *retries += 1;
+        if wait {
+            let backoff = Duration::from_millis(self.backoff.next_backoff_ms());
+            thread::sleep(cmp::min(backoff, deadline - now));
+        }
+        Ok(())
+    }
+
     fn send_message<A>(&mut self, message: &mut Builder<A>) -> Result<Vec<u8>>
         where A: Allocator
     {
-        let mut members = self.cluster.iter().cloned();
+        let deadline = Instant::now() + self.deadline;
+        let members: Vec<SocketAddr> = self.cluster.iter().cloned().collect();
+        let mut next_member = 0;
+        let mut retries = 0;
+        // Whether the request reached a server which did not answer, and may have applied it.
+        let mut maybe_applied = false;
+        self.backoff.reset();
 
         loop {
             // We presume in this loop that most errors are temporary and it may take a redirect
             // (or more!) to find a leader in bad network conditions.
-            // TODO: Have timouts.
             let mut connection = match self.leader_connection.take() {
                 Some(cxn) => {
-                    scoped_debug!("had existing connection {:?}", cxn.get_ref().peer_addr());
+                    scoped_debug!("had existing connection to {}", cxn.addr);
                     cxn
                 }
                 None => {
-                    let leader = try!(members.next().ok_or(RaftError::LeaderSearchExhausted));
-                    scoped_debug!("connecting to potential leader {}", leader);
-                    // Send the preamble.
-                    let preamble = messages::client_connection_preamble(self.id);
-                    let mut stream = match TcpStream::connect(leader) {
-                        Ok(stream) => {
-                            let timeout = Some(Duration::from_millis(CLIENT_TIMEOUT));
-                            if stream.set_read_timeout(timeout).is_err() {
-                                continue;
-                            }
-                            BufStream::new(stream)
+                    if members.is_empty() {
+                        return Err(RaftError::LeaderSearchExhausted.into());
+                    }
+                    let leader = members[next_member % members.len()];
+                    next_member += 1;
+                    match self.connect(leader, deadline) {
+                        Ok(stream) => stream,
+                        Err(_) => {
+                            try!(self.retry(&mut retries, deadline, maybe_applied, true));
+                            continue;
                         }
-                        Err(_) => continue,
-                    };
-                    scoped_debug!("connected");
-                    if serialize::write_message(&mut stream, &*preamble).is_err() {
-                        continue;
-                    };
-                    stream
+                    }
                 }
             };
-            if serialize::write_message(&mut connection, message).is_err() {
-                continue;
-            };
-            if connection.flush().is_err() {
-                continue;
+            let timeout = match remaining(deadline, self.request_timeout) {
+                Ok(timeout) => Some(timeout),
+                Err(_) => {
+                    try!(self.retry(&mut retries, deadline, maybe_applied, false));
+                    continue;
+                }
             };
+            connection.set_timeout(timeout);
+            let sent = connection.writer.send(frame(message));
             scoped_debug!("awaiting response from connection");
-            let response = match serialize::read_message(&mut connection, ReaderOptions::new()) {
+            let response = sent.map_err(From::from).and_then(|_| connection.receive());
+            let response = match response {
                 Ok(res) => res,
-                Err(_) => continue,
+                Err(_) => {
+                    // The request may have been delivered before the connection failed.
+                    maybe_applied = true;
+                    try!(self.retry(&mut retries, deadline, maybe_applied, true));
+                    continue;
+                }
             };
             let reader = match response.get_root::<client_response::Reader>() {
                 Ok(reader) => reader,
-                Err(_) => continue,
+                Err(_) => {
+                    maybe_applied = true;
+                    try!(self.retry(&mut retries, deadline, maybe_applied, true));
+                    continue;
+                }
             };
             match reader.which() {
                 Ok(client_response::Which::Proposal(Ok(status))) => {
@@ -122,54 +428,845 @@ impl Client {
                         }
                         Ok(command_response::Which::UnknownLeader(())) => {
                             scoped_debug!("received response UnknownLeader");
-                            () // Keep looping.
+                            try!(self.retry(&mut retries, deadline, maybe_applied, true));
                         }
                         Ok(command_response::Which::NotLeader(leader)) => {
                             scoped_debug!("received response NotLeader");
-                            let leader_str = try!(leader);
-                            if !self.cluster.contains(&try!(SocketAddr::from_str(leader_str))) {
+                            let leader = try!(SocketAddr::from_str(try!(leader)));
+                            if !self.cluster.contains(&leader) {
                                 scoped_debug!("cluster violation detected");
                                 return Err(RaftError::ClusterViolation.into()); // Exit the function.
                             }
-                            let mut connection: TcpStream = try!(TcpStream::connect(leader_str));
-                            let preamble = messages::client_connection_preamble(self.id);
-                            if serialize::write_message(&mut connection, &*preamble).is_err() {
-                                continue;
-                            };
-                            self.leader_connection = Some(BufStream::new(connection));
+                            try!(self.retry(&mut retries, deadline, maybe_applied, false));
+                            match self.connect(leader, deadline) {
+                                Ok(stream) => self.leader_connection = Some(stream),
+                                Err(_) => {
+                                    try!(self.retry(&mut retries, deadline, maybe_applied, true))
+                                }
+                            }
+                        }
+                        Ok(command_response::Which::Rejected(reason)) => {
+                            scoped_debug!("received response Rejected");
+                            self.leader_connection = Some(connection);
+                            let reason = try!(reason).to_owned();
+                            return Err(RaftError::RequestRejected(reason).into());
+                        }
+                        Ok(command_response::Which::SessionExpired(())) => {
+                            scoped_debug!("received response SessionExpired");
+                            self.leader_connection = Some(connection);
+                            self.session = ClientId::new();
+                            self.sequence = 0;
+                            return Err(RaftError::SessionExpired.into());
+                        }
+                        Err(_) => {
+                            maybe_applied = true;
+                            try!(self.retry(&mut retries, deadline, maybe_applied, true));
                         }
-                        Err(_) => continue,
                     }
                 }
-                _ => panic!("Unexpected message type"), // TODO: return a proper error
+                _ => {
+                    scoped_warn!("received unexpected response type");
+                    return Err(RaftError::MalformedMessage.into());
+                }
             };
         }
     }
 }
 
-impl fmt::Debug for Client {
+/// Returns the time left until `deadline`, at most `timeout`, or `RaftError::DeadlineExceeded`
+/// if the deadline has passed.
+fn remaining(deadline: Instant, timeout: Duration) -> Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(RaftError::DeadlineExceeded.into());
+    }
+    Ok(cmp::min(timeout, deadline - now))
+}
+
+impl<T> fmt::Debug for Client<T>
+    where T: Transport
+{
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "Client({})", self.id)
     }
 }
 
+/// The stream of a connection from a client to a server, shared between the threads writing
+/// requests to it.
+struct Stream<S> {
+    /// The stream, or `None` once the connection has been shut down.
+    stream: Option<Framed<S>>,
+    /// Wakes the connection's reader once the connection is shut down.
+    wakeup: SetReadiness,
+}
+
+/// The error an operation on a connection fails with once the connection has been shut down.
+fn shut_down() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection shut down")
+}
+
+/// Writes requests to a connection. It may be cloned and used from any thread.
+struct ConnectionWriter<S> {
+    stream: Arc<Mutex<Stream<S>>>,
+}
+
+impl<S> ConnectionWriter<S>
+    where S: Read + Write
+{
+    /// Queues a frame made with `transport::frame`, and writes as much of it as the stream
+    /// accepts. The connection's reader writes the rest once the stream becomes writable.
+    fn send(&self, frame: Vec<u8>) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        match stream.stream {
+            Some(ref mut framed) => framed.send_frame(frame),
+            None => Err(shut_down()),
+        }
+    }
+
+    /// Closes the connection, and wakes its reader.
+    fn shutdown(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.stream = None;
+        let _ = stream.wakeup.set_readiness(Ready::readable());
+    }
+}
+
+impl<S> Clone for ConnectionWriter<S> {
+    fn clone(&self) -> ConnectionWriter<S> {
+        ConnectionWriter { stream: self.stream.clone() }
+    }
+}
+
+/// A connection from a client to a server, over a stream opened by the client's `Transport`.
+/// The stream does not block, so the connection waits for it to become ready with a `Poll` of
+/// its own. Receiving from the connection also writes the outgoing frames, whichever thread
+/// queued them.
+struct Connection<S> {
+    /// The address of the server.
+    addr: SocketAddr,
+    writer: ConnectionWriter<S>,
+    poll: Poll,
+    events: Events,
+    /// How long to wait for the stream to become ready, or `None` to wait indefinitely.
+    timeout: Option<Duration>,
+    /// Kept for the wakeups to be delivered.
+    #[allow(dead_code)]
+    registration: Registration,
+}
+
+impl<S> Connection<S>
+    where S: Read + Write + Evented
+{
+    /// Opens a connection to the server at `addr` over the transport, and queues the connection
+    /// preamble. The connection may complete asynchronously.
+    fn open<T>(transport: &T, addr: SocketAddr, id: ClientId) -> io::Result<Connection<S>>
+        where T: Transport<Stream = S>
+    {
+        let stream = try!(transport.connect(&addr));
+        let poll = try!(Poll::new());
+        try!(poll.register(&stream,
+                           STREAM,
+                           Ready::readable() | Ready::writable(),
+                           PollOpt::edge()));
+        let (registration, wakeup) = Registration::new2();
+        try!(poll.register(&registration, WAKEUP, Ready::readable(), PollOpt::edge()));
+        let stream = Stream {
+            stream: Some(Framed::new(stream)),
+            wakeup: wakeup,
+        };
+        let connection = Connection {
+            addr: addr,
+            writer: ConnectionWriter { stream: Arc::new(Mutex::new(stream)) },
+            poll: poll,
+            events: Events::with_capacity(16),
+            timeout: None,
+            registration: registration,
+        };
+        try!(connection.writer.send(frame(&*messages::client_connection_preamble(id))));
+        Ok(connection)
+    }
+
+    /// Sets how long to wait for the stream to become ready, or `None` to wait indefinitely.
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Waits until the stream has accepted every outgoing frame.
+    fn wait_written(&mut self) -> io::Result<()> {
+        loop {
+            {
+                let mut stream = self.writer.stream.lock().unwrap();
+                let framed = match stream.stream {
+                    Some(ref mut framed) => framed,
+                    None => return Err(shut_down()),
+                };
+                try!(framed.flush());
+                if framed.queued() == 0 {
+                    return Ok(());
+                }
+            }
+            try!(self.wait());
+        }
+    }
+
+    /// Receives a message, waiting for the stream to become readable if none has arrived.
+    /// Fails once the connection is shut down.
+    fn receive(&mut self) -> Result<Reader<OwnedSegments>> {
+        loop {
+            {
+                let mut stream = self.writer.stream.lock().unwrap();
+                let framed = match stream.stream {
+                    Some(ref mut framed) => framed,
+                    None => return Err(shut_down().into()),
+                };
+                try!(framed.flush());
+                if let Some(message) = try!(framed.receive()) {
+                    return Ok(message);
+                }
+            }
+            try!(self.wait());
+        }
+    }
+
+    /// Waits for the stream to become ready, or the connection to be shut down. Fails with
+    /// `TimedOut` if the timeout passes first.
+    fn wait(&mut self) -> io::Result<()> {
+        try!(self.poll.poll(&mut self.events, self.timeout));
+        if self.events.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for server"));
+        }
+        Ok(())
+    }
+}
+
+/// The response to a request sent with an `AsyncClient`, which may not have arrived yet.
+pub struct Response {
+    receiver: mpsc::Receiver<Result<Vec<u8>>>,
+}
+
+impl Response {
+    /// Blocks until the response arrives.
+    pub fn wait(self) -> Result<Vec<u8>> {
+        self.receiver.recv().unwrap_or_else(|_| Err(RaftError::ClientClosed.into()))
+    }
+
+    /// Returns the response if it has arrived, without blocking.
+    pub fn poll(&self) -> Option<Result<Vec<u8>>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(RaftError::ClientClosed.into())),
+        }
+    }
+}
+
+/// A callback invoked with the response to a request sent with an `AsyncClient`.
+trait Callback: Send {
+    fn call(self: Box<Self>, result: Result<Vec<u8>>);
+}
+
+impl<F> Callback for F
+    where F: FnOnce(Result<Vec<u8>>) + Send
+{
+    fn call(self: Box<Self>, result: Result<Vec<u8>>) {
+        (*self)(result)
+    }
+}
+
+/// A request which has not been answered yet. It is kept so that it can be resent to a new
+/// leader.
+struct PendingRequest {
+    /// The request, framed with `transport::frame`.
+    message: Vec<u8>,
+    /// The session and the sequence number of the request, if it is a proposal.
+    sequence: Option<(ClientId, u64)>,
+    /// When the request fails if it has not been answered.
+    deadline: Instant,
+    /// The number of failed attempts so far.
+    retries: u32,
+    /// Whether the request has been written to the current connection, and not answered yet.
+    written: bool,
+    /// Whether the request was written to an earlier connection which was abandoned without an
+    /// answer, so that it may have been applied.
+    maybe_applied: bool,
+    callback: Box<Callback>,
+}
+
+impl PendingRequest {
+    /// Returns the error to fail the request with: `error`, unless the request is a proposal
+    /// which may have been applied.
+    fn failure(&self, error: RaftError) -> RaftError {
+        if self.sequence.is_some() && (self.written || self.maybe_applied) {
+            RaftError::MaybeApplied
+        } else {
+            error
+        }
+    }
+}
+
+/// Callbacks to invoke once the client's lock has been released, so that they may send further
+/// requests.
+type Completions = Vec<(Box<Callback>, Result<Vec<u8>>)>;
+
+/// The state an `AsyncClient` shares with the thread reading responses from its connection.
+struct Shared<T>
+    where T: Transport
+{
+    id: ClientId,
+    cluster: HashSet<SocketAddr>,
+    /// The connection to the presumed leader.
+    connection: Option<ConnectionWriter<T::Stream>>,
+    /// Whether a connection is being opened. Requests sent meanwhile are sent once it opens.
+    connecting: bool,
+    /// Incremented with every new connection, so that the reader threads of previous
+    /// connections know to exit.
+    generation: u64,
+    /// The ID of the next request.
+    next_request: u64,
+    /// The ID of the client's session. A new session is started once the session expires.
+    session: ClientId,
+    /// The sequence number of the latest proposal in the session.
+    sequence: u64,
+    /// The requests in flight, by request ID.
+    pending: BTreeMap<u64, PendingRequest>,
+    /// Whether the client has been dropped.
+    closed: bool,
+    /// How long to wait for a connection to a server.
+    connect_timeout: Duration,
+    /// How long to keep trying a request.
+    deadline: Duration,
+    /// How many times to retry a request.
+    max_retries: u32,
+    /// The wait before looking for the leader again.
+    backoff: Backoff,
+    /// Wakes the thread which fails requests once their deadline passes.
+    timer: mpsc::Sender<()>,
+    /// The transport connections are opened over.
+    transport: T,
+}
+
+impl<T> Shared<T>
+    where T: Transport
+{
+    /// Returns the lowest sequence number among the proposals of the session in flight, below
+    /// which every proposal has been answered. The latest proposal counts as in flight even
+    /// before it is sent, so that it is never acknowledged ahead of its own application.
+    fn acknowledged(&self) -> u64 {
+        self.pending
+            .values()
+            .filter_map(|request| match request.sequence {
+                Some((session, sequence)) if session == self.session => Some(sequence),
+                _ => None,
+            })
+            .min()
+            .unwrap_or(self.sequence)
+    }
+
+    /// Fails every request in flight.
+    fn fail_pending<F>(&mut self, error: F, completions: &mut Completions)
+        where F: Fn() -> RaftError
+    {
+        for (_, request) in ::std::mem::replace(&mut self.pending, BTreeMap::new()) {
+            completions.push((request.callback, Err(error().into())));
+        }
+    }
+
+    /// Counts a failed attempt against the retry budget of every request in flight, and fails
+    /// the requests which have exhausted it.
+    fn retry_pending(&mut self, completions: &mut Completions) {
+        let max_retries = self.max_retries;
+        let exhausted: Vec<u64> = self.pending
+                                      .iter_mut()
+                                      .filter_map(|(&id, request)| {
+                                          if request.retries >= max_retries {
+                                              return Some(id);
+                                          }
+                                          request.retries += 1;
+                                          None
+                                      })
+                                      .collect();
+        for id in exhausted {
+            let request = self.pending.remove(&id).unwrap();
+            let error = request.failure(RaftError::LeaderSearchExhausted);
+            completions.push((request.callback, Err(error.into())));
+        }
+    }
+}
+
+/// Replaces the connection to the leader. The new connection is opened on a thread of its own,
+/// trying `hint` first and then every member of the cluster, so that the client's lock is not
+/// held while connecting. If `wait` is set, the thread first waits out the backoff.
+fn reconnect<T>(handle: &Arc<Mutex<Shared<T>>>,
+                shared: &mut Shared<T>,
+                hint: Option<SocketAddr>,
+                wait: bool)
+    where T: Transport,
+          T::Stream: Send + 'static
+{
+    shared.generation += 1;
+    shared.connecting = true;
+    if let Some(connection) = shared.connection.take() {
+        connection.shutdown();
+    }
+    for request in shared.pending.values_mut() {
+        request.maybe_applied |= request.written;
+        request.written = false;
+    }
+    let candidates: Vec<SocketAddr> = hint.into_iter()
+                                          .chain(shared.cluster.iter().cloned())
+                                          .collect();
+    let pause = if wait {
+        Some(Duration::from_millis(shared.backoff.next_backoff_ms()))
+    } else {
+        None
+    };
+    let handle = handle.clone();
+    let generation = shared.generation;
+    let id = shared.id;
+    let timeout = shared.connect_timeout;
+    thread::spawn(move || connect(handle, generation, id, candidates, timeout, pause));
+}
+
+/// Connects to the first of `candidates` which accepts a connection, resends the requests in
+/// flight over it, and then reads the responses. If no server can be reached, the attempt counts
+/// against the requests' retry budget, and is repeated after the backoff.
+fn connect<T>(handle: Arc<Mutex<Shared<T>>>,
+              generation: u64,
+              id: ClientId,
+              candidates: Vec<SocketAddr>,
+              timeout: Duration,
+              mut pause: Option<Duration>)
+    where T: Transport,
+          T::Stream: Send + 'static
+{
+    loop {
+        if let Some(pause) = pause {
+            thread::sleep(pause);
+        }
+        if try_connect(&handle, generation, id, &candidates, timeout) {
+            return;
+        }
+        let mut completions = Vec::new();
+        {
+            let mut shared = handle.lock().unwrap();
+            if shared.generation != generation || shared.closed {
+                return;
+            }
+            shared.retry_pending(&mut completions);
+            pause = if shared.pending.is_empty() {
+                shared.connecting = false;
+                None
+            } else {
+                Some(Duration::from_millis(shared.backoff.next_backoff_ms()))
+            };
+        }
+        complete(completions);
+        if pause.is_none() {
+            return;
+        }
+    }
+}
+
+/// Makes one attempt at connecting to each of `candidates` in turn, and reads the responses
+/// arriving over the first connection which opens. Returns false if no server could be reached,
+/// and true once the connection closes or another connection supersedes the attempt.
+fn try_connect<T>(handle: &Arc<Mutex<Shared<T>>>,
+                  generation: u64,
+                  id: ClientId,
+                  candidates: &[SocketAddr],
+                  timeout: Duration)
+                  -> bool
+    where T: Transport,
+          T::Stream: Send + 'static
+{
+    for &addr in candidates {
+        scoped_debug!("connecting to potential leader {}", addr);
+        let connection = {
+            let shared = handle.lock().unwrap();
+            if shared.generation != generation || shared.closed {
+                return true;
+            }
+            // Opening the connection does not block; it completes below, without the lock.
+            Connection::open(&shared.transport, addr, id)
+        };
+        let mut connection = match connection {
+            Ok(connection) => connection,
+            Err(_) => continue,
+        };
+        connection.set_timeout(Some(timeout));
+        if connection.wait_written().is_err() {
+            continue;
+        }
+        connection.set_timeout(None);
+        {
+            let mut shared = handle.lock().unwrap();
+            if shared.generation != generation || shared.closed {
+                connection.writer.shutdown();
+                return true;
+            }
+            let writer = connection.writer.clone();
+            let resent = shared.pending.values_mut().all(|request| {
+                request.written = writer.send(request.message.clone()).is_ok();
+                request.written
+            });
+            if !resent {
+                for request in shared.pending.values_mut() {
+                    request.maybe_applied |= request.written;
+                    request.written = false;
+                }
+                writer.shutdown();
+                continue;
+            }
+            shared.connecting = false;
+            shared.connection = Some(writer);
+        }
+        read_responses(handle.clone(), generation, connection);
+        return true;
+    }
+    false
+}
+
+/// Fails the requests whose deadline has passed, waking up whenever a request is sent or the
+/// earliest deadline passes. Exits once the client is dropped.
+fn expire_requests<T>(handle: Arc<Mutex<Shared<T>>>, wakeups: mpsc::Receiver<()>)
+    where T: Transport
+{
+    loop {
+        let mut completions = Vec::new();
+        let next_deadline = {
+            let mut shared = handle.lock().unwrap();
+            if shared.closed {
+                return;
+            }
+            let now = Instant::now();
+            let expired: Vec<u64> = shared.pending
+                                          .iter()
+                                          .filter(|&(_, request)| request.deadline <= now)
+                                          .map(|(&id, _)| id)
+                                          .collect();
+            for id in expired {
+                let request = shared.pending.remove(&id).unwrap();
+                let error = request.failure(RaftError::DeadlineExceeded);
+                completions.push((request.callback, Err(error.into())));
+            }
+            shared.pending.values().map(|request| request.deadline).min()
+        };
+        complete(completions);
+        match next_deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline > now {
+                    let _ = wakeups.recv_timeout(deadline - now);
+                }
+            }
+            None => {
+                let _ = wakeups.recv();
+            }
+        }
+    }
+}
+
+/// Reads the responses arriving over a connection, and hands them to the matching requests'
+/// callbacks. Exits once the connection is replaced or closed.
+fn read_responses<T>(handle: Arc<Mutex<Shared<T>>>,
+                     generation: u64,
+                     mut connection: Connection<T::Stream>)
+    where T: Transport,
+          T::Stream: Send + 'static
+{
+    loop {
+        let message = connection.receive();
+        let mut completions = Vec::new();
+        let mut exit = false;
+        {
+            let mut shared = handle.lock().unwrap();
+            if shared.generation != generation || shared.closed {
+                return;
+            }
+            let response = message.as_ref()
+                                  .map_err(|_| ())
+                                  .and_then(|message| {
+                                      message.get_root::<client_response::Reader>()
+                                             .map_err(|_| ())
+                                  });
+            let response = match response {
+                Ok(response) => response,
+                Err(()) => {
+                    scoped_debug!("connection to the leader failed; reconnecting");
+                    reconnect(&handle, &mut shared, None, true);
+                    shared.retry_pending(&mut completions);
+                    drop(shared);
+                    complete(completions);
+                    return;
+                }
+            };
+            let id = response.get_id();
+            let status = match response.which() {
+                Ok(client_response::Which::Proposal(Ok(status))) |
+                Ok(client_response::Which::Query(Ok(status))) => status,
+                _ => {
+                    scoped_warn!("unexpected response to request {}", id);
+                    continue;
+                }
+            };
+            match status.which() {
+                Ok(command_response::Which::Success(data)) => {
+                    shared.backoff.reset();
+                    if let Some(request) = shared.pending.remove(&id) {
+                        let result = data.map(Vec::from).map_err(From::from);
+                        completions.push((request.callback, result));
+                    }
+                }
+                Ok(command_response::Which::Rejected(reason)) => {
+                    if let Some(request) = shared.pending.remove(&id) {
+                        let reason = reason.map(|reason| reason.to_owned()).unwrap_or_default();
+                        completions.push((request.callback,
+                                          Err(RaftError::RequestRejected(reason).into())));
+                    }
+                }
+                Ok(command_response::Which::SessionExpired(())) => {
+                    if let Some(request) = shared.pending.remove(&id) {
+                        // Later proposals start a new session, unless one was started already.
+                        let current = shared.session;
+                        if request.sequence.map_or(false, |(session, _)| session == current) {
+                            shared.session = ClientId::new();
+                            shared.sequence = 0;
+                        }
+                        completions.push((request.callback,
+                                          Err(RaftError::SessionExpired.into())));
+                    }
+                }
+                Ok(command_response::Which::NotLeader(leader)) => {
+                    let leader = leader.ok().and_then(|leader| SocketAddr::from_str(leader).ok());
+                    match leader {
+                        Some(leader) if shared.cluster.contains(&leader) => {
+                            // Redirects count as retries, but are followed without waiting.
+                            scoped_debug!("redirected to leader {}", leader);
+                            if let Some(request) = shared.pending.get_mut(&id) {
+                                request.written = false;
+                            }
+                            reconnect(&handle, &mut shared, Some(leader), false);
+                            shared.retry_pending(&mut completions);
+                            exit = true;
+                        }
+                        _ => {
+                            scoped_debug!("cluster violation detected");
+                            shared.fail_pending(|| RaftError::ClusterViolation, &mut completions);
+                            shared.generation += 1;
+                            if let Some(connection) = shared.connection.take() {
+                                connection.shutdown();
+                            }
+                        }
+                    }
+                }
+                Ok(command_response::Which::UnknownLeader(())) => {
+                    // The cluster is electing a leader; look for it once the election had time
+                    // to end.
+                    if let Some(request) = shared.pending.get_mut(&id) {
+                        request.written = false;
+                    }
+                    reconnect(&handle, &mut shared, None, true);
+                    shared.retry_pending(&mut completions);
+                    exit = true;
+                }
+                Err(_) => scoped_warn!("unable to decode the response to request {}", id),
+            }
+        }
+        complete(completions);
+        if exit {
+            return;
+        }
+    }
+}
+
+/// Invokes the callbacks of the answered requests.
+fn complete(completions: Completions) {
+    for (callback, result) in completions {
+        callback.call(result);
+    }
+}
+
+/// A client which keeps many requests in flight on a single connection to the leader, without
+/// blocking the caller. Each response is matched to its request by request ID, and handed to
+/// the request's callback, or returned through a `Response`.
+///
+/// Requests are resent to the new leader if the connection fails or the server turns out not
+/// to be the leader; proposals carry session sequence numbers, so they are applied only once.
+/// A request which has not been answered within the deadline fails with
+/// `RaftError::DeadlineExceeded`, or `RaftError::MaybeApplied` for a proposal which reached a
+/// server. An `AsyncClient` may be shared between threads.
+pub struct AsyncClient<T = TcpTransport>
+    where T: Transport
+{
+    /// The `Uuid` of the client, should be unique in the cluster.
+    pub id: ClientId,
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl AsyncClient {
+    /// Creates a new client with the default timeouts and retry policy. It connects to the
+    /// cluster with its first request.
+    pub fn new(cluster: HashSet<SocketAddr>) -> AsyncClient {
+        AsyncClient::builder(cluster).finalize_async()
+    }
+
+    /// Returns a builder for a client with non-default timeouts or retry policy; build the
+    /// client with `.finalize_async()`.
+    pub fn builder(cluster: HashSet<SocketAddr>) -> ClientBuilder {
+        ClientBuilder::new(cluster)
+    }
+}
+
+impl<T> AsyncClient<T>
+    where T: Transport,
+          T::Stream: Send + 'static
+{
+    /// Proposes an entry to be appended to the replicated log. The response arrives once the
+    /// entry has been durably committed and applied.
+    pub fn propose(&self, entry: &[u8]) -> Response {
+        let (sender, receiver) = mpsc::channel();
+        self.propose_then(entry, move |result| {
+            let _ = sender.send(result);
+        });
+        Response { receiver: receiver }
+    }
+
+    /// Proposes an entry to be appended to the replicated log, and calls `callback` with the
+    /// result once the entry has been durably committed and applied. The callback may run on the
+    /// calling thread, the client's reader thread or the thread which expires requests, and
+    /// should not block.
+    pub fn propose_then<F>(&self, entry: &[u8], callback: F)
+        where F: FnOnce(Result<Vec<u8>>) + Send + 'static
+    {
+        scoped_trace!("{:?}: propose", self);
+        let mut completions = Vec::new();
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.sequence += 1;
+            let session = shared.session;
+            let sequence = shared.sequence;
+            let acknowledged = shared.acknowledged();
+            let message = messages::session_proposal_request(entry,
+                                                             session,
+                                                             sequence,
+                                                             acknowledged);
+            self.send(&mut shared,
+                      message,
+                      Some((session, sequence)),
+                      Box::new(callback),
+                      &mut completions);
+        }
+        complete(completions);
+    }
+
+    /// Queries the state machine with the given consistency; see `Client::query_with`.
+    pub fn query(&self, query: &[u8], consistency: Consistency) -> Response {
+        let (sender, receiver) = mpsc::channel();
+        self.query_then(query, consistency, move |result| {
+            let _ = sender.send(result);
+        });
+        Response { receiver: receiver }
+    }
+
+    /// Queries the state machine with the given consistency, and calls `callback` with the
+    /// result. The callback may run on the calling thread, the client's reader thread or the
+    /// thread which expires requests, and should not block.
+    pub fn query_then<F>(&self, query: &[u8], consistency: Consistency, callback: F)
+        where F: FnOnce(Result<Vec<u8>>) + Send + 'static
+    {
+        scoped_trace!("{:?}: query with {:?}", self, consistency);
+        let mut completions = Vec::new();
+        {
+            let mut shared = self.shared.lock().unwrap();
+            let message = messages::query_request(query, consistency);
+            self.send(&mut shared, message, None, Box::new(callback), &mut completions);
+        }
+        complete(completions);
+    }
+
+    /// Assigns the request an ID, and sends it to the leader.
+    fn send(&self,
+            shared: &mut Shared<T>,
+            mut message: Builder<HeapAllocator>,
+            sequence: Option<(ClientId, u64)>,
+            callback: Box<Callback>,
+            completions: &mut Completions) {
+        if shared.closed {
+            completions.push((callback, Err(RaftError::ClientClosed.into())));
+            return;
+        }
+        let id = shared.next_request;
+        shared.next_request += 1;
+        message.get_root::<client_request::Builder>().unwrap().set_id(id);
+        let message = frame(&message);
+
+        let sent = match shared.connection {
+            Some(ref connection) => connection.send(message.clone()).is_ok(),
+            None => false,
+        };
+        shared.pending.insert(id,
+                              PendingRequest {
+                                  message: message,
+                                  sequence: sequence,
+                                  deadline: Instant::now() + shared.deadline,
+                                  retries: 0,
+                                  written: sent,
+                                  maybe_applied: false,
+                                  callback: callback,
+                              });
+        let _ = shared.timer.send(());
+        if !sent && !shared.connecting {
+            // Connecting sends every request in flight, including this one.
+            reconnect(&self.shared, shared, None, false);
+        }
+    }
+}
+
+impl<T> Drop for AsyncClient<T>
+    where T: Transport
+{
+    /// Closes the connection, and fails the requests still in flight.
+    fn drop(&mut self) {
+        let mut completions = Vec::new();
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.closed = true;
+            if let Some(connection) = shared.connection.take() {
+                connection.shutdown();
+            }
+            shared.fail_pending(|| RaftError::ClientClosed, &mut completions);
+            let _ = shared.timer.send(());
+        }
+        complete(completions);
+    }
+}
+
+impl<T> fmt::Debug for AsyncClient<T>
+    where T: Transport
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "AsyncClient({})", self.id)
+    }
+}
 
 #[cfg(test)]
 mod tests {
     extern crate env_logger;
 
     use std::collections::HashSet;
-    use std::io::Write;
+    use std::io::{Read, Write};
     use std::net::{TcpStream, TcpListener};
     use std::thread;
+    use std::time::{Duration, Instant};
 
     use uuid::Uuid;
     use capnp::serialize;
     use capnp::message::ReaderOptions;
-    use bufstream::BufStream;
 
-    use {Client, messages, Result};
+    use {AsyncClient, Client, Error, RaftError, ServerId, messages, Result};
     use messages_capnp::{connection_preamble, client_request};
+    use transport::TcpTransport;
+    use super::Connection;
 
     fn expect_preamble(connection: &mut TcpStream, client_id: Uuid) -> Result<bool> {
         let message = try!(serialize::read_message(connection, ReaderOptions::new()));
@@ -216,7 +1313,7 @@ mod tests {
             expect_preamble(&mut connection, client_id).unwrap();
             expect_proposal(&mut connection, to_propose).unwrap();
             // Send response! (success!)
-            let response = messages::command_response_success(b"Foxes");
+            let response = messages::command_response_success(0, b"Foxes");
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush().unwrap();
         });
@@ -238,7 +1335,7 @@ mod tests {
         let test_addr = test_server.local_addr().unwrap();
         cluster.insert(test_addr);
 
-        let mut client = Client::new(cluster);
+        let mut client = Client::builder(cluster).with_max_retries(0).finalize();
         let to_propose = b"Bears";
 
         // The client connects on the proposal.
@@ -250,13 +1347,53 @@ mod tests {
             scoped_debug!("Should get proposal. Responds UnknownLeader");
             expect_proposal(&mut connection, to_propose).unwrap();
             // Send response! (unknown leader!) Client should drop connection.
-            let response = messages::command_response_unknown_leader();
+            let response = messages::command_response_unknown_leader(0);
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush().unwrap();
         });
 
         // Propose. It's a marriage made in heaven! :)
-        assert!(client.propose(to_propose).is_err());
+        // The server answered, so the proposal was certainly not applied.
+        match client.propose(to_propose) {
+            Err(Error::Raft(RaftError::LeaderSearchExhausted)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        child.join().unwrap();
+    }
+
+    /// Tests that a proposal which is never answered fails once the client's deadline passes,
+    /// reporting that it may have been applied.
+    #[test]
+    fn test_proposal_deadline() {
+        setup_test!("test_proposal_deadline");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(test_addr);
+
+        let mut client = Client::builder(cluster)
+                             .with_request_timeout_millis(50)
+                             .with_deadline_millis(300)
+                             .with_backoff_millis(10, 20)
+                             .finalize();
+        let client_id = client.id.0.clone();
+
+        // Accept the connections, but never answer.
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+            expect_preamble(&mut connection, client_id).unwrap();
+            expect_proposal(&mut connection, b"Bears").unwrap();
+            let _ = connection.read_to_end(&mut Vec::new());
+        });
+
+        let start = Instant::now();
+        match client.propose(b"Bears") {
+            Err(Error::Raft(ref error)) if error.maybe_applied() => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(client);
 
         child.join().unwrap();
     }
@@ -287,7 +1424,7 @@ mod tests {
             expect_proposal(&mut connection, to_propose).unwrap();
 
             // Send response! (not leader!)
-            let response = messages::command_response_not_leader(&second_addr);
+            let response = messages::command_response_not_leader(0, &second_addr);
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush().unwrap();
 
@@ -298,17 +1435,13 @@ mod tests {
             expect_proposal(&mut connection, to_propose).unwrap();
 
             // Send final response! (Success!)
-            let response = messages::command_response_success(b"Foxes");
+            let response = messages::command_response_success(0, b"Foxes");
             serialize::write_message(&mut connection, &*response).unwrap();
         });
 
         // Workaround to set up rigged selection of servers.
-        client.leader_connection = {
-            let preamble = messages::client_connection_preamble(client.id);
-            let mut stream = BufStream::new(TcpStream::connect(test_addr).unwrap());
-            serialize::write_message(&mut stream, &*preamble).unwrap();
-            Some(stream)
-        };
+        client.leader_connection = Some(Connection::open(&TcpTransport, test_addr, client.id)
+                                            .unwrap());
 
         // Should be ok, change leader connection.
         assert_eq!(client.propose(to_propose).unwrap(), b"Foxes");
@@ -345,7 +1478,7 @@ mod tests {
             expect_proposal(&mut connection, to_propose).unwrap();
 
             // Send response! (not leader!)
-            let response = messages::command_response_not_leader(&second_addr);
+            let response = messages::command_response_not_leader(0, &second_addr);
             serialize::write_message(&mut connection, &*response).unwrap();
             connection.flush().unwrap();
 
@@ -353,12 +1486,8 @@ mod tests {
         });
 
         // Workaround to set up rigged selection of servers.
-        client.leader_connection = {
-            let preamble = messages::client_connection_preamble(client.id);
-            let mut stream = BufStream::new(TcpStream::connect(test_addr).unwrap());
-            serialize::write_message(&mut stream, &*preamble).unwrap();
-            Some(stream)
-        };
+        client.leader_connection = Some(Connection::open(&TcpTransport, test_addr, client.id)
+                                            .unwrap());
 
         // Should be err, change leader connection but to wrong ip..
         assert!(client.propose(to_propose).is_err());
@@ -366,4 +1495,155 @@ mod tests {
 
         child.join().unwrap();
     }
+
+    /// Tests that a membership change refused by the leader is reported to the caller instead of
+    /// being retried.
+    #[test]
+    fn test_add_server_rejected() {
+        setup_test!("test_add_server_rejected");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(test_addr);
+
+        let mut client = Client::new(cluster);
+        let client_id = client.id.0.clone();
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+            expect_preamble(&mut connection, client_id).unwrap();
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let request = message.get_root::<client_request::Reader>().unwrap();
+            match request.which().unwrap() {
+                client_request::Which::AddServer(Ok(request)) => {
+                    assert_eq!(7, request.get_server().unwrap().get_id());
+                }
+                _ => panic!("expected AddServer request"),
+            }
+
+            let response = messages::command_response_rejected(0, "change in progress");
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush().unwrap();
+        });
+
+        match client.add_server(ServerId(7), test_addr) {
+            Err(Error::Raft(RaftError::RequestRejected(ref reason))) => {
+                assert_eq!("change in progress", reason)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(client.leader_connection.is_some());
+
+        child.join().unwrap();
+    }
+
+    /// Tests that an `AsyncClient` keeps several proposals in flight over one connection, and
+    /// matches responses arriving out of order to their requests.
+    #[test]
+    fn test_async_pipelined_proposals() {
+        setup_test!("test_async_pipelined_proposals");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(test_addr);
+
+        let client = AsyncClient::new(cluster);
+        let client_id = client.id.0.clone();
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+            assert!(expect_preamble(&mut connection, client_id).unwrap());
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let message = serialize::read_message(&mut connection, ReaderOptions::new())
+                                  .unwrap();
+                let request = message.get_root::<client_request::Reader>().unwrap();
+                match request.which().unwrap() {
+                    client_request::Which::Proposal(Ok(proposal)) => {
+                        assert_eq!(ids.len() as u64 + 1, proposal.get_sequence());
+                    }
+                    _ => panic!("expected Proposal request"),
+                }
+                ids.push(request.get_id());
+            }
+            assert!(ids[0] != ids[1]);
+
+            // Answer the second proposal first.
+            let response = messages::command_response_success(ids[1], b"Foxes");
+            serialize::write_message(&mut connection, &*response).unwrap();
+            let response = messages::command_response_success(ids[0], b"Bears");
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush().unwrap();
+        });
+
+        let first = client.propose(b"first");
+        let second = client.propose(b"second");
+        assert_eq!(b"Bears".to_vec(), first.wait().unwrap());
+        assert_eq!(b"Foxes".to_vec(), second.wait().unwrap());
+
+        child.join().unwrap();
+    }
+
+    /// Tests that an `AsyncClient` request fails once it has used up its retries, and that an
+    /// answered proposal is known not to have been applied.
+    #[test]
+    fn test_async_retries() {
+        setup_test!("test_async_retries");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(test_addr);
+
+        let client = AsyncClient::builder(cluster).with_max_retries(0).finalize_async();
+        let client_id = client.id.0.clone();
+
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+            assert!(expect_preamble(&mut connection, client_id).unwrap());
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            let id = message.get_root::<client_request::Reader>().unwrap().get_id();
+            let response = messages::command_response_unknown_leader(id);
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush().unwrap();
+        });
+
+        match client.propose(b"Bears").wait() {
+            Err(Error::Raft(RaftError::LeaderSearchExhausted)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        child.join().unwrap();
+    }
+
+    /// Tests that an `AsyncClient` request which is never answered fails once its deadline
+    /// passes.
+    #[test]
+    fn test_async_deadline() {
+        setup_test!("test_async_deadline");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(test_addr);
+
+        let client = AsyncClient::builder(cluster).with_deadline_millis(200).finalize_async();
+        let client_id = client.id.0.clone();
+
+        // Accept the connection, but never answer.
+        let child = thread::spawn(move || {
+            let (mut connection, _) = test_server.accept().unwrap();
+            assert!(expect_preamble(&mut connection, client_id).unwrap());
+            assert!(expect_proposal(&mut connection, b"Bears").unwrap());
+            let _ = connection.read_to_end(&mut Vec::new());
+        });
+
+        let start = Instant::now();
+        match client.propose(b"Bears").wait() {
+            Err(Error::Raft(RaftError::MaybeApplied)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(client);
+
+        child.join().unwrap();
+    }
 }
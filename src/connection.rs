@@ -1,20 +1,18 @@
 use std::fmt;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::rc::Rc;
 
-use mio::tcp::TcpStream;
-use mio::{Poll, Ready, PollOpt, Token};
-use capnp::message::{Builder, HeapAllocator, Reader, ReaderOptions};
-use capnp_nonblock::{MessageStream, Segments};
+use mio::{Evented, Poll, Ready, PollOpt, Token};
+use capnp::message::{Builder, HeapAllocator, Reader};
+use capnp::serialize::OwnedSegments;
 
 use ClientId;
 use Result;
 use ServerId;
 use backoff::Backoff;
 use messages;
-use server::{Server, ServerTimeout};
-use state_machine::StateMachine;
-use persistent_log::Log;
+use transport::{Framed, Transport};
 
 fn poll_opt() -> PollOpt {
     PollOpt::edge() | PollOpt::oneshot()
@@ -41,39 +39,44 @@ impl ConnectionKind {
     }
 }
 
-pub struct Connection {
+/// A connection to a peer or client, over a stream opened by the server's `Transport`.
+pub struct Connection<S> {
     kind: ConnectionKind,
     /// The address to reconnect to - for a connection initiated by the remote,
     /// this is not the remote address.
     addr: SocketAddr,
-    stream: Option<MessageStream<TcpStream, HeapAllocator, Rc<Builder<HeapAllocator>>>>,
+    stream: Option<Framed<S>>,
     backoff: Backoff,
 }
 
-impl Connection {
-    /// Creates a new `Connection` wrapping the provided socket stream.
+impl<S> Connection<S>
+    where S: Read + Write + Evented
+{
+    /// Creates a new `Connection` wrapping the provided stream, which was accepted from the
+    /// given remote address.
     ///
-    /// The socket must already be connected.
+    /// The stream must already be connected.
     ///
     /// Note: the caller must manually set the token field after inserting the
     /// connection into a slab.
-    pub fn unknown(socket: TcpStream) -> Result<Connection> {
-        let addr = try!(socket.peer_addr());
-        Ok(Connection {
+    pub fn unknown(stream: S, addr: SocketAddr) -> Connection<S> {
+        Connection {
             kind: ConnectionKind::Unknown,
             addr: addr,
-            stream: Some(MessageStream::new(socket, ReaderOptions::new())),
+            stream: Some(Framed::new(stream)),
             backoff: Backoff::with_duration_range(50, 10000),
-        })
+        }
     }
 
-    /// Creates a new peer connection.
-    pub fn peer(id: ServerId, addr: SocketAddr) -> Result<Connection> {
-        let stream = try!(TcpStream::connect(&addr));
+    /// Creates a new peer connection over the transport.
+    pub fn peer<T>(transport: &T, id: ServerId, addr: SocketAddr) -> Result<Connection<S>>
+        where T: Transport<Stream = S>
+    {
+        let stream = try!(transport.connect(&addr));
         Ok(Connection {
             kind: ConnectionKind::Peer(id),
             addr: addr,
-            stream: Some(MessageStream::new(stream, ReaderOptions::new())),
+            stream: Some(Framed::new(stream)),
             backoff: Backoff::with_duration_range(50, 10000),
         })
     }
@@ -96,7 +99,7 @@ impl Connection {
 
     /// Returns the connection's stream.
     /// Must only be called while the connection is active.
-    fn stream(&self) -> &MessageStream<TcpStream, HeapAllocator, Rc<Builder<HeapAllocator>>> {
+    fn stream(&self) -> &Framed<S> {
         match self.stream {
             Some(ref stream) => stream,
             None => panic!(format!("{:?}: not connected", self)),
@@ -105,8 +108,7 @@ impl Connection {
 
     /// Returns the connection's mutable stream.
     /// Must only be called while the connection is active.
-    fn stream_mut(&mut self)
-                  -> &mut MessageStream<TcpStream, HeapAllocator, Rc<Builder<HeapAllocator>>> {
+    fn stream_mut(&mut self) -> &mut Framed<S> {
         match self.stream {
             Some(ref mut stream) => stream,
             None => panic!(format!("{:?}: not connected", self)),
@@ -117,7 +119,7 @@ impl Connection {
     pub fn writable(&mut self) -> Result<()> {
         scoped_trace!("{:?}: writable", self);
         if let Connection { stream: Some(ref mut stream), ref mut backoff, .. } = *self {
-            try!(stream.write());
+            try!(stream.flush());
             backoff.reset();
             Ok(())
         } else {
@@ -130,9 +132,9 @@ impl Connection {
     ///
     /// Connections are edge-triggered, so the handler must continue calling
     /// until no more messages are returned.
-    pub fn readable(&mut self) -> Result<Option<Reader<Segments>>> {
+    pub fn readable(&mut self) -> Result<Option<Reader<OwnedSegments>>> {
         scoped_trace!("{:?}: readable", self);
-        self.stream_mut().read_message().map_err(From::from)
+        self.stream_mut().receive()
     }
 
     /// Queues a message to send to the connection. Returns `true` if the connection should be
@@ -142,12 +144,12 @@ impl Connection {
         match self.stream {
             Some(ref mut stream) => {
                 // Reregister if the connection is not already registered, and
-                // there are still messages left to send. MessageStream
+                // there are still messages left to send. Framed
                 // optimistically sends messages, so it's likely that small
                 // messages can be sent without ever registering.
-                let unregistered = stream.outbound_queue_len() == 0;
-                try!(stream.write_message(message));
-                Ok(unregistered && stream.outbound_queue_len() > 0)
+                let unregistered = stream.queued() == 0;
+                try!(stream.send(&*message));
+                Ok(unregistered && stream.queued() > 0)
             }
             None => Ok(false),
         }
@@ -155,22 +157,16 @@ impl Connection {
 
     fn ready(&self) -> Ready {
         let mut ready = Ready::all();
-        if self.stream().outbound_queue_len() == 0 {
+        if self.stream().queued() == 0 {
             ready = ready - Ready::writable();
         }
         ready
     }
 
     /// Registers the connection with the event loop.
-    pub fn register<L, M>(&mut self,
-                          poll: &Poll,
-                          token: Token)
-                          -> Result<()>
-        where L: Log,
-              M: StateMachine
-    {
+    pub fn register(&mut self, poll: &Poll, token: Token) -> Result<()> {
         scoped_trace!("{:?}: register", self);
-        poll.register(self.stream().inner(), token, self.ready(), poll_opt())
+        poll.register(self.stream().get_ref(), token, self.ready(), poll_opt())
                   .map_err(|error| {
                       scoped_warn!("{:?}: reregister failed: {}", self, error);
                       From::from(error)
@@ -178,60 +174,50 @@ impl Connection {
     }
 
     /// Reregisters the connection with the event loop.
-    pub fn reregister<L, M>(&mut self,
-                            poll: &Poll,
-                            token: Token)
-                            -> Result<()>
-        where L: Log,
-              M: StateMachine
-    {
+    pub fn reregister(&mut self, poll: &Poll, token: Token) -> Result<()> {
         scoped_trace!("{:?}: reregister", self);
-        poll.reregister(self.stream().inner(), token, self.ready(), poll_opt())
+        poll.reregister(self.stream().get_ref(), token, self.ready(), poll_opt())
                   .map_err(|error| {
                       scoped_warn!("{:?}: register failed: {}", self, error);
                       From::from(error)
                   })
     }
 
-    /// Reconnects to the given peer ID and sends the preamble, advertising the
+    /// Reconnects to the given peer ID over the transport and sends the preamble, advertising the
     /// given local address to the peer.
-    pub fn reconnect_peer(&mut self, id: ServerId, local_addr: &SocketAddr) -> Result<()> {
+    pub fn reconnect_peer<T>(&mut self,
+                             transport: &T,
+                             id: ServerId,
+                             local_addr: &SocketAddr)
+                             -> Result<()>
+        where T: Transport<Stream = S>
+    {
         scoped_assert!(self.kind.is_peer());
         scoped_trace!("{:?}: reconnect", self);
-        self.stream = Some(MessageStream::new(try!(TcpStream::connect(&self.addr)),
-                                              ReaderOptions::new()));
+        self.stream = Some(Framed::new(try!(transport.connect(&self.addr))));
         try!(self.send_message(messages::server_connection_preamble(id, local_addr)));
         Ok(())
     }
 
-    /// Resets a peer connection.
-    pub fn reset_peer<L, M>(&mut self,
-                            poll: &Poll,
-                            token: Token)
-                            -> Result<(ServerTimeout, TimeoutHandle)>
-        where L: Log,
-              M: StateMachine
-    {
+    /// Resets a peer connection. Returns how many milliseconds to wait before reconnecting.
+    pub fn reset_peer(&mut self) -> u64 {
         scoped_assert!(self.kind.is_peer());
         self.stream = None;
         let duration = self.backoff.next_backoff_ms();
-        let timeout = ServerTimeout::Reconnect(token);
-        let handle = poll.timeout_ms(timeout, duration).unwrap();
-
         scoped_info!("{:?}: reset, will attempt to reconnect in {}ms",
                      self,
                      duration);
-        Ok((timeout, handle))
+        duration
     }
 
     pub fn clear_messages(&mut self) {
         if let Some(ref mut stream) = self.stream {
-            stream.clear_outbound_queue();
+            stream.clear();
         }
     }
 }
 
-impl fmt::Debug for Connection {
+impl<S> fmt::Debug for Connection<S> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self.kind {
             ConnectionKind::Peer(id) => write!(fmt, "PeerConnection({})", id),
@@ -3,31 +3,101 @@
 //! `Server`. The set of possible ready is specified by the Raft Protocol:
 //!
 //! ```text
-//! Event = AppendEntriesRequest | AppendEntriesResponse
-//!       | RequestVoteRequest   | RequestVoteResponse
-//!       | ElectionTimeout      | HeartbeatTimeout
-//!       | ClientProposal       | ClientQuery
+//! Event = AppendEntriesRequest   | AppendEntriesResponse
+//!       | RequestVoteRequest     | RequestVoteResponse
+//!       | PreVoteRequest         | PreVoteResponse
+//!       | InstallSnapshotRequest | InstallSnapshotResponse
+//!       | ReadIndexRequest       | ReadIndexResponse
+//!       | ElectionTimeout        | HeartbeatTimeout
+//!       | ClientProposal         | ClientQuery
+//!       | ClientAddServer        | ClientRemoveServer
+//!       | TimeoutNow             | LeadershipTransferTimeout
 //! ```
 //!
 //! In response to an event, the `Consensus` may mutate its own state, apply a command to the local
 //! `StateMachine`, or return an event to be sent to one or more remote peers or clients.
 
-use std::{cmp, fmt};
+use std::{cmp, error, fmt};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use capnp::message::{Builder, HeapAllocator, Reader, ReaderSegments};
-use rand::{self, Rng};
+use capnp::message::{Builder, HeapAllocator, Reader, ReaderOptions, ReaderSegments};
+use capnp::{serialize, struct_list};
+use rand::Rng;
 
-use {LogIndex, Term, ServerId, ClientId, messages};
-use messages_capnp::{append_entries_request, append_entries_response, client_request,
-                     proposal_request, query_request, message, request_vote_request,
-                     request_vote_response};
-use state::{ConsensusState, LeaderState, CandidateState, FollowerState};
+use {EntryKind, LogIndex, Term, ServerId, ClientId, Error, RaftError, Result, messages};
+use membership::{LearnerStatus, Membership};
+use session::{self, Applied, SessionEntry, Sessions};
+use messages_capnp::{self, add_server_request, append_entries_request, append_entries_response,
+                     client_request, entry, install_snapshot_request, install_snapshot_response,
+                     learner_status_request, pre_vote_request, pre_vote_response,
+                     promote_server_request, proposal_request, timeout_now,
+                     transfer_leadership_request,
+                     query_request, message, read_index_request, read_index_response,
+                     remove_server_request, request_vote_request, request_vote_response,
+                     snapshot, Consistency};
+use state::{ConsensusState, LeaderState, CandidateState, FollowerState, LeadershipTransfer,
+            PendingQuery};
 use state_machine::StateMachine;
 use persistent_log::Log;
 
+/// The result of an operation on the persistent log `L`.
+type LogResult<T, L> = ::std::result::Result<T, LogError<<L as Log>::Error>>;
+
+/// A failure of the persistent log, after which the in-memory state of a `Consensus` can no
+/// longer be trusted to match the log.
+#[derive(Debug)]
+pub enum LogError<E> {
+    /// The log returned an error.
+    Log(E),
+    /// The log holds an entry or a snapshot which cannot be decoded.
+    Corrupt(String),
+}
+
+impl<E> LogError<E> {
+    /// Returns the error for the entry at `index`, which cannot be decoded.
+    fn corrupt_entry(index: LogIndex, error: Error) -> LogError<E> {
+        LogError::Corrupt(format!("entry {} cannot be decoded: {}", index, error))
+    }
+}
+
+impl<E> From<E> for LogError<E> {
+    fn from(error: E) -> LogError<E> {
+        LogError::Log(error)
+    }
+}
+
+impl<E> fmt::Display for LogError<E>
+    where E: fmt::Display
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogError::Log(ref error) => fmt::Display::fmt(error, fmt),
+            LogError::Corrupt(ref reason) => write!(fmt, "corrupt log: {}", reason),
+        }
+    }
+}
+
+impl<E> error::Error for LogError<E>
+    where E: error::Error
+{
+    fn description(&self) -> &str {
+        match *self {
+            LogError::Log(ref error) => error.description(),
+            LogError::Corrupt(..) => "corrupt log",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            LogError::Log(ref error) => Some(error),
+            LogError::Corrupt(..) => None,
+        }
+    }
+}
 
 /// Consensus timeout types.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -36,6 +106,10 @@ pub enum ConsensusTimeout {
     Election,
     // A heartbeat timeout. Stable value.
     Heartbeat(ServerId),
+    // The deadline for a leadership transfer. The minimum election timeout.
+    LeadershipTransfer,
+    // The period within which the leader must hear from a majority. The minimum election timeout.
+    CheckQuorum,
 }
 
 pub struct TimeoutConfiguration {
@@ -45,13 +119,82 @@ pub struct TimeoutConfiguration {
 }
 
 impl ConsensusTimeout {
-    /// Returns the timeout period in milliseconds.
-    pub fn duration_ms(&self, config: &TimeoutConfiguration) -> u64 {
+    /// Returns the timeout period in milliseconds. Election timeouts are drawn from `rng`.
+    pub fn duration_ms<R>(&self, config: &TimeoutConfiguration, rng: &mut R) -> u64
+        where R: Rng
+    {
         match *self {
             ConsensusTimeout::Election => {
-                rand::thread_rng().gen_range::<u64>(config.election_min_ms, config.election_max_ms)
+                rng.gen_range::<u64>(config.election_min_ms, config.election_max_ms)
             }
             ConsensusTimeout::Heartbeat(..) => config.heartbeat_ms,
+            ConsensusTimeout::LeadershipTransfer |
+            ConsensusTimeout::CheckQuorum => config.election_min_ms,
+        }
+    }
+}
+
+/// Tunable behaviour of a `Consensus` instance.
+#[derive(Clone, Debug)]
+pub struct ConsensusConfiguration {
+    /// The number of entries applied to the state machine since the latest snapshot after which
+    /// a new snapshot is taken and the log is compacted. `None` disables log compaction.
+    pub snapshot_threshold: Option<u64>,
+    /// The maximum number of snapshot bytes sent in a single `InstallSnapshot` request.
+    pub snapshot_chunk_bytes: usize,
+    /// The duration of the leader lease, if leases are enabled. While it holds the lease the
+    /// leader answers queries without confirming its leadership with a majority, and followers
+    /// refuse to vote for the same duration after hearing from the leader. The lease must be
+    /// shorter than the minimum election timeout by more than the worst clock drift between
+    /// servers; leases trade safety under clock faults for read latency.
+    pub leader_lease: Option<Duration>,
+    /// Whether to run a pre-vote round before starting an election. A server then only
+    /// increments its term once a majority would grant it a vote, so that a server rejoining
+    /// after a partition does not force a healthy leader to step down.
+    pub pre_vote: bool,
+    /// The period within which the leader must hear from a majority of voters, or else step
+    /// down so that clients go looking for the real leader. `None` disables the check; `Server`
+    /// enables it with the minimum election timeout by default.
+    pub check_quorum: Option<Duration>,
+    /// The maximum number of proposals appended to the log together. Proposals are batched
+    /// until `flush_proposals` is called, or either limit is reached.
+    pub max_batch_entries: usize,
+    /// The maximum total size in bytes of the proposals appended to the log together.
+    pub max_batch_bytes: usize,
+    /// The maximum number of AppendEntries requests carrying entries in flight to a follower.
+    pub replication_window: usize,
+    /// The maximum number of entries carried by an AppendEntries request. Followers which are
+    /// further behind receive the missing entries over several requests.
+    pub max_append_entries: usize,
+    /// The maximum total size in bytes of the entries carried by an AppendEntries request. A
+    /// single larger entry is still sent on its own.
+    pub max_append_bytes: usize,
+    /// How long a client session lasts without proposals before it expires, after which its
+    /// proposals are rejected until the client starts a new session. It must be longer than the
+    /// clients keep retrying a proposal. The leader records its setting in the entries it
+    /// appends, so that the servers agree on when sessions expire.
+    pub session_expiry: Duration,
+    /// How long a follower answers bounded-staleness queries after last hearing from the
+    /// leader. A follower cut off from the leader for longer redirects clients elsewhere rather
+    /// than serve reads of unbounded age. `None` disables the bound.
+    pub max_staleness: Option<Duration>,
+}
+
+impl Default for ConsensusConfiguration {
+    fn default() -> ConsensusConfiguration {
+        ConsensusConfiguration {
+            snapshot_threshold: None,
+            snapshot_chunk_bytes: 1024 * 1024,
+            leader_lease: None,
+            pre_vote: true,
+            check_quorum: None,
+            max_batch_entries: 256,
+            max_batch_bytes: 1024 * 1024,
+            replication_window: 8,
+            max_append_entries: 512,
+            max_append_bytes: 1024 * 1024,
+            session_expiry: Duration::from_secs(60 * 60),
+            max_staleness: None,
         }
     }
 }
@@ -69,6 +212,12 @@ pub struct Actions {
     pub timeouts: Vec<ConsensusTimeout>,
     /// Whether to clear outbound peer message queues.
     pub clear_peer_messages: bool,
+    /// The new set of peers, if the cluster configuration changed.
+    pub peers: Option<HashMap<ServerId, SocketAddr>>,
+    /// The error returned by the persistent log if it failed, or the reason it is corrupt. The
+    /// in-memory state of the `Consensus` can no longer be trusted to match the log, so the
+    /// `Server` should shut down.
+    pub log_error: Option<Box<error::Error>>,
 }
 
 impl fmt::Debug for Actions {
@@ -83,12 +232,14 @@ impl fmt::Debug for Actions {
                                                  .collect();
         write!(fmt,
                "Actions {{ peer_messages: {:?}, client_messages: {:?}, clear_timeouts: {:?}, \
-                timeouts: {:?}, clear_peer_messages: {} }}",
+                timeouts: {:?}, clear_peer_messages: {}, peers: {:?}, log_error: {:?} }}",
                peer_messages,
                client_messages,
                self.clear_timeouts,
                self.timeouts,
-               self.clear_peer_messages)
+               self.clear_peer_messages,
+               self.peers.as_ref().map(|peers| peers.keys().cloned().collect::<Vec<_>>()),
+               self.log_error)
     }
 }
 
@@ -101,22 +252,43 @@ impl Actions {
             clear_timeouts: false,
             timeouts: vec![],
             clear_peer_messages: false,
+            peers: None,
+            log_error: None,
         }
     }
 }
 
+/// Identifies a client request, so that its response can be matched to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId {
+    /// The client which sent the request.
+    pub client: ClientId,
+    /// The client's ID for the request, which the response echoes.
+    pub id: u64,
+}
+
 /// An instance of a Raft state machine. The Consensus controls a client state machine, to which it
 /// applies entries in a globally consistent order.
 pub struct Consensus<L, M> {
     /// The ID of this consensus instance.
     id: ServerId,
-    /// The IDs of peers in the consensus group.
+    /// The address of this consensus instance.
+    addr: SocketAddr,
+    /// The members of the active configuration other than this instance.
     peers: HashMap<ServerId, SocketAddr>,
+    /// The configurations in the log, ordered by index. The first element is the configuration
+    /// as of the snapshot (or the bootstrap configuration), and the last is the active one.
+    memberships: Vec<(LogIndex, Membership)>,
 
     /// The persistent log.
     log: L,
     /// The client state machine to which client commands are applied.
     state_machine: M,
+    /// The results of the proposals made by clients with sessions, as of the last applied
+    /// entry.
+    sessions: Sessions,
+    /// Tunable behaviour.
+    config: ConsensusConfiguration,
 
     /// Index of the latest entry known to be committed.
     commit_index: LogIndex,
@@ -131,36 +303,94 @@ pub struct Consensus<L, M> {
     candidate_state: CandidateState,
     /// State necessary while a `Follower`. Should not be used otherwise.
     follower_state: FollowerState,
+
+    /// The time set with `set_time`, or `None` to read the system clock.
+    time: Option<Instant>,
+    /// An instant on the consensus clock, along with the session timestamp it stands for.
+    clock_base: (Instant, u64),
 }
 
 impl<L, M> Consensus<L, M>
     where L: Log,
           M: StateMachine
 {
-    /// Creates a `Consensus`.
+    /// Creates a `Consensus`. If the log has been compacted, the state machine is restored from
+    /// the latest snapshot.
+    ///
+    /// The active configuration is the latest one found in the log or snapshot. If there is none,
+    /// the cluster is bootstrapped with `peers` and this instance; if `peers` is `None` the
+    /// instance starts outside of any configuration, waiting to be added to an existing cluster.
+    ///
+    /// Returns an error if the log fails, or holds a snapshot or configuration entry which cannot
+    /// be decoded.
     pub fn new(id: ServerId,
-               peers: HashMap<ServerId, SocketAddr>,
+               addr: SocketAddr,
+               peers: Option<HashMap<ServerId, SocketAddr>>,
                log: L,
-               state_machine: M)
-               -> Consensus<L, M> {
-        let leader_state = LeaderState::new(log.latest_log_index().unwrap(),
-                                            &peers.keys().cloned().collect());
-        Consensus {
+               mut state_machine: M,
+               config: ConsensusConfiguration)
+               -> LogResult<Consensus<L, M>, L> {
+        let snapshot_index = try!(log.snapshot_index());
+        let (base, sessions) = if snapshot_index > LogIndex(0) {
+            let decoded = decode_snapshot(try!(log.snapshot())).map_err(|error| {
+                LogError::Corrupt(format!("snapshot cannot be decoded: {}", error))
+            });
+            let (membership, sessions, data) = try!(decoded);
+            state_machine.restore_snapshot(data);
+            (membership, sessions)
+        } else {
+            let membership = match peers {
+                Some(peers) => Membership::new(peers).with_member(id, addr),
+                None => Membership::default(),
+            };
+            (membership, Sessions::new())
+        };
+        let mut memberships = vec![(snapshot_index, base)];
+        let latest_log_index = try!(log.latest_log_index());
+        for index in (snapshot_index + 1).as_u64()..(latest_log_index + 1).as_u64() {
+            let (_, kind, entry) = try!(log.entry(LogIndex(index)));
+            if kind == EntryKind::Configuration {
+                let membership = try!(Membership::from_bytes(&entry).map_err(|error| {
+                    LogError::corrupt_entry(LogIndex(index), error)
+                }));
+                memberships.push((LogIndex(index), membership));
+            }
+        }
+        let membership = memberships[memberships.len() - 1].1.clone();
+        let peers = membership.peers(id);
+        let mut leader_state = LeaderState::new(latest_log_index,
+                                                &peers.keys().cloned().collect());
+        for peer in peers.keys() {
+            leader_state.set_learner(*peer, membership.is_learner(peer));
+        }
+        Ok(Consensus {
             id: id,
+            addr: addr,
             peers: peers,
+            memberships: memberships,
             log: log,
             state_machine: state_machine,
-            commit_index: LogIndex(0),
-            last_applied: LogIndex(0),
+            sessions: sessions,
+            config: config,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
             state: ConsensusState::Follower,
             leader_state: leader_state,
             candidate_state: CandidateState::new(),
             follower_state: FollowerState::new(),
-        }
+            time: None,
+            clock_base: (Instant::now(), session::timestamp()),
+        })
     }
 
-    /// Returns the set of initial action which should be executed upon startup.
-    pub fn init(&self) -> Actions {
+    /// Returns the set of initial action which should be executed upon startup. When running on
+    /// virtual time, `set_time` must be called first.
+    pub fn init(&mut self) -> Actions {
+        if self.config.leader_lease.is_some() {
+            // This server may have acknowledged a lease before it restarted, so it must not vote
+            // until that lease could have expired.
+            self.follower_state.leader_contact = Some(self.now());
+        }
         let mut actions = Actions::new();
         actions.timeouts.push(ConsensusTimeout::Election);
         actions
@@ -171,16 +401,50 @@ impl<L, M> Consensus<L, M>
         &self.peers
     }
 
+    /// Returns the ID of this consensus instance.
+    pub fn id(&self) -> ServerId {
+        self.id
+    }
+
+    /// Returns the persistent log.
+    pub fn log(&self) -> &L {
+        &self.log
+    }
+
+    /// Returns the state machine.
+    pub fn state_machine(&self) -> &M {
+        &self.state_machine
+    }
+
+    /// Returns the index of the latest entry known to be committed.
+    pub fn commit_index(&self) -> LogIndex {
+        self.commit_index
+    }
+
+    /// Sets the current time, which measures leader leases, the check-quorum period and client
+    /// sessions in place of the system clock from then on. Allows the consensus state machine to
+    /// run on virtual time; session timestamps then count from the first time set.
+    pub fn set_time(&mut self, now: Instant) {
+        if self.time.is_none() {
+            self.clock_base = (now, 0);
+        }
+        self.time = Some(now);
+    }
+
     /// Applies a peer message to the consensus state machine.
+    ///
+    /// Returns an error if the message cannot be decoded, or is of a kind this server does not
+    /// handle, in which case the connection to the peer should be closed.
     pub fn apply_peer_message<S>(&mut self,
                                  from: ServerId,
                                  message: &Reader<S>,
                                  actions: &mut Actions)
+                                 -> Result<()>
         where S: ReaderSegments
     {
         info!("{:?}", self);
-        let reader = message.get_root::<message::Reader>().unwrap().which().unwrap();
-        match reader {
+        let reader = try!(try!(message.get_root::<message::Reader>()).which());
+        let result = match reader {
             message::Which::AppendEntriesRequest(Ok(request)) => {
                 self.append_entries_request(from, request, actions)
             }
@@ -193,34 +457,117 @@ impl<L, M> Consensus<L, M>
             message::Which::RequestVoteResponse(Ok(response)) => {
                 self.request_vote_response(from, response, actions)
             }
-            _ => panic!("cannot handle message"),
+            message::Which::InstallSnapshotRequest(Ok(request)) => {
+                self.install_snapshot_request(from, request, actions)
+            }
+            message::Which::InstallSnapshotResponse(Ok(response)) => {
+                self.install_snapshot_response(from, response, actions)
+            }
+            message::Which::TimeoutNow(Ok(request)) => self.timeout_now(from, request, actions),
+            message::Which::PreVoteRequest(Ok(request)) => {
+                self.pre_vote_request(from, request, actions)
+            }
+            message::Which::PreVoteResponse(Ok(response)) => {
+                self.pre_vote_response(from, response, actions)
+            }
+            message::Which::ReadIndexRequest(Ok(request)) => {
+                self.read_index_request(from, request, actions)
+            }
+            message::Which::ReadIndexResponse(Ok(response)) => {
+                self.read_index_response(from, response, actions)
+            }
+            _ => {
+                scoped_warn!("{:?}: malformed message from peer {}", self, from);
+                return Err(Error::Raft(RaftError::MalformedMessage));
+            }
         };
+        self.check_log(result, actions);
+        Ok(())
     }
 
     /// Applies a client message to the consensus state machine.
+    ///
+    /// Returns an error if the message cannot be decoded, or is of a kind this server does not
+    /// handle, in which case the connection to the client should be closed.
     pub fn apply_client_message<S>(&mut self,
                                    from: ClientId,
                                    message: &Reader<S>,
                                    actions: &mut Actions)
+                                   -> Result<()>
         where S: ReaderSegments
     {
         info!("{:?}", self);
-        let reader = message.get_root::<client_request::Reader>().unwrap().which().unwrap();
-        match reader {
+        let request = try!(message.get_root::<client_request::Reader>());
+        let from = RequestId {
+            client: from,
+            id: request.get_id(),
+        };
+        let result = match try!(request.which()) {
             client_request::Which::Proposal(Ok(request)) => {
                 self.proposal_request(from, request, actions)
             }
             client_request::Which::Query(Ok(query)) => self.query_request(from, query, actions),
-            _ => panic!("cannot handle message"),
-        }
+            client_request::Which::AddServer(Ok(request)) => {
+                self.add_server_request(from, request, actions)
+            }
+            client_request::Which::RemoveServer(Ok(request)) => {
+                self.remove_server_request(from, request, actions)
+            }
+            client_request::Which::PromoteServer(Ok(request)) => {
+                self.promote_server_request(from, request, actions)
+            }
+            client_request::Which::LearnerStatus(Ok(request)) => {
+                self.learner_status_request(from, request, actions)
+            }
+            client_request::Which::TransferLeadership(Ok(request)) => {
+                self.transfer_leadership_request(from, request, actions)
+            }
+            _ => {
+                scoped_warn!("{:?}: malformed request from client {}", self, from.client);
+                return Err(Error::Raft(RaftError::MalformedMessage));
+            }
+        };
+        self.check_log(result, actions);
+        Ok(())
     }
 
     /// Applies a timeout's actions to the `Consensus`.
     pub fn apply_timeout(&mut self, timeout: ConsensusTimeout, actions: &mut Actions) {
         info!("{:?}", self);
-        match timeout {
+        let result = match timeout {
             ConsensusTimeout::Election => self.election_timeout(actions),
             ConsensusTimeout::Heartbeat(peer) => self.heartbeat_timeout(peer, actions),
+            ConsensusTimeout::LeadershipTransfer => {
+                self.leadership_transfer_timeout(actions);
+                Ok(())
+            }
+            ConsensusTimeout::CheckQuorum => {
+                self.check_quorum_timeout(actions);
+                Ok(())
+            }
+        };
+        self.check_log(result, actions);
+    }
+
+    /// Appends the batched proposals to the log, and sends them to the peers which are up to date.
+    /// Should be called once the events of an event loop turn have been applied.
+    pub fn flush_proposals(&mut self, actions: &mut Actions) {
+        let result = self.append_batch(actions);
+        self.check_log(result, actions);
+    }
+
+    /// Starts transferring leadership to the target voter. The leader stops accepting new
+    /// entries, brings the target up to date, and then tells it to start an election. The
+    /// transfer is aborted if the target has not caught up by the minimum election timeout.
+    pub fn transfer_leadership(&mut self, target: ServerId, actions: &mut Actions) -> Result<()> {
+        match self.start_transfer(target, None, actions) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(reason)) => Err(Error::Raft(RaftError::RequestRejected(reason.to_owned()))),
+            Err(error) => {
+                let reason = error.to_string();
+                self.log_failure(error, actions);
+                Err(Error::Raft(RaftError::LogFailure(reason)))
+            }
         }
     }
 
@@ -231,80 +578,151 @@ impl<L, M> Consensus<L, M>
                                  addr: SocketAddr,
                                  actions: &mut Actions) {
         info!("{:?}", self);
-        self.peers.insert(peer, addr).expect("new peer insertion not supported");
+        let result = self.resume_peer(peer, addr, actions);
+        self.check_log(result, actions);
+    }
+
+    /// Completes an operation on behalf of a public entry point. If the log failed, the failure
+    /// is recorded in the actions.
+    fn check_log(&mut self, result: LogResult<(), L>, actions: &mut Actions) {
+        if let Err(error) = result {
+            self.log_failure(error, actions);
+        }
+    }
+
+    /// Handles a failure of the persistent log. The in-memory state may no longer match the log,
+    /// so a leader steps down, and the `Server` is asked to shut down through the actions.
+    fn log_failure(&mut self, error: LogError<L::Error>, actions: &mut Actions) {
+        scoped_error!("{:?}: persistent log failure: {}", self, error);
+        if self.is_leader() {
+            self.step_down(actions);
+        }
+        actions.log_error = Some(Box::new(error));
+    }
+
+    /// Appends the batched proposals to the log, and sends them to the peers which are up to
+    /// date.
+    fn append_batch(&mut self, actions: &mut Actions) -> LogResult<(), L> {
+        if !self.is_leader() || !self.leader_state.has_batch() {
+            return Ok(());
+        }
+        let batch = self.leader_state.take_batch();
+        let term = try!(self.current_term());
+        let first_index = try!(self.latest_log_index()) + 1;
+        {
+            let entries: Vec<(Term, EntryKind, &[u8])> =
+                batch.iter().map(|&(_, kind, ref entry)| (term, kind, &entry[..])).collect();
+            try!(self.log.append_entries(first_index, &entries));
+            try!(self.track_memberships(first_index, &entries, actions));
+        }
+        scoped_debug!("appended {} entries from index {}", batch.len(), first_index);
+        for (offset, &(request, _, _)) in batch.iter().enumerate() {
+            self.leader_state.proposals.push_back((request, first_index + offset as u64));
+        }
+
+        // Peers which are behind already have entries on the way, and receive the new ones
+        // when they respond.
+        let peers: Vec<ServerId> = self.peers.keys().cloned().collect();
+        for peer in peers {
+            if self.leader_state.next_index(&peer) == first_index {
+                try!(self.replicate(peer, actions));
+            }
+        }
+        // Without other voters the entries commit immediately.
+        self.advance_commit_index(actions)
+    }
+
+    /// Brings a peer with a new connection up to date.
+    fn resume_peer(&mut self,
+                   peer: ServerId,
+                   addr: SocketAddr,
+                   actions: &mut Actions)
+                   -> LogResult<(), L> {
+        if !self.peers.contains_key(&peer) {
+            // The peer is not part of the active configuration. It may have been removed, or
+            // this instance may not have learned the configuration which includes it yet.
+            scoped_debug!("connection reset by peer {} outside of the configuration", peer);
+            return Ok(());
+        }
+        self.peers.insert(peer, addr);
         match self.state {
             ConsensusState::Leader => {
                 // Send any outstanding entries to the peer, or an empty heartbeat if there are no
-                // outstanding entries.
-                let from_index = self.leader_state.next_index(&peer);
-                let until_index = self.latest_log_index() + 1;
-
-                let prev_log_index = from_index - 1;
-                let prev_log_term = if prev_log_index == LogIndex::from(0) {
-                    Term::from(0)
+                // outstanding entries. If the outstanding entries have been compacted, send the
+                // snapshot instead.
+                // Requests sent over the previous connection may have been lost.
+                self.leader_state.clear_in_flight(peer);
+                if self.leader_state.next_index(&peer) > try!(self.latest_log_index()) {
+                    let message = try!(self.heartbeat_message(peer));
+                    actions.peer_messages.push((peer, message));
                 } else {
-                    self.log.entry(prev_log_index).unwrap().0
-                };
-
-                let entries = self.log.entries(from_index, until_index).unwrap();
-                let message = messages::append_entries_request(self.current_term(),
-                                                               prev_log_index,
-                                                               prev_log_term,
-                                                               &entries,
-                                                               self.commit_index);
-
-                self.leader_state.set_next_index(peer, until_index);
-                actions.peer_messages.push((peer, message));
+                    try!(self.replicate(peer, actions));
+                }
             }
             ConsensusState::Candidate => {
                 // Resend the request vote request if a response has not yet been receieved.
-                if self.candidate_state.peer_voted(peer) {
-                    return;
+                // Learners are not asked for their vote.
+                if self.candidate_state.peer_voted(peer) || !self.membership().is_voter(&peer) {
+                    return Ok(());
                 }
-                let current_term = self.current_term();
-                let latest_index = self.latest_log_index();
-                let latest_term = self.log.latest_log_term().unwrap();
+                let current_term = try!(self.current_term());
+                let latest_index = try!(self.latest_log_index());
+                let latest_term = try!(self.latest_log_term());
 
-                let message = messages::request_vote_request(current_term,
-                                                             latest_index,
-                                                             latest_term);
+                let message =
+                    messages::request_vote_request(current_term,
+                                                   latest_index,
+                                                   latest_term,
+                                                   self.candidate_state.leadership_transfer);
                 actions.peer_messages.push((peer, message));
             }
             ConsensusState::Follower => {
                 // No message is necessary; if the peer is a leader or candidate they will send a
-                // message.
+                // message. Read index requests to the leader may have been lost, however.
+                if self.follower_state.leader == Some(peer) {
+                    self.fail_follower_reads(actions);
+                }
             }
         }
+        Ok(())
     }
 
     /// Apply an append entries request to the consensus state machine.
     fn append_entries_request(&mut self,
                               from: ServerId,
                               request: append_entries_request::Reader,
-                              actions: &mut Actions) {
+                              actions: &mut Actions)
+                              -> LogResult<(), L> {
         scoped_trace!("AppendEntriesRequest from peer {}", &from);
 
         let leader_term = Term(request.get_term());
-        let current_term = self.current_term();
+        let current_term = try!(self.current_term());
 
         if leader_term < current_term {
             let message = messages::append_entries_response_stale_term(current_term);
             actions.peer_messages.push((from, message));
-            return;
+            return Ok(());
         }
 
         match self.state {
             ConsensusState::Follower => {
                 let message = {
                     if current_term < leader_term {
-                        self.log.set_current_term(leader_term).unwrap();
+                        try!(self.log.set_current_term(leader_term));
                         self.follower_state.set_leader(from);
                     }
+                    // Only the leader of the current term sends AppendEntries requests.
+                    self.follower_state.leader = Some(from);
+                    self.follower_state.leader_contact = Some(self.now());
+                    self.follower_state.leader_active = true;
+                    self.follower_state.pre_candidate = false;
 
                     let leader_prev_log_index = LogIndex(request.get_prev_log_index());
                     let leader_prev_log_term = Term(request.get_prev_log_term());
+                    let read_sequence = request.get_read_sequence();
 
-                    let latest_log_index = self.latest_log_index();
+                    let latest_log_index = try!(self.latest_log_index());
+                    let snapshot_index = try!(self.log.snapshot_index());
                     if latest_log_index < leader_prev_log_index {
                         // If the previous entries index was not the same we'd leave a gap! Reply failure.
                         scoped_debug!("AppendEntriesRequest: inconsistent previous log index: \
@@ -312,12 +730,18 @@ impl<L, M> Consensus<L, M>
                                       leader_prev_log_index,
                                       latest_log_index);
                         messages::append_entries_response_inconsistent_prev_entry(
-                            self.current_term(), leader_prev_log_index)
+                            try!(self.current_term()),
+                            leader_prev_log_index,
+                            Term(0),
+                            latest_log_index + 1,
+                            read_sequence)
                     } else {
-                        let existing_term = if leader_prev_log_index == LogIndex::from(0) {
-                            Term::from(0)
+                        let existing_term = if leader_prev_log_index < snapshot_index {
+                            // Entries covered by the snapshot are committed, and therefore match
+                            // the leader's log.
+                            leader_prev_log_term
                         } else {
-                            self.log.entry(leader_prev_log_index).unwrap().0
+                            try!(self.term_at(leader_prev_log_index))
                         };
 
                         if existing_term != leader_prev_log_term {
@@ -325,49 +749,76 @@ impl<L, M> Consensus<L, M>
                                           leader term: {}, local term: {}",
                                           leader_prev_log_term,
                                           existing_term);
-                            // If an existing entry conflicts with a new one (same index but different terms),
-                            // delete the existing entry and all that follow it
-                            messages::append_entries_response_inconsistent_prev_entry(self.current_term(),
-                                leader_prev_log_index)
+                            // Every entry of the conflicting term is reported as suspect, so that
+                            // the leader can skip past the term in one round trip.
+                            messages::append_entries_response_inconsistent_prev_entry(
+                                try!(self.current_term()),
+                                leader_prev_log_index,
+                                existing_term,
+                                try!(self.first_index_of_term(leader_prev_log_index)),
+                                read_sequence)
                         } else {
-                            if let Ok(entries) = request.get_entries() {
-                                let num_entries: u32 = entries.len();
+                            let entries = request.get_entries().ok().and_then(decode_entries);
+                            if let Some(entries) = entries {
+                                let num_entries = entries.len();
                                 let new_latest_log_index = leader_prev_log_index +
                                                            num_entries as u64;
                                 if new_latest_log_index < self.follower_state.min_index {
                                     // Stale entry; ignore. This guards against overwriting a
                                     // possibly committed part of the log if messages get
-                                    // rearranged; see ktoso/akka-raft#66.
-                                    return;
+                                    // rearranged; see ktoso/akka-raft#66. The entries are held
+                                    // already, so the request is still acknowledged, which
+                                    // confirms the leader's read round.
+                                    let message = messages::append_entries_response_success(
+                                        try!(self.current_term()),
+                                        new_latest_log_index,
+                                        read_sequence);
+                                    actions.peer_messages.push((from, message));
+                                    return Ok(());
                                 }
                                 scoped_debug!("AppendEntriesRequest: {} entries from leader: {}",
                                               num_entries,
                                               from);
 
-                                let entries_vec: Vec<(Term, &[u8])> =
-                                    entries.iter()
-                                           .map(|entry| {
-                                               (Term::from(entry.get_term()),
-                                                entry.get_data().unwrap_or(b""))
-                                           })
-                                           .collect();
-
-                                self.log
-                                    .append_entries(leader_prev_log_index + 1, &entries_vec)
-                                    .unwrap();
+                                // Skip any entries which have already been compacted into the
+                                // snapshot.
+                                let compacted = if leader_prev_log_index < snapshot_index {
+                                    snapshot_index - leader_prev_log_index
+                                } else {
+                                    0
+                                };
+                                let entries_vec = &entries[cmp::min(compacted as usize,
+                                                                    num_entries)..];
+
+                                if compacted == 0 || !entries_vec.is_empty() {
+                                    let from_index = leader_prev_log_index + 1 + compacted;
+                                    try!(self.log.append_entries(from_index, entries_vec));
+                                    try!(self.track_memberships(from_index, entries_vec, actions));
+                                }
                                 self.follower_state.min_index = new_latest_log_index;
                                 // We are matching the leader's log up to and including `new_latest_log_index`.
-                                self.commit_index =
-                                    cmp::min(LogIndex::from(request.get_leader_commit()),
-                                             new_latest_log_index);
-                                self.apply_commits();
+                                let leader_commit = LogIndex::from(request.get_leader_commit());
+                                if leader_commit > self.commit_index {
+                                    self.commit_index = cmp::max(self.commit_index,
+                                                                 cmp::min(leader_commit,
+                                                                          new_latest_log_index));
+                                }
+                                try!(self.apply_commits());
+                                self.advance_follower_reads(actions);
                             } else {
-                                panic!("AppendEntriesRequest: no entry list")
+                                scoped_warn!("AppendEntriesRequest from peer {}: unable to \
+                                              decode entries",
+                                             from);
+                                let message = messages::append_entries_response_internal_error(
+                                    try!(self.current_term()),
+                                    "unable to decode entries");
+                                actions.peer_messages.push((from, message));
+                                return Ok(());
                             }
-                            messages::append_entries_response_success(self.current_term(),
-                                                                      self.log
-                                                                          .latest_log_index()
-                                                                          .unwrap())
+                            messages::append_entries_response_success(
+                                try!(self.current_term()),
+                                try!(self.latest_log_index()),
+                                read_sequence)
                         }
                     }
                 };
@@ -380,8 +831,8 @@ impl<L, M> Consensus<L, M>
                               }} with newer term; transitioning to Follower",
                              from,
                              leader_term);
-                self.transition_to_follower(leader_term, from, actions);
-                self.append_entries_request(from, request, actions);
+                try!(self.transition_to_follower(leader_term, from, actions));
+                try!(self.append_entries_request(from, request, actions));
             }
             ConsensusState::Leader => {
                 if leader_term == current_term {
@@ -398,10 +849,11 @@ impl<L, M> Consensus<L, M>
                               }} with newer term; transitioning to Follower",
                              from,
                              leader_term);
-                self.transition_to_follower(leader_term, from, actions);
-                self.append_entries_request(from, request, actions);
+                try!(self.transition_to_follower(leader_term, from, actions));
+                try!(self.append_entries_request(from, request, actions));
             }
         }
+        Ok(())
     }
 
     /// Apply an append entries response to the consensus state machine.
@@ -411,10 +863,11 @@ impl<L, M> Consensus<L, M>
     fn append_entries_response(&mut self,
                                from: ServerId,
                                response: append_entries_response::Reader,
-                               actions: &mut Actions) {
-        let local_term = self.current_term();
+                               actions: &mut Actions)
+                               -> LogResult<(), L> {
+        let local_term = try!(self.current_term());
         let responder_term = Term::from(response.get_term());
-        let local_latest_log_index = self.latest_log_index();
+        let local_latest_log_index = try!(self.latest_log_index());
 
         if local_term < responder_term {
             // Responder has a higher term number. Relinquish leader position (if it is held), and
@@ -426,16 +879,31 @@ impl<L, M> Consensus<L, M>
                          transitioning to Follower",
                          from,
                          responder_term);
-            self.transition_to_follower(responder_term, from, actions);
-            return;
+            try!(self.transition_to_follower(responder_term, from, actions));
+            return Ok(());
         } else if local_term > responder_term {
             scoped_debug!("AppendEntriesResponse from peer {} with a different term: {}",
                           from,
                           responder_term);
             // Responder is responding to an AppendEntries request from a different term. Ignore
             // the response.
-            return;
+            return Ok(());
+        } else if !self.peers.contains_key(&from) {
+            scoped_debug!("AppendEntriesResponse from peer {} outside of the configuration",
+                          from);
+            return Ok(());
+        }
+
+        // A response in the current term confirms that the peer still recognizes this leader.
+        let now = self.now();
+        self.leader_state.record_response(from, now);
+        self.leader_state.ack_read(from, response.get_read_sequence());
+        if let Some(lease) = self.config.leader_lease {
+            let majority = self.majority();
+            let is_voter = self.is_voter();
+            self.leader_state.renew_lease(majority, is_voter, lease);
         }
+        self.advance_reads(actions);
 
         match response.which() {
             Ok(append_entries_response::Which::Success(follower_latest_log_index)) => {
@@ -444,15 +912,50 @@ impl<L, M> Consensus<L, M>
                 let follower_latest_log_index = LogIndex::from(follower_latest_log_index);
                 scoped_assert!(follower_latest_log_index <= local_latest_log_index);
                 self.leader_state.set_match_index(from, follower_latest_log_index);
-                self.advance_commit_index(actions);
+                self.leader_state.ack_entries(from, follower_latest_log_index);
+                try!(self.advance_commit_index(actions));
+                if self.is_leader() {
+                    try!(self.advance_transfer(actions));
+                }
             }
-            Ok(append_entries_response::Which::InconsistentPrevEntry(next_index)) => {
+            Ok(append_entries_response::Which::InconsistentPrevEntry(inconsistent)) => {
                 scoped_assert!(self.is_leader());
+                let index = LogIndex(inconsistent.get_index());
+                let conflict_term = Term(inconsistent.get_conflict_term());
+                let conflict_index = LogIndex(inconsistent.get_conflict_index());
                 scoped_debug!("AppendEntriesResponse from peer {}: \
-                              inconsistent previous entry index: {}",
+                              inconsistent previous entry index: {}; conflict term: {}, \
+                              conflict index: {}",
                               from,
-                              next_index);
-                self.leader_state.set_next_index(from, LogIndex::from(next_index));
+                              index,
+                              conflict_term,
+                              conflict_index);
+                // The requests which followed a rejected one are rejected too, and responses may
+                // be reordered or duplicated. Only a rejection of a request still in flight, or
+                // of a heartbeat at the follower's next index, backs up the next index again.
+                let current = if self.leader_state.in_flight(&from) == 0 {
+                    index + 1 == self.leader_state.next_index(&from)
+                } else {
+                    self.leader_state.is_in_flight(&from, index)
+                };
+                if !current {
+                    scoped_debug!("AppendEntriesResponse from peer {}: ignoring stale rejection",
+                                  from);
+                    return Ok(());
+                }
+                // If the leader has entries in the conflicting term, the logs agree up to its
+                // last one; otherwise the whole term is skipped.
+                let next_index = match try!(self.last_index_of_term(conflict_term, index)) {
+                    Some(last_index) => last_index + 1,
+                    None => conflict_index,
+                };
+                // Back up by at least one entry, so that replication always makes progress, but
+                // not past the entries the follower is known to hold.
+                let next_index = cmp::max(cmp::min(next_index, index),
+                                          self.leader_state.match_index(&from) + 1);
+                self.leader_state.set_next_index(from, next_index);
+                // The requests which followed the rejected one will be rejected too.
+                self.leader_state.clear_in_flight(from);
             }
             Ok(append_entries_response::Which::StaleTerm(..)) => {
                 // The peer is reporting a stale term, but the term number matches the local term.
@@ -460,7 +963,7 @@ impl<L, M> Consensus<L, M>
                 // has already transitioned to the new term.
                 scoped_debug!("AppendEntriesResponse from peer {}: stale term (outdated)",
                               from);
-                return;
+                return Ok(());
             }
             Ok(append_entries_response::Which::InternalError(error_result)) => {
                 let error = error_result.unwrap_or("[unable to decode internal error]");
@@ -476,49 +979,354 @@ impl<L, M> Consensus<L, M>
             }
         }
 
-        let next_index = self.leader_state.next_index(&from);
-        if next_index <= local_latest_log_index {
-            // If the peer is behind, send it entries to catch up.
-            scoped_debug!("AppendEntriesResponse: peer {} is missing at least {} entries; \
-                          sending missing entries",
-                          from,
+        // The leader steps down once the configuration which removes it commits.
+        if self.is_leader() {
+            try!(self.replicate(from, actions));
+        }
+        Ok(())
+    }
+
+    /// Sends the peer any log entries it is missing, or the latest snapshot if those entries
+    /// have been compacted. If the peer is caught up, a heartbeat is scheduled instead.
+    fn replicate(&mut self, peer: ServerId, actions: &mut Actions) -> LogResult<(), L> {
+        let local_latest_log_index = try!(self.latest_log_index());
+        let next_index = self.leader_state.next_index(&peer);
+        if next_index <= try!(self.log.snapshot_index()) {
+            // The peer is missing entries which are only available in the snapshot.
+            scoped_debug!("peer {} is missing compacted entries; sending snapshot", peer);
+            try!(self.send_snapshot_chunk(peer, 0, actions));
+        } else if next_index <= local_latest_log_index &&
+                  self.leader_state.in_flight(&peer) >= self.config.replication_window {
+            // The peer is sent the missing entries once it has responded to earlier requests.
+            // Heartbeats continue meanwhile, in case those requests were lost.
+            scoped_trace!("replication window to peer {} is full", peer);
+            actions.timeouts.push(ConsensusTimeout::Heartbeat(peer));
+        } else if next_index <= local_latest_log_index {
+            // If the peer is behind, send it entries to catch up, a page per request, until the
+            // replication window fills up.
+            scoped_debug!("peer {} is missing at least {} entries; sending missing entries",
+                          peer,
                           (local_latest_log_index + 1 - next_index.0).0);
-            let prev_log_index = next_index - 1;
-            let prev_log_term = if prev_log_index == LogIndex(0) {
-                Term(0)
-            } else {
-                self.log.entry(prev_log_index).unwrap().0
-            };
+            while self.leader_state.next_index(&peer) <= local_latest_log_index &&
+                  self.leader_state.in_flight(&peer) < self.config.replication_window {
+                try!(self.send_entries_page(peer, actions));
+            }
+        } else {
+            // If the peer is caught up, set a heartbeat timeout.
+            scoped_trace!("scheduling heartbeat for peer {}", peer);
+            let timeout = ConsensusTimeout::Heartbeat(peer);
+            actions.timeouts.push(timeout);
+        }
+        Ok(())
+    }
+
+    /// Sends the peer an AppendEntries request carrying the entries from its next index, up to
+    /// the configured limits.
+    fn send_entries_page(&mut self, peer: ServerId, actions: &mut Actions) -> LogResult<(), L> {
+        let from_index = self.leader_state.next_index(&peer);
+        let entries = try!(self.page(from_index));
+        let until_index = from_index + entries.len() as u64;
+        let prev_log_index = from_index - 1;
+        let prev_log_term = try!(self.term_at(prev_log_index));
+
+        let entries: Vec<(Term, EntryKind, &[u8])> =
+            entries.iter().map(|&(term, kind, ref entry)| (term, kind, &entry[..])).collect();
+        let message = messages::append_entries_request(try!(self.current_term()),
+                                                       prev_log_index,
+                                                       prev_log_term,
+                                                       &entries,
+                                                       self.commit_index,
+                                                       self.leader_state.read_sequence());
 
-            let from_index = next_index;
-            let until_index = local_latest_log_index + 1;
+        self.leader_state.set_next_index(peer, until_index);
+        self.leader_state.send_entries(peer, prev_log_index, until_index - 1);
+        actions.peer_messages.push((peer, message));
+        Ok(())
+    }
+
+    /// Returns the entries to send in a request beginning at `from_index`, reading each entry
+    /// once. At least one entry is sent, however large.
+    fn page(&self, from_index: LogIndex) -> LogResult<Vec<(Term, EntryKind, Vec<u8>)>, L> {
+        let latest_log_index = try!(self.latest_log_index());
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+        let mut index = from_index;
+        while index <= latest_log_index &&
+              entries.len() < cmp::max(self.config.max_append_entries, 1) {
+            let entry = try!(self.log.entry(index));
+            if !entries.is_empty() && bytes + entry.2.len() > self.config.max_append_bytes {
+                break;
+            }
+            bytes += entry.2.len();
+            entries.push(entry);
+            index = index + 1;
+        }
+        Ok(entries)
+    }
+
+    /// Sends the chunk of the latest snapshot beginning at `offset` to the peer.
+    fn send_snapshot_chunk(&self,
+                           peer: ServerId,
+                           offset: u64,
+                           actions: &mut Actions)
+                           -> LogResult<(), L> {
+        let snapshot = try!(self.log.snapshot());
+        let start = cmp::min(offset, snapshot.len() as u64) as usize;
+        let end = cmp::min(start + cmp::max(self.config.snapshot_chunk_bytes, 1),
+                           snapshot.len());
+        scoped_debug!("sending snapshot bytes {}..{} of {} to peer {}",
+                      start,
+                      end,
+                      snapshot.len(),
+                      peer);
+        let message = messages::install_snapshot_request(try!(self.current_term()),
+                                                         try!(self.log.snapshot_index()),
+                                                         try!(self.log.snapshot_term()),
+                                                         start as u64,
+                                                         &snapshot[start..end],
+                                                         end == snapshot.len());
+        actions.peer_messages.push((peer, message));
+        Ok(())
+    }
 
-            let entries = self.log
-                              .entries(LogIndex::from(from_index), LogIndex::from(until_index))
-                              .unwrap();
+    /// Applies an install snapshot request to the consensus state machine.
+    fn install_snapshot_request(&mut self,
+                                from: ServerId,
+                                request: install_snapshot_request::Reader,
+                                actions: &mut Actions)
+                                -> LogResult<(), L> {
+        scoped_trace!("InstallSnapshotRequest from peer {}", &from);
 
-            let message = messages::append_entries_request(local_term,
-                                                           prev_log_index,
-                                                           prev_log_term,
-                                                           &entries,
-                                                           self.commit_index);
+        let leader_term = Term(request.get_term());
+        let current_term = try!(self.current_term());
+        let last_included_index = LogIndex(request.get_last_included_index());
+        let last_included_term = Term(request.get_last_included_term());
 
-            self.leader_state.set_next_index(from, local_latest_log_index + 1);
+        if leader_term < current_term {
+            let message = messages::install_snapshot_response_stale_term(current_term,
+                                                                         last_included_index);
             actions.peer_messages.push((from, message));
+            return Ok(());
+        }
+
+        match self.state {
+            ConsensusState::Follower => {
+                if current_term < leader_term {
+                    try!(self.log.set_current_term(leader_term));
+                    self.follower_state.set_leader(from);
+                }
+                // Only the leader of the current term sends InstallSnapshot requests.
+                self.follower_state.leader = Some(from);
+                self.follower_state.leader_contact = Some(self.now());
+                self.follower_state.leader_active = true;
+                self.follower_state.pre_candidate = false;
+            }
+            ConsensusState::Candidate => {
+                scoped_info!("received InstallSnapshotRequest from Consensus {{ id: {}, term: {} \
+                              }}; transitioning to Follower",
+                             from,
+                             leader_term);
+                try!(self.transition_to_follower(leader_term, from, actions));
+            }
+            ConsensusState::Leader => {
+                if leader_term == current_term {
+                    // The single leader-per-term invariant is broken; there is a bug in the Raft
+                    // implementation.
+                    panic!("{:?}: peer leader {} with matching term {:?} detected.",
+                           self,
+                           from,
+                           current_term);
+                }
+                scoped_info!("received InstallSnapshotRequest from Consensus {{ id: {}, term: {} \
+                              }} with newer term; transitioning to Follower",
+                             from,
+                             leader_term);
+                try!(self.transition_to_follower(leader_term, from, actions));
+            }
+        }
+
+        let term = try!(self.current_term());
+        let message = if last_included_index <= self.commit_index {
+            // Every entry covered by the snapshot is already committed locally.
+            scoped_debug!("InstallSnapshotRequest: snapshot through entry {} is already \
+                           committed",
+                          last_included_index);
+            self.follower_state.snapshot = None;
+            messages::install_snapshot_response_installed(term, last_included_index)
+        } else if let Ok(data) = request.get_data() {
+            let offset = request.get_offset();
+            if offset == 0 {
+                self.follower_state.snapshot = Some((last_included_index,
+                                                     last_included_term,
+                                                     Vec::new()));
+            }
+            let next_offset = match self.follower_state.snapshot {
+                Some((index, _, ref mut buf)) if index == last_included_index &&
+                                                 buf.len() as u64 == offset => {
+                    buf.extend_from_slice(data);
+                    buf.len() as u64
+                }
+                // A chunk was lost or reordered; ask for the chunk which follows the bytes
+                // received so far.
+                Some((index, _, ref buf)) if index == last_included_index => buf.len() as u64,
+                _ => 0,
+            };
+
+            if request.get_done() && next_offset == offset + data.len() as u64 {
+                let (index, snapshot_term, snapshot) = self.follower_state.snapshot.take().unwrap();
+                match decode_snapshot(&snapshot) {
+                    Ok(decoded) => {
+                        try!(self.install_snapshot(index,
+                                                   snapshot_term,
+                                                   &snapshot,
+                                                   decoded,
+                                                   actions));
+                        messages::install_snapshot_response_installed(try!(self.current_term()),
+                                                                      index)
+                    }
+                    Err(error) => {
+                        // The snapshot was damaged in transit. It is discarded, and the leader
+                        // sends it again from the start.
+                        scoped_warn!("InstallSnapshotRequest from peer {}: unable to decode \
+                                      snapshot: {}",
+                                     from,
+                                     error);
+                        messages::install_snapshot_response_internal_error(term,
+                                                                           index,
+                                                                           "unable to decode \
+                                                                            snapshot")
+                    }
+                }
+            } else {
+                messages::install_snapshot_response_success(term,
+                                                            last_included_index,
+                                                            next_offset)
+            }
         } else {
-            // If the peer is caught up, set a heartbeat timeout.
-            scoped_trace!("AppendEntriesResponse: scheduling heartbeat for peer {}",
+            // The transfer is discarded, and the leader sends the snapshot again from the start.
+            scoped_warn!("InstallSnapshotRequest from peer {}: unable to decode snapshot chunk",
+                         from);
+            self.follower_state.snapshot = None;
+            messages::install_snapshot_response_internal_error(term,
+                                                               last_included_index,
+                                                               "unable to decode snapshot chunk")
+        };
+        actions.peer_messages.push((from, message));
+        actions.timeouts.push(ConsensusTimeout::Election);
+        Ok(())
+    }
+
+    /// Replaces the state machine and the log prefix through `index` with the snapshot received
+    /// from the leader.
+    fn install_snapshot(&mut self,
+                        index: LogIndex,
+                        term: Term,
+                        snapshot: &[u8],
+                        decoded: (Membership, Sessions, Vec<u8>),
+                        actions: &mut Actions)
+                        -> LogResult<(), L> {
+        scoped_info!("installing snapshot through entry {} (term {})", index, term);
+        let (membership, sessions, data) = decoded;
+        try!(self.log.compact(index, term, snapshot));
+        self.state_machine.restore_snapshot(data);
+        self.sessions = sessions;
+        self.commit_index = cmp::max(self.commit_index, index);
+        self.last_applied = index;
+
+        // Configurations beyond the snapshot are kept only if the log still holds them.
+        let latest_log_index = try!(self.latest_log_index());
+        self.memberships.retain(|&(i, _)| index < i && i <= latest_log_index);
+        self.memberships.insert(0, (index, membership));
+        try!(self.update_peers(actions));
+        self.advance_follower_reads(actions);
+        Ok(())
+    }
+
+    /// Applies an install snapshot response to the consensus state machine.
+    fn install_snapshot_response(&mut self,
+                                 from: ServerId,
+                                 response: install_snapshot_response::Reader,
+                                 actions: &mut Actions)
+                                 -> LogResult<(), L> {
+        let local_term = try!(self.current_term());
+        let responder_term = Term::from(response.get_term());
+        let last_included_index = LogIndex::from(response.get_last_included_index());
+
+        if local_term < responder_term {
+            scoped_info!("InstallSnapshotResponse from peer {} with newer term: {}; \
+                         transitioning to Follower",
+                         from,
+                         responder_term);
+            try!(self.transition_to_follower(responder_term, from, actions));
+            return Ok(());
+        } else if local_term > responder_term {
+            scoped_debug!("InstallSnapshotResponse from peer {} with a different term: {}",
+                          from,
+                          responder_term);
+            return Ok(());
+        } else if !self.peers.contains_key(&from) {
+            scoped_debug!("InstallSnapshotResponse from peer {} outside of the configuration",
                           from);
-            let timeout = ConsensusTimeout::Heartbeat(from);
-            actions.timeouts.push(timeout);
+            return Ok(());
+        }
+        let now = self.now();
+        self.leader_state.record_response(from, now);
+
+        match response.which() {
+            Ok(install_snapshot_response::Which::Success(next_offset)) => {
+                scoped_assert!(self.is_leader());
+                if last_included_index == try!(self.log.snapshot_index()) {
+                    try!(self.send_snapshot_chunk(from, next_offset, actions));
+                } else {
+                    // The log has been compacted again since the transfer began; start over
+                    // with the latest snapshot.
+                    try!(self.send_snapshot_chunk(from, 0, actions));
+                }
+            }
+            Ok(install_snapshot_response::Which::Installed(())) => {
+                scoped_assert!(self.is_leader());
+                scoped_debug!("InstallSnapshotResponse from peer {}: installed snapshot through \
+                               entry {}",
+                              from,
+                              last_included_index);
+                self.leader_state.set_match_index(from, last_included_index);
+                self.leader_state.set_next_index(from, last_included_index + 1);
+                try!(self.advance_commit_index(actions));
+                if self.is_leader() {
+                    try!(self.replicate(from, actions));
+                }
+            }
+            Ok(install_snapshot_response::Which::StaleTerm(..)) => {
+                scoped_debug!("InstallSnapshotResponse from peer {}: stale term (outdated)",
+                              from);
+            }
+            Ok(install_snapshot_response::Which::InternalError(error_result)) => {
+                let error = error_result.unwrap_or("[unable to decode internal error]");
+                scoped_warn!("InstallSnapshotResponse from peer {}: internal error: {}",
+                             from,
+                             error);
+                // The follower discarded the transfer. The next heartbeat finds it still behind,
+                // and the snapshot is sent again from the start.
+                if self.is_leader() {
+                    actions.timeouts.push(ConsensusTimeout::Heartbeat(from));
+                }
+            }
+            Err(error) => {
+                scoped_warn!("InstallSnapshotResponse from peer {}: unable to deserialize \
+                              response: {}",
+                             from,
+                             error);
+            }
         }
+        Ok(())
     }
 
     /// Applies a peer request vote request to the consensus state machine.
     fn request_vote_request(&mut self,
                             candidate: ServerId,
                             request: request_vote_request::Reader,
-                            actions: &mut Actions) {
+                            actions: &mut Actions)
+                            -> LogResult<(), L> {
         let candidate_term = Term(request.get_term());
         let candidate_log_term = Term(request.get_last_log_term());
         let candidate_log_index = LogIndex(request.get_last_log_index());
@@ -528,14 +1336,29 @@ impl<L, M> Consensus<L, M>
                       candidate_term,
                       candidate_log_term,
                       candidate_log_index);
-        let local_term = self.current_term();
+        let local_term = try!(self.current_term());
+
+        if let Some(lease) = self.config.leader_lease {
+            let now = self.now();
+            let within_lease = self.follower_state
+                                   .leader_contact
+                                   .map_or(false, |contact| now < contact + lease);
+            if self.is_follower() && within_lease && !request.get_leadership_transfer() {
+                // The leader may still hold a lease which this server acknowledged; a new leader
+                // elected meanwhile could commit writes that the old leader's reads would miss.
+                scoped_debug!("ignoring RequestVoteRequest from Consensus {{ id: {} }} within \
+                               the leader lease",
+                              candidate);
+                return Ok(());
+            }
+        }
 
         let new_local_term = if candidate_term > local_term {
             scoped_info!("received RequestVoteRequest from Consensus {{ id: {}, term: {} }} \
                          with newer term; transitioning to Follower",
                          candidate,
                          candidate_term);
-            self.transition_to_follower(candidate_term, candidate, actions);
+            try!(self.transition_to_follower(candidate_term, candidate, actions));
             candidate_term
         } else {
             local_term
@@ -543,13 +1366,13 @@ impl<L, M> Consensus<L, M>
 
         let message = if candidate_term < local_term {
             messages::request_vote_response_stale_term(new_local_term)
-        } else if candidate_log_term < self.latest_log_term() ||
-                         candidate_log_index < self.latest_log_index() {
+        } else if candidate_log_term < try!(self.latest_log_term()) ||
+                         candidate_log_index < try!(self.latest_log_index()) {
             messages::request_vote_response_inconsistent_log(new_local_term)
         } else {
-            match self.log.voted_for().unwrap() {
+            match try!(self.log.voted_for()) {
                 None => {
-                    self.log.set_voted_for(candidate).unwrap();
+                    try!(self.log.set_voted_for(candidate));
                     messages::request_vote_response_granted(new_local_term)
                 }
                 Some(voted_for) if voted_for == candidate => {
@@ -559,16 +1382,18 @@ impl<L, M> Consensus<L, M>
             }
         };
         actions.peer_messages.push((candidate, message));
+        Ok(())
     }
 
     /// Applies a request vote response to the consensus state machine.
     fn request_vote_response(&mut self,
                              from: ServerId,
                              response: request_vote_response::Reader,
-                             actions: &mut Actions) {
+                             actions: &mut Actions)
+                             -> LogResult<(), L> {
         scoped_debug!("RequestVoteResponse from peer {}", from);
 
-        let local_term = self.current_term();
+        let local_term = try!(self.current_term());
         let voter_term = Term::from(response.get_term());
 
         let majority = self.majority();
@@ -583,264 +1408,1253 @@ impl<L, M> Consensus<L, M>
                          with newer term; transitioning to Follower",
                          from,
                          voter_term);
-            self.transition_to_follower(voter_term, from, actions);
+            try!(self.transition_to_follower(voter_term, from, actions));
         } else if local_term > voter_term {
             // Ignore this message; it came from a previous election cycle.
         } else if self.is_candidate() {
             // A vote was received!
             if let Ok(request_vote_response::Granted(_)) = response.which() {
+                if !self.membership().is_voter(&from) {
+                    scoped_debug!("ignoring vote from non-voting peer {}", from);
+                    return Ok(());
+                }
                 self.candidate_state.record_vote(from);
                 if self.candidate_state.count_votes() >= majority {
                     scoped_info!("election for term {} won; transitioning to Leader",
                                  local_term);
-                    self.transition_to_leader(actions);
+                    try!(self.transition_to_leader(actions));
                 }
             }
         };
+        Ok(())
     }
 
-    /// Applies a client proposal to the consensus state machine.
-    fn proposal_request(&mut self,
-                        from: ClientId,
-                        request: proposal_request::Reader,
-                        actions: &mut Actions) {
-        if self.is_candidate() || (self.is_follower() && self.follower_state.leader.is_none()) {
-            actions.client_messages.push((from, messages::command_response_unknown_leader()));
-        } else if self.is_follower() {
-            let message = messages::command_response_not_leader(&self.peers[&self.follower_state
-                                                                                 .leader
-                                                                                 .unwrap()]);
-            actions.client_messages.push((from, message));
-        } else if let Ok(entry) = request.get_entry() {
-            let prev_log_index = self.latest_log_index();
-            let prev_log_term = self.latest_log_term();
-            let term = self.current_term();
-            let log_index = prev_log_index + 1;
-            self.log.append_entries(log_index, &[(term, entry)]).unwrap();
-            self.leader_state.proposals.push_back((from, log_index));
-            if self.peers.is_empty() {
-                scoped_debug!("ProposalRequest from client {}: entry {}", from, log_index);
-                self.advance_commit_index(actions);
-            } else {
-                scoped_debug!("ProposalRequest from client {}: sending entry {} to peers",
-                              from,
-                              log_index);
-                let message = messages::append_entries_request(term,
-                                                               prev_log_index,
-                                                               prev_log_term,
-                                                               &[(term, entry)],
-                                                               self.commit_index);
-                for &peer in self.peers.keys() {
-                    if self.leader_state.next_index(&peer) == log_index {
-                        actions.peer_messages.push((peer, message.clone()));
-                        self.leader_state.set_next_index(peer, log_index + 1);
-                    }
-                }
-            }
-        } else {
-            panic!("ProposalRequest: no entry given")
-        }
+    /// Applies a pre-vote request to the consensus state machine. The vote is granted if it would
+    /// be in an election for the proposed term, unless this server still has an active leader.
+    /// Neither the term nor the recorded vote changes.
+    fn pre_vote_request(&mut self,
+                        candidate: ServerId,
+                        request: pre_vote_request::Reader,
+                        actions: &mut Actions)
+                        -> LogResult<(), L> {
+        let candidate_term = Term(request.get_term());
+        let candidate_log_term = Term(request.get_last_log_term());
+        let candidate_log_index = LogIndex(request.get_last_log_index());
+        scoped_debug!("PreVoteRequest from Consensus {{ id: {}, term: {}, latest_log_term: {}, \
+                       latest_log_index: {} }}",
+                      &candidate,
+                      candidate_term,
+                      candidate_log_term,
+                      candidate_log_index);
+        let local_term = try!(self.current_term());
+
+        let leader_active = self.is_leader() ||
+                            (self.is_follower() && self.follower_state.leader_active);
+        let granted = candidate_term > local_term && !leader_active &&
+                      candidate_log_term >= try!(self.latest_log_term()) &&
+                      candidate_log_index >= try!(self.latest_log_index());
+        let message = messages::pre_vote_response(local_term, granted);
+        actions.peer_messages.push((candidate, message));
+        Ok(())
     }
 
-    /// Applies a client query to the state machine.
-    fn query_request(&mut self,
-                     from: ClientId,
-                     request: query_request::Reader,
-                     actions: &mut Actions) {
-        scoped_trace!("query from Client({})", from);
-
-        if self.is_candidate() || (self.is_follower() && self.follower_state.leader.is_none()) {
-            actions.client_messages.push((from, messages::command_response_unknown_leader()));
-        } else if self.is_follower() {
-            let message = messages::command_response_not_leader(&self.peers[&self.follower_state
-                                                                                 .leader
-                                                                                 .unwrap()]);
-            actions.client_messages.push((from, message));
-        } else {
-            // TODO: This is probably not exactly safe.
-            let query = request.get_query().unwrap();
-            let result = self.state_machine.query(query);
-            let message = messages::command_response_success(&result);
-            actions.client_messages.push((from, message));
+    /// Applies a pre-vote response to the consensus state machine, starting the election once a
+    /// majority would grant a vote.
+    fn pre_vote_response(&mut self,
+                         from: ServerId,
+                         response: pre_vote_response::Reader,
+                         actions: &mut Actions)
+                         -> LogResult<(), L> {
+        scoped_debug!("PreVoteResponse from peer {}", from);
+        if !self.is_follower() || !self.follower_state.pre_candidate {
+            // The pre-vote has been abandoned or has already succeeded.
+            return Ok(());
         }
-    }
 
-    /// Triggers a heartbeat timeout for the peer.
-    fn heartbeat_timeout(&mut self, peer: ServerId, actions: &mut Actions) {
-        scoped_assert!(self.is_leader());
-        scoped_debug!("HeartbeatTimeout for peer: {}", peer);
-        let mut message = Builder::new_default();
-        {
-            let mut request = message.init_root::<message::Builder>()
-                                     .init_append_entries_request();
-            request.set_term(self.current_term().as_u64());
-            request.set_prev_log_index(self.latest_log_index().as_u64());
-            request.set_prev_log_term(self.log.latest_log_term().unwrap().as_u64());
-            request.set_leader_commit(self.commit_index.as_u64());
-            request.init_entries(0);
+        let local_term = try!(self.current_term());
+        let voter_term = Term::from(response.get_term());
+        if response.get_granted() {
+            if !self.membership().is_voter(&from) {
+                scoped_debug!("ignoring pre-vote from non-voting peer {}", from);
+                return Ok(());
+            }
+            self.candidate_state.record_vote(from);
+            if self.candidate_state.count_votes() >= self.majority() {
+                scoped_info!("pre-vote for term {} won; transitioning to Candidate",
+                             local_term + 1);
+                try!(self.transition_to_candidate(false, actions));
+            }
+        } else if local_term < voter_term {
+            scoped_info!("received PreVoteResponse from Consensus {{ id: {}, term: {} }} with \
+                          newer term; transitioning to Follower",
+                         from,
+                         voter_term);
+            try!(self.transition_to_follower(voter_term, from, actions));
+        }
+        Ok(())
+    }
+
+    /// Returns the response redirecting a client request to the leader, or `None` if this
+    /// instance is the leader.
+    fn leader_redirect(&self, request: RequestId) -> Option<Rc<Builder<HeapAllocator>>> {
+        if self.is_leader() {
+            return None;
+        }
+        let leader_addr = self.follower_state
+                              .leader
+                              .and_then(|leader| self.peers.get(&leader));
+        match leader_addr {
+            Some(addr) if self.is_follower() => {
+                Some(messages::command_response_not_leader(request.id, addr))
+            }
+            _ => Some(messages::command_response_unknown_leader(request.id)),
+        }
+    }
+
+    /// Applies a client proposal to the consensus state machine.
+    fn proposal_request(&mut self,
+                        from: RequestId,
+                        request: proposal_request::Reader,
+                        actions: &mut Actions)
+                        -> LogResult<(), L> {
+        if let Some(message) = self.leader_redirect(from) {
+            actions.client_messages.push((from.client, message));
+        } else if let Ok(entry) = request.get_entry() {
+            scoped_debug!("ProposalRequest from client {}", from.client);
+            let sequence = request.get_sequence();
+            if sequence == 0 {
+                // The client has no session; its proposal is not deduplicated.
+                try!(self.append_client_entry(from, EntryKind::Normal, entry, actions));
+                return Ok(());
+            }
+            let client = match request.get_client().map(ClientId::from_bytes) {
+                Ok(Ok(client)) => client,
+                _ => {
+                    let message = messages::command_response_rejected(from.id,
+                                                                      "invalid client id");
+                    actions.client_messages.push((from.client, message));
+                    return Ok(());
+                }
+            };
+            let entry = SessionEntry {
+                client: client,
+                sequence: sequence,
+                acknowledged: request.get_acknowledged(),
+                timestamp: self.timestamp(),
+                data: entry.to_vec(),
+                expiry: session::millis(self.config.session_expiry),
+            };
+            try!(self.append_client_entry(from, EntryKind::Session, &entry.to_bytes(), actions));
+        } else {
+            let message = messages::command_response_rejected(from.id, "malformed proposal");
+            actions.client_messages.push((from.client, message));
+        }
+        Ok(())
+    }
+
+    /// Applies a client request to add a server to the cluster configuration.
+    fn add_server_request(&mut self,
+                          from: RequestId,
+                          request: add_server_request::Reader,
+                          actions: &mut Actions)
+                          -> LogResult<(), L> {
+        if let Some(message) = self.leader_redirect(from) {
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let server = match request.get_server() {
+            Ok(server) => server,
+            Err(_) => {
+                let message = messages::command_response_rejected(from.id, "malformed request");
+                actions.client_messages.push((from.client, message));
+                return Ok(());
+            }
+        };
+        let id = ServerId(server.get_id());
+        let addr = match server.get_addr().map(SocketAddr::from_str) {
+            Ok(Ok(addr)) => addr,
+            _ => {
+                let message = messages::command_response_rejected(from.id,
+                                                                  "invalid server address");
+                actions.client_messages.push((from.client, message));
+                return Ok(());
+            }
+        };
+        let learner = request.get_learner();
+        scoped_info!("AddServerRequest from client {}: server {} at {} (learner: {})",
+                     from.client,
+                     id,
+                     addr,
+                     learner);
+        if self.membership().is_voter(&id) || (learner && self.membership().is_learner(&id)) {
+            let message = messages::command_response_success(from.id, &[]);
+            actions.client_messages.push((from.client, message));
+        } else if learner {
+            let membership = self.membership().with_learner(id, addr);
+            try!(self.propose_membership(from, membership, actions));
+        } else if self.membership().is_learner(&id) {
+            try!(self.promote_learner(from, id, actions));
+        } else {
+            let membership = self.membership().with_member(id, addr);
+            try!(self.propose_membership(from, membership, actions));
+        }
+        Ok(())
+    }
+
+    /// Applies a client request to remove a server from the cluster configuration.
+    fn remove_server_request(&mut self,
+                             from: RequestId,
+                             request: remove_server_request::Reader,
+                             actions: &mut Actions)
+                             -> LogResult<(), L> {
+        if let Some(message) = self.leader_redirect(from) {
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let id = ServerId(request.get_id());
+        scoped_info!("RemoveServerRequest from client {}: server {}", from.client, id);
+        if !self.membership().contains(&id) {
+            let message = messages::command_response_success(from.id, &[]);
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let membership = self.membership().without_member(id);
+        try!(self.propose_membership(from, membership, actions));
+        Ok(())
+    }
+
+    /// Applies a client request to promote a learner to a voting member.
+    fn promote_server_request(&mut self,
+                              from: RequestId,
+                              request: promote_server_request::Reader,
+                              actions: &mut Actions)
+                              -> LogResult<(), L> {
+        if let Some(message) = self.leader_redirect(from) {
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let id = ServerId(request.get_id());
+        scoped_info!("PromoteServerRequest from client {}: server {}", from.client, id);
+        if self.membership().is_voter(&id) {
+            let message = messages::command_response_success(from.id, &[]);
+            actions.client_messages.push((from.client, message));
+        } else if self.membership().is_learner(&id) {
+            try!(self.promote_learner(from, id, actions));
+        } else {
+            let message = messages::command_response_rejected(from.id,
+                                                              "server is not a learner");
+            actions.client_messages.push((from.client, message));
+        }
+        Ok(())
+    }
+
+    /// Applies a client request for the catch-up status of a learner.
+    fn learner_status_request(&mut self,
+                              from: RequestId,
+                              request: learner_status_request::Reader,
+                              actions: &mut Actions)
+                              -> LogResult<(), L> {
+        if let Some(message) = self.leader_redirect(from) {
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let id = ServerId(request.get_id());
+        let message = match self.learner_status(id) {
+            Some(status) => messages::command_response_success(from.id, &status.to_bytes()),
+            None => messages::command_response_rejected(from.id, "server is not a learner"),
+        };
+        actions.client_messages.push((from.client, message));
+        Ok(())
+    }
+
+    /// Applies a client request to transfer leadership.
+    fn transfer_leadership_request(&mut self,
+                                   from: RequestId,
+                                   request: transfer_leadership_request::Reader,
+                                   actions: &mut Actions)
+                                   -> LogResult<(), L> {
+        if let Some(message) = self.leader_redirect(from) {
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let target = ServerId(request.get_id());
+        if let Err(reason) = try!(self.start_transfer(target, Some(from), actions)) {
+            let message = messages::command_response_rejected(from.id, reason);
+            actions.client_messages.push((from.client, message));
+        }
+        Ok(())
+    }
+
+    /// Starts a leadership transfer, or returns the reason it cannot be started. The client
+    /// request, if any, is answered once the target has been told to start an election.
+    fn start_transfer(&mut self,
+                      target: ServerId,
+                      request: Option<RequestId>,
+                      actions: &mut Actions)
+                      -> LogResult<::std::result::Result<(), &'static str>, L> {
+        if !self.is_leader() {
+            return Ok(Err("not the leader"));
+        } else if target == self.id {
+            return Ok(Err("already the leader"));
+        } else if !self.membership().is_voter(&target) {
+            return Ok(Err("target is not a voting member of the cluster"));
+        } else if self.leader_state.transfer.is_some() {
+            return Ok(Err("a leadership transfer is already in progress"));
+        }
+        scoped_info!("transferring leadership to {}", target);
+        // The target has to catch up with the proposals received so far.
+        try!(self.append_batch(actions));
+        self.leader_state.transfer = Some(LeadershipTransfer {
+            target: target,
+            request: request,
+            timeout_now_sent: false,
+        });
+        actions.timeouts.push(ConsensusTimeout::LeadershipTransfer);
+        try!(self.advance_transfer(actions));
+        Ok(Ok(()))
+    }
+
+    /// Tells the target of the leadership transfer to start an election once it holds every
+    /// entry of the leader's log.
+    fn advance_transfer(&mut self, actions: &mut Actions) -> LogResult<(), L> {
+        let target = match self.leader_state.transfer {
+            Some(ref transfer) if !transfer.timeout_now_sent => transfer.target,
+            _ => return Ok(()),
+        };
+        if self.leader_state.match_index(&target) < try!(self.latest_log_index()) {
+            // The target catches up through regular replication.
+            return Ok(());
+        }
+        scoped_debug!("leadership transfer target {} caught up; sending TimeoutNow", target);
+        actions.peer_messages.push((target, messages::timeout_now(try!(self.current_term()))));
+        // Voters grant the target's votes regardless of the lease.
+        self.leader_state.revoke_lease();
+        let transfer = self.leader_state.transfer.as_mut().unwrap();
+        transfer.timeout_now_sent = true;
+        if let Some(request) = transfer.request.take() {
+            let message = messages::command_response_success(request.id, b"");
+            actions.client_messages.push((request.client, message));
+        }
+        Ok(())
+    }
+
+    /// Aborts the leadership transfer if it is still in progress, and resumes accepting entries.
+    fn leadership_transfer_timeout(&mut self, actions: &mut Actions) {
+        if !self.is_leader() {
+            return;
+        }
+        if let Some(transfer) = self.leader_state.transfer.take() {
+            scoped_info!("leadership transfer to {} timed out; aborting", transfer.target);
+            if let Some(request) = transfer.request {
+                let message = messages::command_response_rejected(request.id,
+                                                                  "the target did not catch up \
+                                                                   in time");
+                actions.client_messages.push((request.client, message));
+            }
+        }
+    }
+
+    /// Applies a TimeoutNow request from the leader, starting an election straight away.
+    fn timeout_now(&mut self,
+                   from: ServerId,
+                   request: timeout_now::Reader,
+                   actions: &mut Actions)
+                   -> LogResult<(), L> {
+        let term = Term(request.get_term());
+        if term != try!(self.current_term()) || !self.is_follower() || !self.is_voter() {
+            scoped_debug!("ignoring TimeoutNow from peer {} in term {}", from, term);
+            return Ok(());
+        }
+        scoped_info!("TimeoutNow from leader {}: transitioning to Candidate", from);
+        try!(self.transition_to_candidate(true, actions));
+        Ok(())
+    }
+
+    /// Returns how far the learner has caught up with the leader's log.
+    fn learner_status(&self, id: ServerId) -> Option<LearnerStatus> {
+        scoped_assert!(self.is_leader());
+        if !self.membership().is_learner(&id) {
+            return None;
+        }
+        let match_index = self.leader_state.match_index(&id);
+        Some(LearnerStatus {
+            match_index: match_index,
+            commit_index: self.commit_index,
+            // The commit index may lag until the leader's no-op entry commits.
+            caught_up: self.is_ready() && match_index >= self.commit_index,
+        })
+    }
+
+    /// Promotes the learner to a voting member, provided it has caught up with the log.
+    /// Promoting a learner which is far behind would stall commitment until it catches up.
+    fn promote_learner(&mut self,
+                       from: RequestId,
+                       id: ServerId,
+                       actions: &mut Actions)
+                       -> LogResult<(), L> {
+        if !self.learner_status(id).map_or(false, |status| status.caught_up) {
+            let message = messages::command_response_rejected(from.id,
+                                                              "learner has not caught up");
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let membership = self.membership().promote(id);
+        try!(self.propose_membership(from, membership, actions));
+        Ok(())
+    }
+
+    /// Appends a configuration entry on behalf of the client, unless a previous change has yet to
+    /// commit, or the leader has yet to commit the no-op entry of its term.
+    fn propose_membership(&mut self,
+                          from: RequestId,
+                          membership: Membership,
+                          actions: &mut Actions)
+                          -> LogResult<(), L> {
+        let (latest_change, _) = self.memberships[self.memberships.len() - 1];
+        if latest_change > self.commit_index {
+            let message = messages::command_response_rejected(from.id,
+                                                              "a configuration change is \
+                                                               already in progress");
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        } else if !self.is_ready() {
+            // A change committed by a previous leader may not be known to be committed yet, and
+            // two changes in flight at once could leave the cluster with disjoint majorities.
+            let message = messages::command_response_rejected(from.id,
+                                                              "the leader has not yet \
+                                                               committed an entry in its term");
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        try!(self.append_client_entry(from,
+                                      EntryKind::Configuration,
+                                      &membership.to_bytes(),
+                                      actions));
+        // Configuration changes take effect as soon as they are appended.
+        try!(self.append_batch(actions));
+        Ok(())
+    }
+
+    /// Batches an entry to be appended to the leader's log on behalf of the client. The batch is
+    /// flushed once it reaches the configured limits. The client is answered once the entry
+    /// commits.
+    fn append_client_entry(&mut self,
+                           from: RequestId,
+                           kind: EntryKind,
+                           entry: &[u8],
+                           actions: &mut Actions)
+                           -> LogResult<(), L> {
+        if self.leader_state.transfer.is_some() {
+            let message = messages::command_response_rejected(from.id,
+                                                              "a leadership transfer is in \
+                                                               progress");
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let (entries, bytes) = self.leader_state.batch_proposal(from, kind, entry.to_vec());
+        if entries >= self.config.max_batch_entries || bytes >= self.config.max_batch_bytes {
+            try!(self.append_batch(actions));
+        }
+        Ok(())
+    }
+
+    /// Applies a client query to the state machine.
+    fn query_request(&mut self,
+                     from: RequestId,
+                     request: query_request::Reader,
+                     actions: &mut Actions)
+                     -> LogResult<(), L> {
+        scoped_trace!("query from Client({})", from.client);
+
+        let query = match request.get_query() {
+            Ok(query) => query.to_vec(),
+            Err(_) => {
+                let message = messages::command_response_rejected(from.id, "malformed query");
+                actions.client_messages.push((from.client, message));
+                return Ok(());
+            }
+        };
+        // Unknown consistency levels are served with the strongest guarantee.
+        match request.get_consistency().unwrap_or(Consistency::Linearizable) {
+            Consistency::Linearizable => self.linearizable_query(from, query, actions),
+            Consistency::BoundedStaleness => self.bounded_staleness_query(from, query, actions),
+            Consistency::AnyReplica => {
+                let result = self.state_machine.query(&query);
+                let message = messages::command_response_success(from.id, &result);
+                actions.client_messages.push((from.client, message));
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers a linearizable query once leadership is confirmed, or redirects the client to the
+    /// leader.
+    fn linearizable_query(&mut self,
+                          from: RequestId,
+                          query: Vec<u8>,
+                          actions: &mut Actions)
+                          -> LogResult<(), L> {
+        if let Some(message) = self.leader_redirect(from) {
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+
+        if self.is_ready() && self.leader_state.holds_lease(self.now()) &&
+           self.last_applied >= self.commit_index {
+            // No other leader can have been elected while the lease holds.
+            scoped_trace!("answering query from client {} under the leader lease", from.client);
+            let result = self.state_machine.query(&query);
+            let message = messages::command_response_success(from.id, &result);
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+
+        // ReadIndex: the query may be answered once a majority has confirmed that this server
+        // is still the leader, and the state machine has caught up with the current commit index.
+        // Until the no-op entry of the term commits, the commit index may lag behind entries
+        // committed in earlier terms, so the query waits for the no-op too.
+        let sequence = self.start_read_round();
+        let read_index = self.read_index();
+        self.leader_state.queries.push_back(PendingQuery {
+            request: from,
+            read_index: read_index,
+            sequence: sequence,
+            query: query,
+        });
+        // Each follower is sent a heartbeat following the entries it has been sent so far, which
+        // it accepts even if it lags behind the leader's log.
+        let peers: Vec<ServerId> = self.peers.keys().cloned().collect();
+        for peer in peers {
+            let message = try!(self.heartbeat_message(peer));
+            actions.peer_messages.push((peer, message));
+        }
+        self.advance_reads(actions);
+        Ok(())
+    }
+
+    /// Answers a bounded-staleness query once the state machine has applied the leader's commit
+    /// index. Followers which have not heard from the leader within `max_staleness` refuse it.
+    fn bounded_staleness_query(&mut self,
+                               from: RequestId,
+                               query: Vec<u8>,
+                               actions: &mut Actions)
+                               -> LogResult<(), L> {
+        if self.is_leader() && !self.is_ready() {
+            // Until the no-op entry of its term commits, the leader may lag behind entries
+            // committed in earlier terms, so the query waits for it like a linearizable one.
+            return self.linearizable_query(from, query, actions);
+        }
+        if self.is_leader() {
+            // Committed entries are applied straight away, so the leader is never behind.
+            let result = self.state_machine.query(&query);
+            let message = messages::command_response_success(from.id, &result);
+            actions.client_messages.push((from.client, message));
+            return Ok(());
+        }
+        let leader = match self.follower_state.leader {
+            Some(leader) if self.is_follower() && self.peers.contains_key(&leader) => leader,
+            _ => {
+                let message = messages::command_response_unknown_leader(from.id);
+                actions.client_messages.push((from.client, message));
+                return Ok(());
+            }
+        };
+        if let Some(max_staleness) = self.config.max_staleness {
+            let now = self.now();
+            let recent = self.follower_state
+                             .leader_contact
+                             .map_or(false, |contact| now < contact + max_staleness);
+            if !recent {
+                // The leader may have been deposed while this server was cut off from it.
+                scoped_debug!("rejecting query from client {}: no recent leader contact",
+                              from.client);
+                let message = messages::command_response_unknown_leader(from.id);
+                actions.client_messages.push((from.client, message));
+                return Ok(());
+            }
+        }
+        let sequence = self.follower_state.request_read_index(from, query);
+        let message = messages::read_index_request(try!(self.current_term()), sequence);
+        actions.peer_messages.push((leader, message));
+        Ok(())
+    }
+
+    /// Applies a read index request from a follower, returning the commit index if this server
+    /// leads the follower's term.
+    fn read_index_request(&mut self,
+                          from: ServerId,
+                          request: read_index_request::Reader,
+                          actions: &mut Actions)
+                          -> LogResult<(), L> {
+        scoped_trace!("ReadIndexRequest from peer {}", from);
+        let term = try!(self.current_term());
+        let read_sequence = request.get_read_sequence();
+        let message = if self.is_leader() && Term(request.get_term()) == term {
+            messages::read_index_response(term, read_sequence, self.read_index())
+        } else {
+            messages::read_index_response_not_leader(term, read_sequence)
+        };
+        actions.peer_messages.push((from, message));
+        Ok(())
+    }
+
+    /// Applies a read index response from the leader, queueing the query until the read index
+    /// has been applied.
+    fn read_index_response(&mut self,
+                           from: ServerId,
+                           response: read_index_response::Reader,
+                           actions: &mut Actions)
+                           -> LogResult<(), L> {
+        let read_sequence = response.get_read_sequence();
+        let (request, query) = match self.follower_state.read_requests.remove(&read_sequence) {
+            Some(request) => request,
+            None => {
+                scoped_debug!("ReadIndexResponse from peer {} for an unknown query", from);
+                return Ok(());
+            }
+        };
+        match response.which() {
+            Ok(read_index_response::Which::ReadIndex(read_index))
+                if Term(response.get_term()) == try!(self.current_term()) => {
+                scoped_trace!("ReadIndexResponse from peer {}: read index {}", from, read_index);
+                self.follower_state.queries.push(PendingQuery {
+                    request: request,
+                    read_index: LogIndex(read_index),
+                    sequence: read_sequence,
+                    query: query,
+                });
+                self.advance_follower_reads(actions);
+            }
+            _ => {
+                scoped_debug!("ReadIndexResponse from peer {}: not leader", from);
+                let message = messages::command_response_unknown_leader(request.id);
+                actions.client_messages.push((request.client, message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the bounded-staleness queries whose read index has been applied.
+    fn advance_follower_reads(&mut self, actions: &mut Actions) {
+        let last_applied = self.last_applied;
+        let (ready, waiting): (Vec<PendingQuery>, Vec<PendingQuery>) =
+            self.follower_state
+                .queries
+                .drain(..)
+                .partition(|query| query.read_index <= last_applied);
+        self.follower_state.queries = waiting;
+        for query in ready {
+            let result = self.state_machine.query(&query.query);
+            let message = messages::command_response_success(query.request.id, &result);
+            actions.client_messages.push((query.request.client, message));
+        }
+    }
+
+    /// Fails the pending bounded-staleness queries, which may never be answered now that the
+    /// leader is unreachable. Clients retry them.
+    fn fail_follower_reads(&mut self, actions: &mut Actions) {
+        for request in self.follower_state.drain_queries() {
+            let message = messages::command_response_unknown_leader(request.id);
+            actions.client_messages.push((request.client, message));
+        }
+    }
+
+    /// Returns the index the state machine has to reach before a query may be answered: the
+    /// commit index, or the no-op entry which begins the leader's term if it has yet to commit.
+    fn read_index(&self) -> LogIndex {
+        cmp::max(self.commit_index, self.leader_state.term_start)
+    }
+
+    /// Answers the queued queries for which leadership has been confirmed and the read index
+    /// applied.
+    fn advance_reads(&mut self, actions: &mut Actions) {
+        let majority = self.majority();
+        while let Some(sequence) = self.leader_state.queries.front().map(|query| query.sequence) {
+            let mut confirmations = self.leader_state.count_read_acks(sequence);
+            if !self.is_voter() {
+                // A leader which is removing itself does not count towards the majority.
+                confirmations -= 1;
+            }
+            if confirmations < majority ||
+               self.last_applied < self.leader_state.queries[0].read_index {
+                // Queries are confirmed and applied in order, so the rest have to wait too.
+                break;
+            }
+            let query = self.leader_state.queries.pop_front().unwrap();
+            scoped_trace!("answering query from client {} at read index {}",
+                          query.request.client,
+                          query.read_index);
+            let result = self.state_machine.query(&query.query);
+            let message = messages::command_response_success(query.request.id, &result);
+            actions.client_messages.push((query.request.client, message));
+        }
+    }
+
+    /// Triggers a heartbeat timeout for the peer.
+    fn heartbeat_timeout(&mut self, peer: ServerId, actions: &mut Actions) -> LogResult<(), L> {
+        scoped_assert!(self.is_leader());
+        scoped_debug!("HeartbeatTimeout for peer: {}", peer);
+        if !self.peers.contains_key(&peer) {
+            // The peer has left the configuration since the heartbeat was scheduled.
+            return Ok(());
+        }
+        if self.config.leader_lease.is_some() {
+            // Every heartbeat is a round of confirmation which may renew the lease.
+            self.start_read_round();
+        }
+        let message = try!(self.heartbeat_message(peer));
+        actions.peer_messages.push((peer, message));
+        Ok(())
+    }
+
+    /// Steps down if a majority of voters has not responded within the check-quorum period, or
+    /// else schedules the next check. The check runs on its own timer, so that it happens even
+    /// when no heartbeats are due, for example while every follower is being sent entries.
+    fn check_quorum_timeout(&mut self, actions: &mut Actions) {
+        scoped_assert!(self.is_leader());
+        let period = match self.config.check_quorum {
+            Some(period) => period,
+            None => return,
+        };
+        let mut active = self.leader_state.count_active(self.now(), period);
+        if !self.is_voter() {
+            active -= 1;
+        }
+        if active < self.majority() {
+            scoped_info!("CheckQuorumTimeout: no response from a majority within {:?}; stepping \
+                          down",
+                         period);
+            self.step_down(actions);
+        } else {
+            actions.timeouts.push(ConsensusTimeout::CheckQuorum);
+        }
+    }
+
+    /// Starts a new round of leadership confirmation, remembering when it started if leases
+    /// are enabled.
+    fn start_read_round(&mut self) -> u64 {
+        let sequence = self.leader_state.start_read_round();
+        if let Some(lease) = self.config.leader_lease {
+            let now = self.now();
+            self.leader_state.record_round_start(sequence, now, lease);
         }
-        actions.peer_messages.push((peer, Rc::new(message)));
+        sequence
+    }
+
+    /// Returns an empty AppendEntries request for the peer, which asserts leadership and carries
+    /// the latest read sequence number. The request follows the last entry sent to the peer
+    /// rather than the end of the leader's log, so that a follower which is catching up accepts
+    /// it.
+    fn heartbeat_message(&self, peer: ServerId) -> LogResult<Rc<Builder<HeapAllocator>>, L> {
+        // Entries covered by the snapshot have no term of their own in the log.
+        let prev_log_index = cmp::max(self.leader_state.next_index(&peer) - 1,
+                                      try!(self.log.snapshot_index()));
+        Ok(messages::append_entries_request(try!(self.current_term()),
+                                            prev_log_index,
+                                            try!(self.term_at(prev_log_index)),
+                                            &[],
+                                            self.commit_index,
+                                            self.leader_state.read_sequence()))
     }
 
     /// Triggers an election timeout.
-    fn election_timeout(&mut self, actions: &mut Actions) {
+    fn election_timeout(&mut self, actions: &mut Actions) -> LogResult<(), L> {
         scoped_assert!(!self.is_leader());
-        if self.peers.is_empty() {
-            // Solitary replica special case; jump straight to Leader state.
+        self.fail_follower_reads(actions);
+        self.follower_state.leader_active = false;
+        if !self.is_voter() {
+            // Learners and servers outside of the configuration do not campaign, but keep
+            // waiting to hear from a leader.
+            scoped_debug!("ElectionTimeout: not a voting member of the configuration");
+            actions.timeouts.push(ConsensusTimeout::Election);
+        } else if self.majority() == 1 {
+            // Solitary voter special case; jump straight to Leader state.
             scoped_info!("ElectionTimeout: transitioning to Leader");
             scoped_assert!(self.is_follower());
-            scoped_assert!(self.log.voted_for().unwrap().is_none());
-            self.log.inc_current_term().unwrap();
-            self.log.set_voted_for(self.id).unwrap();
-            let latest_log_index = self.latest_log_index();
-            self.state = ConsensusState::Leader;
-            self.leader_state.reinitialize(latest_log_index);
+            scoped_assert!(try!(self.log.voted_for()).is_none());
+            try!(self.log.inc_current_term());
+            try!(self.log.set_voted_for(self.id));
+            try!(self.transition_to_leader(actions));
+        } else if self.config.pre_vote && self.is_follower() {
+            scoped_info!("ElectionTimeout: starting pre-vote");
+            try!(self.start_pre_vote(actions));
         } else {
             scoped_info!("ElectionTimeout: transitioning to Candidate");
-            self.transition_to_candidate(actions);
+            try!(self.transition_to_candidate(false, actions));
+        }
+        Ok(())
+    }
+
+    /// Asks the voters whether they would grant a vote in the next term, without incrementing
+    /// the term. The pre-vote is retried on the next election timeout if it fails.
+    fn start_pre_vote(&mut self, actions: &mut Actions) -> LogResult<(), L> {
+        scoped_trace!("starting pre-vote");
+        self.follower_state.pre_candidate = true;
+        self.candidate_state.clear();
+        self.candidate_state.record_vote(self.id);
+
+        let message = messages::pre_vote_request(try!(self.current_term()) + 1,
+                                                 try!(self.latest_log_index()),
+                                                 try!(self.log.latest_log_term()));
+        for &peer in self.peers().keys() {
+            if self.membership().is_voter(&peer) {
+                actions.peer_messages.push((peer, message.clone()));
+            }
         }
+        actions.timeouts.push(ConsensusTimeout::Election);
+        Ok(())
     }
 
     /// Transitions this consensus state machine to Leader state.
-    fn transition_to_leader(&mut self, actions: &mut Actions) {
+    ///
+    /// The leader begins its term with an empty no-op entry. Entries from earlier terms are only
+    /// committed along with an entry from the current term, so the leader learns the commit index
+    /// once the no-op commits.
+    fn transition_to_leader(&mut self, actions: &mut Actions) -> LogResult<(), L> {
         scoped_trace!("transitioning to Leader");
-        let current_term = self.current_term();
-        let latest_log_index = self.latest_log_index();
-        let latest_log_term = self.log.latest_log_term().unwrap();
+        let current_term = try!(self.current_term());
+        let latest_log_index = try!(self.latest_log_index());
         self.state = ConsensusState::Leader;
-        self.leader_state.reinitialize(latest_log_index);
+        let now = self.now();
+        self.leader_state.reinitialize(latest_log_index, now);
 
-        let message = messages::append_entries_request(current_term,
-                                                       latest_log_index,
-                                                       latest_log_term,
-                                                       &[],
-                                                       self.commit_index);
-        for &peer in self.peers().keys() {
-            actions.peer_messages.push((peer, message.clone()));
-        }
+        let term_start = self.leader_state.term_start;
+        let noop: (Term, EntryKind, &[u8]) = (current_term, EntryKind::NoOp, &[]);
+        try!(self.log.append_entries(term_start, &[noop]));
+        scoped_debug!("appended no-op entry {} to begin term {}", term_start, current_term);
 
         actions.clear_timeouts = true;
         actions.clear_peer_messages = true;
+        if self.config.check_quorum.is_some() {
+            actions.timeouts.push(ConsensusTimeout::CheckQuorum);
+        }
+        let peers: Vec<ServerId> = self.peers.keys().cloned().collect();
+        for peer in peers {
+            try!(self.replicate(peer, actions));
+        }
+        // Without other voters the no-op commits immediately.
+        self.advance_commit_index(actions)
     }
 
-    /// Transitions the consensus state machine to Candidate state.
-    fn transition_to_candidate(&mut self, actions: &mut Actions) {
+    /// Transitions the consensus state machine to Candidate state. Voters do not refuse an
+    /// election started by a leadership transfer on account of the leader lease.
+    fn transition_to_candidate(&mut self,
+                               leadership_transfer: bool,
+                               actions: &mut Actions)
+                               -> LogResult<(), L> {
         scoped_trace!("transitioning to Candidate");
-        self.log.inc_current_term().unwrap();
-        self.log.set_voted_for(self.id).unwrap();
+        self.follower_state.pre_candidate = false;
+        try!(self.log.inc_current_term());
+        try!(self.log.set_voted_for(self.id));
         self.state = ConsensusState::Candidate;
         self.candidate_state.clear();
         self.candidate_state.record_vote(self.id);
+        self.candidate_state.leadership_transfer = leadership_transfer;
 
-        let message = messages::request_vote_request(self.current_term(),
-                                                     self.latest_log_index(),
-                                                     self.log.latest_log_term().unwrap());
+        let message = messages::request_vote_request(try!(self.current_term()),
+                                                     try!(self.latest_log_index()),
+                                                     try!(self.log.latest_log_term()),
+                                                     leadership_transfer);
 
         for &peer in self.peers().keys() {
-            actions.peer_messages.push((peer, message.clone()));
+            if self.membership().is_voter(&peer) {
+                actions.peer_messages.push((peer, message.clone()));
+            }
         }
         actions.timeouts.push(ConsensusTimeout::Election);
         actions.clear_peer_messages = true;
+        Ok(())
     }
 
     /// Advances the commit index and applies committed entries to the state machine.
-    fn advance_commit_index(&mut self, actions: &mut Actions) {
+    ///
+    /// Only an entry from the current term is committed by counting its replicas; the entries
+    /// preceding it commit along with it. An entry from an earlier term may be replicated on a
+    /// majority and still be overwritten by a later leader (see figure 8 of the Raft paper).
+    fn advance_commit_index(&mut self, actions: &mut Actions) -> LogResult<(), L> {
         scoped_assert!(self.is_leader());
         let majority = self.majority();
-        // TODO: Figure out failure condition here.
-        while self.commit_index < self.log.latest_log_index().unwrap() {
-            if self.leader_state.count_match_indexes(self.commit_index + 1) >= majority {
-                self.commit_index = self.commit_index + 1;
-                scoped_debug!("commit index advanced to {}", self.commit_index);
+        let latest_log_index = try!(self.log.latest_log_index());
+        let mut index = self.commit_index;
+        while index < latest_log_index {
+            let mut replicas = self.leader_state.count_match_indexes(index + 1);
+            if !self.is_voter() {
+                // A leader which is removing itself does not count towards the majority.
+                replicas -= 1;
+            }
+            if replicas >= majority {
+                index = index + 1;
             } else {
                 break; // If there isn't a majority now, there won't be one later.
             }
         }
+        if index > self.commit_index && try!(self.term_at(index)) == try!(self.current_term()) {
+            let was_ready = self.is_ready();
+            self.commit_index = index;
+            scoped_debug!("commit index advanced to {}", self.commit_index);
+            if !was_ready && self.is_ready() {
+                scoped_info!("no-op entry {} committed; ready to lead term {}",
+                             self.leader_state.term_start,
+                             try!(self.current_term()));
+            }
+        }
 
-        let results = self.apply_commits();
+        let results = try!(self.apply_commits());
+        self.advance_reads(actions);
 
-        while let Some(&(client, index)) = self.leader_state.proposals.get(0) {
+        while let Some(&(request, index)) = self.leader_state.proposals.get(0) {
             if index <= self.commit_index {
-                scoped_trace!("responding to client {} for entry {}", client, index);
-                // We know that there will be an index here since it was commited
-                // and the index is less than that which has been commited.
-                let result = &results[&index];
-                let message = messages::command_response_success(result);
-                actions.client_messages.push((client, message));
+                scoped_trace!("responding to client {} for entry {}", request.client, index);
+                // Every proposal up to the commit index was applied just now; configuration
+                // entries are not applied to the state machine, and have an empty result.
+                let message = match results.get(&index) {
+                    Some(&None) => messages::command_response_session_expired(request.id),
+                    Some(&Some(ref result)) => {
+                        messages::command_response_success(request.id, result)
+                    }
+                    None => messages::command_response_success(request.id, b""),
+                };
+                actions.client_messages.push((request.client, message));
                 self.leader_state.proposals.pop_front();
             } else {
                 break;
             }
         }
+
+        let (latest_change, _) = self.memberships[self.memberships.len() - 1];
+        if !self.is_voter() && latest_change <= self.commit_index {
+            // The configuration which removes this server has committed; step down and leave
+            // the remaining members to elect a new leader.
+            scoped_info!("removed from the configuration; stepping down");
+            self.step_down(actions);
+        }
+        Ok(())
+    }
+
+    /// Returns the leader to Follower state in the current term, without a known leader. Queries
+    /// waiting for confirmation are failed so that the clients retry elsewhere.
+    fn step_down(&mut self, actions: &mut Actions) {
+        scoped_trace!("stepping down");
+        self.abandon_batch(actions);
+        self.state = ConsensusState::Follower;
+        self.follower_state = FollowerState::new();
+        for query in self.leader_state.queries.drain(..) {
+            let message = messages::command_response_unknown_leader(query.request.id);
+            actions.client_messages.push((query.request.client, message));
+        }
+        actions.clear_timeouts = true;
+        actions.timeouts.push(ConsensusTimeout::Election);
     }
 
     /// Applies all committed but unapplied log entries to the state machine.  Returns the set of
-    /// return values from the commits applied.
-    fn apply_commits(&mut self) -> HashMap<LogIndex, Vec<u8>> {
+    /// return values from the commits applied, with `None` for the proposals rejected because
+    /// their client's session had expired.
+    fn apply_commits(&mut self) -> LogResult<HashMap<LogIndex, Option<Vec<u8>>>, L> {
         let mut results = HashMap::new();
         while self.last_applied < self.commit_index {
-            // Unwrap justified here since we know there is an entry here.
-            let (_, entry) = self.log.entry(self.last_applied + 1).unwrap();
+            let index = self.last_applied + 1;
+            let (_, kind, entry) = try!(self.log.entry(index));
+
+            match kind {
+                EntryKind::Normal => {
+                    let result = self.state_machine.apply(&entry);
+                    results.insert(index, Some(result));
+                }
+                EntryKind::Session => {
+                    // Duplicates are answered with the result of the first application.
+                    let entry = try!(SessionEntry::from_bytes(&entry).map_err(|error| {
+                        LogError::corrupt_entry(index, error)
+                    }));
+                    let state_machine = &mut self.state_machine;
+                    match self.sessions.apply(&entry, |data| state_machine.apply(data)) {
+                        Applied::Result(result) => {
+                            results.insert(index, Some(result));
+                        }
+                        Applied::Discarded => (),
+                        Applied::Expired => {
+                            scoped_debug!("session of client {} expired; entry {} not applied",
+                                          entry.client,
+                                          index);
+                            results.insert(index, None);
+                        }
+                    }
+                }
+                // Configurations take effect when appended, and no-ops carry nothing to apply.
+                EntryKind::Configuration | EntryKind::NoOp => (),
+            }
+            self.last_applied = index;
+        }
+        try!(self.compact_log());
+        Ok(results)
+    }
+
+    /// Takes a snapshot of the state machine and compacts the log through the last applied entry
+    /// if the configured number of entries has been applied since the previous snapshot.
+    fn compact_log(&mut self) -> LogResult<(), L> {
+        let threshold = match self.config.snapshot_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let snapshot_index = try!(self.log.snapshot_index());
+        if self.last_applied - snapshot_index < cmp::max(threshold, 1) {
+            return Ok(());
+        }
+        let index = self.last_applied;
+        let term = try!(self.term_at(index));
+        // The snapshot records the configuration as of `index`, which is the latest
+        // configuration entry at or before it.
+        let position = self.memberships.iter().rposition(|&(i, _)| i <= index).unwrap();
+        let snapshot = encode_snapshot(&self.memberships[position].1,
+                                       &self.sessions,
+                                       &self.state_machine.snapshot());
+        scoped_info!("compacting log through entry {} (term {})", index, term);
+        try!(self.log.compact(index, term, &snapshot));
+        self.memberships.drain(..position);
+        self.memberships[0].0 = index;
+        Ok(())
+    }
+
+    /// Records the configuration entries among `entries`, which were appended to the log
+    /// beginning at `from`, and makes the latest configuration in the log the active one.
+    fn track_memberships(&mut self,
+                         from: LogIndex,
+                         entries: &[(Term, EntryKind, &[u8])],
+                         actions: &mut Actions)
+                         -> LogResult<(), L> {
+        // Appending may have truncated the log, or replaced entries.
+        let latest_log_index = try!(self.latest_log_index());
+        let until = from + entries.len() as u64;
+        let base = self.memberships[0].0;
+        self.memberships.retain(|&(i, _)| {
+            i == base || ((i < from || until <= i) && i <= latest_log_index)
+        });
+        for (n, &(_, kind, entry)) in entries.iter().enumerate() {
+            if kind == EntryKind::Configuration {
+                let index = from + n as u64;
+                let membership = try!(Membership::from_bytes(entry).map_err(|error| {
+                    LogError::corrupt_entry(index, error)
+                }));
+                self.memberships.push((index, membership));
+            }
+        }
+        self.memberships.sort_by_key(|&(i, _)| i);
+        try!(self.update_peers(actions));
+        Ok(())
+    }
 
-            if !entry.is_empty() {
-                let result = self.state_machine.apply(entry);
-                results.insert(self.last_applied + 1, result);
+    /// Brings the peer set in line with the active configuration, and notifies the `Server` if
+    /// it changed.
+    fn update_peers(&mut self, actions: &mut Actions) -> LogResult<(), L> {
+        let peers = self.membership().peers(self.id);
+        if peers != self.peers {
+            scoped_info!("configuration changed: {:?}", self.membership());
+            let latest_log_index = try!(self.latest_log_index());
+            let now = self.now();
+            for peer in self.peers.keys() {
+                if !peers.contains_key(peer) {
+                    self.leader_state.remove_peer(peer);
+                }
+            }
+            for peer in peers.keys() {
+                if !self.peers.contains_key(peer) {
+                    self.leader_state.add_peer(*peer, latest_log_index, now);
+                }
             }
-            self.last_applied = self.last_applied + 1;
+            self.peers = peers;
+            actions.peers = Some(self.peers.clone());
+        }
+        // A learner may have been promoted without the peer set changing.
+        let roles: Vec<(ServerId, bool)> = self.peers
+                                               .keys()
+                                               .map(|&peer| {
+                                                   (peer, self.membership().is_learner(&peer))
+                                               })
+                                               .collect();
+        for (peer, learner) in roles {
+            self.leader_state.set_learner(peer, learner);
         }
-        results
+        Ok(())
     }
 
     /// Transitions the consensus state machine to Follower state with the provided term. The
     /// `voted_for` field will be reset. The provided leader hint will replace the last known
     /// leader.
-    fn transition_to_follower(&mut self, term: Term, leader: ServerId, actions: &mut Actions) {
+    fn transition_to_follower(&mut self,
+                              term: Term,
+                              leader: ServerId,
+                              actions: &mut Actions)
+                              -> LogResult<(), L> {
         scoped_trace!("transitioning to Follower");
-        self.log.set_current_term(term).unwrap();
+        self.abandon_batch(actions);
+        try!(self.log.set_current_term(term));
         self.state = ConsensusState::Follower;
         self.follower_state.set_leader(leader);
         actions.clear_timeouts = true;
         actions.clear_peer_messages = true;
         actions.timeouts.push(ConsensusTimeout::Election);
+        Ok(())
+    }
+
+    /// Fails the batched proposals which were never appended, so that the clients retry them
+    /// with the new leader.
+    fn abandon_batch(&mut self, actions: &mut Actions) {
+        for (request, _, _) in self.leader_state.take_batch() {
+            let message = messages::command_response_unknown_leader(request.id);
+            actions.client_messages.push((request.client, message));
+        }
     }
 
     /// Returns whether the consensus state machine is currently a Leader.
-    fn is_leader(&self) -> bool {
+    pub fn is_leader(&self) -> bool {
         self.state == ConsensusState::Leader
     }
 
+    /// Returns whether this server is the leader, and has committed the no-op entry which begins
+    /// its term. Only then does it know the commit index.
+    fn is_ready(&self) -> bool {
+        self.is_leader() && self.commit_index >= self.leader_state.term_start
+    }
+
     /// Returns whether the consensus state machine is currently a Follower.
     fn is_follower(&self) -> bool {
         self.state == ConsensusState::Follower
     }
 
+    /// Returns the current time.
+    fn now(&self) -> Instant {
+        self.time.unwrap_or_else(Instant::now)
+    }
+
+    /// Returns the time on the consensus clock in milliseconds, for timestamping proposals.
+    fn timestamp(&self) -> u64 {
+        let (base, timestamp) = self.clock_base;
+        let now = self.now();
+        if now > base {
+            timestamp + session::millis(now - base)
+        } else {
+            timestamp
+        }
+    }
+
     /// Returns whether the consensus state machine is currently a Candidate.
     fn is_candidate(&self) -> bool {
         self.state == ConsensusState::Candidate
     }
 
     /// Returns the current term.
-    fn current_term(&self) -> Term {
-        self.log.current_term().unwrap()
+    fn current_term(&self) -> LogResult<Term, L> {
+        Ok(try!(self.log.current_term()))
     }
 
     /// Returns the term of the latest applied log entry.
-    fn latest_log_term(&self) -> Term {
-        self.log.latest_log_term().unwrap()
+    fn latest_log_term(&self) -> LogResult<Term, L> {
+        Ok(try!(self.log.latest_log_term()))
     }
 
     /// Returns the index of the latest applied log entry.
-    fn latest_log_index(&self) -> LogIndex {
-        self.log.latest_log_index().unwrap()
+    fn latest_log_index(&self) -> LogResult<LogIndex, L> {
+        Ok(try!(self.log.latest_log_index()))
+    }
+
+    /// Returns the term of the log entry at the provided index, which may be the last entry
+    /// covered by the snapshot.
+    fn term_at(&self, index: LogIndex) -> LogResult<Term, L> {
+        if index == LogIndex(0) {
+            Ok(Term(0))
+        } else if index == try!(self.log.snapshot_index()) {
+            Ok(try!(self.log.snapshot_term()))
+        } else {
+            Ok(try!(self.log.term(index)))
+        }
+    }
+
+    /// Returns the index of the first entry in the term of the entry at `index`, or the index
+    /// following the snapshot if the term began before it.
+    fn first_index_of_term(&self, index: LogIndex) -> LogResult<LogIndex, L> {
+        let term = try!(self.term_at(index));
+        let snapshot_index = try!(self.log.snapshot_index());
+        let mut first = index;
+        while first - 1 > snapshot_index && try!(self.term_at(first - 1)) == term {
+            first = first - 1;
+        }
+        Ok(first)
+    }
+
+    /// Returns the index of the last entry in the term at or before `index`, if the log holds
+    /// any entry of the term.
+    fn last_index_of_term(&self, term: Term, index: LogIndex) -> LogResult<Option<LogIndex>, L> {
+        if term == Term(0) {
+            return Ok(None);
+        }
+        let snapshot_index = try!(self.log.snapshot_index());
+        let mut last = cmp::min(index, try!(self.latest_log_index()));
+        // Terms only increase along the log.
+        while last > snapshot_index && try!(self.term_at(last)) > term {
+            last = last - 1;
+        }
+        if last > LogIndex(0) && last >= snapshot_index && try!(self.term_at(last)) == term {
+            Ok(Some(last))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the active configuration.
+    fn membership(&self) -> &Membership {
+        &self.memberships[self.memberships.len() - 1].1
+    }
+
+    /// Returns whether this instance is a voting member of the active configuration.
+    pub fn is_voter(&self) -> bool {
+        self.membership().is_voter(&self.id)
     }
 
     /// Get the cluster quorum majority size.
     fn majority(&self) -> usize {
-        let peers = self.peers.len();
-        let cluster_members = peers.checked_add(1)
-                                   .expect(&format!("unable to support {} cluster members", peers));
-        (cluster_members >> 1) + 1
+        self.membership().majority()
+    }
+}
+
+/// Encodes the snapshot stored in the log, which wraps the state machine snapshot together with
+/// the configuration and the client sessions it was taken under.
+fn encode_snapshot(membership: &Membership, sessions: &Sessions, data: &[u8]) -> Vec<u8> {
+    let mut message = Builder::new_default();
+    {
+        let mut snapshot = message.init_root::<snapshot::Builder>();
+        snapshot.set_data(data);
+        membership.write(snapshot.borrow().init_membership());
+        sessions.write(snapshot);
     }
+    let mut bytes = Vec::new();
+    serialize::write_message(&mut bytes, &message).unwrap();
+    bytes
+}
+
+/// Decodes the entries of an AppendEntries request, returning `None` if any of them is malformed
+/// or of an unknown kind.
+pub fn decode_entries<'a>(entries: struct_list::Reader<'a, entry::Owned>)
+                          -> Option<Vec<(Term, EntryKind, &'a [u8])>> {
+    entries.iter()
+           .map(|entry| {
+               let kind = match entry.get_kind() {
+                   Ok(messages_capnp::EntryKind::Normal) => EntryKind::Normal,
+                   Ok(messages_capnp::EntryKind::Noop) => EntryKind::NoOp,
+                   Ok(messages_capnp::EntryKind::Configuration) => EntryKind::Configuration,
+                   Ok(messages_capnp::EntryKind::Session) => EntryKind::Session,
+                   Err(_) => return None,
+               };
+               entry.get_data().ok().map(|data| (Term(entry.get_term()), kind, data))
+           })
+           .collect()
+}
+
+/// Decodes a snapshot encoded with `encode_snapshot`.
+fn decode_snapshot(mut bytes: &[u8]) -> Result<(Membership, Sessions, Vec<u8>)> {
+    let message = try!(serialize::read_message(&mut bytes, ReaderOptions::new()));
+    let snapshot = try!(message.get_root::<snapshot::Reader>());
+    let membership = try!(Membership::read(try!(snapshot.get_membership())));
+    let sessions = try!(Sessions::read(snapshot));
+    Ok((membership, sessions, try!(snapshot.get_data()).to_vec()))
 }
 
 impl<L, M> fmt::Debug for Consensus<L, M>
@@ -848,25 +2662,16 @@ impl<L, M> fmt::Debug for Consensus<L, M>
           M: StateMachine
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self.state {
-            ConsensusState::Follower => {
-                write!(fmt,
-                       "Follower {{ term: {}, index: {} }}",
-                       self.current_term(),
-                       self.latest_log_index())
-            }
-            ConsensusState::Candidate => {
-                write!(fmt,
-                       "Candidate {{ term: {}, index: {} }}",
-                       self.current_term(),
-                       self.latest_log_index())
-            }
-            ConsensusState::Leader => {
-                write!(fmt,
-                       "Leader {{ term: {}, index: {} }}",
-                       self.current_term(),
-                       self.latest_log_index())
+        let state = match self.state {
+            ConsensusState::Follower => "Follower",
+            ConsensusState::Candidate => "Candidate",
+            ConsensusState::Leader => "Leader",
+        };
+        match (self.log.current_term(), self.log.latest_log_index()) {
+            (Ok(term), Ok(index)) => {
+                write!(fmt, "{} {{ term: {}, index: {} }}", state, term, index)
             }
+            _ => write!(fmt, "{} {{ log unavailable }}", state),
         }
     }
 }
@@ -876,26 +2681,39 @@ mod tests {
     extern crate env_logger;
     extern crate test;
 
+    use std::{io, result};
     use std::collections::{HashMap, VecDeque};
     use std::io::Cursor;
     use std::net::SocketAddr;
     use std::rc::Rc;
     use std::str::FromStr;
+    use std::time::{Duration, Instant};
 
     use capnp::serialize::{self, OwnedSegments};
     use capnp::message::{Allocator, Builder, HeapAllocator, Reader, ReaderOptions};
     use ClientId;
+    use Consistency;
+    use EntryKind;
+    use Error;
     use LogIndex;
+    use RaftError;
     use ServerId;
     use Term;
     use messages;
-    use consensus::{Actions, Consensus, ConsensusTimeout};
+    use consensus::{Actions, Consensus, ConsensusConfiguration, ConsensusTimeout, LogError};
+    use messages_capnp::{client_request, message};
     use state_machine::NullStateMachine;
     use persistent_log::{MemLog, Log};
 
     type TestPeer = Consensus<MemLog, NullStateMachine>;
 
     fn new_cluster(size: u64) -> HashMap<ServerId, TestPeer> {
+        new_cluster_with_config(size, ConsensusConfiguration::default())
+    }
+
+    fn new_cluster_with_config(size: u64,
+                               config: ConsensusConfiguration)
+                               -> HashMap<ServerId, TestPeer> {
         let ids: HashMap<ServerId, SocketAddr> = (0..size)
                                                      .map(Into::into)
                                                      .map(|id| {
@@ -911,7 +2729,14 @@ mod tests {
                let mut peers = ids.clone();
                peers.remove(&id);
                let store = MemLog::new();
-               (id, Consensus::new(id, peers, store, NullStateMachine))
+               (id,
+                Consensus::new(id,
+                               ids[&id],
+                               Some(peers),
+                               store,
+                               NullStateMachine,
+                               config.clone())
+                    .unwrap())
            })
            .collect()
     }
@@ -934,6 +2759,10 @@ mod tests {
                      -> Vec<(ClientId, Rc<Builder<HeapAllocator>>)> {
         let mut queue: VecDeque<(ServerId, ServerId, Rc<Builder<HeapAllocator>>)> = VecDeque::new();
 
+        // The event loop turn ends, so batched proposals are appended.
+        if let Some(peer) = peers.get_mut(&from) {
+            peer.flush_proposals(&mut actions);
+        }
         for (to, message) in actions.peer_messages.iter().cloned() {
             queue.push_back((from, to, message));
         }
@@ -941,7 +2770,11 @@ mod tests {
 
         while let Some((from, to, message)) = queue.pop_front() {
             let reader = into_reader(&*message);
-            peers.get_mut(&to).unwrap().apply_peer_message(from, &reader, &mut actions);
+            // Messages to peers which are not part of the map are dropped.
+            match peers.get_mut(&to) {
+                Some(peer) => peer.apply_peer_message(from, &reader, &mut actions).unwrap(),
+                None => continue,
+            }
             let inner_from = to;
             for (inner_to, message) in actions.peer_messages.iter().cloned() {
                 queue.push_back((inner_from, inner_to, message));
@@ -1029,7 +2862,7 @@ mod tests {
         let leader_append_entries = {
             let mut actions = Actions::new();
             let leader = peers.get_mut(&leader_id).unwrap();
-            leader.heartbeat_timeout(follower_id.clone(), &mut actions);
+            leader.heartbeat_timeout(follower_id.clone(), &mut actions).unwrap();
 
             let peer_message = actions.peer_messages.iter().next().unwrap();
             assert_eq!(peer_message.0, follower_id.clone());
@@ -1041,7 +2874,7 @@ mod tests {
         let follower_response = {
             let mut actions = Actions::new();
             let follower = peers.get_mut(&follower_id).unwrap();
-            follower.apply_peer_message(leader_id.clone(), &reader, &mut actions);
+            follower.apply_peer_message(leader_id.clone(), &reader, &mut actions).unwrap();
 
             let election_timeout = actions.timeouts.iter().next().unwrap();
             assert_eq!(election_timeout, &ConsensusTimeout::Election);
@@ -1055,7 +2888,7 @@ mod tests {
         // Leader applies and sends back a heartbeat to establish leadership.
         let leader = peers.get_mut(&leader_id).unwrap();
         let mut actions = Actions::new();
-        leader.apply_peer_message(follower_id.clone(), &reader, &mut actions);
+        leader.apply_peer_message(follower_id.clone(), &reader, &mut actions).unwrap();
         let heartbeat_timeout = actions.timeouts.iter().next().unwrap();
         assert_eq!(heartbeat_timeout,
                    &ConsensusTimeout::Heartbeat(follower_id.clone()));
@@ -1071,7 +2904,12 @@ mod tests {
     #[test]
     fn test_slow_heartbeat() {
         setup_test!("test_heartbeat");
-        let mut peers = new_cluster(2);
+        // Pre-vote would keep Consensus 1 from disrupting the leader.
+        let config = ConsensusConfiguration {
+            pre_vote: false,
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(2, config);
         let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
         let peer_0 = &peer_ids[0];
         let peer_1 = &peer_ids[1];
@@ -1100,6 +2938,147 @@ mod tests {
         assert!(peers[peer_1].is_leader());
     }
 
+    /// Tests that a follower which times out while the leader is healthy does not disrupt it,
+    /// and that the followers elect a new leader once the leader is gone.
+    #[test]
+    fn test_pre_vote() {
+        setup_test!("test_pre_vote");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+        let term = peers[&leader].current_term().unwrap();
+
+        let mut actions = Actions::new();
+        peers.get_mut(&peer_ids[1])
+             .unwrap()
+             .apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert_eq!(2, actions.peer_messages.len());
+        assert!(apply_actions(peer_ids[1], actions, &mut peers).is_empty());
+        assert!(peers[&leader].is_leader());
+        assert!(peers[&peer_ids[1]].is_follower());
+        for peer in peers.values() {
+            assert_eq!(term, peer.current_term().unwrap());
+        }
+
+        // The leader goes away. The first follower has not heard from it since its election
+        // timeout, so it grants the second follower's pre-vote.
+        peers.remove(&leader);
+        let mut actions = Actions::new();
+        peers.get_mut(&peer_ids[2])
+             .unwrap()
+             .apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert!(apply_actions(peer_ids[2], actions, &mut peers).is_empty());
+        assert!(peers[&peer_ids[2]].is_leader());
+        assert_eq!(term + 1, peers[&peer_ids[2]].current_term().unwrap());
+    }
+
+    /// Tests that the leader steps down once it has not heard from a majority within the check
+    /// quorum period.
+    #[test]
+    fn test_check_quorum() {
+        setup_test!("test_check_quorum");
+        let config = ConsensusConfiguration {
+            check_quorum: Some(Duration::from_secs(60)),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(3, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().check_quorum_timeout(&mut actions);
+        assert!(peers[&leader].is_leader());
+        assert_eq!(vec![ConsensusTimeout::CheckQuorum], actions.timeouts);
+
+        // Nothing has been heard within an empty period. Heartbeats do not check the quorum.
+        peers.get_mut(&leader).unwrap().config.check_quorum = Some(Duration::from_millis(0));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().heartbeat_timeout(peer_ids[1], &mut actions).unwrap();
+        assert_eq!(1, actions.peer_messages.len());
+        assert!(peers[&leader].is_leader());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().check_quorum_timeout(&mut actions);
+        assert!(peers[&leader].is_follower());
+        assert_eq!(vec![ConsensusTimeout::Election], actions.timeouts);
+    }
+
+    /// Tests that leadership moves to the target of a transfer once it has caught up.
+    #[test]
+    fn test_leadership_transfer() {
+        setup_test!("test_leadership_transfer");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let target = peer_ids[1];
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        let request = into_reader(&messages::transfer_leadership_request(target));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &request, &mut actions)
+             .unwrap();
+        assert!(actions.timeouts.contains(&ConsensusTimeout::LeadershipTransfer));
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert!(peers[&target].is_leader());
+        assert!(peers[&leader].is_follower());
+    }
+
+    /// Tests that proposals are refused during a leadership transfer, and that the transfer is
+    /// aborted if the target does not catch up in time.
+    #[test]
+    fn test_leadership_transfer_timeout() {
+        setup_test!("test_leadership_transfer_timeout");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let target = peer_ids[1];
+        elect_leader(leader, &mut peers);
+
+        // The target is partitioned, and falls behind.
+        peers.remove(&target);
+        let client = ClientId::new();
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().transfer_leadership(target, &mut actions).unwrap();
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        let mut actions = Actions::new();
+        let result = peers.get_mut(&leader).unwrap().transfer_leadership(target, &mut actions);
+        assert!(result.is_err());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(LogIndex(2), peers[&leader].latest_log_index().unwrap());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_timeout(ConsensusTimeout::LeadershipTransfer, &mut actions);
+        assert!(peers[&leader].is_leader());
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert_eq!(LogIndex(3), peers[&leader].latest_log_index().unwrap());
+    }
+
     /// Tests that a client proposal is correctly replicated to peers, and the client is notified
     /// of the success.
     #[test]
@@ -1121,16 +3100,403 @@ mod tests {
 
             peers.get_mut(&leader)
                  .unwrap()
-                 .apply_client_message(client, &proposal, &mut actions);
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
 
             let client_messages = apply_actions(leader, actions, &mut peers);
             assert_eq!(1, client_messages.len());
             for peer in peers.values() {
-                assert_eq!((Term(1), value), peer.log.entry(LogIndex(1)).unwrap());
+                assert_eq!((Term(1), EntryKind::Normal, value.to_vec()),
+                           peer.log.entry(LogIndex(2)).unwrap());
             }
         }
     }
 
+    /// Tests that entry kinds are replicated, and that an empty proposal is applied like any
+    /// other rather than mistaken for a no-op.
+    #[test]
+    fn test_entry_kinds() {
+        setup_test!("test_entry_kinds");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        let proposal = into_reader(&messages::proposal_request(b""));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        for peer in peers.values() {
+            assert_eq!((Term(1), EntryKind::NoOp, Vec::new()),
+                       peer.log.entry(LogIndex(1)).unwrap());
+            assert_eq!((Term(1), EntryKind::Normal, Vec::new()),
+                       peer.log.entry(LogIndex(2)).unwrap());
+        }
+    }
+
+    /// Tests that a new leader begins its term with a no-op entry, that an entry from an earlier
+    /// term is not committed by counting its replicas, and that queries wait for the no-op to
+    /// commit.
+    #[test]
+    fn test_noop_entry() {
+        setup_test!("test_noop_entry");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+
+        // The leader of the new term and one follower hold an entry from the previous term.
+        for &id in &[leader, follower] {
+            let peer = peers.get_mut(&id).unwrap();
+            peer.log.append_entries(LogIndex(1),
+                                    &[(Term(1), EntryKind::Normal, &b"foo"[..])])
+                .unwrap();
+            peer.log.set_current_term(Term(2)).unwrap();
+        }
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.transition_to_leader(&mut actions).unwrap();
+            assert_eq!((Term(2), EntryKind::NoOp, Vec::new()),
+                       peer.log.entry(LogIndex(2)).unwrap());
+
+            // The entry is on a majority, but was not replicated in the current term.
+            peer.leader_state.set_match_index(follower, LogIndex(1));
+            peer.advance_commit_index(&mut actions).unwrap();
+            assert_eq!(LogIndex(0), peer.commit_index);
+
+            let client = ClientId::new();
+            let query = into_reader(&messages::query_request(b"foo",
+                                                             Consistency::BoundedStaleness));
+            peer.apply_client_message(client, &query, &mut actions).unwrap();
+            assert!(actions.client_messages.is_empty());
+            assert_eq!(LogIndex(2), peer.leader_state.queries[0].read_index);
+        }
+
+        // The no-op commits, and the earlier entry along with it.
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert_eq!(LogIndex(2), peers[&leader].commit_index);
+        for peer in peers.values() {
+            assert_eq!(LogIndex(2), peer.latest_log_index().unwrap());
+        }
+    }
+
+    /// Tests that the proposals received during an event loop turn are appended to the log
+    /// together, and sent to each follower in a single AppendEntries request.
+    #[test]
+    fn test_proposal_batching() {
+        setup_test!("test_proposal_batching");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        let mut actions = Actions::new();
+        for value in &[b"foo", b"bar", b"baz"] {
+            let proposal = into_reader(&messages::proposal_request(&value[..]));
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+        }
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(LogIndex(1), peers[&leader].latest_log_index().unwrap());
+
+        peers.get_mut(&leader).unwrap().flush_proposals(&mut actions);
+        assert_eq!(LogIndex(4), peers[&leader].latest_log_index().unwrap());
+        assert_eq!(2, actions.peer_messages.len());
+
+        assert_eq!(3, apply_actions(leader, actions, &mut peers).len());
+        for peer in peers.values() {
+            assert_eq!((Term(1), EntryKind::Normal, b"baz".to_vec()),
+                       peer.log.entry(LogIndex(4)).unwrap());
+        }
+        assert_eq!(LogIndex(4), peers[&leader].commit_index);
+
+        // The batch is flushed as soon as it reaches the limit.
+        let config = ConsensusConfiguration {
+            max_batch_entries: 2,
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(1, config);
+        let leader = *peers.keys().next().unwrap();
+        elect_leader(leader, &mut peers);
+        let mut actions = Actions::new();
+        for value in &[b"foo", b"bar", b"baz"] {
+            let proposal = into_reader(&messages::proposal_request(&value[..]));
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+        }
+        assert_eq!(LogIndex(3), peers[&leader].latest_log_index().unwrap());
+        assert_eq!(2, actions.client_messages.len());
+    }
+
+    /// Tests that a follower which is behind receives the missing entries in pages, with a
+    /// bounded number of requests in flight.
+    #[test]
+    fn test_append_entries_paging() {
+        setup_test!("test_append_entries_paging");
+        let config = ConsensusConfiguration {
+            replication_window: 2,
+            max_append_entries: 2,
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(2, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        let mut actions = Actions::new();
+        for value in &[b"foo", b"bar", b"baz", b"qux", b"quu"] {
+            let proposal = into_reader(&messages::proposal_request(&value[..]));
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+        }
+        peers.get_mut(&leader).unwrap().flush_proposals(&mut actions);
+        // Two requests of two entries each; the last entry waits for a response.
+        assert_eq!(2, actions.peer_messages.len());
+        assert_eq!(2, peers[&leader].leader_state.in_flight(&follower));
+        assert_eq!(LogIndex(6), peers[&leader].leader_state.next_index(&follower));
+
+        assert_eq!(5, apply_actions(leader, actions, &mut peers).len());
+        assert_eq!((Term(1), EntryKind::Normal, b"quu".to_vec()),
+                   peers[&follower].log.entry(LogIndex(6)).unwrap());
+        assert_eq!(LogIndex(6), peers[&leader].commit_index);
+        assert_eq!(0, peers[&leader].leader_state.in_flight(&follower));
+    }
+
+    /// Tests that a query is only answered once a majority has confirmed the leadership, so a
+    /// leader cut off from its followers cannot serve stale reads.
+    #[test]
+    fn test_read_index() {
+        setup_test!("test_read_index");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        let query = into_reader(&messages::query_request(b"foo", Consistency::Linearizable));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().apply_client_message(client, &query, &mut actions).unwrap();
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+
+        // Partition the leader from its followers.
+        let mut isolated = new_cluster(0);
+        isolated.insert(leader, peers.remove(&leader).unwrap());
+        let mut actions = Actions::new();
+        isolated.get_mut(&leader)
+                .unwrap()
+                .apply_client_message(client, &query, &mut actions)
+                .unwrap();
+        assert!(apply_actions(leader, actions, &mut isolated).is_empty());
+        assert_eq!(1, isolated[&leader].leader_state.queries.len());
+    }
+
+    /// Tests that a follower which lags behind the leader's log still confirms a linearizable
+    /// read.
+    #[test]
+    fn test_read_index_lagging_follower() {
+        setup_test!("test_read_index_lagging_follower");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let lagging = peer_ids[2];
+        elect_leader(leader, &mut peers);
+
+        // The lagging follower misses entries committed by the others.
+        let partitioned = peers.remove(&lagging).unwrap();
+        let client = ClientId::new();
+        for value in &[b"foo", b"bar"] {
+            let proposal = into_reader(&messages::proposal_request(&value[..]));
+            let mut actions = Actions::new();
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+            assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        }
+
+        // Only the lagging follower can confirm the read.
+        peers.remove(&peer_ids[1]);
+        peers.insert(lagging, partitioned);
+        let query = into_reader(&messages::query_request(b"foo", Consistency::Linearizable));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().apply_client_message(client, &query, &mut actions).unwrap();
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert!(peers[&leader].leader_state.queries.is_empty());
+    }
+
+    /// Tests that followers answer relaxed queries, fetching the read index from the leader
+    /// for bounded-staleness queries.
+    #[test]
+    fn test_follower_reads() {
+        setup_test!("test_follower_reads");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        let client = ClientId::new();
+
+        // Without a known leader only any-replica queries are answered.
+        let query = into_reader(&messages::query_request(b"foo", Consistency::BoundedStaleness));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+             .unwrap()
+             .apply_client_message(client, &query, &mut actions)
+             .unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert!(actions.peer_messages.is_empty());
+
+        elect_leader(leader, &mut peers);
+
+        let query = into_reader(&messages::query_request(b"foo", Consistency::AnyReplica));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+             .unwrap()
+             .apply_client_message(client, &query, &mut actions)
+             .unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert!(actions.peer_messages.is_empty());
+
+        let query = into_reader(&messages::query_request(b"foo", Consistency::BoundedStaleness));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+             .unwrap()
+             .apply_client_message(client, &query, &mut actions)
+             .unwrap();
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(vec![leader],
+                   actions.peer_messages.iter().map(|&(to, _)| to).collect::<Vec<_>>());
+        assert_eq!(1, apply_actions(follower, actions, &mut peers).len());
+        assert!(peers[&follower].follower_state.read_requests.is_empty());
+    }
+
+    /// Tests that a follower which has not heard from the leader within the staleness bound
+    /// refuses bounded-staleness queries.
+    #[test]
+    fn test_follower_read_staleness() {
+        setup_test!("test_follower_read_staleness");
+        let config = ConsensusConfiguration {
+            max_staleness: Some(Duration::from_secs(1)),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(3, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        let client = ClientId::new();
+        let start = Instant::now();
+        for peer in peers.values_mut() {
+            peer.set_time(start);
+        }
+        elect_leader(leader, &mut peers);
+
+        let query = into_reader(&messages::query_request(b"foo", Consistency::BoundedStaleness));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+             .unwrap()
+             .apply_client_message(client, &query, &mut actions)
+             .unwrap();
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(1, actions.peer_messages.len());
+
+        // Cut off from the leader for longer than the bound, the follower redirects the client.
+        peers.get_mut(&follower).unwrap().set_time(start + Duration::from_secs(2));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+             .unwrap()
+             .apply_client_message(client, &query, &mut actions)
+             .unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(1, actions.client_messages.len());
+    }
+
+    /// Tests that a leader holding the lease answers queries without a round of heartbeats, and
+    /// that followers refuse to vote while the lease may be held.
+    #[test]
+    fn test_leader_lease() {
+        setup_test!("test_leader_lease");
+        let config = ConsensusConfiguration {
+            leader_lease: Some(Duration::from_secs(60)),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(3, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+
+        let mut actions = Actions::new();
+        for &follower in &peer_ids[1..] {
+            peers.get_mut(&leader).unwrap().heartbeat_timeout(follower, &mut actions).unwrap();
+        }
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+
+        let client = ClientId::new();
+        let query = into_reader(&messages::query_request(b"foo", Consistency::Linearizable));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().apply_client_message(client, &query, &mut actions).unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert!(actions.peer_messages.is_empty());
+
+        let follower = peers.get_mut(&peer_ids[1]).unwrap();
+        let term = follower.current_term().unwrap();
+        let latest_index = follower.latest_log_index().unwrap();
+        let latest_term = follower.latest_log_term().unwrap();
+        let request = into_reader(&messages::request_vote_request(term + 1,
+                                                                  latest_index,
+                                                                  latest_term,
+                                                                  false));
+        let mut actions = Actions::new();
+        follower.apply_peer_message(peer_ids[2], &request, &mut actions).unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(term, follower.current_term().unwrap());
+    }
+
+    /// Tests that a server which may have acknowledged a lease before it restarted does not vote
+    /// until the lease could have expired, measured on its own clock.
+    #[test]
+    fn test_restart_lease() {
+        setup_test!("test_restart_lease");
+        let config = ConsensusConfiguration {
+            leader_lease: Some(Duration::from_secs(1)),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(2, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let follower = peers.get_mut(&peer_ids[0]).unwrap();
+        let start = Instant::now();
+        follower.set_time(start);
+        follower.init();
+
+        let request = into_reader(&messages::request_vote_request(Term(1),
+                                                                  LogIndex(0),
+                                                                  Term(0),
+                                                                  false));
+        let mut actions = Actions::new();
+        follower.apply_peer_message(peer_ids[1], &request, &mut actions).unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(Term(0), follower.current_term().unwrap());
+
+        follower.set_time(start + Duration::from_secs(2));
+        let mut actions = Actions::new();
+        follower.apply_peer_message(peer_ids[1], &request, &mut actions).unwrap();
+        assert_eq!(1, actions.peer_messages.len());
+        assert_eq!(Term(1), follower.current_term().unwrap());
+    }
+
     #[test]
     // Verify that out-of-order appends don't lead to the log tail being
     // dropped. See https://github.com/ktoso/akka-raft/issues/66; it's
@@ -1143,22 +3509,617 @@ mod tests {
         let mut actions = Actions::new();
         let mut follower = peers.get_mut(&peer_ids[0]).unwrap();
         let value: &[u8] = b"foo";
-        let entries = vec![(Term(1), value), (Term(1), value)];
+        let entries = vec![(Term(1), EntryKind::Normal, value),
+                           (Term(1), EntryKind::Normal, value)];
         let msg1 = into_reader(&*messages::append_entries_request(Term(1),
                                                                   LogIndex(0),
                                                                   Term(0),
                                                                   &entries,
-                                                                  LogIndex(0)));
+                                                                  LogIndex(0),
+                                                                  0));
         let msg2 = into_reader(&*messages::append_entries_request(Term(1),
                                                                   LogIndex(0),
                                                                   Term(0),
                                                                   &entries[0..1],
-                                                                  LogIndex(0)));
-        follower.apply_peer_message(peer_ids[1], &msg1, &mut actions);
-        follower.apply_peer_message(peer_ids[1], &msg2, &mut actions);
+                                                                  LogIndex(0),
+                                                                  0));
+        follower.apply_peer_message(peer_ids[1], &msg1, &mut actions).unwrap();
+        follower.apply_peer_message(peer_ids[1], &msg2, &mut actions).unwrap();
+
+        assert_eq!((Term(1), EntryKind::Normal, value.to_vec()),
+                   follower.log.entry(LogIndex(1)).unwrap());
+        assert_eq!((Term(1), EntryKind::Normal, value.to_vec()),
+                   follower.log.entry(LogIndex(2)).unwrap());
+    }
+
+    /// Tests that the log is compacted once the configured number of entries has been applied,
+    /// and that followers skip entries already covered by their snapshot.
+    #[test]
+    fn test_log_compaction() {
+        setup_test!("test_log_compaction");
+        let config = ConsensusConfiguration {
+            snapshot_threshold: Some(2),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(3, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        for value in &[b"foo", b"bar"] {
+            let proposal = into_reader(&messages::proposal_request(&value[..]));
+            let mut actions = Actions::new();
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+            assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        }
+
+        let leader = &peers[&leader];
+        assert_eq!(LogIndex(2), leader.log.snapshot_index().unwrap());
+        assert_eq!(Term(1), leader.log.snapshot_term().unwrap());
+        assert_eq!(LogIndex(3), leader.log.latest_log_index().unwrap());
+        assert_eq!((Term(1), EntryKind::Normal, b"bar".to_vec()),
+                   leader.log.entry(LogIndex(3)).unwrap());
+    }
 
-        assert_eq!((Term(1), value), follower.log.entry(LogIndex(1)).unwrap());
-        assert_eq!((Term(1), value), follower.log.entry(LogIndex(2)).unwrap());
+    /// Tests that the leader skips a follower's divergent tail a whole term at a time, instead
+    /// of backing up one entry per round trip.
+    #[test]
+    fn test_conflict_hints() {
+        setup_test!("test_conflict_hints");
+        let mut peers = new_cluster(2);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        elect_leader(leader, &mut peers);
+
+        // The logs agree on the first three entries, after which the follower holds a long tail
+        // from a term the leader never saw.
+        let common = vec![(Term(1), EntryKind::Normal, &b"foo"[..]); 3];
+        {
+            let peer = peers.get_mut(&follower).unwrap();
+            peer.log.set_current_term(Term(2)).unwrap();
+            peer.log.append_entries(LogIndex(1), &common).unwrap();
+            peer.log.append_entries(LogIndex(4),
+                                    &vec![(Term(2), EntryKind::Normal, &b"bar"[..]); 97])
+                .unwrap();
+        }
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.log.set_current_term(Term(3)).unwrap();
+            peer.log.append_entries(LogIndex(1), &common).unwrap();
+            peer.log.append_entries(LogIndex(4),
+                                    &vec![(Term(3), EntryKind::Normal, &b"baz"[..]); 7])
+                .unwrap();
+            peer.leader_state.set_next_index(follower, LogIndex(11));
+        }
+
+        let addr = peers[&leader].peers()[&follower];
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(follower, addr, &mut actions);
+        // One round trip finds the conflict, and a second one repairs the log.
+        for _ in 0..2 {
+            let (to, request) = actions.peer_messages.pop().unwrap();
+            assert_eq!(follower, to);
+            assert!(actions.peer_messages.is_empty());
+            let mut responses = Actions::new();
+            peers.get_mut(&follower)
+                 .unwrap()
+                 .apply_peer_message(leader, &into_reader(&*request), &mut responses)
+                 .unwrap();
+            actions = Actions::new();
+            for (_, response) in responses.peer_messages {
+                peers.get_mut(&leader)
+                     .unwrap()
+                     .apply_peer_message(follower, &into_reader(&*response), &mut actions)
+                     .unwrap();
+            }
+        }
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(LogIndex(10), peers[&follower].latest_log_index().unwrap());
+        assert_eq!((Term(3), EntryKind::Normal, b"baz".to_vec()),
+                   peers[&follower].log.entry(LogIndex(10)).unwrap());
+        assert_eq!(LogIndex(10), peers[&leader].leader_state.match_index(&follower));
+    }
+
+    /// Tests that a retried proposal is answered from the client's session, and that the session
+    /// table travels with snapshots.
+    #[test]
+    fn test_session_proposals() {
+        setup_test!("test_session_proposals");
+        let config = ConsensusConfiguration {
+            snapshot_threshold: Some(2),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(3, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let lagging = peer_ids[2];
+        elect_leader(leader, &mut peers);
+
+        let mut partitioned = peers.remove(&lagging).unwrap();
+        let client = ClientId::new();
+        let requests = [messages::session_proposal_request(b"foo", client, 1, 1),
+                        messages::session_proposal_request(b"foo", client, 1, 1),
+                        messages::session_proposal_request(b"bar", client, 2, 2)];
+        for request in &requests {
+            let proposal = into_reader(request);
+            let mut actions = Actions::new();
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+            assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        }
+        assert_eq!(LogIndex(4), peers[&leader].log.snapshot_index().unwrap());
+        assert_eq!(1, peers[&leader].sessions.len());
+
+        // The lagging follower receives the sessions along with the snapshot.
+        let addr = peers[&leader].peers()[&lagging];
+        peers.insert(lagging, partitioned);
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(lagging, addr, &mut actions);
+        apply_actions(leader, actions, &mut peers);
+        assert_eq!(LogIndex(4), peers[&lagging].log.snapshot_index().unwrap());
+        assert_eq!(peers[&leader].sessions, peers[&lagging].sessions);
+    }
+
+    /// Tests that a follower which missed compacted entries is brought up to date with the
+    /// leader's snapshot once it reconnects.
+    #[test]
+    fn test_install_snapshot() {
+        setup_test!("test_install_snapshot");
+        let config = ConsensusConfiguration {
+            snapshot_threshold: Some(2),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(3, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let lagging = peer_ids[2];
+        elect_leader(leader, &mut peers);
+
+        // Partition the lagging follower while the leader commits and compacts.
+        let mut partitioned = peers.remove(&lagging).unwrap();
+        let client = ClientId::new();
+        for value in &[b"foo", b"bar"] {
+            let proposal = into_reader(&messages::proposal_request(&value[..]));
+            let mut actions = Actions::new();
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+            assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        }
+        assert_eq!(LogIndex(2), peers[&leader].log.snapshot_index().unwrap());
+        assert_eq!(LogIndex(1), partitioned.log.latest_log_index().unwrap());
+
+        // Heal the partition.
+        let addr = peers[&leader].peers()[&lagging];
+        peers.insert(lagging, partitioned);
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(lagging, addr, &mut actions);
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+
+        let follower = &peers[&lagging];
+        assert_eq!(LogIndex(2), follower.log.snapshot_index().unwrap());
+        assert_eq!(LogIndex(3), follower.log.latest_log_index().unwrap());
+        assert_eq!((Term(1), EntryKind::Normal, b"bar".to_vec()),
+                   follower.log.entry(LogIndex(3)).unwrap());
+        assert_eq!(LogIndex(3), follower.commit_index);
+        assert_eq!(LogIndex(3), follower.last_applied);
+    }
+
+    /// Tests that a follower discards a snapshot which arrives damaged instead of installing it,
+    /// and that the leader sends the snapshot again.
+    #[test]
+    fn test_corrupt_snapshot_transfer() {
+        setup_test!("test_corrupt_snapshot_transfer");
+        let config = ConsensusConfiguration {
+            snapshot_threshold: Some(2),
+            ..ConsensusConfiguration::default()
+        };
+        let mut peers = new_cluster_with_config(3, config);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let lagging = peer_ids[2];
+        elect_leader(leader, &mut peers);
+
+        let partitioned = peers.remove(&lagging).unwrap();
+        let client = ClientId::new();
+        for value in &[b"foo", b"bar"] {
+            let proposal = into_reader(&messages::proposal_request(&value[..]));
+            let mut actions = Actions::new();
+            peers.get_mut(&leader)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+            assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        }
+        assert_eq!(LogIndex(2), peers[&leader].log.snapshot_index().unwrap());
+
+        // Relay messages between the leader and the lagging follower until the snapshot is sent,
+        // and truncate its final chunk.
+        let addr = peers[&leader].peers()[&lagging];
+        peers.insert(lagging, partitioned);
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(lagging, addr, &mut actions);
+        let mut corrupted = false;
+        while !corrupted {
+            let (to, mut request) = actions.peer_messages.pop().unwrap();
+            assert_eq!(lagging, to);
+            let reader = into_reader(&*request);
+            if let message::Which::InstallSnapshotRequest(Ok(snapshot)) =
+                   reader.get_root::<message::Reader>().unwrap().which().unwrap() {
+                let data = snapshot.get_data().unwrap();
+                let index = LogIndex(snapshot.get_last_included_index());
+                let term = Term(snapshot.get_last_included_term());
+                assert!(snapshot.get_done());
+                request = messages::install_snapshot_request(Term(snapshot.get_term()),
+                                                             index,
+                                                             term,
+                                                             snapshot.get_offset(),
+                                                             &data[..data.len() / 2],
+                                                             true);
+                corrupted = true;
+            }
+            let mut responses = Actions::new();
+            peers.get_mut(&lagging)
+                 .unwrap()
+                 .apply_peer_message(leader, &into_reader(&*request), &mut responses)
+                 .unwrap();
+            actions = Actions::new();
+            for (_, response) in responses.peer_messages {
+                peers.get_mut(&leader)
+                     .unwrap()
+                     .apply_peer_message(lagging, &into_reader(&*response), &mut actions)
+                     .unwrap();
+            }
+        }
+        assert!(actions.log_error.is_none());
+        assert_eq!(LogIndex(0), peers[&lagging].log.snapshot_index().unwrap());
+        assert!(peers[&lagging].follower_state.snapshot.is_none());
+        assert!(actions.timeouts.contains(&ConsensusTimeout::Heartbeat(lagging)));
+
+        // The snapshot is sent again with the next heartbeat, and installed.
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_timeout(ConsensusTimeout::Heartbeat(lagging), &mut actions);
+        apply_actions(leader, actions, &mut peers);
+        assert_eq!(LogIndex(2), peers[&lagging].log.snapshot_index().unwrap());
+        assert_eq!(LogIndex(3), peers[&lagging].last_applied);
+    }
+
+    /// Tests that a joining server is added to the cluster, catches up on the log, and counts
+    /// towards the majority once the configuration is committed.
+    #[test]
+    fn test_add_server() {
+        setup_test!("test_add_server");
+        let mut peers = new_cluster(1);
+        let leader = *peers.keys().next().unwrap();
+        elect_leader(leader, &mut peers);
+
+        let id = ServerId(1);
+        let addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        let joining = Consensus::new(id,
+                                     addr,
+                                     None,
+                                     MemLog::new(),
+                                     NullStateMachine,
+                                     ConsensusConfiguration::default())
+                          .unwrap();
+        peers.insert(id, joining);
+
+        // A server outside of the configuration does not campaign.
+        let mut actions = Actions::new();
+        peers.get_mut(&id).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert!(peers[&id].is_follower());
+
+        let client = ClientId::new();
+        let request = into_reader(&messages::add_server_request(id, &addr, false));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &request, &mut actions)
+             .unwrap();
+        assert_eq!(Some(&addr), actions.peers.as_ref().and_then(|peers| peers.get(&id)));
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+
+        // The server connects to the new peer, which catches up and commits the change.
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(id, addr, &mut actions);
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert_eq!(LogIndex(2), peers[&leader].commit_index);
+        assert!(peers[&id].is_voter());
+        assert!(peers[&id].peers().contains_key(&leader));
+        assert_eq!(2, peers[&leader].majority());
+
+        // Entries now need the new server to commit.
+        peers.remove(&id);
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        assert_eq!(LogIndex(2), peers[&leader].commit_index);
+    }
+
+    /// Tests that a learner replicates the log without counting towards the majority, and that
+    /// it can only be promoted once it has caught up.
+    #[test]
+    fn test_learner() {
+        setup_test!("test_learner");
+        let mut peers = new_cluster(1);
+        let leader = *peers.keys().next().unwrap();
+        elect_leader(leader, &mut peers);
+
+        let id = ServerId(1);
+        let addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        let learner = Consensus::new(id,
+                                     addr,
+                                     None,
+                                     MemLog::new(),
+                                     NullStateMachine,
+                                     ConsensusConfiguration::default())
+                          .unwrap();
+        peers.insert(id, learner);
+
+        // The learner does not hold back commitment of the configuration which adds it.
+        let client = ClientId::new();
+        let request = into_reader(&messages::add_server_request(id, &addr, true));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &request, &mut actions)
+             .unwrap();
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert_eq!(LogIndex(2), peers[&leader].commit_index);
+        assert_eq!(1, peers[&leader].majority());
+        assert!(!peers[&leader].learner_status(id).unwrap().caught_up);
+
+        // Promotion is refused until the learner catches up.
+        let promote = into_reader(&messages::promote_server_request(id));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &promote, &mut actions)
+             .unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(LogIndex(2), peers[&leader].latest_log_index().unwrap());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(id, addr, &mut actions);
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        assert!(peers[&leader].learner_status(id).unwrap().caught_up);
+
+        // Learners do not campaign.
+        let mut actions = Actions::new();
+        peers.get_mut(&id).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert!(peers[&id].is_follower());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &promote, &mut actions)
+             .unwrap();
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert_eq!(LogIndex(3), peers[&leader].commit_index);
+        assert_eq!(2, peers[&leader].majority());
+        assert!(peers[&id].is_voter());
+    }
+
+    /// Tests that a leader which removes itself from the configuration steps down once the
+    /// change commits, and that a second change is refused while the first is in progress.
+    #[test]
+    fn test_remove_leader() {
+        setup_test!("test_remove_leader");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        let request = into_reader(&messages::remove_server_request(leader));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &request, &mut actions)
+             .unwrap();
+        assert!(peers[&leader].is_leader());
+        assert!(!peers[&leader].is_voter());
+
+        let request = into_reader(&messages::remove_server_request(peer_ids[1]));
+        let mut rejected = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &request, &mut rejected)
+             .unwrap();
+        assert_eq!(1, rejected.client_messages.len());
+        assert_eq!(LogIndex(2), peers[&leader].latest_log_index().unwrap());
+
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
+        assert!(peers[&leader].is_follower());
+        for follower in &peer_ids[1..] {
+            assert!(!peers[follower].peers().contains_key(&leader));
+            assert_eq!(2, peers[follower].majority());
+        }
+    }
+
+    /// Tests that a client request of an unknown kind is reported as an error instead of crashing
+    /// the server, and that a proposal without an entry is rejected.
+    #[test]
+    fn test_malformed_messages() {
+        setup_test!("test_malformed_messages");
+        let mut peers = new_cluster(2);
+        let leader = *peers.keys().next().unwrap();
+        elect_leader(leader, &mut peers);
+
+        // A request of a kind the server does not handle.
+        let mut message = Builder::new_default();
+        message.init_root::<client_request::Builder>().init_ping();
+        let client = ClientId::new();
+        let mut actions = Actions::new();
+        let result = peers.get_mut(&leader)
+                          .unwrap()
+                          .apply_client_message(client, &into_reader(&message), &mut actions);
+        match result {
+            Err(Error::Raft(RaftError::MalformedMessage)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(actions.client_messages.is_empty());
+
+        let mut message = Builder::new_default();
+        message.init_root::<client_request::Builder>().init_proposal();
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_client_message(client, &into_reader(&message), &mut actions)
+             .unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(LogIndex(1), peers[&leader].latest_log_index().unwrap());
+    }
+
+    /// A log which refuses new entries once it is full, like a log on a full disk.
+    #[derive(Clone, Debug)]
+    struct FullLog {
+        log: MemLog,
+        full: bool,
+    }
+
+    impl Log for FullLog {
+        type Error = io::Error;
+
+        fn current_term(&self) -> result::Result<Term, io::Error> {
+            Ok(self.log.current_term().unwrap())
+        }
+
+        fn set_current_term(&mut self, term: Term) -> result::Result<(), io::Error> {
+            Ok(self.log.set_current_term(term).unwrap())
+        }
+
+        fn inc_current_term(&mut self) -> result::Result<Term, io::Error> {
+            Ok(self.log.inc_current_term().unwrap())
+        }
+
+        fn voted_for(&self) -> result::Result<Option<ServerId>, io::Error> {
+            Ok(self.log.voted_for().unwrap())
+        }
+
+        fn set_voted_for(&mut self, server: ServerId) -> result::Result<(), io::Error> {
+            Ok(self.log.set_voted_for(server).unwrap())
+        }
+
+        fn latest_log_index(&self) -> result::Result<LogIndex, io::Error> {
+            Ok(self.log.latest_log_index().unwrap())
+        }
+
+        fn latest_log_term(&self) -> result::Result<Term, io::Error> {
+            Ok(self.log.latest_log_term().unwrap())
+        }
+
+        fn snapshot_index(&self) -> result::Result<LogIndex, io::Error> {
+            Ok(self.log.snapshot_index().unwrap())
+        }
+
+        fn snapshot_term(&self) -> result::Result<Term, io::Error> {
+            Ok(self.log.snapshot_term().unwrap())
+        }
+
+        fn snapshot(&self) -> result::Result<&[u8], io::Error> {
+            Ok(self.log.snapshot().unwrap())
+        }
+
+        fn entry(&self,
+                 index: LogIndex)
+                 -> result::Result<(Term, EntryKind, Vec<u8>), io::Error> {
+            Ok(self.log.entry(index).unwrap())
+        }
+
+        fn append_entries(&mut self,
+                          from: LogIndex,
+                          entries: &[(Term, EntryKind, &[u8])])
+                          -> result::Result<(), io::Error> {
+            if self.full {
+                return Err(io::Error::new(io::ErrorKind::Other, "no space left on device"));
+            }
+            Ok(self.log.append_entries(from, entries).unwrap())
+        }
+
+        fn compact(&mut self,
+                   index: LogIndex,
+                   term: Term,
+                   snapshot: &[u8])
+                   -> result::Result<(), io::Error> {
+            Ok(self.log.compact(index, term, snapshot).unwrap())
+        }
+    }
+
+    /// Tests that a failure of the persistent log is reported in the actions instead of
+    /// panicking, and that the leader steps down.
+    #[test]
+    fn test_log_failure() {
+        setup_test!("test_log_failure");
+        let log = FullLog {
+            log: MemLog::new(),
+            full: false,
+        };
+        let mut peer = Consensus::new(ServerId(0),
+                                      SocketAddr::from_str("127.0.0.1:9000").unwrap(),
+                                      Some(HashMap::new()),
+                                      log,
+                                      NullStateMachine,
+                                      ConsensusConfiguration::default())
+                           .unwrap();
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert!(peer.is_leader());
+        assert!(actions.log_error.is_none());
+        let latest_log_index = peer.latest_log_index().unwrap();
+
+        peer.log.full = true;
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        let mut actions = Actions::new();
+        peer.apply_client_message(ClientId::new(), &proposal, &mut actions).unwrap();
+        peer.flush_proposals(&mut actions);
+        assert!(actions.log_error.is_some());
+        assert!(!peer.is_leader());
+        assert_eq!(latest_log_index, peer.latest_log_index().unwrap());
+    }
+
+    /// Tests that a log holding a damaged snapshot or configuration entry is reported as corrupt
+    /// when the consensus state machine starts, instead of panicking.
+    #[test]
+    fn test_corrupt_log() {
+        setup_test!("test_corrupt_log");
+        let new_peer = |log: MemLog| {
+            Consensus::new(ServerId(0),
+                           SocketAddr::from_str("127.0.0.1:9000").unwrap(),
+                           Some(HashMap::new()),
+                           log,
+                           NullStateMachine,
+                           ConsensusConfiguration::default())
+        };
+
+        let mut log = MemLog::new();
+        log.compact(LogIndex(1), Term(1), b"garbage").unwrap();
+        match new_peer(log) {
+            Err(LogError::Corrupt(..)) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        let mut log = MemLog::new();
+        log.append_entries(LogIndex(1), &[(Term(1), EntryKind::Configuration, &b"garbage"[..])])
+           .unwrap();
+        match new_peer(log) {
+            Err(LogError::Corrupt(..)) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
     }
 
     #[bench]
@@ -1191,7 +4152,8 @@ mod tests {
             let mut actions = Actions::new();
             peers.get_mut(&leader)
                  .unwrap()
-                 .apply_client_message(client, &proposal, &mut actions);
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
 
             let client_messages = apply_actions(leader, actions, &mut peers);
             assert_eq!(1, client_messages.len());
@@ -41,7 +41,12 @@
 //!
 //!   * A PostgreSQL / SQLite instance.
 //!   * A plain old file.
-//!   * A vector in memory *(Note: Log compaction is still pending, so be aware of running out!)*
+//!   * A vector in memory.
+//!
+//! Once a configurable number of entries has been applied (see
+//! `ServerBuilder::with_snapshot_threshold`), the `StateMachine` is snapshotted and the `Log`
+//! discards every entry covered by the snapshot, keeping only the snapshot and its index and term.
+//! Peers which fall behind the compacted log are sent the snapshot in chunks.
 //!
 //! > It is our belief that in many cases the implementation of `Log` will be generic to
 //! > application purposes. You are encouraged to submit your own implementations to us!
@@ -70,15 +75,63 @@
 //!
 //! This means `.propose()` won't return until the entry is durably replicated into the log of at
 //! least the majority of the cluster and has been commited. `.query()` will perform better if
-//! you wish to only read data and not have it pass through the persisted log.
+//! you wish to only read data and not have it pass through the persisted log. Queries are still
+//! linearizable: the leader answers only once a heartbeat round has confirmed its leadership with
+//! a majority of the cluster, and its state machine has applied every entry committed so far.
+//!
+//! A `Client` retries a request with a randomized exponential backoff until it is answered, or
+//! its deadline or retry budget runs out; `Client::builder` sets these along with the connect
+//! and request timeouts. `RaftError::maybe_applied` tells whether a failed proposal may have
+//! been applied nonetheless.
+//!
+//! Servers built with `ServerBuilder::with_leader_lease` skip the heartbeat round while the leader
+//! holds a lease renewed by its regular heartbeats. This saves a round trip per query, but relies
+//! on bounded clock drift between servers: with badly skewed clocks a deposed leader may serve
+//! stale reads.
+//!
+//! Reads which can tolerate some staleness are better spread over the whole cluster with
+//! `.query_with()`: `Consistency::BoundedStaleness` queries are answered by any server once it has
+//! caught up with the leader's commit index, and `Consistency::AnyReplica` queries straight from
+//! the local state machine.
+//!
+//! `AsyncClient` issues the same requests without blocking: `.propose()` and `.query()` return a
+//! `Response` to wait on or poll, and `.propose_then()` and `.query_then()` take a callback
+//! instead. Any number of requests may be in flight at once over a single connection; the
+//! responses carry the request's ID, and may arrive in any order. `AsyncClient::builder` takes
+//! the same deadline and retry policy as `Client::builder`, applied to each request in flight.
+//!
+//! ## Membership Changes
+//!
+//! Servers are added to and removed from a running cluster one at a time with the `Client`'s
+//! `.add_server()` and `.remove_server()` calls. A new server should be started with
+//! `ServerBuilder::joining`, so that it waits to be added instead of forming a cluster of its own.
+//! The configuration is stored in the log, and survives restarts and compaction.
+//!
+//! A server may also be added as a non-voting learner with `.add_learner()`, for instance as a
+//! read replica or a warm standby. Learners receive every entry but are not counted towards a
+//! majority. `.learner_status()` reports whether a learner has caught up, at which point it can
+//! be made a voting member with `.promote_server()`.
+//!
+//! ## Embedding
+//!
+//! Applications with their own networking and event loop can drive the consensus algorithm
+//! directly with a `RawNode` instead of a `Server`. A `RawNode` does no IO of its own: peer
+//! messages and client requests are passed in as plain Rust values, time is advanced with
+//! `.tick()`, and each call returns the messages to send and the responses to deliver.
+//!
+//! Servers connect to each other over TCP by default. `ServerBuilder::with_transport` selects
+//! another `Transport`, such as `ChannelTransport` to run a cluster within a single process
+//! without binding any ports, or `UnixTransport` for servers sharing a host. Clients take the
+//! same kind of transport with `ClientBuilder::with_transport`.
 //!
 
 #![cfg_attr(test, feature(test))]
-extern crate bufstream;
 extern crate byteorder;
 extern crate capnp;
-extern crate capnp_nonblock;
+extern crate crc;
 extern crate mio;
+#[cfg(unix)]
+extern crate mio_uds;
 extern crate rand;
 extern crate uuid;
 #[macro_use]
@@ -107,6 +160,10 @@ macro_rules! setup_test {
 
 pub mod state_machine;
 pub mod persistent_log;
+pub mod raw_node;
+#[cfg(any(test, feature = "simulator"))]
+pub mod simulator;
+pub mod transport;
 pub mod messages_capnp {
     #![allow(dead_code)]
     include!(concat!(env!("OUT_DIR"), "/messages_capnp.rs"));
@@ -115,15 +172,20 @@ pub mod messages_capnp {
 mod backoff;
 mod client;
 mod connection;
+mod membership;
 mod messages;
 mod consensus;
 mod server;
+mod session;
 mod state;
 
-pub use server::Server;
+pub use server::{Server, ServerHandle};
 pub use state_machine::StateMachine;
 pub use persistent_log::Log;
-pub use client::Client;
+pub use raw_node::RawNode;
+pub use transport::Transport;
+pub use client::{AsyncClient, Client, ClientBuilder, Response};
+pub use membership::LearnerStatus;
 
 use std::{io, net, ops, fmt};
 
@@ -169,8 +231,37 @@ pub enum RaftError {
     InvalidPeerSet,
     /// Registering a connection failed
     ConnectionRegisterFailed,
-    /// Failed to find a leader in the cluster. Try again later.
+    /// Failed to find a leader in the cluster within the client's retry budget. The request was
+    /// not applied. Try again later.
     LeaderSearchExhausted,
+    /// The client's deadline passed before a leader was found. The request was not applied.
+    DeadlineExceeded,
+    /// The client gave up on a request which reached a server without being answered, because
+    /// its deadline or retry budget ran out. The request may or may not have been applied.
+    MaybeApplied,
+    /// The leader refused the request, for the given reason.
+    RequestRejected(String),
+    /// The proposal was not applied, because the client's session had expired. Later proposals
+    /// are made in a new session.
+    SessionExpired,
+    /// The client was dropped before the request was answered.
+    ClientClosed,
+    /// A remote party sent a message which could not be decoded, or of a kind this version does
+    /// not handle. The connection it arrived on is closed.
+    MalformedMessage,
+    /// The persistent log failed, for the given reason. The server shuts down, since its state
+    /// may no longer match the log.
+    LogFailure(String),
+}
+
+impl RaftError {
+    /// Returns whether a request which failed with this error may have been applied regardless.
+    pub fn maybe_applied(&self) -> bool {
+        match *self {
+            RaftError::MaybeApplied => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -295,11 +386,45 @@ impl fmt::Display for ServerId {
     }
 }
 
+/// The consistency required of a query, which decides the servers allowed to answer it.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Consistency {
+    /// Answered by the leader once it has confirmed its leadership. The result reflects every
+    /// proposal committed before the query was sent.
+    Linearizable,
+    /// Answered by any server once its state machine has applied the leader's commit index as of
+    /// when the query arrived, which the server fetches from the leader. The result reflects
+    /// every proposal committed before the query was sent, unless the leader has been deposed
+    /// without yet noticing. Followers which have not heard from the leader recently refuse the
+    /// query, so that a partitioned follower does not serve reads of unbounded age.
+    BoundedStaleness,
+    /// Answered by any server straight from its own state machine, which may lag arbitrarily far
+    /// behind the leader.
+    AnyReplica,
+}
+
+/// The kind of a log entry, which decides how the entry is applied once committed.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A client proposal, applied to the state machine.
+    Normal,
+    /// The empty entry a leader appends at the start of its term. Never applied.
+    NoOp,
+    /// A cluster membership configuration. Takes effect when appended, and is never applied to
+    /// the state machine.
+    Configuration,
+    /// A proposal made by a client with a session, which the session table applies to the
+    /// state machine at most once.
+    Session,
+}
+
 /// The ID of a Raft client.
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct ClientId(Uuid);
 impl ClientId {
-    fn new() -> ClientId {
+    /// Creates a random client ID.
+    #[cfg_attr(feature = "cargo-clippy", allow(new_without_default))]
+    pub fn new() -> ClientId {
         ClientId(Uuid::new_v4())
     }
     fn as_bytes(&self) -> &[u8] {
@@ -0,0 +1,242 @@
+//! Cluster membership.
+//!
+//! The set of servers taking part in consensus is changed one server at a time, following the
+//! single-server membership change scheme from the Raft dissertation (section 4.1). A change is
+//! made by appending a configuration entry to the log; every server uses the latest
+//! configuration in its log, whether or not it is committed. The leader will not start a new
+//! change until the previous one has committed, which guarantees that the majorities of the old
+//! and the new configuration overlap.
+//!
+//! A configuration may also hold learners: non-voting members which receive entries from the
+//! leader but take no part in elections or commitment. A new server is best added as a learner
+//! and promoted once it has caught up, so that it does not hold back commitment meanwhile.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use capnp::message::{Builder, ReaderOptions};
+use capnp::{serialize, struct_list};
+
+use {LogIndex, Result, ServerId};
+use messages_capnp::{learner_status, membership, peer};
+
+/// A cluster configuration: the servers taking part in consensus, and their addresses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Membership {
+    /// The voting members.
+    members: HashMap<ServerId, SocketAddr>,
+    /// The non-voting members.
+    learners: HashMap<ServerId, SocketAddr>,
+}
+
+impl Membership {
+    /// Creates a `Membership` from the set of voting members.
+    pub fn new(members: HashMap<ServerId, SocketAddr>) -> Membership {
+        Membership {
+            members: members,
+            learners: HashMap::new(),
+        }
+    }
+
+    /// Returns whether the server is a member of the configuration, either voting or not.
+    pub fn contains(&self, id: &ServerId) -> bool {
+        self.members.contains_key(id) || self.learners.contains_key(id)
+    }
+
+    /// Returns whether the server is a voting member of the configuration.
+    pub fn is_voter(&self, id: &ServerId) -> bool {
+        self.members.contains_key(id)
+    }
+
+    /// Returns whether the server is a learner in the configuration.
+    pub fn is_learner(&self, id: &ServerId) -> bool {
+        self.learners.contains_key(id)
+    }
+
+    /// Returns the members of the configuration other than `id`, including learners.
+    pub fn peers(&self, id: ServerId) -> HashMap<ServerId, SocketAddr> {
+        self.members
+            .iter()
+            .chain(self.learners.iter())
+            .filter(|&(&member, _)| member != id)
+            .map(|(&member, &addr)| (member, addr))
+            .collect()
+    }
+
+    /// Returns the number of voting members which make up a majority of the configuration.
+    pub fn majority(&self) -> usize {
+        (self.members.len() >> 1) + 1
+    }
+
+    /// Returns a copy of the configuration with the server added as a voting member.
+    pub fn with_member(&self, id: ServerId, addr: SocketAddr) -> Membership {
+        let mut membership = self.without_member(id);
+        membership.members.insert(id, addr);
+        membership
+    }
+
+    /// Returns a copy of the configuration with the server added as a learner.
+    pub fn with_learner(&self, id: ServerId, addr: SocketAddr) -> Membership {
+        let mut membership = self.without_member(id);
+        membership.learners.insert(id, addr);
+        membership
+    }
+
+    /// Returns a copy of the configuration with the learner promoted to a voting member.
+    pub fn promote(&self, id: ServerId) -> Membership {
+        let addr = self.learners[&id];
+        self.with_member(id, addr)
+    }
+
+    /// Returns a copy of the configuration with the server removed.
+    pub fn without_member(&self, id: ServerId) -> Membership {
+        let mut membership = self.clone();
+        membership.members.remove(&id);
+        membership.learners.remove(&id);
+        membership
+    }
+
+    /// Serializes the configuration.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = Builder::new_default();
+        self.write(message.init_root::<membership::Builder>());
+        let mut bytes = Vec::new();
+        serialize::write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    /// Deserializes a configuration serialized with `to_bytes`.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Membership> {
+        let message = try!(serialize::read_message(&mut bytes, ReaderOptions::new()));
+        Membership::read(try!(message.get_root::<membership::Reader>()))
+    }
+
+    /// Writes the configuration into a Cap'n Proto message.
+    pub fn write(&self, mut builder: membership::Builder) {
+        write_peers(&self.members,
+                    builder.borrow().init_members(self.members.len() as u32));
+        write_peers(&self.learners,
+                    builder.init_learners(self.learners.len() as u32));
+    }
+
+    /// Reads a configuration from a Cap'n Proto message.
+    pub fn read(reader: membership::Reader) -> Result<Membership> {
+        Ok(Membership {
+            members: try!(read_peers(try!(reader.get_members()))),
+            learners: try!(read_peers(try!(reader.get_learners()))),
+        })
+    }
+}
+
+/// Writes the servers into a list of Cap'n Proto peers.
+fn write_peers(peers: &HashMap<ServerId, SocketAddr>, mut list: struct_list::Builder<peer::Owned>) {
+    for (n, (id, addr)) in peers.iter().enumerate() {
+        let mut slot = list.borrow().get(n as u32);
+        slot.set_id(id.as_u64());
+        slot.set_addr(&format!("{}", addr));
+    }
+}
+
+/// Reads the servers from a list of Cap'n Proto peers.
+fn read_peers(list: struct_list::Reader<peer::Owned>)
+              -> Result<HashMap<ServerId, SocketAddr>> {
+    let mut peers = HashMap::new();
+    for peer in list.iter() {
+        let addr = try!(SocketAddr::from_str(try!(peer.get_addr())));
+        peers.insert(ServerId(peer.get_id()), addr);
+    }
+    Ok(peers)
+}
+
+/// How far a learner has caught up with the leader's log, as reported by
+/// `Client::learner_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LearnerStatus {
+    /// The index of the latest entry known to be replicated on the learner.
+    pub match_index: LogIndex,
+    /// The leader's commit index.
+    pub commit_index: LogIndex,
+    /// Whether the learner holds every committed entry. The leader only promotes learners which
+    /// have caught up.
+    pub caught_up: bool,
+}
+
+impl LearnerStatus {
+    /// Serializes the status.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = Builder::new_default();
+        {
+            let mut status = message.init_root::<learner_status::Builder>();
+            status.set_match_index(self.match_index.as_u64());
+            status.set_commit_index(self.commit_index.as_u64());
+            status.set_caught_up(self.caught_up);
+        }
+        let mut bytes = Vec::new();
+        serialize::write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    /// Deserializes a status serialized with `to_bytes`.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<LearnerStatus> {
+        let message = try!(serialize::read_message(&mut bytes, ReaderOptions::new()));
+        let status = try!(message.get_root::<learner_status::Reader>());
+        Ok(LearnerStatus {
+            match_index: LogIndex(status.get_match_index()),
+            commit_index: LogIndex(status.get_commit_index()),
+            caught_up: status.get_caught_up(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use ServerId;
+    use membership::Membership;
+
+    /// Tests that configurations survive encoding.
+    #[test]
+    fn test_round_trip() {
+        let mut members = HashMap::new();
+        members.insert(ServerId(1), SocketAddr::from_str("127.0.0.1:9001").unwrap());
+        members.insert(ServerId(2), SocketAddr::from_str("127.0.0.1:9002").unwrap());
+        let membership = Membership::new(members)
+                             .with_learner(ServerId(3),
+                                           SocketAddr::from_str("127.0.0.1:9003").unwrap());
+
+        assert_eq!(membership, Membership::from_bytes(&membership.to_bytes()).unwrap());
+    }
+
+    /// Tests the majority size as servers are added and removed.
+    #[test]
+    fn test_majority() {
+        let addr = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        let membership = Membership::default();
+        let membership = membership.with_member(ServerId(1), addr);
+        assert_eq!(1, membership.majority());
+        let membership = membership.with_member(ServerId(2), addr);
+        assert_eq!(2, membership.majority());
+        let membership = membership.with_member(ServerId(3), addr);
+        assert_eq!(2, membership.majority());
+        let membership = membership.without_member(ServerId(1));
+        assert_eq!(2, membership.majority());
+        assert!(!membership.contains(&ServerId(1)));
+        assert_eq!(1, membership.peers(ServerId(2)).len());
+
+        // Learners do not count towards the majority until they are promoted.
+        let membership = membership.with_learner(ServerId(4), addr);
+        assert_eq!(2, membership.majority());
+        assert!(membership.contains(&ServerId(4)));
+        assert!(!membership.is_voter(&ServerId(4)));
+        assert_eq!(2, membership.peers(ServerId(2)).len());
+        let membership = membership.promote(ServerId(4));
+        assert_eq!(2, membership.majority());
+        assert!(membership.is_voter(&ServerId(4)));
+        let membership = membership.with_learner(ServerId(5), addr).promote(ServerId(5));
+        assert_eq!(3, membership.majority());
+    }
+}
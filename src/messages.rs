@@ -6,8 +6,8 @@ use std::rc::Rc;
 
 use capnp::message::{Builder, HeapAllocator};
 
-use {ClientId, Term, LogIndex, ServerId};
-use messages_capnp::{client_request, client_response, connection_preamble, message};
+use {ClientId, Consistency, EntryKind, Term, LogIndex, ServerId};
+use messages_capnp::{self, client_request, client_response, connection_preamble, message};
 
 // ConnectionPreamble
 
@@ -38,8 +38,9 @@ pub fn client_connection_preamble(id: ClientId) -> Rc<Builder<HeapAllocator>> {
 pub fn append_entries_request(term: Term,
                               prev_log_index: LogIndex,
                               prev_log_term: Term,
-                              entries: &[(Term, &[u8])],
-                              leader_commit: LogIndex)
+                              entries: &[(Term, EntryKind, &[u8])],
+                              leader_commit: LogIndex,
+                              read_sequence: u64)
                               -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
@@ -49,25 +50,34 @@ pub fn append_entries_request(term: Term,
         request.set_prev_log_index(prev_log_index.as_u64());
         request.set_prev_log_term(prev_log_term.as_u64());
         request.set_leader_commit(leader_commit.as_u64());
+        request.set_read_sequence(read_sequence);
 
         let mut entry_list = request.init_entries(entries.len() as u32);
         for (n, entry) in entries.iter().enumerate() {
             let mut slot = entry_list.borrow().get(n as u32);
             slot.set_term(entry.0.into());
-            slot.set_data(entry.1);
+            slot.set_kind(match entry.1 {
+                EntryKind::Normal => messages_capnp::EntryKind::Normal,
+                EntryKind::NoOp => messages_capnp::EntryKind::Noop,
+                EntryKind::Configuration => messages_capnp::EntryKind::Configuration,
+                EntryKind::Session => messages_capnp::EntryKind::Session,
+            });
+            slot.set_data(entry.2);
         }
     }
     Rc::new(message)
 }
 
 pub fn append_entries_response_success(term: Term,
-                                       log_index: LogIndex)
+                                       log_index: LogIndex,
+                                       read_sequence: u64)
                                        -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_append_entries_response();
         response.set_term(term.as_u64());
+        response.set_read_sequence(read_sequence);
         response.set_success(log_index.as_u64());
     }
     Rc::new(message)
@@ -85,14 +95,21 @@ pub fn append_entries_response_stale_term(term: Term) -> Rc<Builder<HeapAllocato
 }
 
 pub fn append_entries_response_inconsistent_prev_entry(term: Term,
-                                                       index: LogIndex)
+                                                       index: LogIndex,
+                                                       conflict_term: Term,
+                                                       conflict_index: LogIndex,
+                                                       read_sequence: u64)
                                                        -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_append_entries_response();
         response.set_term(term.as_u64());
-        response.set_inconsistent_prev_entry(index.into());
+        response.set_read_sequence(read_sequence);
+        let mut inconsistent = response.init_inconsistent_prev_entry();
+        inconsistent.set_index(index.into());
+        inconsistent.set_conflict_term(conflict_term.into());
+        inconsistent.set_conflict_index(conflict_index.into());
     }
     Rc::new(message)
 }
@@ -114,7 +131,8 @@ pub fn append_entries_response_internal_error(term: Term,
 
 pub fn request_vote_request(term: Term,
                             last_log_index: LogIndex,
-                            last_log_term: Term)
+                            last_log_term: Term,
+                            leadership_transfer: bool)
                             -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
@@ -123,6 +141,7 @@ pub fn request_vote_request(term: Term,
         request.set_term(term.as_u64());
         request.set_last_log_index(last_log_index.as_u64());
         request.set_last_log_term(last_log_term.as_u64());
+        request.set_leadership_transfer(leadership_transfer);
     }
     Rc::new(message)
 }
@@ -182,6 +201,169 @@ pub fn request_vote_response_internal_error(term: Term, error: &str) -> Rc<Build
     Rc::new(message)
 }
 
+// TimeoutNow
+
+pub fn timeout_now(term: Term) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        message.init_root::<message::Builder>()
+               .init_timeout_now()
+               .set_term(term.as_u64());
+    }
+    Rc::new(message)
+}
+
+// PreVote
+
+pub fn pre_vote_request(term: Term,
+                        last_log_index: LogIndex,
+                        last_log_term: Term)
+                        -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>()
+                                 .init_pre_vote_request();
+        request.set_term(term.as_u64());
+        request.set_last_log_index(last_log_index.as_u64());
+        request.set_last_log_term(last_log_term.as_u64());
+    }
+    Rc::new(message)
+}
+
+pub fn pre_vote_response(term: Term, granted: bool) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>()
+                                  .init_pre_vote_response();
+        response.set_term(term.as_u64());
+        response.set_granted(granted);
+    }
+    Rc::new(message)
+}
+
+// InstallSnapshot
+
+pub fn install_snapshot_request(term: Term,
+                                last_included_index: LogIndex,
+                                last_included_term: Term,
+                                offset: u64,
+                                data: &[u8],
+                                done: bool)
+                                -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>()
+                                 .init_install_snapshot_request();
+        request.set_term(term.as_u64());
+        request.set_last_included_index(last_included_index.as_u64());
+        request.set_last_included_term(last_included_term.as_u64());
+        request.set_offset(offset);
+        request.set_data(data);
+        request.set_done(done);
+    }
+    Rc::new(message)
+}
+
+pub fn install_snapshot_response_success(term: Term,
+                                         last_included_index: LogIndex,
+                                         next_offset: u64)
+                                         -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>()
+                                  .init_install_snapshot_response();
+        response.set_term(term.as_u64());
+        response.set_last_included_index(last_included_index.as_u64());
+        response.set_success(next_offset);
+    }
+    Rc::new(message)
+}
+
+pub fn install_snapshot_response_installed(term: Term,
+                                           last_included_index: LogIndex)
+                                           -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>()
+                                  .init_install_snapshot_response();
+        response.set_term(term.as_u64());
+        response.set_last_included_index(last_included_index.as_u64());
+        response.set_installed(());
+    }
+    Rc::new(message)
+}
+
+pub fn install_snapshot_response_stale_term(term: Term,
+                                            last_included_index: LogIndex)
+                                            -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>()
+                                  .init_install_snapshot_response();
+        response.set_term(term.as_u64());
+        response.set_last_included_index(last_included_index.as_u64());
+        response.set_stale_term(());
+    }
+    Rc::new(message)
+}
+
+pub fn install_snapshot_response_internal_error(term: Term,
+                                                last_included_index: LogIndex,
+                                                error: &str)
+                                                -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>()
+                                  .init_install_snapshot_response();
+        response.set_term(term.as_u64());
+        response.set_last_included_index(last_included_index.as_u64());
+        response.set_internal_error(error);
+    }
+    Rc::new(message)
+}
+
+// ReadIndex
+
+pub fn read_index_request(term: Term, read_sequence: u64) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>()
+                                 .init_read_index_request();
+        request.set_term(term.as_u64());
+        request.set_read_sequence(read_sequence);
+    }
+    Rc::new(message)
+}
+
+pub fn read_index_response(term: Term,
+                           read_sequence: u64,
+                           read_index: LogIndex)
+                           -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>()
+                                  .init_read_index_response();
+        response.set_term(term.as_u64());
+        response.set_read_sequence(read_sequence);
+        response.set_read_index(read_index.as_u64());
+    }
+    Rc::new(message)
+}
+
+pub fn read_index_response_not_leader(term: Term,
+                                      read_sequence: u64)
+                                      -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>()
+                                  .init_read_index_response();
+        response.set_term(term.as_u64());
+        response.set_read_sequence(read_sequence);
+        response.set_not_leader(());
+    }
+    Rc::new(message)
+}
+
 // Ping
 
 pub fn ping_request() -> Builder<HeapAllocator> {
@@ -195,12 +377,17 @@ pub fn ping_request() -> Builder<HeapAllocator> {
 
 // Query
 
-pub fn query_request(entry: &[u8]) -> Builder<HeapAllocator> {
+pub fn query_request(entry: &[u8], consistency: Consistency) -> Builder<HeapAllocator> {
     let mut message = Builder::new_default();
     {
-        message.init_root::<client_request::Builder>()
-               .init_query()
-               .set_query(entry);
+        let mut request = message.init_root::<client_request::Builder>()
+                                 .init_query();
+        request.set_query(entry);
+        request.set_consistency(match consistency {
+            Consistency::Linearizable => messages_capnp::Consistency::Linearizable,
+            Consistency::BoundedStaleness => messages_capnp::Consistency::BoundedStaleness,
+            Consistency::AnyReplica => messages_capnp::Consistency::AnyReplica,
+        });
     }
     message
 }
@@ -218,34 +405,130 @@ pub fn proposal_request(entry: &[u8]) -> Builder<HeapAllocator> {
     message
 }
 
+pub fn session_proposal_request(entry: &[u8],
+                                client: ClientId,
+                                sequence: u64,
+                                acknowledged: u64)
+                                -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>().init_proposal();
+        request.set_entry(entry);
+        request.set_client(client.as_bytes());
+        request.set_sequence(sequence);
+        request.set_acknowledged(acknowledged);
+    }
+    message
+}
+
+// Membership
+
+pub fn add_server_request(id: ServerId,
+                          addr: &SocketAddr,
+                          learner: bool)
+                          -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>()
+                                 .init_add_server();
+        request.set_learner(learner);
+        let mut server = request.init_server();
+        server.set_id(id.as_u64());
+        server.set_addr(&format!("{}", addr));
+    }
+    message
+}
+
+pub fn remove_server_request(id: ServerId) -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        message.init_root::<client_request::Builder>()
+               .init_remove_server()
+               .set_id(id.as_u64());
+    }
+    message
+}
+
+pub fn promote_server_request(id: ServerId) -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        message.init_root::<client_request::Builder>()
+               .init_promote_server()
+               .set_id(id.as_u64());
+    }
+    message
+}
+
+pub fn learner_status_request(id: ServerId) -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        message.init_root::<client_request::Builder>()
+               .init_learner_status()
+               .set_id(id.as_u64());
+    }
+    message
+}
+
+pub fn transfer_leadership_request(id: ServerId) -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        message.init_root::<client_request::Builder>()
+               .init_transfer_leadership()
+               .set_id(id.as_u64());
+    }
+    message
+}
+
 // Query / Proposal Response
 
-pub fn command_response_success(data: &[u8]) -> Rc<Builder<HeapAllocator>> {
+pub fn command_response_success(id: u64, data: &[u8]) -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
-        message.init_root::<client_response::Builder>()
-               .init_proposal()
-               .set_success(data);
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_id(id);
+        response.init_proposal().set_success(data);
     }
     Rc::new(message)
 }
 
-pub fn command_response_unknown_leader() -> Rc<Builder<HeapAllocator>> {
+pub fn command_response_unknown_leader(id: u64) -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
-        message.init_root::<client_response::Builder>()
-               .init_proposal()
-               .set_unknown_leader(());
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_id(id);
+        response.init_proposal().set_unknown_leader(());
     }
     Rc::new(message)
 }
 
-pub fn command_response_not_leader(leader_hint: &SocketAddr) -> Rc<Builder<HeapAllocator>> {
+pub fn command_response_not_leader(id: u64,
+                                   leader_hint: &SocketAddr)
+                                   -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
-        message.init_root::<client_response::Builder>()
-               .init_proposal()
-               .set_not_leader(&format!("{}", leader_hint));
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_id(id);
+        response.init_proposal().set_not_leader(&format!("{}", leader_hint));
+    }
+    Rc::new(message)
+}
+
+pub fn command_response_rejected(id: u64, reason: &str) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_id(id);
+        response.init_proposal().set_rejected(reason);
+    }
+    Rc::new(message)
+}
+
+pub fn command_response_session_expired(id: u64) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_id(id);
+        response.init_proposal().set_session_expired(());
     }
     Rc::new(message)
 }
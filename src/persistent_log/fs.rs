@@ -1,15 +1,33 @@
-use std::{error, fmt, fs, path, result};
+use std::{cmp, error, fmt, fs, io, mem, path, result, thread};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, SeekFrom};
+use std::io::{BufReader, SeekFrom};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
 use persistent_log::Log;
+use EntryKind;
 use LogIndex;
 use ServerId;
 use Term;
 
-/// This is a `Log` implementation that stores entries in the filesystem
-/// as well as in a struct. It is chiefly intended for testing.
+/// This is a `Log` implementation that stores entries in the filesystem.
+///
+/// The log is a directory holding the current term and vote, the snapshot,
+/// and the entries split over segment files. Only the term and position of
+/// each entry are held in memory; entries are read from their segment when
+/// needed, and the most recently used ones are cached.
+///
+/// Every record in the log carries a CRC-32 checksum. A write torn by a crash
+/// leaves an invalid record at the tail of the last segment, which is
+/// truncated away when the log is next opened. Under `SyncPolicy::Never`
+/// earlier segments may be torn too, since they are not synced before the
+/// next segment is started; a log opened with that policy is truncated at
+/// the first invalid record in any segment, and the segments following it
+/// are deleted.
 ///
 /// # Panic
 ///
@@ -18,175 +36,857 @@ use Term;
 
 
 /// Error type for FsLog
-
-#[derive(Debug, PartialEq, Eq)]
-pub struct Error;
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a log file failed.
+    Io(io::Error),
+    /// The log was written with an unsupported version of the format.
+    Version(u64),
+    /// The path names a file rather than a directory: a log written in the
+    /// single-file format of the given version, which is no longer read.
+    SingleFile(path::PathBuf, u64),
+    /// The log file is corrupt at the given offset, in a way which a torn
+    /// write cannot explain.
+    Corrupt(path::PathBuf, u64),
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "An error occurred")
+        match *self {
+            Error::Io(ref error) => fmt::Display::fmt(error, fmt),
+            Error::Version(version) => {
+                write!(fmt,
+                       "unsupported log file version {} (expected version {})",
+                       version,
+                       VERSION)
+            }
+            Error::SingleFile(ref path, version) => {
+                write!(fmt,
+                       "{} is a single-file log of version {}; logs of version {} are stored as \
+                        a directory, and single-file logs are not converted",
+                       path.display(),
+                       version,
+                       VERSION)
+            }
+            Error::Corrupt(ref path, offset) => {
+                write!(fmt, "log file {} corrupt at offset {}", path.display(), offset)
+            }
+        }
     }
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
-        "An error occurred"
+        match *self {
+            Error::Io(ref error) => error.description(),
+            Error::Version(..) => "unsupported log file version",
+            Error::SingleFile(..) => "single-file log no longer supported",
+            Error::Corrupt(..) => "log file corrupt",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref error) => Some(error),
+            _ => None,
+        }
     }
 }
 
-impl ::std::convert::From<::std::io::Error> for Error {
-    fn from(_err: ::std::io::Error) -> Error {
-        Error
+impl ::std::convert::From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
     }
 }
 
 pub type Result<T> = result::Result<T, Error>;
-pub type Entry = (Term, Vec<u8>);
-
-/// Version of the log file format.  A logfile will always start with an eight
-/// byte version specifier.  If the format ever changes, this version will be
-/// updated, so FsLog will not read the log incorrectly.
-const VERSION: u64 = 1;
-
-/// Stores log on disk as 8 bytes for the version identifier, 8 bytes for
-/// current_term, 8 bytes for voted_for, and as much as needed for the log.
-/// Each log entry is stored as an 8 byte length specifier which is the total
-/// length of the entry in bytes, including the length specifier, followed by 8
-/// bytes specifying the term, plus a variable length entry, which is the
-/// serialized command sent to raft by the client.
+pub type Entry = (Term, EntryKind, Vec<u8>);
+
+/// When `FsLog` syncs its writes to disk. A write which has not been synced
+/// may be lost if the machine fails, though not if only the process crashes.
+///
+/// Raft's safety depends on the current term, the vote and the entries being
+/// durable before the server answers; only `Always` guarantees that.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync every write before returning.
+    Always,
+    /// Sync once the given number of writes are unsynced, and at the latest
+    /// the given number of milliseconds after a write. The timed syncs run on
+    /// a thread of their own, and a failure is returned by the next write.
+    Batched(usize, u64),
+    /// Leave syncing to the operating system.
+    Never,
+}
+
+/// Version of the log format.  The metadata file and every segment start
+/// with an eight byte version specifier.  If the format ever changes, this
+/// version will be updated, so FsLog will not read the log incorrectly.
+const VERSION: u64 = 5;
+
+/// The name of the file holding the current term and vote.
+const METADATA_FILE: &'static str = "meta";
+
+/// The name of the file holding the snapshot.
+const SNAPSHOT_FILE: &'static str = "snapshot";
+
+/// The extension of segment files, which are named after the index of their
+/// first entry.
+const SEGMENT_EXTENSION: &'static str = "log";
+
+/// The size of a metadata slot: a sequence number, the current term and the
+/// vote, followed by the checksum.
+const METADATA_LEN: u64 = 28;
+
+/// The size of the snapshot header: the snapshot index, term and length,
+/// followed by the checksum.
+const SNAPSHOT_HEADER_LEN: u64 = 28;
+
+/// The size of a segment header: the version.
+const SEGMENT_HEADER_LEN: u64 = 8;
+
+/// The size of an entry record's header: the data length, the checksum, the
+/// term and the kind.
+const RECORD_HEADER_LEN: u64 = 21;
+
+/// Configures and opens an `FsLog`.
+#[derive(Clone, Debug)]
+pub struct FsLogBuilder {
+    dir: path::PathBuf,
+    sync_policy: SyncPolicy,
+    segment_bytes: u64,
+    cache_entries: usize,
+}
+
+impl FsLogBuilder {
+    /// Sets when writes are synced to disk. Defaults to `SyncPolicy::Always`.
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> FsLogBuilder {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets the size in bytes at which a segment is closed and the next one
+    /// started. Defaults to 64 MiB.
+    pub fn with_segment_bytes(mut self, segment_bytes: u64) -> FsLogBuilder {
+        self.segment_bytes = segment_bytes;
+        self
+    }
+
+    /// Sets the number of recently used entries held in memory. Defaults to
+    /// 1024.
+    pub fn with_cache_entries(mut self, cache_entries: usize) -> FsLogBuilder {
+        self.cache_entries = cache_entries;
+        self
+    }
+
+    /// Opens the log stored in the directory, creating it if necessary.
+    pub fn finalize(self) -> Result<FsLog> {
+        FsLog::open(self)
+    }
+}
+
+/// Stores the log as a directory of files.
+///
+/// The metadata file holds 8 bytes for the version identifier, followed by
+/// two metadata slots. A metadata slot holds an 8 byte sequence number, 8
+/// bytes for current_term, 8 bytes for voted_for, and a 4 byte checksum.
+/// Updates alternate between the slots, so that a torn update leaves the other
+/// slot intact; the valid slot with the highest sequence number is current.
+///
+/// The snapshot file holds 8 bytes each for the snapshot index, term and
+/// length, a 4 byte checksum of those and the snapshot, and the snapshot
+/// itself. It is absent until the log is first compacted, and replaced as a
+/// whole on every compaction.
+///
+/// Each segment file is named after the index of its first entry, and holds 8
+/// bytes for the version identifier followed by entries. Each log entry is
+/// stored as an 8 byte length of the entry data, a 4 byte checksum of the
+/// term, kind and data, 8 bytes specifying the term, 1 byte specifying the
+/// kind, and the data, which is the serialized command sent to raft by the
+/// client. Compaction deletes the segments whose entries all precede the
+/// snapshot.
 #[derive(Debug)]
 pub struct FsLog {
-    reader: BufReader<fs::File>,
-    writer: BufWriter<fs::File>,
+    dir: path::PathBuf,
+    metadata: fs::File,
+    sync_policy: SyncPolicy,
+    segment_bytes: u64,
+    /// The number of writes since the last sync.
+    unsynced: usize,
+    /// The files awaiting a timed sync under a `Batched` sync policy.
+    flusher: Option<Arc<Mutex<Flusher>>>,
+    /// The sequence number of the current metadata slot.
+    sequence: u64,
     current_term: Term,
     voted_for: Option<ServerId>,
-    entries: Vec<(Term, Vec<u8>)>,
-    offsets: Vec<u64>,
+    snapshot_index: LogIndex,
+    snapshot_term: Term,
+    snapshot: Vec<u8>,
+    /// The segments in index order. Entries at the start of the first segment
+    /// may precede the snapshot.
+    segments: Vec<Segment>,
+    cache: RefCell<EntryCache>,
 }
 
+/// A segment file, and the term and offset of each entry in it.
+#[derive(Debug)]
+struct Segment {
+    /// The index of the first entry in the segment.
+    first_index: LogIndex,
+    path: path::PathBuf,
+    file: fs::File,
+    entries: Vec<(Term, u64)>,
+    /// The length of the segment file.
+    len: u64,
+}
 
-impl FsLog {
-    pub fn new(filename: &path::Path) -> Result<FsLog> {
+impl Segment {
+    /// Returns the index of the last entry in the segment, or the index
+    /// preceding the segment if it is empty.
+    fn last_index(&self) -> LogIndex {
+        self.first_index + self.entries.len() as u64 - 1
+    }
+
+    /// Reads the entry at the provided position in the segment.
+    fn read(&self, position: usize) -> Result<Entry> {
+        let offset = self.entries[position].1;
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        match read_record(&mut BufReader::new(file), self.len - offset)? {
+            Some((entry, _)) => Ok(entry),
+            None => Err(Error::Corrupt(self.path.clone(), offset)),
+        }
+    }
+}
+
+/// The files with unsynced writes, which a thread syncs on behalf of a log
+/// with a `Batched` sync policy.
+#[derive(Debug, Default)]
+struct Flusher {
+    /// Handles to the files to sync.
+    files: Vec<fs::File>,
+    /// The error of a failed sync, which has yet to be returned.
+    error: Option<io::Error>,
+}
+
+impl Flusher {
+    /// Starts a thread which syncs the registered files at the interval of a
+    /// `Batched` sync policy, until the log is dropped. Returns `None` under
+    /// other policies.
+    fn spawn(sync_policy: SyncPolicy) -> Option<Arc<Mutex<Flusher>>> {
+        let interval = match sync_policy {
+            SyncPolicy::Batched(_, millis) => Duration::from_millis(cmp::max(millis, 1)),
+            _ => return None,
+        };
+        let flusher = Arc::new(Mutex::new(Flusher::default()));
+        let handle = Arc::downgrade(&flusher);
+        thread::spawn(move || Flusher::run(handle, interval));
+        Some(flusher)
+    }
 
-        let mut w = BufWriter::new(
-            fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&filename)?);
+    fn run(handle: Weak<Mutex<Flusher>>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            let flusher = match handle.upgrade() {
+                Some(flusher) => flusher,
+                None => return,
+            };
+            let mut flusher = flusher.lock().unwrap();
+            for file in mem::replace(&mut flusher.files, Vec::new()) {
+                if let Err(error) = file.sync_data() {
+                    flusher.error = Some(error);
+                }
+            }
+        }
+    }
+}
 
-        let filelen = w.get_ref().metadata()?.len();
+/// Holds a bounded number of entries, evicting the least recently used.
+#[derive(Debug)]
+struct EntryCache {
+    capacity: usize,
+    /// Advanced on every use of the cache.
+    clock: u64,
+    /// The cached entries, and when each was last used.
+    entries: HashMap<LogIndex, (u64, Entry)>,
+    /// The cached indexes by when they were last used.
+    usage: BTreeMap<u64, LogIndex>,
+}
 
-        if filelen == 0 {
-            w.write_u64::<BigEndian>(VERSION)?;  // Term (0)
-            w.write_u64::<BigEndian>(0)?;  // Term (0)
-            w.write_u64::<BigEndian>(<u64>::max_value())?;  // Voted for (None)
-            w.flush()?;
+impl EntryCache {
+    fn new(capacity: usize) -> EntryCache {
+        EntryCache {
+            capacity: capacity,
+            clock: 0,
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
         }
+    }
 
-        let mut r = BufReader::new(fs::File::open(&filename)?);
+    /// Returns a copy of the cached entry, if any.
+    fn get(&mut self, index: LogIndex) -> Option<Entry> {
+        self.clock += 1;
+        match self.entries.get_mut(&index) {
+            Some(&mut (ref mut used, ref entry)) => {
+                self.usage.remove(used);
+                self.usage.insert(self.clock, index);
+                *used = self.clock;
+                Some(entry.clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Caches the entry, evicting the least recently used entry if the cache
+    /// is full.
+    fn insert(&mut self, index: LogIndex, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((used, _)) = self.entries.insert(index, (self.clock, entry)) {
+            self.usage.remove(&used);
+        }
+        self.usage.insert(self.clock, index);
+        if self.entries.len() > self.capacity {
+            let oldest = *self.usage.keys().next().unwrap();
+            let index = self.usage.remove(&oldest).unwrap();
+            self.entries.remove(&index);
+        }
+    }
 
-        let version = r.read_u64::<BigEndian>()?;
+    /// Evicts the entries at and following the provided index.
+    fn truncate(&mut self, from: LogIndex) {
+        let evicted: Vec<(LogIndex, u64)> = self.entries
+                                                .iter()
+                                                .filter(|&(&index, _)| index >= from)
+                                                .map(|(&index, &(used, _))| (index, used))
+                                                .collect();
+        for (index, used) in evicted {
+            self.entries.remove(&index);
+            self.usage.remove(&used);
+        }
+    }
+
+    /// Evicts every entry.
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.usage.clear();
+    }
+}
+
+/// Returns the CRC-32 checksum of the concatenated byte strings.
+fn checksum(parts: &[&[u8]]) -> u32 {
+    parts.iter().fold(0, |crc, part| crc32::update(crc, &crc32::IEEE_TABLE, part))
+}
+
+/// Encodes the metadata slot for the given state.
+fn encode_metadata(sequence: u64, term: Term, voted_for: Option<ServerId>) -> [u8; 28] {
+    let mut slot = [0u8; METADATA_LEN as usize];
+    BigEndian::write_u64(&mut slot[0..8], sequence);
+    BigEndian::write_u64(&mut slot[8..16], term.into());
+    BigEndian::write_u64(&mut slot[16..24], match voted_for {
+        None => <u64>::max_value(),
+        Some(ServerId(n)) => n,
+    });
+    let crc = checksum(&[&slot[..24]]);
+    BigEndian::write_u32(&mut slot[24..], crc);
+    slot
+}
+
+/// Decodes a metadata slot, returning `None` if it fails its checksum.
+fn decode_metadata(slot: &[u8]) -> Option<(u64, Term, Option<ServerId>)> {
+    if BigEndian::read_u32(&slot[24..]) != checksum(&[&slot[..24]]) {
+        return None;
+    }
+    let voted_for = match BigEndian::read_u64(&slot[16..24]) {
+        x if x == <u64>::max_value() => None,
+        x => Some(x.into()),
+    };
+    Some((BigEndian::read_u64(&slot[0..8]), BigEndian::read_u64(&slot[8..16]).into(), voted_for))
+}
+
+/// Encodes an entry kind as its tag in a record.
+fn encode_kind(kind: EntryKind) -> u8 {
+    match kind {
+        EntryKind::Normal => 0,
+        EntryKind::NoOp => 1,
+        EntryKind::Configuration => 2,
+        EntryKind::Session => 3,
+    }
+}
+
+/// Decodes the tag of an entry kind, returning `None` if it is unknown.
+fn decode_kind(tag: u8) -> Option<EntryKind> {
+    match tag {
+        0 => Some(EntryKind::Normal),
+        1 => Some(EntryKind::NoOp),
+        2 => Some(EntryKind::Configuration),
+        3 => Some(EntryKind::Session),
+        _ => None,
+    }
+}
+
+/// Writes an entry record.
+fn write_record<W>(w: &mut W, term: Term, kind: EntryKind, command: &[u8]) -> Result<()>
+    where W: Write
+{
+    let mut header = [0u8; 9];
+    BigEndian::write_u64(&mut header[..8], term.into());
+    header[8] = encode_kind(kind);
+    w.write_u64::<BigEndian>(command.len() as u64)?;
+    w.write_u32::<BigEndian>(checksum(&[&header, command]))?;
+    w.write_all(&header)?;
+    w.write_all(command)?;
+    Ok(())
+}
+
+/// Reads the entry record beginning `remaining` bytes before the end of the
+/// file, along with the record's length. Returns `None` if the record is
+/// incomplete, fails its checksum or has an unknown kind.
+fn read_record<R>(r: &mut R, remaining: u64) -> Result<Option<(Entry, u64)>>
+    where R: Read
+{
+    if remaining < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let length = r.read_u64::<BigEndian>()?;
+    let crc = r.read_u32::<BigEndian>()?;
+    if length > remaining - RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0u8; 9];
+    r.read_exact(&mut header)?;
+    let mut command = vec![0u8; length as usize];
+    r.read_exact(&mut command)?;
+    if crc != checksum(&[&header, &command]) {
+        return Ok(None);
+    }
+    let term = BigEndian::read_u64(&header[..8]).into();
+    match decode_kind(header[8]) {
+        Some(kind) => Ok(Some(((term, kind, command), RECORD_HEADER_LEN + length))),
+        None => Ok(None),
+    }
+}
+
+/// Encodes the snapshot header for the snapshot.
+fn encode_snapshot_header(index: LogIndex, term: Term, snapshot: &[u8]) -> [u8; 28] {
+    let mut header = [0u8; SNAPSHOT_HEADER_LEN as usize];
+    BigEndian::write_u64(&mut header[0..8], index.into());
+    BigEndian::write_u64(&mut header[8..16], term.into());
+    BigEndian::write_u64(&mut header[16..24], snapshot.len() as u64);
+    let crc = checksum(&[&header[..24], snapshot]);
+    BigEndian::write_u32(&mut header[24..], crc);
+    header
+}
+
+/// Syncs the directory, making the creation, removal and renaming of the
+/// files in it durable.
+fn sync_dir(dir: &path::Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+impl FsLog {
+    /// Opens the log stored in the directory, creating it if necessary. Every
+    /// write is synced. Returns `Error::SingleFile` if the path names a log
+    /// written in the single-file format of earlier versions.
+    pub fn new(dir: &path::Path) -> Result<FsLog> {
+        FsLog::builder(dir).finalize()
+    }
+
+    /// Returns a builder for the log stored in the directory.
+    pub fn builder(dir: &path::Path) -> FsLogBuilder {
+        FsLogBuilder {
+            dir: dir.to_path_buf(),
+            sync_policy: SyncPolicy::Always,
+            segment_bytes: 64 * 1024 * 1024,
+            cache_entries: 1024,
+        }
+    }
+
+    fn open(builder: FsLogBuilder) -> Result<FsLog> {
+        let FsLogBuilder { dir, sync_policy, segment_bytes, cache_entries } = builder;
+        if dir.is_file() {
+            // Logs were stored in a single file up to version 3 of the format.
+            let version = fs::File::open(&dir)?.read_u64::<BigEndian>()?;
+            return Err(Error::SingleFile(dir, version));
+        }
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(METADATA_FILE);
+        let mut metadata = fs::OpenOptions::new()
+                               .read(true)
+                               .write(true)
+                               .create(true)
+                               .open(&path)?;
+        if metadata.metadata()?.len() == 0 {
+            metadata.write_u64::<BigEndian>(VERSION)?;  // Version
+            metadata.write_all(&encode_metadata(0, Term(0), None))?;  // Term (0), voted for (None)
+            metadata.write_all(&[0u8; METADATA_LEN as usize])?;  // Unused slot
+            metadata.sync_all()?;
+            sync_dir(&dir)?;
+        }
+        metadata.seek(SeekFrom::Start(0))?;
+        let version = metadata.read_u64::<BigEndian>()?;
         if version != VERSION {
-            return Err(Error);
-        } 
-        let current_term: Term = r.read_u64::<BigEndian>()?.into();
-        let voted_for: Option<ServerId> = match r.read_u64::<BigEndian>()? {
-            x if x == <u64>::max_value() => None,
-            x => Some(x.into())
-        };
+            return Err(Error::Version(version));
+        }
+        let mut slots = [0u8; 2 * METADATA_LEN as usize];
+        metadata.read_exact(&mut slots)?;
+        let (sequence, current_term, voted_for) =
+            match (decode_metadata(&slots[..METADATA_LEN as usize]),
+                   decode_metadata(&slots[METADATA_LEN as usize..])) {
+                (Some(a), Some(b)) => if a.0 > b.0 { a } else { b },
+                (Some(slot), None) | (None, Some(slot)) => slot,
+                (None, None) => return Err(Error::Corrupt(path, 8)),
+            };
 
+        let (snapshot_index, snapshot_term, snapshot) = read_snapshot(&dir.join(SNAPSHOT_FILE))?;
+
+        let flusher = Flusher::spawn(sync_policy);
         let mut log = FsLog {
-            reader: r,
-            writer: w,
+            dir: dir,
+            metadata: metadata,
+            sync_policy: sync_policy,
+            segment_bytes: segment_bytes,
+            unsynced: 0,
+            flusher: flusher,
+            sequence: sequence,
             current_term: current_term,
             voted_for: voted_for,
+            snapshot_index: snapshot_index,
+            snapshot_term: snapshot_term,
+            snapshot: snapshot,
+            segments: Vec::new(),
+            cache: RefCell::new(EntryCache::new(cache_entries)),
+        };
+        log.open_segments()?;
+        Ok(log)
+    }
+
+    /// Reads the term and offset of every entry in the segment files. A torn
+    /// write at the tail of the last segment, or of any segment under
+    /// `SyncPolicy::Never`, is truncated away along with the segments
+    /// following it, and segments left behind by an interrupted compaction are
+    /// deleted.
+    fn open_segments(&mut self) -> Result<()> {
+        let mut first_indexes = Vec::new();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().map_or(true, |extension| extension != SEGMENT_EXTENSION) {
+                continue;
+            }
+            let first_index = path.file_stem()
+                                  .and_then(|stem| stem.to_str())
+                                  .and_then(|stem| stem.parse::<u64>().ok());
+            match first_index {
+                Some(first_index) => first_indexes.push(LogIndex(first_index)),
+                None => return Err(Error::Corrupt(path, 0)),
+            }
+        }
+        first_indexes.sort();
+
+        let count = first_indexes.len();
+        let mut torn = false;
+        for (n, first_index) in first_indexes.into_iter().enumerate() {
+            let path = self.segment_path(first_index);
+            if torn {
+                // The entries following a torn write no longer follow on from the log.
+                warn!("{}: discarding segment following a torn write", path.display());
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let filelen = fs::metadata(&path)?.len();
+            let tearable = n + 1 == count || self.sync_policy == SyncPolicy::Never;
+            let segment = self.open_segment(first_index, tearable)?;
+            torn = segment.as_ref().map_or(true, |segment| segment.len < filelen);
+            if let Some(segment) = segment {
+                let expected = self.segments.last().map(|previous| previous.last_index() + 1);
+                if expected.map_or(false, |expected| expected != segment.first_index) {
+                    return Err(Error::Corrupt(segment.path, 0));
+                }
+                self.segments.push(segment);
+            }
+        }
+        if torn {
+            sync_dir(&self.dir)?;
+        }
+
+        // A compaction removes the segments which precede the snapshot, or every segment if the
+        // snapshot supersedes the log; finish any compaction which was interrupted.
+        let superseded = match (self.segments.first(), self.segments.last()) {
+            (Some(first), _) if first.first_index > self.snapshot_index + 1 => {
+                return Err(Error::Corrupt(first.path.clone(), 0));
+            }
+            (_, Some(last)) if last.last_index() < self.snapshot_index => true,
+            (Some(first), _) if first.first_index <= self.snapshot_index => {
+                self.term(self.snapshot_index)? != self.snapshot_term
+            }
+            _ => false,
+        };
+        let count = if superseded {
+            self.segments.len()
+        } else {
+            let snapshot_index = self.snapshot_index;
+            self.segments.iter().take_while(|s| s.last_index() <= snapshot_index).count()
+        };
+        self.remove_segments(0, count)
+    }
+
+    /// Opens the segment file, reading the term and offset of every entry in
+    /// it. If the segment may hold a torn write, it is truncated at the first
+    /// invalid record, or deleted and `None` returned if it was never written.
+    fn open_segment(&self, first_index: LogIndex, tearable: bool) -> Result<Option<Segment>> {
+        let path = self.segment_path(first_index);
+        let file = fs::OpenOptions::new().read(true).append(true).open(&path)?;
+        let filelen = file.metadata()?.len();
+        if filelen < SEGMENT_HEADER_LEN && tearable {
+            // Interrupted while being created.
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        let mut segment = Segment {
+            first_index: first_index,
+            path: path,
+            file: file,
             entries: Vec::new(),
-            offsets: Vec::new(),
+            len: SEGMENT_HEADER_LEN,
         };
+        {
+            let mut r = BufReader::new(&segment.file);
+            let version = r.read_u64::<BigEndian>()?;
+            if version != VERSION {
+                return Err(Error::Version(version));
+            }
+            while segment.len < filelen {
+                match read_record(&mut r, filelen - segment.len)? {
+                    Some(((term, _, _), length)) => {
+                        segment.entries.push((term, segment.len));
+                        segment.len += length;
+                    }
+                    None if tearable => {
+                        // Only the last segment is written to, and earlier ones are synced
+                        // unless the sync policy is `Never`, so a record which is incomplete or
+                        // fails its checksum there was torn by a crash. Neither it nor anything
+                        // following it can have been durably acknowledged.
+                        warn!("{}: discarding torn write of {} bytes at offset {}",
+                              segment.path.display(),
+                              filelen - segment.len,
+                              segment.len);
+                        break;
+                    }
+                    None => return Err(Error::Corrupt(segment.path.clone(), segment.len)),
+                }
+            }
+        }
+        if segment.len < filelen {
+            segment.file.set_len(segment.len)?;
+            segment.file.sync_all()?;
+        }
+        Ok(Some(segment))
+    }
 
-        let mut offset = 24; // The size of the header.
-        while offset < filelen {
-            log.offsets.push(offset);
-            let entry = log.read_entry(None)?;
-            log.entries.push(entry);
-            offset = log.reader.seek(SeekFrom::Current(0))?;
+    /// Returns the path of the segment whose first entry is at the provided
+    /// index.
+    fn segment_path(&self, first_index: LogIndex) -> path::PathBuf {
+        self.dir.join(format!("{:020}.{}", first_index.as_u64(), SEGMENT_EXTENSION))
+    }
+
+    /// Returns the position of the segment holding the entry at the provided
+    /// index, and the entry's position within the segment.
+    fn locate(&self, index: LogIndex) -> (usize, usize) {
+        let n = match self.segments.binary_search_by_key(&index, |segment| segment.first_index) {
+            Ok(n) => n,
+            Err(n) => n - 1,
+        };
+        (n, (index - self.segments[n].first_index) as usize)
+    }
+
+    /// Deletes the segments in the given range of positions.
+    fn remove_segments(&mut self, lo: usize, hi: usize) -> Result<()> {
+        if lo == hi {
+            return Ok(());
         }
-        Ok(log)
+        for segment in self.segments.drain(lo..hi) {
+            fs::remove_file(&segment.path)?;
+        }
+        sync_dir(&self.dir)
     }
 
-    fn write_term(&mut self) -> Result<()> {
-        self.writer.seek(SeekFrom::Start(8))?;
-        self.writer.write_u64::<BigEndian>(self.current_term.into())?;
-        // Set voted_for to None
-        self.writer.write_u64::<BigEndian>(<u64>::max_value())?;
-        self.writer.flush()?;
+    /// Syncs every write made so far to disk, whatever the sync policy.
+    pub fn sync(&mut self) -> Result<()> {
+        self.metadata.sync_data()?;
+        if let Some(segment) = self.segments.last() {
+            segment.file.sync_data()?;
+        }
+        self.unsynced = 0;
+        if let Some(ref flusher) = self.flusher {
+            flusher.lock().unwrap().files.clear();
+        }
         Ok(())
     }
 
-    fn write_voted_for(&mut self) -> Result<()> {
-        self.writer.seek(SeekFrom::Start(16))?;
-        self.writer.write_u64::<BigEndian>(
-            match self.voted_for {
-                None => <u64>::max_value(),
-                Some(ServerId(n)) => n,
+    /// Completes a write, syncing it if the sync policy requires, or else
+    /// leaving it to the timed sync.
+    fn finish_write(&mut self) -> Result<()> {
+        if let Some(ref flusher) = self.flusher {
+            if let Some(error) = flusher.lock().unwrap().error.take() {
+                return Err(error.into());
             }
-        )?;
-        self.writer.flush()?;
+        }
+        self.unsynced += 1;
+        let sync = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Batched(writes, _) => self.unsynced >= writes,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            self.sync()?;
+        } else if let Some(ref flusher) = self.flusher {
+            let mut files = vec![self.metadata.try_clone()?];
+            if let Some(segment) = self.segments.last() {
+                files.push(segment.file.try_clone()?);
+            }
+            flusher.lock().unwrap().files = files;
+        }
         Ok(())
     }
 
-    fn read_entry(&mut self, index: Option<usize>) -> Result<Entry> {
-        // Could be more efficient about not copying data here.
-        if let Some(index) = index {
-            let offset = self.offsets.get(index).ok_or(Error)?;
-            self.reader.seek(SeekFrom::Start(*offset))?;
-        }
-        let length = self.reader.read_u64::<BigEndian>()? as usize;
-        let term = self.reader.read_u64::<BigEndian>()?.into();
-        let mut command = vec![0u8; length - 16];
-        self.reader.read_exact(&mut command[..length - 16])?;
-        Ok((term, command))
+    /// Writes the current term and vote to the older metadata slot.
+    fn write_metadata(&mut self) -> Result<()> {
+        self.sequence += 1;
+        let slot = encode_metadata(self.sequence, self.current_term, self.voted_for);
+        self.metadata.seek(SeekFrom::Start(8 + (self.sequence % 2) * METADATA_LEN))?;
+        self.metadata.write_all(&slot)?;
+        self.finish_write()
     }
 
-    fn truncate_file(&mut self, index: usize) -> Result<()> {
-        match self.offsets.get(index) {
-            None => {},
-            Some(offset) => self.writer.get_mut().set_len(*offset)?,
-        };
-        self.reader.seek(SeekFrom::End(0))?; // Clear the buffer
-        self.writer.seek(SeekFrom::End(0))?; // Clear the buffer
+    /// Starts a new segment for the entries following the latest one. The
+    /// previous segment is synced first, since only the last segment may hold
+    /// a torn write.
+    fn start_segment(&mut self) -> Result<()> {
+        if self.sync_policy != SyncPolicy::Never {
+            self.sync()?;
+        }
+        let first_index = self.latest_log_index()? + 1;
+        let path = self.segment_path(first_index);
+        let mut file = fs::OpenOptions::new()
+                           .read(true)
+                           .append(true)
+                           .create(true)
+                           .open(&path)?;
+        file.set_len(0)?;
+        file.write_u64::<BigEndian>(VERSION)?;
+        if self.sync_policy != SyncPolicy::Never {
+            sync_dir(&self.dir)?;
+        }
+        self.segments.push(Segment {
+            first_index: first_index,
+            path: path,
+            file: file,
+            entries: Vec::new(),
+            len: SEGMENT_HEADER_LEN,
+        });
         Ok(())
     }
 
-    ///Add an entry to the log at the current location
-    fn write_entry(&mut self, index: usize, term: Term, command: &[u8]) -> Result<()> {
-        if index > self.entries.len() {
-            Err(Error)
-        } else {
-            let new_offset = self.reader.seek(SeekFrom::End(0))?;
-            self.offsets.push(new_offset);
-            let entry_len = (command.len() + 16) as u64;
-            self.writer.write_u64::<BigEndian>(entry_len)?;
-            self.writer.write_u64::<BigEndian>(term.into())?;
-            self.writer.write_all(&command[..])?;
-            Ok(())
+    /// Writes the entries to the log beginning at the given index, replacing
+    /// any entries at and following it.
+    fn rewrite_entries(&mut self,
+                       from: LogIndex,
+                       entries: &[(Term, EntryKind, &[u8])])
+                       -> Result<()> {
+        assert!(self.latest_log_index()? + 1 >= from);
+        if from <= self.latest_log_index()? {
+            self.cache.borrow_mut().truncate(from);
+            let (n, position) = self.locate(from);
+            let len = self.segments.len();
+            self.remove_segments(n + 1, len)?;
+            let segment = &mut self.segments[n];
+            segment.len = segment.entries[position].1;
+            segment.entries.truncate(position);
+            segment.file.set_len(segment.len)?;
+        }
+
+        // Records are gathered into a buffer, and written to their segment at once.
+        let mut buffer = Vec::new();
+        for (i, &(term, kind, command)) in entries.iter().enumerate() {
+            let full = self.segments.last().map_or(true, |segment| {
+                !segment.entries.is_empty() &&
+                segment.len + buffer.len() as u64 >= self.segment_bytes
+            });
+            if full {
+                self.write_buffer(&mut buffer)?;
+                self.start_segment()?;
+            }
+            {
+                let segment = self.segments.last_mut().unwrap();
+                segment.entries.push((term, segment.len + buffer.len() as u64));
+            }
+            write_record(&mut buffer, term, kind, command)?;
+            self.cache.borrow_mut().insert(from + i as u64, (term, kind, command.to_vec()));
         }
+        self.write_buffer(&mut buffer)?;
+        self.finish_write()
     }
 
-    fn rewrite_entries(&mut self, from: LogIndex, entries: &[(Term, &[u8])]) -> Result<()> {
-        assert!(self.latest_log_index()? + 1 >= from);
-        let mut index = (from - 1).as_u64() as usize;
-        self.truncate_file(index)?;
-        self.entries.truncate(index);
-        self.offsets.truncate(index);
-        self.entries.extend(entries.iter().map(|&(term, command)| (term, command.to_vec())));
-        for &(term, command) in entries {
-            self.write_entry(index, term, command)?;
-            index += 1;
-        }
-        self.writer.flush()?;
+    /// Writes the buffered records to the end of the last segment.
+    fn write_buffer(&mut self, buffer: &mut Vec<u8>) -> Result<()> {
+        if let Some(segment) = self.segments.last_mut() {
+            segment.file.write_all(buffer)?;
+            segment.len += buffer.len() as u64;
+        }
+        buffer.clear();
         Ok(())
     }
+
+    /// Replaces the snapshot file with the current snapshot. The snapshot is
+    /// written to a temporary file, which then replaces the snapshot file.
+    fn write_snapshot(&self) -> Result<()> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(&encode_snapshot_header(self.snapshot_index,
+                                                   self.snapshot_term,
+                                                   &self.snapshot))?;
+            file.write_all(&self.snapshot)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        // The rename itself is only durable once the directory is synced.
+        sync_dir(&self.dir)
+    }
+}
+
+/// Reads the snapshot file. A missing file is an empty snapshot.
+fn read_snapshot(path: &path::Path) -> Result<(LogIndex, Term, Vec<u8>)> {
+    let mut r = match fs::File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok((LogIndex(0), Term(0), Vec::new()));
+        }
+        Err(error) => return Err(error.into()),
+    };
+    let filelen = r.get_ref().metadata()?.len();
+    let mut header = [0u8; SNAPSHOT_HEADER_LEN as usize];
+    if filelen < SNAPSHOT_HEADER_LEN {
+        return Err(Error::Corrupt(path.to_path_buf(), 0));
+    }
+    r.read_exact(&mut header)?;
+    let snapshot_len = BigEndian::read_u64(&header[16..24]);
+    if snapshot_len != filelen - SNAPSHOT_HEADER_LEN {
+        return Err(Error::Corrupt(path.to_path_buf(), 0));
+    }
+    let mut snapshot = vec![0u8; snapshot_len as usize];
+    r.read_exact(&mut snapshot)?;
+    if BigEndian::read_u32(&header[24..]) != checksum(&[&header[..24], &snapshot]) {
+        return Err(Error::Corrupt(path.to_path_buf(), 0));
+    }
+    Ok((BigEndian::read_u64(&header[0..8]).into(),
+        BigEndian::read_u64(&header[8..16]).into(),
+        snapshot))
 }
 
 
@@ -200,14 +900,14 @@ impl Log for FsLog {
     fn set_current_term(&mut self, term: Term) -> Result<()> {
         self.current_term = term;
         self.voted_for = None;
-        self.write_term()?;
+        self.write_metadata()?;
         Ok(())
     }
 
     fn inc_current_term(&mut self) -> Result<Term> {
         self.current_term = self.current_term + 1;
         self.voted_for = None;
-        self.write_term()?;
+        self.write_metadata()?;
         self.current_term()
     }
 
@@ -217,95 +917,167 @@ impl Log for FsLog {
 
     fn set_voted_for(&mut self, address: ServerId) -> Result<()> {
         self.voted_for = Some(address);
-        self.write_voted_for()?;
+        self.write_metadata()?;
         Ok(())
     }
 
     fn latest_log_index(&self) -> Result<LogIndex> {
-        Ok(LogIndex(self.entries.len() as u64))
+        match self.segments.last() {
+            Some(segment) if segment.last_index() > self.snapshot_index => {
+                Ok(segment.last_index())
+            }
+            _ => Ok(self.snapshot_index),
+        }
     }
 
     fn latest_log_term(&self) -> Result<Term> {
-        let len = self.entries.len();
-        if len == 0 {
-            Ok(Term::from(0))
+        let latest_log_index = self.latest_log_index()?;
+        if latest_log_index == self.snapshot_index {
+            Ok(self.snapshot_term)
         } else {
-            Ok(self.entries[len - 1].0)
+            self.term(latest_log_index)
+        }
+    }
+
+    fn snapshot_index(&self) -> Result<LogIndex> {
+        Ok(self.snapshot_index)
+    }
+
+    fn snapshot_term(&self) -> Result<Term> {
+        Ok(self.snapshot_term)
+    }
+
+    fn snapshot(&self) -> Result<&[u8]> {
+        Ok(&self.snapshot)
+    }
+
+    fn entry(&self, index: LogIndex) -> Result<Entry> {
+        assert!(self.snapshot_index < index);
+        if let Some(entry) = self.cache.borrow_mut().get(index) {
+            return Ok(entry);
         }
+        let (n, position) = self.locate(index);
+        let entry = self.segments[n].read(position)?;
+        self.cache.borrow_mut().insert(index, entry.clone());
+        Ok(entry)
     }
 
-    fn entry(&self, index: LogIndex) -> Result<(Term, &[u8])> {
-        let (term, ref bytes) = self.entries[(index - 1).as_u64() as usize];
-        Ok((term, bytes))
+    fn term(&self, index: LogIndex) -> Result<Term> {
+        let (n, position) = self.locate(index);
+        Ok(self.segments[n].entries[position].0)
     }
 
     /// Append entries sent from the leader.
     fn append_entries(&mut self,
                       from: LogIndex,
-                      entries: &[(Term, &[u8])])
+                      entries: &[(Term, EntryKind, &[u8])])
                       -> Result<()> {
-        assert!(self.latest_log_index()? + 1 >= from);
-        let from_idx = (from - 1).as_u64() as usize;
+        let latest_log_index = self.latest_log_index()?;
+        assert!(latest_log_index + 1 >= from);
+        assert!(self.snapshot_index < from);
         for idx in 0..entries.len() {
-            match self.entries.get(from_idx + idx).map(|entry| entry.0) {
-                Some(term) => {
-                    let sent_term = entries[idx].0;
-                    if term == sent_term {
-                        continue;
-                    } else {
-                        self.rewrite_entries(from + idx as u64, &entries[idx..])?;
-                        break;
-                    }
-                },
-                None => {
-                    self.rewrite_entries(from + idx as u64, &entries[idx..])?;
-                    break;
-                }
-            };
+            let index = from + idx as u64;
+            if index <= latest_log_index && self.term(index)? == entries[idx].0 {
+                continue;
+            }
+            self.rewrite_entries(index, &entries[idx..])?;
+            break;
         }
         Ok(())
     }
+
+    fn compact(&mut self, index: LogIndex, term: Term, snapshot: &[u8]) -> Result<()> {
+        assert!(self.snapshot_index < index);
+        let retained = index <= self.latest_log_index()? && self.term(index)? == term;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot = snapshot.to_vec();
+        self.write_snapshot()?;
+
+        // Segments are only ever deleted whole, so the first retained segment may begin with
+        // compacted entries.
+        let count = if retained {
+            self.segments.iter().take_while(|segment| segment.last_index() <= index).count()
+        } else {
+            self.segments.len()
+        };
+        self.cache.borrow_mut().clear();
+        self.remove_segments(0, count)
+    }
 }
 
 
 impl Clone for FsLog {
     fn clone(&self) -> FsLog {
         // Wish I didn't have to unwrap the filehandles...
+        let segments = self.segments
+                           .iter()
+                           .map(|segment| {
+                               Segment {
+                                   first_index: segment.first_index,
+                                   path: segment.path.clone(),
+                                   file: segment.file.try_clone().expect("cloning segment.file"),
+                                   entries: segment.entries.clone(),
+                                   len: segment.len,
+                               }
+                           })
+                           .collect();
         FsLog {
-            reader: BufReader::new(self.reader.get_ref().try_clone().expect("cloning self.reader")),
-            writer: BufWriter::new(self.writer.get_ref().try_clone().expect("cloning self.writer")),
+            dir: self.dir.clone(),
+            metadata: self.metadata.try_clone().expect("cloning self.metadata"),
+            sync_policy: self.sync_policy,
+            segment_bytes: self.segment_bytes,
+            unsynced: self.unsynced,
+            flusher: Flusher::spawn(self.sync_policy),
+            sequence: self.sequence,
             current_term: self.current_term,
             voted_for: self.voted_for,
-            entries: self.entries.clone(),
-            offsets: self.offsets.clone(),
+            snapshot_index: self.snapshot_index,
+            snapshot_term: self.snapshot_term,
+            snapshot: self.snapshot.clone(),
+            segments: segments,
+            cache: RefCell::new(EntryCache::new(self.cache.borrow().capacity)),
         }
     }
 }
 
-
 #[cfg(test)]
 mod test {
-    use std::fs::remove_file;
+    use std::fs::{OpenOptions, remove_dir_all};
+    use std::io::{Seek, SeekFrom, Write};
     use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
     use super::*;
+    use EntryKind;
     use LogIndex;
     use ServerId;
     use Term;
     use persistent_log::Log;
 
+    /// Asserts that the log holds entries with the expected terms and data.
     fn assert_entries_equal(store: &FsLog, expected: Vec<(Term, &[u8])>) {
         assert_eq!(LogIndex::from(expected.len() as u64), store.latest_log_index().unwrap());
         assert_eq!(expected[expected.len() - 1].0, store.latest_log_term().unwrap());
         for i in 0..expected.len() {
-            assert_eq!(store.entry(LogIndex::from((i + 1) as u64)).unwrap(), expected[i]);
+            let (term, _, entry) = store.entry(LogIndex::from((i + 1) as u64)).unwrap();
+            assert_eq!((term, &entry[..]), expected[i]);
         }
     }
 
+    /// Returns the term and offset of every entry in each segment.
+    fn segments(store: &FsLog) -> Vec<(LogIndex, Vec<(Term, u64)>)> {
+        store.segments
+             .iter()
+             .map(|segment| (segment.first_index, segment.entries.clone()))
+             .collect()
+    }
+
     #[test]
     fn test_current_term() {
-        let filename = Path::new("/tmp/raft-store.1");
-        remove_file(&filename).unwrap_or(());
-        let mut store = FsLog::new(&filename).unwrap();
+        let dir = Path::new("/tmp/raft-store.1");
+        remove_dir_all(&dir).unwrap_or(());
+        let mut store = FsLog::new(&dir).unwrap();
         assert_eq!(Term(0), store.current_term().unwrap());
         store.set_voted_for(ServerId::from(0)).unwrap();
         store.set_current_term(Term(42)).unwrap();
@@ -313,35 +1085,35 @@ mod test {
         assert_eq!(Term(42), store.current_term().unwrap());
         store.inc_current_term().unwrap();
         assert_eq!(Term(43), store.current_term().unwrap());
-        remove_file(&filename).unwrap();
+        remove_dir_all(&dir).unwrap();
     }
 
     #[test]
     fn test_voted_for() {
-        let filename = Path::new("/tmp/raft-store.2");
-        remove_file(&filename).unwrap_or(());
-        let mut store = FsLog::new(&filename).unwrap();
+        let dir = Path::new("/tmp/raft-store.2");
+        remove_dir_all(&dir).unwrap_or(());
+        let mut store = FsLog::new(&dir).unwrap();
         assert_eq!(None, store.voted_for().unwrap());
         let id = ServerId::from(0);
         store.set_voted_for(id).unwrap();
         assert_eq!(Some(id), store.voted_for().unwrap());
-        remove_file(&filename).unwrap();
+        remove_dir_all(&dir).unwrap();
     }
 
     #[test]
     fn test_append_entries() {
-        let filename = Path::new("/tmp/raft-store.3");
-        remove_file(&filename).unwrap_or(());
-        let mut store = FsLog::new(&filename).unwrap();
+        let dir = Path::new("/tmp/raft-store.3");
+        remove_dir_all(&dir).unwrap_or(());
+        let mut store = FsLog::new(&dir).unwrap();
         assert_eq!(LogIndex::from(0), store.latest_log_index().unwrap());
         assert_eq!(Term::from(0), store.latest_log_term().unwrap());
 
         // [0.1, 0.2, 0.3, 1.4]  Initial log
         store.append_entries(LogIndex(1),
-                             &[(Term::from(0), &[1]),
-                               (Term::from(0), &[2]),
-                               (Term::from(0), &[3]),
-                               (Term::from(1), &[4])])
+                             &[(Term::from(0), EntryKind::Normal, &[1]),
+                               (Term::from(0), EntryKind::Normal, &[2]),
+                               (Term::from(0), EntryKind::Normal, &[3]),
+                               (Term::from(1), EntryKind::Normal, &[4])])
              .unwrap();
         assert_entries_equal(&store, vec![(Term::from(0), &*vec![1]),
                                           (Term::from(0), &*vec![2]),
@@ -357,8 +1129,8 @@ mod test {
 
         // [0.1, 0.2, 0.3, 1.4]  All match, non-exhaustive
         store.append_entries(LogIndex::from(2),
-                             &[(Term::from(0), &[2]),
-                               (Term::from(0), &[3])])
+                             &[(Term::from(0), EntryKind::Normal, &[2]),
+                               (Term::from(0), EntryKind::Normal, &[3])])
              .unwrap();
         assert_entries_equal(&store, vec![(Term::from(0), &[1u8]),
                                          (Term::from(0), &[2u8]),
@@ -367,9 +1139,9 @@ mod test {
 
         // [0.1, 0.2, 2.5, 2.6]  One match, two new
         store.append_entries(LogIndex::from(2),
-                             &[(Term::from(0), &[2]),
-                               (Term::from(2), &[5]),
-                               (Term::from(2), &[6])])
+                             &[(Term::from(0), EntryKind::Normal, &[2]),
+                               (Term::from(2), EntryKind::Normal, &[5]),
+                               (Term::from(2), EntryKind::Normal, &[6])])
              .unwrap();
         assert_entries_equal(&store, vec![(Term::from(0), &*vec![1]),
                                           (Term::from(0), &*vec![2u8]),
@@ -377,39 +1149,282 @@ mod test {
                                           (Term::from(2), &*vec![6u8])]);
 
         // [0.1, 0.2, 4.7, 5.8]  All new entries
-        store.append_entries(LogIndex::from(3), &[(Term(4), &[7]), (Term(5), &[8])]).unwrap();
+        store.append_entries(LogIndex::from(3),
+                             &[(Term(4), EntryKind::Normal, &[7]),
+                               (Term(5), EntryKind::Normal, &[8])])
+             .unwrap();
         assert_entries_equal(&store, vec![(Term::from(0), &*vec![1]),
                                           (Term::from(0), &*vec![2]),
                                           (Term::from(4), &*vec![7]),
                                           (Term::from(5), &*vec![8])]);
-        remove_file(&filename).unwrap();
+        remove_dir_all(&dir).unwrap();
     }
 
     #[test]
     fn test_restore_log() {
-        let filename = Path::new("/tmp/raft-store.4");
-        remove_file(&filename).unwrap_or(());
+        let dir = Path::new("/tmp/raft-store.4");
+        remove_dir_all(&dir).unwrap_or(());
         {
-            let mut store = FsLog::new(&filename).unwrap();
+            let mut store = FsLog::new(&dir).unwrap();
             store.set_current_term(Term(42)).unwrap();
             store.set_voted_for(ServerId::from(4)).unwrap();
             store.append_entries(LogIndex(1),
-                                &[(Term::from(0), &[1]),
-                                (Term::from(0), &[2]),
-                                (Term::from(0), &[3]),
-                                (Term::from(1), &[4])])
+                                &[(Term::from(0), EntryKind::Normal, &[1]),
+                                (Term::from(0), EntryKind::Normal, &[2]),
+                                (Term::from(0), EntryKind::Normal, &[3]),
+                                (Term::from(1), EntryKind::Configuration, &[4])])
                 .unwrap();
         }
 
-        // New store with the same backing file starts with the same state.
-        let store = FsLog::new(&filename).unwrap();
+        // New store with the same backing directory starts with the same state.
+        let store = FsLog::new(&dir).unwrap();
         assert_eq!(store.voted_for().unwrap(), Some(ServerId::from(4)));
         assert_eq!(store.current_term().unwrap(), Term(42));
         assert_entries_equal(&store, vec![(Term::from(0), &[1]),
                                           (Term::from(0), &[2]),
                                           (Term::from(0), &[3]),
                                           (Term::from(1), &[4])]);
-        assert_eq!(store.offsets, [24, 41, 58, 75]);
-        remove_file(&filename).unwrap();
+        assert_eq!(EntryKind::Configuration, store.entry(LogIndex(4)).unwrap().1);
+        let offsets = vec![(Term(0), 8), (Term(0), 30), (Term(0), 52), (Term(1), 74)];
+        assert_eq!(segments(&store), [(LogIndex(1), offsets)]);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = Path::new("/tmp/raft-store.5");
+        remove_dir_all(&dir).unwrap_or(());
+        {
+            let mut store = FsLog::new(&dir).unwrap();
+            store.set_current_term(Term(42)).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term::from(0), EntryKind::Normal, &[1]),
+                                (Term::from(0), EntryKind::Normal, &[2]),
+                                (Term::from(1), EntryKind::Normal, &[3]),
+                                (Term::from(1), EntryKind::Normal, &[4])])
+                .unwrap();
+            store.compact(LogIndex(2), Term(0), &[7, 7]).unwrap();
+            assert_eq!(LogIndex(3), store.first_log_index().unwrap());
+            assert_eq!((Term::from(1), EntryKind::Normal, vec![3u8]),
+                       store.entry(LogIndex(3)).unwrap());
+
+            // Appending after compaction continues the retained entries.
+            store.append_entries(LogIndex(5), &[(Term::from(2), EntryKind::Normal, &[5])]).unwrap();
+        }
+
+        // The snapshot and the retained entries survive a restart.
+        let mut store = FsLog::new(&dir).unwrap();
+        assert_eq!(store.current_term().unwrap(), Term(42));
+        assert_eq!(LogIndex(2), store.snapshot_index().unwrap());
+        assert_eq!(Term(0), store.snapshot_term().unwrap());
+        assert_eq!(&[7u8, 7], store.snapshot().unwrap());
+        assert_eq!(LogIndex(5), store.latest_log_index().unwrap());
+        assert_eq!((Term::from(1), EntryKind::Normal, vec![3u8]),
+                   store.entry(LogIndex(3)).unwrap());
+        assert_eq!((Term::from(1), EntryKind::Normal, vec![4u8]),
+                   store.entry(LogIndex(4)).unwrap());
+        assert_eq!((Term::from(2), EntryKind::Normal, vec![5u8]),
+                   store.entry(LogIndex(5)).unwrap());
+
+        // A snapshot which conflicts with the log supersedes it.
+        store.compact(LogIndex(4), Term(3), &[8]).unwrap();
+        assert_eq!(LogIndex(4), store.latest_log_index().unwrap());
+        assert_eq!(Term(3), store.latest_log_term().unwrap());
+        assert!(segments(&store).is_empty());
+        store.append_entries(LogIndex(5), &[(Term::from(3), EntryKind::Normal, &[6])]).unwrap();
+        assert_eq!((Term::from(3), EntryKind::Normal, vec![6u8]),
+                   store.entry(LogIndex(5)).unwrap());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = Path::new("/tmp/raft-store.8");
+        remove_dir_all(&dir).unwrap_or(());
+        let entries: Vec<(Term, EntryKind, &[u8])> =
+            (0..10).map(|_| (Term(1), EntryKind::Normal, &b"abc"[..])).collect();
+        {
+            // Each record is 24 bytes, so a segment holds four entries.
+            let mut store = FsLog::builder(&dir).with_segment_bytes(100).finalize().unwrap();
+            store.append_entries(LogIndex(1), &entries).unwrap();
+            let first_indexes: Vec<LogIndex> =
+                segments(&store).into_iter().map(|(index, _)| index).collect();
+            assert_eq!(first_indexes, [LogIndex(1), LogIndex(5), LogIndex(9)]);
+        }
+
+        let mut store = FsLog::builder(&dir).with_segment_bytes(100).finalize().unwrap();
+        assert_eq!(LogIndex(10), store.latest_log_index().unwrap());
+        assert_eq!((Term(1), EntryKind::Normal, b"abc".to_vec()),
+                   store.entry(LogIndex(6)).unwrap());
+
+        // A conflicting entry removes the segments following it.
+        store.append_entries(LogIndex(3), &[(Term(2), EntryKind::Normal, b"d")]).unwrap();
+        assert_eq!(LogIndex(3), store.latest_log_index().unwrap());
+        assert_eq!(1, segments(&store).len());
+        assert!(!dir.join("00000000000000000005.log").exists());
+
+        // Compaction deletes the segments whose entries all precede the snapshot.
+        store.append_entries(LogIndex(4), &entries[..6]).unwrap();
+        assert_eq!(3, segments(&store).len());
+        store.compact(LogIndex(6), Term(1), b"snapshot").unwrap();
+        assert!(!dir.join("00000000000000000001.log").exists());
+        assert_eq!(LogIndex(9), store.latest_log_index().unwrap());
+        drop(store);
+
+        let store = FsLog::builder(&dir).with_segment_bytes(100).finalize().unwrap();
+        assert_eq!(LogIndex(7), store.first_log_index().unwrap());
+        assert_eq!((Term(1), EntryKind::Normal, b"abc".to_vec()),
+                   store.entry(LogIndex(7)).unwrap());
+        assert_eq!(LogIndex(9), store.latest_log_index().unwrap());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = Path::new("/tmp/raft-store.9");
+        remove_dir_all(&dir).unwrap_or(());
+        let mut store = FsLog::builder(&dir).with_cache_entries(2).finalize().unwrap();
+        store.append_entries(LogIndex(1),
+                             &[(Term(1), EntryKind::Normal, &[1]),
+                               (Term(1), EntryKind::Normal, &[2]),
+                               (Term(1), EntryKind::Normal, &[3])])
+             .unwrap();
+        assert_eq!(2, store.cache.borrow().entries.len());
+        assert!(store.cache.borrow().entries.contains_key(&LogIndex(3)));
+
+        // An evicted entry is read back from its segment, evicting the least recently used one.
+        store.entry(LogIndex(3)).unwrap();
+        assert_eq!((Term(1), EntryKind::Normal, vec![1]), store.entry(LogIndex(1)).unwrap());
+        assert!(store.cache.borrow().entries.contains_key(&LogIndex(1)));
+        assert!(!store.cache.borrow().entries.contains_key(&LogIndex(2)));
+
+        // Rewritten entries are evicted along with the old ones.
+        store.append_entries(LogIndex(3), &[(Term(2), EntryKind::Normal, &[4])]).unwrap();
+        assert_eq!((Term(2), EntryKind::Normal, vec![4]), store.entry(LogIndex(3)).unwrap());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let dir = Path::new("/tmp/raft-store.6");
+        remove_dir_all(&dir).unwrap_or(());
+        {
+            let mut store = FsLog::builder(&dir)
+                                .with_sync_policy(SyncPolicy::Never)
+                                .finalize()
+                                .unwrap();
+            store.set_current_term(Term(7)).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term::from(7), EntryKind::Normal, &[1]),
+                                (Term::from(7), EntryKind::Normal, &[2]),
+                                (Term::from(7), EntryKind::Normal, &[3])])
+                .unwrap();
+            store.sync().unwrap();
+        }
+
+        // Tear the last record, as a crash in the middle of writing it would.
+        let segment = dir.join("00000000000000000001.log");
+        {
+            let file = OpenOptions::new().write(true).open(&segment).unwrap();
+            let len = file.metadata().unwrap().len();
+            file.set_len(len - 1).unwrap();
+        }
+        let mut store = FsLog::new(&dir).unwrap();
+        assert_eq!(Term(7), store.current_term().unwrap());
+        assert_entries_equal(&store, vec![(Term::from(7), &[1]), (Term::from(7), &[2])]);
+
+        // The log carries on from the last intact record.
+        store.append_entries(LogIndex(3), &[(Term::from(8), EntryKind::Normal, &[4])]).unwrap();
+        drop(store);
+
+        // A record which fails its checksum is discarded too.
+        {
+            let mut file = OpenOptions::new().write(true).open(&segment).unwrap();
+            file.seek(SeekFrom::End(-1)).unwrap();
+            file.write_all(&[5]).unwrap();
+        }
+        let store = FsLog::new(&dir).unwrap();
+        assert_entries_equal(&store, vec![(Term::from(7), &[1]), (Term::from(7), &[2])]);
+        drop(store);
+
+        // Outside the last segment, a bad record cannot be a torn write.
+        {
+            let mut store = FsLog::builder(&dir).with_segment_bytes(40).finalize().unwrap();
+            store.append_entries(LogIndex(3),
+                                 &[(Term::from(8), EntryKind::Normal, &[3]),
+                                   (Term::from(8), EntryKind::Normal, &[4])])
+                 .unwrap();
+            let mut file = OpenOptions::new().write(true).open(&segment).unwrap();
+            file.seek(SeekFrom::End(-1)).unwrap();
+            file.write_all(&[5]).unwrap();
+        }
+        match FsLog::new(&dir) {
+            Err(Error::Corrupt(ref path, 30)) if *path == segment => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Unless earlier segments are not synced either; the log is then truncated at the bad
+        // record, and the segments following it are deleted.
+        let store = FsLog::builder(&dir).with_sync_policy(SyncPolicy::Never).finalize().unwrap();
+        assert_entries_equal(&store, vec![(Term::from(7), &[1])]);
+        assert!(!dir.join("00000000000000000003.log").exists());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a log in the old single-file format is refused.
+    #[test]
+    fn test_single_file() {
+        let path = Path::new("/tmp/raft-store.11");
+        ::std::fs::remove_file(&path).unwrap_or(());
+        {
+            let mut file = OpenOptions::new().write(true).create(true).open(&path).unwrap();
+            file.write_all(&[0, 0, 0, 0, 0, 0, 0, 3]).unwrap();
+        }
+        match FsLog::new(&path) {
+            Err(Error::SingleFile(ref file, 3)) if *file == *path => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that writes under a batched sync policy are synced once the interval passes, even
+    /// if the batch is not full.
+    #[test]
+    fn test_batched_sync() {
+        let dir = Path::new("/tmp/raft-store.10");
+        remove_dir_all(&dir).unwrap_or(());
+        let mut store = FsLog::builder(&dir)
+                            .with_sync_policy(SyncPolicy::Batched(100, 10))
+                            .finalize()
+                            .unwrap();
+        store.set_current_term(Term(1)).unwrap();
+        assert_eq!(1, store.unsynced);
+        assert_eq!(1, store.flusher.as_ref().unwrap().lock().unwrap().files.len());
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(store.flusher.as_ref().unwrap().lock().unwrap().files.is_empty());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_metadata() {
+        let dir = Path::new("/tmp/raft-store.7");
+        remove_dir_all(&dir).unwrap_or(());
+        {
+            let mut store = FsLog::new(&dir).unwrap();
+            store.set_current_term(Term(1)).unwrap();
+            store.set_voted_for(ServerId::from(3)).unwrap();
+        }
+
+        // Tear the latest metadata update; the previous one is still intact.
+        {
+            let mut file = OpenOptions::new().write(true).open(dir.join("meta")).unwrap();
+            file.seek(SeekFrom::Start(8 + 16)).unwrap();
+            file.write_all(&[0xff; 4]).unwrap();
+        }
+        let store = FsLog::new(&dir).unwrap();
+        assert_eq!(Term(1), store.current_term().unwrap());
+        assert_eq!(None, store.voted_for().unwrap());
+        remove_dir_all(&dir).unwrap();
     }
 }
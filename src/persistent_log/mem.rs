@@ -1,6 +1,7 @@
 use std::{error, fmt, result};
 
 use persistent_log::Log;
+use EntryKind;
 use LogIndex;
 use ServerId;
 use Term;
@@ -16,7 +17,10 @@ use Term;
 pub struct MemLog {
     current_term: Term,
     voted_for: Option<ServerId>,
-    entries: Vec<(Term, Vec<u8>)>,
+    snapshot_index: LogIndex,
+    snapshot_term: Term,
+    snapshot: Vec<u8>,
+    entries: Vec<(Term, EntryKind, Vec<u8>)>,
 }
 
 /// Non-instantiable error type for MemLog
@@ -45,6 +49,9 @@ impl MemLog {
         MemLog {
             current_term: Term(0),
             voted_for: None,
+            snapshot_index: LogIndex(0),
+            snapshot_term: Term(0),
+            snapshot: Vec::new(),
             entries: Vec::new(),
         }
     }
@@ -77,30 +84,68 @@ impl Log for MemLog {
     }
 
     fn latest_log_index(&self) -> result::Result<LogIndex, Error> {
-        Ok(LogIndex(self.entries.len() as u64))
+        Ok(self.snapshot_index + self.entries.len() as u64)
     }
 
     fn latest_log_term(&self) -> result::Result<Term, Error> {
         let len = self.entries.len();
         if len == 0 {
-            Ok(Term::from(0))
+            Ok(self.snapshot_term)
         } else {
             Ok(self.entries[len - 1].0)
         }
     }
 
-    fn entry(&self, index: LogIndex) -> result::Result<(Term, &[u8]), Error> {
-        let (term, ref bytes) = self.entries[(index - 1).as_u64() as usize];
-        Ok((term, bytes))
+    fn snapshot_index(&self) -> result::Result<LogIndex, Error> {
+        Ok(self.snapshot_index)
+    }
+
+    fn snapshot_term(&self) -> result::Result<Term, Error> {
+        Ok(self.snapshot_term)
+    }
+
+    fn snapshot(&self) -> result::Result<&[u8], Error> {
+        Ok(&self.snapshot)
+    }
+
+    fn entry(&self, index: LogIndex) -> result::Result<(Term, EntryKind, Vec<u8>), Error> {
+        let (term, kind, ref bytes) = self.entries[(index - self.snapshot_index - 1) as usize];
+        Ok((term, kind, bytes.clone()))
+    }
+
+    fn term(&self, index: LogIndex) -> result::Result<Term, Error> {
+        Ok(self.entries[(index - self.snapshot_index - 1) as usize].0)
     }
 
     fn append_entries(&mut self,
                       from: LogIndex,
-                      entries: &[(Term, &[u8])])
+                      entries: &[(Term, EntryKind, &[u8])])
                       -> result::Result<(), Error> {
         assert!(self.latest_log_index().unwrap() + 1 >= from);
-        self.entries.truncate((from - 1).as_u64() as usize);
-        Ok(self.entries.extend(entries.iter().map(|&(term, command)| (term, command.to_vec()))))
+        assert!(self.snapshot_index < from);
+        self.entries.truncate((from - self.snapshot_index - 1) as usize);
+        Ok(self.entries.extend(entries.iter().map(|&(term, kind, command)| {
+            (term, kind, command.to_vec())
+        })))
+    }
+
+    fn compact(&mut self,
+               index: LogIndex,
+               term: Term,
+               snapshot: &[u8])
+               -> result::Result<(), Error> {
+        assert!(self.snapshot_index < index);
+        let retained = if index <= self.latest_log_index().unwrap() &&
+                          self.term(index).unwrap() == term {
+            self.entries.split_off((index - self.snapshot_index) as usize)
+        } else {
+            Vec::new()
+        };
+        self.entries = retained;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot = snapshot.to_vec();
+        Ok(())
     }
 }
 
@@ -108,6 +153,7 @@ impl Log for MemLog {
 mod test {
 
     use super::*;
+    use EntryKind;
     use LogIndex;
     use ServerId;
     use Term;
@@ -142,45 +188,84 @@ mod test {
 
         // [0.1, 0.2, 0.3, 1.4]
         store.append_entries(LogIndex(1),
-                             &[(Term::from(0), &[1]),
-                               (Term::from(0), &[2]),
-                               (Term::from(0), &[3]),
-                               (Term::from(1), &[4])])
+                             &[(Term::from(0), EntryKind::Normal, &[1]),
+                               (Term::from(0), EntryKind::Normal, &[2]),
+                               (Term::from(0), EntryKind::Normal, &[3]),
+                               (Term::from(1), EntryKind::Normal, &[4])])
              .unwrap();
         assert_eq!(LogIndex::from(4), store.latest_log_index().unwrap());
         assert_eq!(Term::from(1), store.latest_log_term().unwrap());
-        assert_eq!((Term::from(0), &*vec![1u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![1u8]),
                    store.entry(LogIndex::from(1)).unwrap());
-        assert_eq!((Term::from(0), &*vec![2u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![2u8]),
                    store.entry(LogIndex::from(2)).unwrap());
-        assert_eq!((Term::from(0), &*vec![3u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![3u8]),
                    store.entry(LogIndex::from(3)).unwrap());
-        assert_eq!((Term::from(1), &*vec![4u8]),
+        assert_eq!((Term::from(1), EntryKind::Normal, vec![4u8]),
                    store.entry(LogIndex::from(4)).unwrap());
 
         // [0.1, 0.2, 0.3]
         store.append_entries(LogIndex::from(4), &[]).unwrap();
         assert_eq!(LogIndex(3), store.latest_log_index().unwrap());
         assert_eq!(Term::from(0), store.latest_log_term().unwrap());
-        assert_eq!((Term::from(0), &*vec![1u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![1u8]),
                    store.entry(LogIndex::from(1)).unwrap());
-        assert_eq!((Term::from(0), &*vec![2u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![2u8]),
                    store.entry(LogIndex::from(2)).unwrap());
-        assert_eq!((Term::from(0), &*vec![3u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![3u8]),
                    store.entry(LogIndex::from(3)).unwrap());
 
         // [0.1, 0.2, 2.3, 3.4]
-        store.append_entries(LogIndex::from(3), &[(Term(2), &[3]), (Term(3), &[4])]).unwrap();
+        store.append_entries(LogIndex::from(3),
+                             &[(Term(2), EntryKind::Configuration, &[3]),
+                               (Term(3), EntryKind::Normal, &[4])])
+             .unwrap();
         assert_eq!(LogIndex(4), store.latest_log_index().unwrap());
         assert_eq!(Term::from(3), store.latest_log_term().unwrap());
-        assert_eq!((Term::from(0), &*vec![1u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![1u8]),
                    store.entry(LogIndex::from(1)).unwrap());
-        assert_eq!((Term::from(0), &*vec![2u8]),
+        assert_eq!((Term::from(0), EntryKind::Normal, vec![2u8]),
                    store.entry(LogIndex::from(2)).unwrap());
-        assert_eq!((Term::from(2), &*vec![3u8]),
+        assert_eq!((Term::from(2), EntryKind::Configuration, vec![3u8]),
+                   store.entry(LogIndex::from(3)).unwrap());
+        assert_eq!((Term::from(3), EntryKind::Normal, vec![4u8]),
+                   store.entry(LogIndex::from(4)).unwrap());
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut store = MemLog::new();
+        assert_eq!(LogIndex::from(1), store.first_log_index().unwrap());
+
+        // [0.1, 0.2, 1.3, 1.4]
+        store.append_entries(LogIndex(1),
+                             &[(Term::from(0), EntryKind::Normal, &[1]),
+                               (Term::from(0), EntryKind::Normal, &[2]),
+                               (Term::from(1), EntryKind::Normal, &[3]),
+                               (Term::from(1), EntryKind::Normal, &[4])])
+             .unwrap();
+
+        // (snapshot 0.2) [1.3, 1.4]
+        store.compact(LogIndex(2), Term(0), &[42]).unwrap();
+        assert_eq!(LogIndex::from(2), store.snapshot_index().unwrap());
+        assert_eq!(Term::from(0), store.snapshot_term().unwrap());
+        assert_eq!(&[42u8], store.snapshot().unwrap());
+        assert_eq!(LogIndex::from(3), store.first_log_index().unwrap());
+        assert_eq!(LogIndex::from(4), store.latest_log_index().unwrap());
+        assert_eq!(Term::from(1), store.latest_log_term().unwrap());
+        assert_eq!((Term::from(1), EntryKind::Normal, vec![3u8]),
                    store.entry(LogIndex::from(3)).unwrap());
-        assert_eq!((Term::from(3), &*vec![4u8]),
+
+        // (snapshot 0.2) [1.3, 2.5]
+        store.append_entries(LogIndex(4), &[(Term::from(2), EntryKind::Normal, &[5])]).unwrap();
+        assert_eq!((Term::from(2), EntryKind::Normal, vec![5u8]),
                    store.entry(LogIndex::from(4)).unwrap());
+
+        // (snapshot 3.4) [], a conflicting snapshot discards the whole log.
+        store.compact(LogIndex(4), Term(3), &[43]).unwrap();
+        assert_eq!(LogIndex::from(4), store.latest_log_index().unwrap());
+        assert_eq!(Term::from(3), store.latest_log_term().unwrap());
+        assert_eq!(LogIndex::from(5), store.first_log_index().unwrap());
     }
 }
 
@@ -13,9 +13,10 @@ use std::error;
 use std::fmt::Debug;
 use std::result;
 
-pub use persistent_log::fs::FsLog;
+pub use persistent_log::fs::{FsLog, FsLogBuilder, SyncPolicy, Error as FsLogError};
 pub use persistent_log::mem::{MemLog, Error};
 
+use EntryKind;
 use LogIndex;
 use Term;
 use ServerId;
@@ -40,20 +41,48 @@ pub trait Log: Clone + Debug + Send + 'static {
     /// Sets the candidate id voted for in the current term.
     fn set_voted_for(&mut self, server: ServerId) -> result::Result<(), Self::Error>;
 
-    /// Returns the index of the latest persisted log entry (0 if the log is empty).
+    /// Returns the index of the latest persisted log entry (0 if the log is empty). If every
+    /// entry has been compacted into the snapshot, this is the snapshot index.
     fn latest_log_index(&self) -> result::Result<LogIndex, Self::Error>;
 
-    /// Returns the term of the latest persisted log entry (0 if the log is empty).
+    /// Returns the term of the latest persisted log entry (0 if the log is empty). If every
+    /// entry has been compacted into the snapshot, this is the snapshot term.
     fn latest_log_term(&self) -> result::Result<Term, Self::Error>;
 
-    /// Returns the entry at the provided log index.
-    fn entry(&self, index: LogIndex) -> result::Result<(Term, &[u8]), Self::Error>;
+    /// Returns the index of the last entry covered by the latest snapshot (0 if the log has
+    /// never been compacted).
+    fn snapshot_index(&self) -> result::Result<LogIndex, Self::Error>;
+
+    /// Returns the term of the last entry covered by the latest snapshot (0 if the log has
+    /// never been compacted).
+    fn snapshot_term(&self) -> result::Result<Term, Self::Error>;
+
+    /// Returns the latest snapshot of the state machine (empty if the log has never been
+    /// compacted).
+    fn snapshot(&self) -> result::Result<&[u8], Self::Error>;
+
+    /// Returns the index of the first entry still held in the log.
+    fn first_log_index(&self) -> result::Result<LogIndex, Self::Error> {
+        self.snapshot_index().map(|index| index + 1)
+    }
+
+    /// Returns the term, kind and data of the entry at the provided log index. The index must lie
+    /// between `first_log_index` and `latest_log_index`, inclusive. The entry is copied, since
+    /// the log need not hold it in memory.
+    fn entry(&self, index: LogIndex)
+             -> result::Result<(Term, EntryKind, Vec<u8>), Self::Error>;
+
+    /// Returns the term of the entry at the provided log index. The index must lie between
+    /// `first_log_index` and `latest_log_index`, inclusive.
+    fn term(&self, index: LogIndex) -> result::Result<Term, Self::Error> {
+        self.entry(index).map(|(term, _, _)| term)
+    }
 
     /// Returns the given range of entries (excluding the right endpoint).
     fn entries(&self,
                lo: LogIndex,
                hi: LogIndex)
-               -> result::Result<Vec<(Term, &[u8])>, Self::Error> {
+               -> result::Result<Vec<(Term, EntryKind, Vec<u8>)>, Self::Error> {
         // TODO: can make LogIndex compatible for use in ranges.
         (lo.as_u64()..hi.as_u64())
             .map(|index| self.entry(LogIndex::from(index)))
@@ -64,6 +93,17 @@ pub trait Log: Clone + Debug + Send + 'static {
     /// Appends the provided entries to the log beginning at the given index.
     fn append_entries(&mut self,
                       from: LogIndex,
-                      entries: &[(Term, &[u8])])
+                      entries: &[(Term, EntryKind, &[u8])])
                       -> result::Result<(), Self::Error>;
+
+    /// Replaces the log prefix ending at `index` with the provided state machine snapshot. The
+    /// index must be greater than the current snapshot index.
+    ///
+    /// If the log holds an entry at `index` with the same `term`, the entries following it are
+    /// retained. Otherwise the snapshot supersedes the whole log and every entry is discarded.
+    fn compact(&mut self,
+               index: LogIndex,
+               term: Term,
+               snapshot: &[u8])
+               -> result::Result<(), Self::Error>;
 }
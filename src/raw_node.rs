@@ -0,0 +1,1018 @@
+//! A sans-IO interface to the Raft consensus algorithm.
+//!
+//! `Server` runs Raft over its own TCP connections and `mio` event loop. A `RawNode` is the same
+//! consensus core without any of that: the application delivers peer messages and client
+//! requests with `step` and `step_client`, advances time with `tick`, and carries out the
+//! `Actions` returned by each call, for instance by sending the messages over its own network
+//! stack. Nothing happens between calls, so a cluster of `RawNode`s can just as well be driven
+//! deterministically from a test.
+//!
+//! Messages are plain Rust values; `RawNode` takes care of the wire format used by `Server`
+//! internally.
+//!
+//! The node runs on its own clock, which only moves when `tick` is called: the election and
+//! heartbeat timeouts, the check-quorum period and leader leases are all measured against it.
+//! The election timeouts are drawn from a generator which can be seeded with
+//! `RawNodeBuilder::with_rng_seed`, so a seeded cluster behaves the same on every run.
+//!
+//! `step` and `step_client` encode each message in the wire format before handing it to the
+//! consensus module, and the messages it produces are decoded again into Rust values. This
+//! costs an extra copy of every message, entries included, over handing the wire format to
+//! `Server`.
+
+use std::collections::HashMap;
+use std::error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use capnp::message::{Allocator, Builder, HeapAllocator, Reader, ReaderOptions, ReaderSegments};
+use capnp::serialize::{self, OwnedSegments};
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+
+use {ClientId, Consistency, EntryKind, Error, LogIndex, RaftError, Result, ServerId, Term,
+     messages};
+use consensus::{self, Consensus, ConsensusTimeout, TimeoutConfiguration, decode_entries};
+use messages_capnp::{append_entries_response, client_request, client_response,
+                     command_response, install_snapshot_response, message, read_index_response,
+                     request_vote_response};
+use persistent_log::Log;
+use state_machine::StateMachine;
+
+pub use consensus::{ConsensusConfiguration, RequestId};
+
+/// A log entry, as carried by an `AppendEntriesRequest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The term in which the entry was created.
+    pub term: Term,
+    /// How the entry is applied once committed.
+    pub kind: EntryKind,
+    /// The entry's data.
+    pub data: Vec<u8>,
+}
+
+/// The outcome of an `AppendEntriesRequest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AppendEntriesResult {
+    /// The entries were appended. Includes the follower's latest log index.
+    Success(LogIndex),
+    /// The follower has a greater term than the leader.
+    StaleTerm,
+    /// The follower's log does not hold the entry preceding the new ones.
+    InconsistentPrevEntry {
+        /// The index of the inconsistent entry.
+        index: LogIndex,
+        /// The term of the follower's entry at `index`, or 0 if its log does not reach it.
+        conflict_term: Term,
+        /// The index of the follower's first entry in `conflict_term`, or the index following
+        /// its latest entry if its log is too short.
+        conflict_index: LogIndex,
+    },
+    /// The follower failed; a description is included.
+    InternalError(String),
+}
+
+/// The outcome of a `RequestVoteRequest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VoteResult {
+    /// The voter voted for the candidate.
+    Granted,
+    /// The voter has a greater term than the candidate.
+    StaleTerm,
+    /// The voter already voted for another candidate in the term.
+    AlreadyVoted,
+    /// The candidate's log is not as up to date as the voter's.
+    InconsistentLog,
+    /// The voter failed; a description is included.
+    InternalError(String),
+}
+
+/// The outcome of an `InstallSnapshotRequest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InstallSnapshotResult {
+    /// The chunk was stored. Includes the offset of the next chunk expected.
+    Success(u64),
+    /// The snapshot was installed, or the follower already holds every entry it covers.
+    Installed,
+    /// The follower has a greater term than the leader.
+    StaleTerm,
+    /// The follower failed; a description is included.
+    InternalError(String),
+}
+
+/// A message exchanged between Raft peers.
+///
+/// The responses to failed requests carry no `read_sequence`, and it is 0 once decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Sent by the leader to replicate entries, or with none as a heartbeat.
+    AppendEntriesRequest {
+        term: Term,
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<Entry>,
+        leader_commit: LogIndex,
+        read_sequence: u64,
+    },
+    AppendEntriesResponse {
+        term: Term,
+        result: AppendEntriesResult,
+        read_sequence: u64,
+    },
+    /// Sent by a candidate to request a vote.
+    RequestVoteRequest {
+        term: Term,
+        last_log_index: LogIndex,
+        last_log_term: Term,
+        leadership_transfer: bool,
+    },
+    RequestVoteResponse { term: Term, result: VoteResult },
+    /// Sent before an election, to find out whether a majority would grant a vote.
+    PreVoteRequest {
+        term: Term,
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    },
+    PreVoteResponse { term: Term, granted: bool },
+    /// Sent by the leader to a follower which has fallen behind the compacted log, carrying a
+    /// chunk of the snapshot.
+    InstallSnapshotRequest {
+        term: Term,
+        last_included_index: LogIndex,
+        last_included_term: Term,
+        offset: u64,
+        data: Vec<u8>,
+        done: bool,
+    },
+    InstallSnapshotResponse {
+        term: Term,
+        last_included_index: LogIndex,
+        result: InstallSnapshotResult,
+    },
+    /// Sent by a follower to fetch the leader's commit index for a bounded-staleness query.
+    ReadIndexRequest { term: Term, read_sequence: u64 },
+    /// Carries the leader's commit index, or `None` if the responder is not the leader.
+    ReadIndexResponse {
+        term: Term,
+        read_sequence: u64,
+        read_index: Option<LogIndex>,
+    },
+    /// Sent by the leader to the target of a leadership transfer, to start an election.
+    TimeoutNow { term: Term },
+}
+
+/// A request from a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientRequest {
+    /// Proposes an entry, which is applied to the state machine once committed.
+    Proposal(Vec<u8>),
+    /// Proposes an entry on behalf of a client with a session, so that it is applied only once
+    /// however often it is retried.
+    SessionProposal {
+        entry: Vec<u8>,
+        client: ClientId,
+        sequence: u64,
+        acknowledged: u64,
+    },
+    /// Queries the state machine.
+    Query(Vec<u8>, Consistency),
+    /// Adds a server to the cluster, optionally as a non-voting learner.
+    AddServer {
+        id: ServerId,
+        addr: SocketAddr,
+        learner: bool,
+    },
+    /// Removes a server from the cluster.
+    RemoveServer(ServerId),
+    /// Promotes a learner to a voting member.
+    PromoteServer(ServerId),
+    /// Asks how far a learner has caught up.
+    LearnerStatus(ServerId),
+    /// Transfers leadership to a voting member.
+    TransferLeadership(ServerId),
+}
+
+/// The response to a client request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientResponse {
+    /// The request succeeded, with the given result.
+    Success(Vec<u8>),
+    /// The node is not the leader, and does not know which server is.
+    UnknownLeader,
+    /// The node is not the leader; the request should be sent to the given address.
+    NotLeader(SocketAddr),
+    /// The leader refused the request; a description is included.
+    Rejected(String),
+    /// The proposal was not applied, because the client's session had expired.
+    SessionExpired,
+}
+
+/// The actions for the application to carry out after a call to a `RawNode`.
+#[derive(Debug, Default)]
+pub struct Actions {
+    /// Messages to send to peers.
+    pub messages: Vec<(ServerId, Message)>,
+    /// Responses to send to clients.
+    pub responses: Vec<(RequestId, ClientResponse)>,
+    /// Whether to drop the messages still queued for peers from earlier calls. Set when the
+    /// node changes role, since the queued messages have been superseded.
+    pub clear_messages: bool,
+    /// The new set of peers, if the cluster configuration changed.
+    pub peers: Option<HashMap<ServerId, SocketAddr>>,
+    /// The error returned by the persistent log, if it failed. The node can no longer be
+    /// trusted to match its log, and should be dropped.
+    pub log_error: Option<Box<error::Error>>,
+}
+
+/// Configures and creates a `RawNode`.
+pub struct RawNodeBuilder<L, M> {
+    id: ServerId,
+    addr: SocketAddr,
+    peers: Option<HashMap<ServerId, SocketAddr>>,
+    joining: bool,
+    log: L,
+    state_machine: M,
+    config: ConsensusConfiguration,
+    election_min_millis: u64,
+    election_max_millis: u64,
+    heartbeat_millis: u64,
+    tick_millis: u64,
+    rng_seed: Option<[u32; 4]>,
+}
+
+impl<L, M> RawNodeBuilder<L, M>
+    where L: Log,
+          M: StateMachine
+{
+    /// Sets the other members of the cluster the node bootstraps with, if its log holds no
+    /// configuration. Defaults to none, which makes a single-node cluster.
+    pub fn with_peers(mut self, peers: HashMap<ServerId, SocketAddr>) -> RawNodeBuilder<L, M> {
+        self.peers = Some(peers);
+        self
+    }
+
+    /// Starts the node outside of any configuration, waiting to be added to an existing cluster.
+    pub fn joining(mut self) -> RawNodeBuilder<L, M> {
+        self.joining = true;
+        self
+    }
+
+    /// Sets the tunable behaviour of the consensus algorithm.
+    pub fn with_config(mut self, config: ConsensusConfiguration) -> RawNodeBuilder<L, M> {
+        self.config = config;
+        self
+    }
+
+    /// Sets the minimum election timeout. Defaults to 150 milliseconds.
+    pub fn with_election_min_millis(mut self, timeout: u64) -> RawNodeBuilder<L, M> {
+        self.election_min_millis = timeout;
+        self
+    }
+
+    /// Sets the maximum election timeout. Defaults to 350 milliseconds.
+    pub fn with_election_max_millis(mut self, timeout: u64) -> RawNodeBuilder<L, M> {
+        self.election_max_millis = timeout;
+        self
+    }
+
+    /// Sets the heartbeat period. Defaults to 60 milliseconds.
+    pub fn with_heartbeat_millis(mut self, timeout: u64) -> RawNodeBuilder<L, M> {
+        self.heartbeat_millis = timeout;
+        self
+    }
+
+    /// Sets how far each call to `tick` advances the node's clock. Defaults to 10 milliseconds.
+    pub fn with_tick_millis(mut self, tick: u64) -> RawNodeBuilder<L, M> {
+        self.tick_millis = tick;
+        self
+    }
+
+    /// Seeds the generator the election timeouts are drawn from. The seed must not be all zeros.
+    /// Defaults to a random seed.
+    pub fn with_rng_seed(mut self, seed: [u32; 4]) -> RawNodeBuilder<L, M> {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Creates the node. If the log has been compacted, the state machine is restored from the
+    /// latest snapshot.
+    pub fn finalize(self) -> Result<RawNode<L, M>> {
+        if self.peers.as_ref().map_or(false, |peers| peers.contains_key(&self.id)) {
+            return Err(Error::Raft(RaftError::InvalidPeerSet));
+        }
+        let peers = if self.joining {
+            None
+        } else {
+            Some(self.peers.unwrap_or_else(HashMap::new))
+        };
+        let consensus = Consensus::new(self.id,
+                                       self.addr,
+                                       peers,
+                                       self.log,
+                                       self.state_machine,
+                                       self.config);
+        let mut consensus = try!(consensus.map_err(|error| {
+            Error::Raft(RaftError::LogFailure(error.to_string()))
+        }));
+        let epoch = Instant::now();
+        consensus.set_time(epoch);
+        let rng = match self.rng_seed {
+            Some(seed) => XorShiftRng::from_seed(seed),
+            None => rand::thread_rng().gen(),
+        };
+        let mut node = RawNode {
+            timeout_config: TimeoutConfiguration {
+                election_min_ms: self.election_min_millis,
+                election_max_ms: self.election_max_millis,
+                heartbeat_ms: self.heartbeat_millis,
+            },
+            tick_millis: self.tick_millis,
+            epoch: epoch,
+            now: 0,
+            rng: rng,
+            timeouts: Vec::new(),
+            consensus: consensus,
+        };
+        let actions = node.consensus.init();
+        node.execute(actions, &mut Actions::default());
+        Ok(node)
+    }
+}
+
+/// A Raft node without any IO of its own. See the module documentation.
+pub struct RawNode<L, M> {
+    consensus: Consensus<L, M>,
+    timeout_config: TimeoutConfiguration,
+    /// How far each tick advances the clock, in milliseconds.
+    tick_millis: u64,
+    /// The instant the node's clock started from, as seen by the consensus module.
+    epoch: Instant,
+    /// The node's clock: the milliseconds ticked away since it was created.
+    now: u64,
+    /// The generator the election timeouts are drawn from.
+    rng: XorShiftRng,
+    /// The pending timeouts, and the time at which each expires, in the order they were set.
+    timeouts: Vec<(u64, ConsensusTimeout)>,
+}
+
+impl<L, M> RawNode<L, M>
+    where L: Log,
+          M: StateMachine
+{
+    /// Returns a builder for a node with the given ID and address, which stores its state in
+    /// `log` and applies committed entries to `state_machine`.
+    #[cfg_attr(feature = "cargo-clippy", allow(new_ret_no_self))]
+    pub fn new(id: ServerId, addr: SocketAddr, log: L, state_machine: M) -> RawNodeBuilder<L, M> {
+        RawNodeBuilder {
+            id: id,
+            addr: addr,
+            peers: None,
+            joining: false,
+            log: log,
+            state_machine: state_machine,
+            config: ConsensusConfiguration::default(),
+            election_min_millis: 150,
+            election_max_millis: 350,
+            heartbeat_millis: 60,
+            tick_millis: 10,
+            rng_seed: None,
+        }
+    }
+
+    /// Returns the node's ID.
+    pub fn id(&self) -> ServerId {
+        self.consensus.id()
+    }
+
+    /// Returns the members of the active configuration other than this node.
+    pub fn peers(&self) -> &HashMap<ServerId, SocketAddr> {
+        self.consensus.peers()
+    }
+
+    /// Returns whether the node is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.consensus.is_leader()
+    }
+
+    /// Returns the index of the latest entry the node knows to be committed.
+    pub fn commit_index(&self) -> LogIndex {
+        self.consensus.commit_index()
+    }
+
+    /// Returns the node's persistent log.
+    pub fn log(&self) -> &L {
+        self.consensus.log()
+    }
+
+    /// Returns the node's state machine.
+    pub fn state_machine(&self) -> &M {
+        self.consensus.state_machine()
+    }
+
+    /// Advances the node's clock by one tick, firing the timeouts which expire, and appends the
+    /// proposals received since the previous tick to the log.
+    pub fn tick(&mut self) -> Actions {
+        self.now += self.tick_millis;
+        self.consensus.set_time(self.epoch + Duration::from_millis(self.now));
+        let mut output = Actions::default();
+        loop {
+            // Timeouts expiring together fire in the order they were set.
+            let mut expired = None;
+            for (position, &(deadline, _)) in self.timeouts.iter().enumerate() {
+                if deadline <= self.now &&
+                   expired.map_or(true, |(_, earliest)| deadline < earliest) {
+                    expired = Some((position, deadline));
+                }
+            }
+            let timeout = match expired {
+                Some((position, _)) => self.timeouts.remove(position).1,
+                None => break,
+            };
+            let mut actions = consensus::Actions::new();
+            self.consensus.apply_timeout(timeout, &mut actions);
+            self.execute(actions, &mut output);
+        }
+        self.flush_proposals_into(&mut output);
+        output
+    }
+
+    /// Delivers a message from a peer.
+    pub fn step(&mut self, from: ServerId, message: Message) -> Result<Actions> {
+        let message = try!(into_reader(&*encode_message(&message)));
+        let mut actions = consensus::Actions::new();
+        try!(self.consensus.apply_peer_message(from, &message, &mut actions));
+        let mut output = Actions::default();
+        self.execute(actions, &mut output);
+        Ok(output)
+    }
+
+    /// Delivers a request from a client. Proposals are batched until the next tick, or until
+    /// `flush_proposals` is called.
+    pub fn step_client(&mut self, from: RequestId, request: ClientRequest) -> Result<Actions> {
+        let mut message = encode_client_request(&request);
+        try!(message.get_root::<client_request::Builder>()).set_id(from.id);
+        let message = try!(into_reader(&message));
+        let mut actions = consensus::Actions::new();
+        try!(self.consensus.apply_client_message(from.client, &message, &mut actions));
+        let mut output = Actions::default();
+        self.execute(actions, &mut output);
+        Ok(output)
+    }
+
+    /// Appends the batched proposals to the log straight away.
+    pub fn flush_proposals(&mut self) -> Actions {
+        let mut output = Actions::default();
+        self.flush_proposals_into(&mut output);
+        output
+    }
+
+    /// Starts transferring leadership to the target voter.
+    pub fn transfer_leadership(&mut self, target: ServerId) -> Result<Actions> {
+        let mut actions = consensus::Actions::new();
+        try!(self.consensus.transfer_leadership(target, &mut actions));
+        let mut output = Actions::default();
+        self.execute(actions, &mut output);
+        Ok(output)
+    }
+
+    /// Notifies the node that the connection to a peer was re-established, and that messages
+    /// sent to it since the connection failed may have been lost.
+    pub fn peer_connection_reset(&mut self, peer: ServerId, addr: SocketAddr) -> Actions {
+        let mut actions = consensus::Actions::new();
+        self.consensus.peer_connection_reset(peer, addr, &mut actions);
+        let mut output = Actions::default();
+        self.execute(actions, &mut output);
+        output
+    }
+
+    fn flush_proposals_into(&mut self, output: &mut Actions) {
+        let mut actions = consensus::Actions::new();
+        self.consensus.flush_proposals(&mut actions);
+        self.execute(actions, output);
+    }
+
+    /// Schedules the timeouts set by the consensus actions, and adds the rest of them to the
+    /// output.
+    fn execute(&mut self, actions: consensus::Actions, output: &mut Actions) {
+        let consensus::Actions { peer_messages,
+                                 client_messages,
+                                 clear_timeouts,
+                                 timeouts,
+                                 clear_peer_messages,
+                                 peers,
+                                 log_error } = actions;
+        if clear_timeouts {
+            self.timeouts.clear();
+        }
+        for timeout in timeouts {
+            let duration = timeout.duration_ms(&self.timeout_config, &mut self.rng);
+            let deadline = self.now + duration;
+            self.timeouts.retain(|&(_, pending)| pending != timeout);
+            self.timeouts.push((deadline, timeout));
+        }
+        if clear_peer_messages {
+            output.messages.clear();
+            output.clear_messages = true;
+        }
+        // The messages were built by the consensus module itself, so they always decode.
+        for (peer, message) in peer_messages {
+            let message = into_reader(&*message).and_then(|message| decode_message(&message));
+            output.messages.push((peer, message.unwrap()));
+        }
+        for (client, message) in client_messages {
+            let response = into_reader(&*message)
+                               .and_then(|message| decode_client_response(&message));
+            let (id, response) = response.unwrap();
+            let request = RequestId {
+                client: client,
+                id: id,
+            };
+            output.responses.push((request, response));
+        }
+        if peers.is_some() {
+            output.peers = peers;
+        }
+        if log_error.is_some() {
+            output.log_error = log_error;
+        }
+    }
+}
+
+/// Reads back a message which was built in memory.
+fn into_reader<A>(message: &Builder<A>) -> Result<Reader<OwnedSegments>>
+    where A: Allocator
+{
+    let mut bytes = Vec::new();
+    try!(serialize::write_message(&mut bytes, message));
+    Ok(try!(serialize::read_message(&mut &bytes[..], ReaderOptions::new())))
+}
+
+/// Encodes a peer message in the wire format.
+fn encode_message(message: &Message) -> Rc<Builder<HeapAllocator>> {
+    match *message {
+        Message::AppendEntriesRequest { term,
+                                        prev_log_index,
+                                        prev_log_term,
+                                        ref entries,
+                                        leader_commit,
+                                        read_sequence } => {
+            let entries: Vec<(Term, EntryKind, &[u8])> =
+                entries.iter().map(|entry| (entry.term, entry.kind, &entry.data[..])).collect();
+            messages::append_entries_request(term,
+                                             prev_log_index,
+                                             prev_log_term,
+                                             &entries,
+                                             leader_commit,
+                                             read_sequence)
+        }
+        Message::AppendEntriesResponse { term, ref result, read_sequence } => {
+            match *result {
+                AppendEntriesResult::Success(index) => {
+                    messages::append_entries_response_success(term, index, read_sequence)
+                }
+                AppendEntriesResult::StaleTerm => {
+                    messages::append_entries_response_stale_term(term)
+                }
+                AppendEntriesResult::InconsistentPrevEntry { index,
+                                                             conflict_term,
+                                                             conflict_index } => {
+                    messages::append_entries_response_inconsistent_prev_entry(term,
+                                                                              index,
+                                                                              conflict_term,
+                                                                              conflict_index,
+                                                                              read_sequence)
+                }
+                AppendEntriesResult::InternalError(ref error) => {
+                    messages::append_entries_response_internal_error(term, error)
+                }
+            }
+        }
+        Message::RequestVoteRequest { term,
+                                      last_log_index,
+                                      last_log_term,
+                                      leadership_transfer } => {
+            messages::request_vote_request(term,
+                                           last_log_index,
+                                           last_log_term,
+                                           leadership_transfer)
+        }
+        Message::RequestVoteResponse { term, ref result } => {
+            match *result {
+                VoteResult::Granted => messages::request_vote_response_granted(term),
+                VoteResult::StaleTerm => messages::request_vote_response_stale_term(term),
+                VoteResult::AlreadyVoted => messages::request_vote_response_already_voted(term),
+                VoteResult::InconsistentLog => {
+                    messages::request_vote_response_inconsistent_log(term)
+                }
+                VoteResult::InternalError(ref error) => {
+                    messages::request_vote_response_internal_error(term, error)
+                }
+            }
+        }
+        Message::PreVoteRequest { term, last_log_index, last_log_term } => {
+            messages::pre_vote_request(term, last_log_index, last_log_term)
+        }
+        Message::PreVoteResponse { term, granted } => messages::pre_vote_response(term, granted),
+        Message::InstallSnapshotRequest { term,
+                                          last_included_index,
+                                          last_included_term,
+                                          offset,
+                                          ref data,
+                                          done } => {
+            messages::install_snapshot_request(term,
+                                               last_included_index,
+                                               last_included_term,
+                                               offset,
+                                               data,
+                                               done)
+        }
+        Message::InstallSnapshotResponse { term, last_included_index, ref result } => {
+            match *result {
+                InstallSnapshotResult::Success(next_offset) => {
+                    messages::install_snapshot_response_success(term,
+                                                                last_included_index,
+                                                                next_offset)
+                }
+                InstallSnapshotResult::Installed => {
+                    messages::install_snapshot_response_installed(term, last_included_index)
+                }
+                InstallSnapshotResult::StaleTerm => {
+                    messages::install_snapshot_response_stale_term(term, last_included_index)
+                }
+                InstallSnapshotResult::InternalError(ref error) => {
+                    messages::install_snapshot_response_internal_error(term,
+                                                                       last_included_index,
+                                                                       error)
+                }
+            }
+        }
+        Message::ReadIndexRequest { term, read_sequence } => {
+            messages::read_index_request(term, read_sequence)
+        }
+        Message::ReadIndexResponse { term, read_sequence, read_index } => {
+            match read_index {
+                Some(read_index) => messages::read_index_response(term, read_sequence, read_index),
+                None => messages::read_index_response_not_leader(term, read_sequence),
+            }
+        }
+        Message::TimeoutNow { term } => messages::timeout_now(term),
+    }
+}
+
+/// Decodes a peer message from the wire format.
+fn decode_message<S>(message: &Reader<S>) -> Result<Message>
+    where S: ReaderSegments
+{
+    let message = match try!(try!(message.get_root::<message::Reader>()).which()) {
+        message::Which::AppendEntriesRequest(request) => {
+            let request = try!(request);
+            let entries = match decode_entries(try!(request.get_entries())) {
+                Some(entries) => entries,
+                None => return Err(Error::Raft(RaftError::MalformedMessage)),
+            };
+            Message::AppendEntriesRequest {
+                term: Term(request.get_term()),
+                prev_log_index: LogIndex(request.get_prev_log_index()),
+                prev_log_term: Term(request.get_prev_log_term()),
+                entries: entries.into_iter()
+                                .map(|(term, kind, data)| {
+                                    Entry {
+                                        term: term,
+                                        kind: kind,
+                                        data: data.to_vec(),
+                                    }
+                                })
+                                .collect(),
+                leader_commit: LogIndex(request.get_leader_commit()),
+                read_sequence: request.get_read_sequence(),
+            }
+        }
+        message::Which::AppendEntriesResponse(response) => {
+            let response = try!(response);
+            let result = match try!(response.which()) {
+                append_entries_response::Which::Success(index) => {
+                    AppendEntriesResult::Success(LogIndex(index))
+                }
+                append_entries_response::Which::StaleTerm(()) => AppendEntriesResult::StaleTerm,
+                append_entries_response::Which::InconsistentPrevEntry(inconsistent) => {
+                    AppendEntriesResult::InconsistentPrevEntry {
+                        index: LogIndex(inconsistent.get_index()),
+                        conflict_term: Term(inconsistent.get_conflict_term()),
+                        conflict_index: LogIndex(inconsistent.get_conflict_index()),
+                    }
+                }
+                append_entries_response::Which::InternalError(error) => {
+                    AppendEntriesResult::InternalError(try!(error).to_owned())
+                }
+            };
+            Message::AppendEntriesResponse {
+                term: Term(response.get_term()),
+                result: result,
+                read_sequence: response.get_read_sequence(),
+            }
+        }
+        message::Which::RequestVoteRequest(request) => {
+            let request = try!(request);
+            Message::RequestVoteRequest {
+                term: Term(request.get_term()),
+                last_log_index: LogIndex(request.get_last_log_index()),
+                last_log_term: Term(request.get_last_log_term()),
+                leadership_transfer: request.get_leadership_transfer(),
+            }
+        }
+        message::Which::RequestVoteResponse(response) => {
+            let response = try!(response);
+            let result = match try!(response.which()) {
+                request_vote_response::Which::Granted(()) => VoteResult::Granted,
+                request_vote_response::Which::StaleTerm(()) => VoteResult::StaleTerm,
+                request_vote_response::Which::AlreadyVoted(()) => VoteResult::AlreadyVoted,
+                request_vote_response::Which::InconsistentLog(()) => VoteResult::InconsistentLog,
+                request_vote_response::Which::InternalError(error) => {
+                    VoteResult::InternalError(try!(error).to_owned())
+                }
+            };
+            Message::RequestVoteResponse {
+                term: Term(response.get_term()),
+                result: result,
+            }
+        }
+        message::Which::PreVoteRequest(request) => {
+            let request = try!(request);
+            Message::PreVoteRequest {
+                term: Term(request.get_term()),
+                last_log_index: LogIndex(request.get_last_log_index()),
+                last_log_term: Term(request.get_last_log_term()),
+            }
+        }
+        message::Which::PreVoteResponse(response) => {
+            let response = try!(response);
+            Message::PreVoteResponse {
+                term: Term(response.get_term()),
+                granted: response.get_granted(),
+            }
+        }
+        message::Which::InstallSnapshotRequest(request) => {
+            let request = try!(request);
+            Message::InstallSnapshotRequest {
+                term: Term(request.get_term()),
+                last_included_index: LogIndex(request.get_last_included_index()),
+                last_included_term: Term(request.get_last_included_term()),
+                offset: request.get_offset(),
+                data: try!(request.get_data()).to_vec(),
+                done: request.get_done(),
+            }
+        }
+        message::Which::InstallSnapshotResponse(response) => {
+            let response = try!(response);
+            let result = match try!(response.which()) {
+                install_snapshot_response::Which::Success(next_offset) => {
+                    InstallSnapshotResult::Success(next_offset)
+                }
+                install_snapshot_response::Which::Installed(()) => InstallSnapshotResult::Installed,
+                install_snapshot_response::Which::StaleTerm(()) => InstallSnapshotResult::StaleTerm,
+                install_snapshot_response::Which::InternalError(error) => {
+                    InstallSnapshotResult::InternalError(try!(error).to_owned())
+                }
+            };
+            Message::InstallSnapshotResponse {
+                term: Term(response.get_term()),
+                last_included_index: LogIndex(response.get_last_included_index()),
+                result: result,
+            }
+        }
+        message::Which::ReadIndexRequest(request) => {
+            let request = try!(request);
+            Message::ReadIndexRequest {
+                term: Term(request.get_term()),
+                read_sequence: request.get_read_sequence(),
+            }
+        }
+        message::Which::ReadIndexResponse(response) => {
+            let response = try!(response);
+            let read_index = match try!(response.which()) {
+                read_index_response::Which::ReadIndex(read_index) => Some(LogIndex(read_index)),
+                read_index_response::Which::NotLeader(()) => None,
+            };
+            Message::ReadIndexResponse {
+                term: Term(response.get_term()),
+                read_sequence: response.get_read_sequence(),
+                read_index: read_index,
+            }
+        }
+        message::Which::TimeoutNow(request) => {
+            Message::TimeoutNow { term: Term(try!(request).get_term()) }
+        }
+    };
+    Ok(message)
+}
+
+/// Encodes a client request in the wire format, without its ID.
+fn encode_client_request(request: &ClientRequest) -> Builder<HeapAllocator> {
+    match *request {
+        ClientRequest::Proposal(ref entry) => messages::proposal_request(entry),
+        ClientRequest::SessionProposal { ref entry, client, sequence, acknowledged } => {
+            messages::session_proposal_request(entry, client, sequence, acknowledged)
+        }
+        ClientRequest::Query(ref query, consistency) => {
+            messages::query_request(query, consistency)
+        }
+        ClientRequest::AddServer { id, ref addr, learner } => {
+            messages::add_server_request(id, addr, learner)
+        }
+        ClientRequest::RemoveServer(id) => messages::remove_server_request(id),
+        ClientRequest::PromoteServer(id) => messages::promote_server_request(id),
+        ClientRequest::LearnerStatus(id) => messages::learner_status_request(id),
+        ClientRequest::TransferLeadership(id) => messages::transfer_leadership_request(id),
+    }
+}
+
+/// Decodes a response to a client from the wire format, along with the ID of the request.
+fn decode_client_response<S>(message: &Reader<S>) -> Result<(u64, ClientResponse)>
+    where S: ReaderSegments
+{
+    let response = try!(message.get_root::<client_response::Reader>());
+    let status = match try!(response.which()) {
+        client_response::Which::Proposal(status) |
+        client_response::Which::Query(status) => try!(status),
+        client_response::Which::Ping(..) => {
+            return Err(Error::Raft(RaftError::MalformedMessage));
+        }
+    };
+    let response = match try!(status.which()) {
+        command_response::Which::Success(data) => ClientResponse::Success(try!(data).to_vec()),
+        command_response::Which::UnknownLeader(()) => ClientResponse::UnknownLeader,
+        command_response::Which::NotLeader(leader) => {
+            ClientResponse::NotLeader(try!(SocketAddr::from_str(try!(leader))))
+        }
+        command_response::Which::Rejected(reason) => {
+            ClientResponse::Rejected(try!(reason).to_owned())
+        }
+        command_response::Which::SessionExpired(()) => ClientResponse::SessionExpired,
+    };
+    Ok((response.get_id(), response))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::{HashMap, VecDeque};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use {ClientId, EntryKind, LogIndex, ServerId, Term};
+    use persistent_log::MemLog;
+    use state_machine::NullStateMachine;
+    use super::*;
+
+    type TestNode = RawNode<MemLog, NullStateMachine>;
+
+    fn addr(id: u64) -> SocketAddr {
+        SocketAddr::from_str(&format!("127.0.0.1:{}", 9000 + id)).unwrap()
+    }
+
+    /// Creates a cluster of `size` nodes which know of each other, with generators seeded from
+    /// `seed`.
+    fn new_cluster(size: u64, seed: u32) -> Vec<TestNode> {
+        (1..size + 1)
+            .map(|id| {
+                let peers = (1..size + 1)
+                                .filter(|&peer| peer != id)
+                                .map(|peer| (ServerId::from(peer), addr(peer)))
+                                .collect::<HashMap<_, _>>();
+                RawNode::new(ServerId::from(id), addr(id), MemLog::new(), NullStateMachine)
+                    .with_peers(peers)
+                    .with_rng_seed([seed, id as u32, 0x9e37_79b9, 0x7f4a_7c15])
+                    .finalize()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Delivers the messages, and the messages sent in response, until none are left. Returns the
+    /// responses to clients.
+    fn deliver(nodes: &mut Vec<TestNode>,
+               messages: Vec<(ServerId, ServerId, Message)>)
+               -> Vec<(RequestId, ClientResponse)> {
+        let mut queue = messages.into_iter().collect::<VecDeque<_>>();
+        let mut responses = Vec::new();
+        while let Some((from, to, message)) = queue.pop_front() {
+            let node = nodes.iter_mut().find(|node| node.id() == to).unwrap();
+            let actions = node.step(from, message).unwrap();
+            queue.extend(actions.messages.into_iter().map(|(peer, message)| (to, peer, message)));
+            responses.extend(actions.responses);
+        }
+        responses
+    }
+
+    /// Ticks every node and delivers the messages they send until a leader is elected. Returns
+    /// the number of ticks it took.
+    fn elect(nodes: &mut Vec<TestNode>) -> u64 {
+        let mut ticks = 0;
+        while !nodes.iter().any(|node| node.is_leader()) {
+            assert!(ticks < 1000, "no leader elected");
+            ticks += 1;
+            let mut messages = Vec::new();
+            for node in nodes.iter_mut() {
+                let id = node.id();
+                let actions = node.tick();
+                messages.extend(actions.messages
+                                       .into_iter()
+                                       .map(|(peer, message)| (id, peer, message)));
+            }
+            deliver(nodes, messages);
+        }
+        ticks
+    }
+
+    /// Tests that every peer message survives being encoded to the wire format and back.
+    #[test]
+    fn test_message_round_trip() {
+        let messages = vec![
+            Message::AppendEntriesRequest {
+                term: Term(3),
+                prev_log_index: LogIndex(4),
+                prev_log_term: Term(2),
+                entries: vec![Entry {
+                                  term: Term(3),
+                                  kind: EntryKind::Configuration,
+                                  data: b"members".to_vec(),
+                              }],
+                leader_commit: LogIndex(4),
+                read_sequence: 7,
+            },
+            Message::AppendEntriesResponse {
+                term: Term(3),
+                result: AppendEntriesResult::InconsistentPrevEntry {
+                    index: LogIndex(4),
+                    conflict_term: Term(1),
+                    conflict_index: LogIndex(2),
+                },
+                read_sequence: 7,
+            },
+            Message::RequestVoteResponse {
+                term: Term(3),
+                result: VoteResult::InternalError("disk full".to_owned()),
+            },
+            Message::InstallSnapshotRequest {
+                term: Term(3),
+                last_included_index: LogIndex(10),
+                last_included_term: Term(2),
+                offset: 0,
+                data: b"snapshot".to_vec(),
+                done: true,
+            },
+            Message::ReadIndexResponse {
+                term: Term(3),
+                read_sequence: 8,
+                read_index: None,
+            },
+            Message::TimeoutNow { term: Term(3) },
+        ];
+        for message in messages {
+            let reader = into_reader(&*encode_message(&message)).unwrap();
+            assert_eq!(message, decode_message(&reader).unwrap());
+        }
+    }
+
+    /// Tests that a cluster driven only through `tick` and `step` elects a leader, which then
+    /// commits a proposal.
+    #[test]
+    fn test_elect_and_propose() {
+        let mut nodes = new_cluster(3, 1);
+        elect(&mut nodes);
+
+        let request = RequestId {
+            client: ClientId::new(),
+            id: 1,
+        };
+        let leader = nodes.iter().position(|node| node.is_leader()).unwrap();
+        let leader_id = nodes[leader].id();
+        let actions = nodes[leader].step_client(request, ClientRequest::Proposal(b"foo".to_vec()))
+                                   .unwrap();
+        assert!(actions.responses.is_empty());
+        let actions = nodes[leader].flush_proposals();
+        let messages = actions.messages
+                              .into_iter()
+                              .map(|(peer, message)| (leader_id, peer, message))
+                              .collect();
+        let responses = deliver(&mut nodes, messages);
+        assert_eq!(vec![(request, ClientResponse::Success(vec![]))], responses);
+        assert_eq!(LogIndex(2), nodes[leader].commit_index());
+    }
+
+    /// Tests that clusters seeded alike elect the same leader after the same number of ticks.
+    #[test]
+    fn test_seeded_election() {
+        for seed in 0..10 {
+            let mut first = new_cluster(3, seed);
+            let mut second = new_cluster(3, seed);
+            assert_eq!(elect(&mut first), elect(&mut second));
+            let leader = |nodes: &Vec<TestNode>| {
+                nodes.iter().find(|node| node.is_leader()).unwrap().id()
+            };
+            assert_eq!(leader(&first), leader(&second));
+        }
+    }
+}
@@ -5,14 +5,17 @@
 
 use std::{fmt, io};
 use std::str::FromStr;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
+use std::panic;
+use std::sync::mpsc;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::rc::Rc;
 
-use mio::tcp::TcpListener;
-use mio::{Poll, Ready, PollOpt, Token};
+use mio::{Events, Poll, Ready, PollOpt, Registration, SetReadiness, Token};
 use capnp::message::{Builder, HeapAllocator};
+use rand;
 use slab;
 
 use ClientId;
@@ -22,35 +25,57 @@ use RaftError;
 use ServerId;
 use messages;
 use messages_capnp::connection_preamble;
-use consensus::{Consensus, Actions, ConsensusTimeout, TimeoutConfiguration};
+use consensus::{Consensus, ConsensusConfiguration, Actions, ConsensusTimeout,
+                TimeoutConfiguration};
 use state_machine::StateMachine;
 use persistent_log::Log;
 use connection::{Connection, ConnectionKind};
+use transport::{TcpTransport, Transport};
 
 const LISTENER: Token = Token(0);
+const SHUTDOWN: Token = Token(1);
 
 type Slab<T> = slab::Slab<T, Token>;
 
+/// Identifies a registered timeout: its deadline, and a sequence number which tells apart
+/// timeouts with the same deadline.
+type TimeoutHandle = (Instant, u64);
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum ServerTimeout {
     Consensus(ConsensusTimeout),
     Reconnect(Token),
 }
 
-pub struct ServerBuilder<L, M>
+pub struct ServerBuilder<L, M, T = TcpTransport>
 where
     L: Log,
     M: StateMachine,
+    T: Transport,
 {
     id: ServerId,
     addr: SocketAddr,
     peers: Option<HashMap<ServerId, SocketAddr>>,
+    joining: bool,
     store: L,
     state_machine: M,
     max_connections: usize,
     election_min_millis: u64,
     election_max_millis: u64,
     heartbeat_millis: u64,
+    snapshot_threshold: Option<u64>,
+    snapshot_chunk_bytes: usize,
+    lease_margin_millis: Option<u64>,
+    pre_vote: bool,
+    check_quorum: bool,
+    max_batch_entries: usize,
+    max_batch_bytes: usize,
+    replication_window: usize,
+    max_append_entries: usize,
+    max_append_bytes: usize,
+    session_expiry_secs: u64,
+    max_staleness_millis: Option<u64>,
+    transport: T,
 }
 
 impl <L, M> ServerBuilder<L, M>
@@ -65,58 +90,265 @@ where
             id: id,
             addr: addr,
             peers: None,
+            joining: false,
             store: store,
             state_machine: state_machine,
             max_connections: 128,
             election_min_millis: 150,
             election_max_millis: 350,
             heartbeat_millis: 60,
+            snapshot_threshold: None,
+            snapshot_chunk_bytes: 1024 * 1024,
+            lease_margin_millis: None,
+            pre_vote: true,
+            check_quorum: true,
+            max_batch_entries: 256,
+            max_batch_bytes: 1024 * 1024,
+            replication_window: 8,
+            max_append_entries: 512,
+            max_append_bytes: 1024 * 1024,
+            session_expiry_secs: 60 * 60,
+            max_staleness_millis: None,
+            transport: TcpTransport,
         }
     }
+}
 
-    pub fn finalize(self) -> Result<Server<L, M>> {
+impl <L, M, T> ServerBuilder<L, M, T>
+where
+    L: Log,
+    M: StateMachine,
+    T: Transport,
+{
+    pub fn finalize(self) -> Result<Server<L, M, T>> {
+        let consensus_config = ConsensusConfiguration {
+            snapshot_threshold: self.snapshot_threshold,
+            snapshot_chunk_bytes: self.snapshot_chunk_bytes,
+            leader_lease: self.lease_margin_millis.map(|margin| {
+                Duration::from_millis(self.election_min_millis.saturating_sub(margin))
+            }),
+            pre_vote: self.pre_vote,
+            check_quorum: if self.check_quorum {
+                Some(Duration::from_millis(self.election_min_millis))
+            } else {
+                None
+            },
+            max_batch_entries: self.max_batch_entries,
+            max_batch_bytes: self.max_batch_bytes,
+            replication_window: self.replication_window,
+            max_append_entries: self.max_append_entries,
+            max_append_bytes: self.max_append_bytes,
+            session_expiry: Duration::from_secs(self.session_expiry_secs),
+            max_staleness: Some(Duration::from_millis(self.max_staleness_millis
+                                                          .unwrap_or(self.election_max_millis))),
+        };
+        let peers = if self.joining {
+            None
+        } else {
+            Some(self.peers.unwrap_or_else(HashMap::new))
+        };
         Server::finalize(
             self.id,
             self.addr,
-            self.peers.unwrap_or_else(HashMap::new),
+            peers,
             self.store,
             self.state_machine,
             self.election_min_millis,
             self.election_max_millis,
             self.heartbeat_millis,
             self.max_connections,
+            consensus_config,
+            self.transport,
         )
     }
 
     pub fn run(self) -> Result<()> {
-        let mut server = self.finalize()?;
+        let mut server = try!(self.finalize());
         server.run()
     }
 
-    pub fn with_max_connections(mut self, count: usize) -> ServerBuilder<L, M> {
+    /// Runs the server in a background thread. The returned handle stops it.
+    pub fn spawn(self) -> Result<ServerHandle> {
+        let (sender, receiver) = mpsc::channel();
+        let thread = try!(thread::Builder::new()
+                              .name(format!("raft::Server({})", self.id))
+                              .spawn(move || {
+                                  let mut server = try!(self.finalize());
+                                  let _ = sender.send(server.shutdown_readiness.clone());
+                                  server.run()
+                              }));
+        match receiver.recv() {
+            Ok(shutdown) => {
+                Ok(ServerHandle {
+                    shutdown: shutdown,
+                    thread: thread,
+                })
+            }
+            // The server failed to start, and its thread has exited with the error.
+            Err(_) => {
+                match thread.join() {
+                    Ok(result) => Err(result.err().expect("server exited without starting")),
+                    Err(panic) => panic::resume_unwind(panic),
+                }
+            }
+        }
+    }
+
+    pub fn with_max_connections(mut self, count: usize) -> ServerBuilder<L, M, T> {
         self.max_connections = count;
         self
     }
 
-    pub fn with_election_min_millis(mut self, timeout: u64) -> ServerBuilder<L, M> {
+    pub fn with_election_min_millis(mut self, timeout: u64) -> ServerBuilder<L, M, T> {
         self.election_min_millis = timeout;
         self
     }
 
-    pub fn with_election_max_millis(mut self, timeout: u64) -> ServerBuilder<L, M> {
+    pub fn with_election_max_millis(mut self, timeout: u64) -> ServerBuilder<L, M, T> {
         self.election_max_millis = timeout;
         self
     }
 
-    pub fn with_heartbeat_millis(mut self, timeout: u64) -> ServerBuilder<L, M> {
+    pub fn with_heartbeat_millis(mut self, timeout: u64) -> ServerBuilder<L, M, T> {
         self.heartbeat_millis = timeout;
         self
     }
 
-    pub fn with_peers(mut self, peers: HashMap<ServerId, SocketAddr>) -> ServerBuilder<L, M> {
+    pub fn with_peers(mut self, peers: HashMap<ServerId, SocketAddr>) -> ServerBuilder<L, M, T> {
         self.peers = Some(peers);
         self
     }
+
+    /// Starts the server outside of any cluster configuration, so that it can be added to an
+    /// existing cluster with `Client::add_server`. Until then it does not campaign for
+    /// leadership. Has no effect if the log already holds a configuration.
+    pub fn joining(mut self) -> ServerBuilder<L, M, T> {
+        self.joining = true;
+        self
+    }
+
+    /// Snapshots the state machine and compacts the log each time `entries` entries have been
+    /// applied since the previous snapshot. Compaction is disabled by default.
+    pub fn with_snapshot_threshold(mut self, entries: u64) -> ServerBuilder<L, M, T> {
+        self.snapshot_threshold = Some(entries);
+        self
+    }
+
+    /// Sets the maximum number of snapshot bytes sent to a lagging peer in a single message.
+    pub fn with_snapshot_chunk_bytes(mut self, bytes: usize) -> ServerBuilder<L, M, T> {
+        self.snapshot_chunk_bytes = bytes;
+        self
+    }
+
+    /// Enables leader leases, which let the leader answer queries without first confirming its
+    /// leadership with a majority. The lease lasts from the start of the latest heartbeat round
+    /// acknowledged by a majority, for the minimum election timeout less `margin_millis`.
+    ///
+    /// Leases depend on clocks: they are only safe if the clocks of any two servers drift apart
+    /// by less than `margin_millis` over an election timeout, and no server is paused (for
+    /// example by the scheduler or a virtual machine migration) for longer than that. Leases
+    /// are disabled by default, in which case every query is confirmed with a round of
+    /// heartbeats.
+    pub fn with_leader_lease(mut self, margin_millis: u64) -> ServerBuilder<L, M, T> {
+        self.lease_margin_millis = Some(margin_millis);
+        self
+    }
+
+    /// Sets whether the server runs a pre-vote round before campaigning, so that it only
+    /// increments its term once a majority would vote for it. Enabled by default; all servers in
+    /// a cluster should agree on the setting.
+    pub fn with_pre_vote(mut self, enabled: bool) -> ServerBuilder<L, M, T> {
+        self.pre_vote = enabled;
+        self
+    }
+
+    /// Sets whether the leader steps down when it has not heard from a majority of the cluster
+    /// within the minimum election timeout. Enabled by default.
+    pub fn with_check_quorum(mut self, enabled: bool) -> ServerBuilder<L, M, T> {
+        self.check_quorum = enabled;
+        self
+    }
+
+    /// Sets the limits on the proposals the leader appends to its log at once. Proposals
+    /// received during an event loop turn are appended together, unless there are more than
+    /// `max_entries` of them or they add up to more than `max_bytes`. Defaults to 256 entries
+    /// and 1 MiB.
+    pub fn with_max_batch(mut self,
+                          max_entries: usize,
+                          max_bytes: usize)
+                          -> ServerBuilder<L, M, T> {
+        self.max_batch_entries = max_entries;
+        self.max_batch_bytes = max_bytes;
+        self
+    }
+
+    /// Sets the number of AppendEntries requests carrying entries which the leader keeps in
+    /// flight to each follower. Defaults to 8.
+    pub fn with_replication_window(mut self, window: usize) -> ServerBuilder<L, M, T> {
+        self.replication_window = window;
+        self
+    }
+
+    /// Sets the limits on the entries carried by a single AppendEntries request. Followers which
+    /// are further behind catch up over several requests, so that no message grows without
+    /// bound. Defaults to 512 entries and 1 MiB; a larger entry is sent on its own.
+    pub fn with_max_append(mut self,
+                           max_entries: usize,
+                           max_bytes: usize)
+                           -> ServerBuilder<L, M, T> {
+        self.max_append_entries = max_entries;
+        self.max_append_bytes = max_bytes;
+        self
+    }
+
+    /// Sets how long a client session is kept without proposals from the client. Proposals
+    /// retried after the session expired may be applied twice. Defaults to an hour. The setting
+    /// takes effect while this server leads: it is recorded in the log along with each proposal,
+    /// so servers with different settings still expire sessions at the same entry.
+    pub fn with_session_expiry(mut self, secs: u64) -> ServerBuilder<L, M, T> {
+        self.session_expiry_secs = secs;
+        self
+    }
+
+    /// Sets how long after last hearing from the leader a follower still answers
+    /// bounded-staleness queries. Defaults to the maximum election timeout, after which a
+    /// healthy follower would have started an election.
+    pub fn with_max_staleness_millis(mut self, millis: u64) -> ServerBuilder<L, M, T> {
+        self.max_staleness_millis = Some(millis);
+        self
+    }
+
+    /// Sets the transport over which the server connects to its peers and accepts connections.
+    /// Defaults to `TcpTransport`; all servers in a cluster must use the same kind of transport.
+    pub fn with_transport<U>(self, transport: U) -> ServerBuilder<L, M, U>
+        where U: Transport
+    {
+        ServerBuilder {
+            id: self.id,
+            addr: self.addr,
+            peers: self.peers,
+            joining: self.joining,
+            store: self.store,
+            state_machine: self.state_machine,
+            max_connections: self.max_connections,
+            election_min_millis: self.election_min_millis,
+            election_max_millis: self.election_max_millis,
+            heartbeat_millis: self.heartbeat_millis,
+            snapshot_threshold: self.snapshot_threshold,
+            snapshot_chunk_bytes: self.snapshot_chunk_bytes,
+            lease_margin_millis: self.lease_margin_millis,
+            pre_vote: self.pre_vote,
+            check_quorum: self.check_quorum,
+            max_batch_entries: self.max_batch_entries,
+            max_batch_bytes: self.max_batch_bytes,
+            replication_window: self.replication_window,
+            max_append_entries: self.max_append_entries,
+            max_append_bytes: self.max_append_bytes,
+            session_expiry_secs: self.session_expiry_secs,
+            max_staleness_millis: self.max_staleness_millis,
+            transport: transport,
+        }
+    }
 }
 
 /// The `Server` is responsible for receiving ready from peer `Server` instance or clients,
@@ -131,9 +363,10 @@ where
 /// but recoverable ready. The info level is used for infrequent ready such as connection resets
 /// and election results. The debug level is used for frequent ready such as client proposals and
 /// heartbeats. The trace level is used for very high frequency debugging output.
-pub struct Server<L, M>
+pub struct Server<L, M, T = TcpTransport>
     where L: Log,
-          M: StateMachine
+          M: StateMachine,
+          T: Transport
 {
     /// Id of this server.
     id: ServerId,
@@ -141,11 +374,14 @@ pub struct Server<L, M>
     /// Raft state machine consensus.
     consensus: Consensus<L, M>,
 
+    /// The transport connections are opened over.
+    transport: T,
+
     /// Connection listener.
-    listener: TcpListener,
+    listener: T::Listener,
 
     /// Collection of connections indexed by token.
-    connections: Slab<Connection>,
+    connections: Slab<Connection<T::Stream>>,
 
     /// Index of peer id to connection token.
     peer_tokens: HashMap<ServerId, Token>,
@@ -159,13 +395,48 @@ pub struct Server<L, M>
     /// Currently registered reconnection timeouts.
     reconnection_timeouts: HashMap<Token, TimeoutHandle>,
 
+    /// Every registered timeout, in deadline order.
+    timeouts: BTreeMap<TimeoutHandle, ServerTimeout>,
+
+    /// The sequence number of the latest registered timeout.
+    timeout_sequence: u64,
+
     /// Configured timeouts
     timeout_config: TimeoutConfiguration,
 
+    /// The reason the persistent log failed, if it did. The event loop stops once it is set.
+    log_failure: Option<String>,
+
+    /// Whether the server has been shut down through a `ServerHandle`.
+    shut_down: bool,
+
+    /// Becomes readable once a `ServerHandle` shuts the server down.
+    shutdown: Registration,
+    shutdown_readiness: SetReadiness,
+
     /// Poll
     poll: Poll,
 }
 
+/// A handle to a server running in a background thread, with which it can be shut down.
+pub struct ServerHandle {
+    shutdown: SetReadiness,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// Shuts the server down, and waits for its thread to exit. Returns the error the server
+    /// failed with, if it failed before being shut down.
+    pub fn shutdown(self) -> Result<()> {
+        // The server may have stopped already, in which case only its result is left.
+        let _ = self.shutdown.set_readiness(Ready::readable());
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+}
+
 fn all_interests() -> Ready {
     Ready::readable() | Ready::writable() | Ready::error() | Ready::hup()
 }
@@ -184,21 +455,56 @@ impl<L, M> Server<L, M>
         ServerBuilder::new(id, addr, store, state_machine)
     }
 
+    /// Spawns a new Raft server in a background thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the new node.
+    /// * `addr` - The address of the new node.
+    /// * `peers` - The ID and address of all peers in the Raft cluster.
+    /// * `store` - The persistent log store.
+    /// * `state_machine` - The client state machine to which client commands will be applied.
+    pub fn spawn(id: ServerId,
+                 addr: SocketAddr,
+                 peers: HashMap<ServerId, SocketAddr>,
+                 store: L,
+                 state_machine: M)
+                 -> Result<JoinHandle<Result<()>>> {
+        thread::Builder::new()
+            .name(format!("raft::Server({})", id))
+            .spawn(move || {
+                let mut server = try!(Server::finalize(id, addr, Some(peers), store, state_machine, 1500, 3000, 1000, 129,
+                                                       ConsensusConfiguration::default(),
+                                                       TcpTransport));
+                server.run()
+            })
+            .map_err(From::from)
+    }
+}
+
+impl<L, M, T> Server<L, M, T>
+    where L: Log,
+          M: StateMachine,
+          T: Transport
+{
     /// Creates a new instance of the server.
-    /// *Gotcha:* `peers` must not contain the local `id`.
+    /// *Gotcha:* `peers` must not contain the local `id`. If `peers` is `None`, the server waits
+    /// to be added to an existing cluster.
     #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
     fn finalize(
             id: ServerId,
             addr: SocketAddr,
-            peers: HashMap<ServerId, SocketAddr>,
+            peers: Option<HashMap<ServerId, SocketAddr>>,
             store: L,
             state_machine: M,
             election_min_millis: u64,
             election_max_millis: u64,
             heartbeat_millis: u64,
-            max_connections: usize)
-            -> Result<Server<L, M>> {
-        if peers.contains_key(&id) {
+            max_connections: usize,
+            consensus_config: ConsensusConfiguration,
+            transport: T)
+            -> Result<Server<L, M, T>> {
+        if peers.as_ref().map_or(false, |peers| peers.contains_key(&id)) {
             return Err(Error::Raft(RaftError::InvalidPeerSet));
         }
 
@@ -207,25 +513,41 @@ impl<L, M> Server<L, M>
             election_max_ms: election_max_millis,
             heartbeat_ms: heartbeat_millis,
         };
-        let consensus = Consensus::new(id, peers.clone(), store, state_machine);
-        let listener = try!(TcpListener::bind(&addr));
+        let consensus = Consensus::new(id, addr, peers, store, state_machine, consensus_config);
+        let consensus = try!(consensus.map_err(|error| {
+            Error::Raft(RaftError::LogFailure(error.to_string()))
+        }));
+        let listener = try!(transport.listen(&addr));
+        let (shutdown, shutdown_readiness) = Registration::new2();
 
         let mut server = Server {
             id: id,
             consensus: consensus,
+            transport: transport,
             listener: listener,
-            connections: Slab::new_starting_at(Token(1), max_connections),
+            connections: Slab::new_starting_at(Token(2), max_connections),
             peer_tokens: HashMap::new(),
             client_tokens: HashMap::new(),
             consensus_timeouts: HashMap::new(),
             reconnection_timeouts: HashMap::new(),
+            timeouts: BTreeMap::new(),
+            timeout_sequence: 0,
             timeout_config: timeout_config,
-            poll: Poll::new()?,
+            log_failure: None,
+            shut_down: false,
+            shutdown: shutdown,
+            shutdown_readiness: shutdown_readiness,
+            poll: try!(Poll::new()),
         };
 
+        // Connect to the peers of the active configuration, which may differ from the bootstrap
+        // peers if the log holds a configuration.
+        let peers = server.consensus.peers().clone();
         for (peer_id, peer_addr) in peers {
             let token: Token = try!(server.connections
-                                          .insert(try!(Connection::peer(peer_id, peer_addr)))
+                                          .insert(try!(Connection::peer(&server.transport,
+                                                                        peer_id,
+                                                                        peer_addr)))
                                           .map_err(|_| {
                                               Error::Raft(RaftError::ConnectionLimitReached)
                                           }));
@@ -234,65 +556,101 @@ impl<L, M> Server<L, M>
         Ok(server)
     }
 
-    fn start_loop(&mut self) -> Result<()>
-    where
-        L: Log,
-        M: StateMachine
-    {
-        self.poll.register(&self.listener, LISTENER, all_interests(), PollOpt::level())?;
+    fn start_loop(&mut self) -> Result<()> {
+        try!(self.poll.register(&self.listener, LISTENER, all_interests(), PollOpt::level()));
+        try!(self.poll.register(&self.shutdown, SHUTDOWN, Ready::readable(), PollOpt::edge()));
         let mut tokens = vec![];
         for token in self.peer_tokens.values() {
             tokens.push(*token);
         }
         let id = self.id;
-        let addr = self.listener.local_addr()?;
+        let addr = try!(self.transport.local_addr(&self.listener));
         for token in tokens {
-            self.connections[token].register(&self.poll, token)?;
+            try!(self.connections[token].register(&self.poll, token));
             self.send_message(
                                 token,
                                 messages::server_connection_preamble(id, &addr));
         }
         Ok(())
     }
-    /// Runs a new Raft server in the current thread.
-    ///
-    /// # Arguments
-    ///
-    /// * `id` - The ID of the new node.
-    /// * `addr` - The address of the new node.
-    /// * `peers` - The ID and address of all peers in the Raft cluster.
-    /// * `store` - The persistent log store.
-    /// * `state_machine` - The client state machine to which client commands will be applied.
+
+    /// Runs the Raft server in the current thread, until it is shut down through a `ServerHandle`
+    /// or the persistent log fails.
     pub fn run(&mut self) -> Result<()> {
-        self.start_loop()?;
+        try!(self.start_loop());
         let actions = self.consensus.init();
         self.execute_actions(actions);
-        poll.run(self).map_err(From::from)
+
+        let mut events = Events::with_capacity(1024);
+        while !self.stopped() {
+            try!(self.turn(&mut events));
+        }
+        match self.log_failure.take() {
+            Some(reason) => Err(Error::Raft(RaftError::LogFailure(reason))),
+            None => Ok(()),
+        }
     }
 
-    /// Spawns a new Raft server in a background thread.
-    ///
-    /// # Arguments
-    ///
-    /// * `id` - The ID of the new node.
-    /// * `addr` - The address of the new node.
-    /// * `peers` - The ID and address of all peers in the Raft cluster.
-    /// * `store` - The persistent log store.
-    /// * `state_machine` - The client state machine to which client commands will be applied.
-    pub fn spawn(id: ServerId,
-                 addr: SocketAddr,
-                 peers: HashMap<ServerId, SocketAddr>,
-                 store: L,
-                 state_machine: M)
-                 -> Result<JoinHandle<Result<()>>> {
-        thread::Builder::new()
-            .name(format!("raft::Server({})", id))
-            .spawn(move || {
-                let mut server = try!(Server::finalize(id, addr, peers, store, state_machine, 1500, 3000, 1000, 129));
-                server.run()
-            })
-            .map_err(From::from)
+    /// Returns whether the event loop should stop.
+    fn stopped(&self) -> bool {
+        self.shut_down || self.log_failure.is_some()
     }
+
+    /// Runs a single turn of the event loop: waits for readiness events or the next timeout,
+    /// and handles whatever is ready.
+    fn turn(&mut self, events: &mut Events) -> Result<()> {
+        let wait = self.timeouts.keys().next().map(|&(deadline, _)| {
+            let now = Instant::now();
+            if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_millis(0)
+            }
+        });
+        try!(self.poll.poll(events, wait));
+        for event in events.iter() {
+            if self.stopped() {
+                return Ok(());
+            }
+            self.ready(event.token(), event.readiness());
+        }
+
+        let now = Instant::now();
+        while !self.stopped() {
+            let handle = match self.timeouts.keys().next() {
+                Some(&handle) if handle.0 <= now => handle,
+                _ => break,
+            };
+            let timeout = self.timeouts.remove(&handle).unwrap();
+            self.timeout(timeout);
+        }
+        if !self.stopped() {
+            self.tick();
+        }
+        Ok(())
+    }
+
+    /// Registers a timeout to fire after the given number of milliseconds.
+    fn set_timeout(&mut self, timeout: ServerTimeout, duration_ms: u64) -> TimeoutHandle {
+        self.timeout_sequence += 1;
+        let handle = (Instant::now() + Duration::from_millis(duration_ms), self.timeout_sequence);
+        self.timeouts.insert(handle, timeout);
+        handle
+    }
+
+    /// Starts transferring leadership to the target voting member, for instance before taking
+    /// this server down for maintenance. New proposals are rejected until the target has caught
+    /// up and started an election; if it does not catch up within the minimum election timeout
+    /// the transfer is aborted.
+    /// Returns `RaftError::RequestRejected` if this server is not the leader, or the target
+    /// cannot take over.
+    pub fn transfer_leadership(&mut self, target: ServerId) -> Result<()> {
+        let mut actions = Actions::new();
+        try!(self.consensus.transfer_leadership(target, &mut actions));
+        self.execute_actions(actions);
+        Ok(())
+    }
+
     /// Sends the message to the connection associated with the provided token.
     /// If sending the message fails, the connection is reset.
     fn send_message(&mut self,
@@ -318,16 +676,30 @@ impl<L, M> Server<L, M>
                       client_messages,
                       timeouts,
                       clear_timeouts,
-                      clear_peer_messages } = actions;
+                      clear_peer_messages,
+                      peers,
+                      log_error } = actions;
+
+        if let Some(error) = log_error {
+            // Nothing more can safely be done; stop the event loop, and report the failure from
+            // `run`.
+            scoped_error!("shutting down: persistent log failure: {}", error);
+            self.log_failure = Some(error.to_string());
+            return;
+        }
 
+        if let Some(peers) = peers {
+            self.update_peers(peers);
+        }
         if clear_peer_messages {
             for &token in self.peer_tokens.values() {
                 self.connections[token].clear_messages();
             }
         }
         for (peer, message) in peer_messages {
-            let token = self.peer_tokens[&peer];
-            self.send_message(token, message);
+            if let Some(&token) = self.peer_tokens.get(&peer) {
+                self.send_message(token, message);
+            }
         }
         for (client, message) in client_messages {
             if let Some(&token) = self.client_tokens.get(&client) {
@@ -335,29 +707,71 @@ impl<L, M> Server<L, M>
             }
         }
         if clear_timeouts {
-            for (timeout, &handle) in &self.consensus_timeouts {
-                scoped_assert!(&self.poll.clear_timeout(handle),
+            for (timeout, handle) in &self.consensus_timeouts {
+                scoped_assert!(self.timeouts.remove(handle).is_some(),
                                "unable to clear timeout: {:?}",
                                timeout);
             }
             self.consensus_timeouts.clear();
         }
         for timeout in timeouts {
-            let duration = timeout.duration_ms(&self.timeout_config);
+            let duration = timeout.duration_ms(&self.timeout_config, &mut rand::thread_rng());
+            let handle = self.set_timeout(ServerTimeout::Consensus(timeout), duration);
+            if let Some(handle) = self.consensus_timeouts.insert(timeout, handle) {
+                scoped_assert!(self.timeouts.remove(&handle).is_some(),
+                               "unable to clear timeout: {:?}",
+                               timeout);
+            }
+        }
+    }
+
+    /// Opens connections to peers which joined the cluster configuration, and closes the
+    /// connections to peers which left it.
+    fn update_peers(&mut self, peers: HashMap<ServerId, SocketAddr>) {
+        let removed: Vec<ServerId> = self.peer_tokens
+                                         .keys()
+                                         .filter(|peer| !peers.contains_key(peer))
+                                         .cloned()
+                                         .collect();
+        for peer in removed {
+            scoped_info!("peer {} left the cluster; closing connection", peer);
+            let token = self.peer_tokens.remove(&peer).unwrap();
+            self.connections.remove(token).expect("peer connection not found");
+            if let Some(handle) = self.reconnection_timeouts.remove(&token) {
+                scoped_assert!(self.timeouts.remove(&handle).is_some());
+            }
+        }
 
-            // Registering a timeout may only fail if the maximum number of timeouts
-            // is already registered, which is by default 65,536. We use a
-            // maximum of one timeout per peer, so this unwrap should be safe.
-            let handle = &self.poll.timeout_ms(ServerTimeout::Consensus(timeout), duration)
-                                   .unwrap();
-            self.consensus_timeouts
-                .insert(timeout, handle)
-                .map(|handle| {
-                    //todo;
-                    //scoped_assert!(&self.poll.clear_timeout(handle),
-                                   //"unable to clear timeout: {:?}",
-                                   //timeout)
-                });
+        let id = self.id;
+        let local_addr = match self.transport.local_addr(&self.listener) {
+            Ok(addr) => addr,
+            Err(error) => {
+                scoped_warn!("unable to connect to new peers: {}", error);
+                return;
+            }
+        };
+        for (peer, addr) in peers {
+            if self.peer_tokens.contains_key(&peer) {
+                continue;
+            }
+            scoped_info!("peer {} joined the cluster; connecting to {}", peer, addr);
+            let token = match Connection::peer(&self.transport, peer, addr).and_then(|connection| {
+                self.connections
+                    .insert(connection)
+                    .map_err(|_| Error::Raft(RaftError::ConnectionLimitReached))
+            }) {
+                Ok(token) => token,
+                Err(error) => {
+                    scoped_warn!("unable to connect to peer {}: {}", peer, error);
+                    continue;
+                }
+            };
+            self.peer_tokens.insert(peer, token);
+            if self.connections[token].register(&self.poll, token).is_err() {
+                self.reset_connection(token);
+                continue;
+            }
+            self.send_message(token, messages::server_connection_preamble(id, &local_addr));
         }
     }
 
@@ -371,11 +785,9 @@ impl<L, M> Server<L, M>
         let kind = *self.connections[token].kind();
         match kind {
             ConnectionKind::Peer(..) => {
-                // Crash if reseting the connection fails.
-                let (timeout, handle) = self.connections[token]
-                                            .reset_peer(&self.poll, token)
-                                            .unwrap();
-
+                let duration = self.connections[token].reset_peer();
+                let timeout = ServerTimeout::Reconnect(token);
+                let handle = self.set_timeout(timeout, duration);
                 scoped_assert!(self.reconnection_timeouts.insert(token, handle).is_none(),
                                "timeout already registered: {:?}",
                                timeout);
@@ -403,13 +815,13 @@ impl<L, M> Server<L, M>
             match *self.connections[token].kind() {
                 ConnectionKind::Peer(id) => {
                     let mut actions = Actions::new();
-                    self.consensus.apply_peer_message(id, &message, &mut actions);
-                    self.execute_actions(&self.poll, actions);
+                    try!(self.consensus.apply_peer_message(id, &message, &mut actions));
+                    self.execute_actions(actions);
                 }
                 ConnectionKind::Client(id) => {
                     let mut actions = Actions::new();
-                    self.consensus.apply_client_message(id, &message, &mut actions);
-                    self.execute_actions(&self.poll, actions);
+                    try!(self.consensus.apply_client_message(id, &message, &mut actions));
+                    self.execute_actions(actions);
                 }
                 ConnectionKind::Unknown => {
                     let preamble = try!(message.get_root::<connection_preamble::Reader>());
@@ -430,32 +842,23 @@ impl<L, M> Server<L, M>
                             // address, for future retries in this connection.
                             self.connections[token].set_addr(peer_addr);
 
-                            let prev_token = Some(self.peer_tokens
-                                                      .insert(peer_id, token)
-                                                      .expect("peer token not found"));
-
-                            // Close the existing connection, if any.
-                            // Currently, prev_token is never `None`; see above.
-                            // With config changes, this will have to be handled.
-                            match prev_token {
-                                Some(tok) => {
-                                    self.connections
-                                        .remove(tok)
-                                        .expect("peer connection not found");
-
-                                    // Clear any timeouts associated with the existing connection.
-                                    self.reconnection_timeouts
-                                        .remove(&tok)
-                                        .map(|handle| {
-                                            scoped_assert!(&self.poll.clear_timeout(handle))
-                                        });
+                            // Close the existing connection, if any. A peer outside of the
+                            // active configuration (for instance a leader contacting this server
+                            // before it has learned the configuration) has none.
+                            if let Some(tok) = self.peer_tokens.insert(peer_id, token) {
+                                self.connections
+                                    .remove(tok)
+                                    .expect("peer connection not found");
+
+                                // Clear any timeouts associated with the existing connection.
+                                if let Some(handle) = self.reconnection_timeouts.remove(&tok) {
+                                    scoped_assert!(self.timeouts.remove(&handle).is_some());
                                 }
-                                _ => unreachable!(),
                             }
                             // Notify consensus that the connection reset.
                             let mut actions = Actions::new();
                             self.consensus.peer_connection_reset(peer_id, peer_addr, &mut actions);
-                            self.execute_actions(&self.poll, actions);
+                            self.execute_actions(actions);
                         }
                         connection_preamble::id::Which::Client(Ok(id)) => {
                             let client_id = try!(ClientId::from_bytes(id));
@@ -478,12 +881,12 @@ impl<L, M> Server<L, M>
         Ok(())
     }
 
-    /// Accepts a new TCP connection, adds it to the connection slab, and registers it with the
-    /// event loop.
+    /// Accepts a new connection from the transport, adds it to the connection slab, and registers
+    /// it with the event loop.
     fn accept_connection(&mut self) -> Result<()> {
         scoped_trace!("accept_connection");
-        self.listener
-            .accept()
+        self.transport
+            .accept(&self.listener)
             .map_err(From::from)
             .and_then(|stream_opt| {
                 stream_opt.ok_or_else(|| {
@@ -491,7 +894,7 @@ impl<L, M> Server<L, M>
                                              "listener.accept() returned None"))
                 })
             })
-            .and_then(|(stream, _)| Connection::unknown(stream))
+            .map(|(stream, addr)| Connection::unknown(stream, addr))
             .and_then(|conn| {
                 self.connections
                     .insert(conn)
@@ -500,41 +903,40 @@ impl<L, M> Server<L, M>
             .and_then(|token|
                 // Until this point if any failures occur the connection is simply dropped. From
                 // this point down, the connection is stored in the slab, so dropping it would
-                // result in a leaked TCP stream and slab entry. Instead of dropping the
+                // result in a leaked stream and slab entry. Instead of dropping the
                 // connection, it will be reset if an error occurs.
                 self.connections[token]
                     .register(&self.poll, token)
                     .or_else(|_| {
-                        self.reset_connection(&self.poll, token);
+                        self.reset_connection(token);
                         Err(Error::Raft(RaftError::ConnectionRegisterFailed))
                     })
                     .map(|_| scoped_debug!("new connection accepted from {}",
                                            self.connections[token].addr())))
     }
-}
-
-impl<L, M> Handler for Server<L, M>
-    where L: Log,
-          M: StateMachine
-{
-    type Message = ();
-    type Timeout = ServerTimeout;
 
+    /// Handles a readiness event from the event loop.
     fn ready(&mut self, token: Token, ready: Ready) {
         info!("{:?}", self);
         scoped_trace!("ready; token: {:?}; ready: {:?}", token, ready);
 
+        if token == SHUTDOWN {
+            scoped_info!("shutting down");
+            self.shut_down = true;
+            return;
+        }
+
         if ready.is_error() {
             scoped_assert!(token != LISTENER, "unexpected error event from LISTENER");
             scoped_warn!("{:?}: error event", self.connections[token]);
-            self.reset_connection(&self.poll, token);
+            self.reset_connection(token);
             return;
         }
 
         if ready.is_hup() {
             scoped_assert!(token != LISTENER, "unexpected hup event from LISTENER");
             scoped_trace!("{:?}: hup event", self.connections[token]);
-            self.reset_connection(&self.poll, token);
+            self.reset_connection(token);
             return;
         }
 
@@ -542,34 +944,43 @@ impl<L, M> Handler for Server<L, M>
             scoped_assert!(token != LISTENER, "unexpected writeable event for LISTENER");
             if let Err(error) = self.connections[token].writable() {
                 scoped_warn!("{:?}: failed write: {}", self.connections[token], error);
-                self.reset_connection(&self.poll, token);
+                self.reset_connection(token);
                 return;
             }
             if !ready.is_readable() {
                 self.connections[token]
                     .reregister(&self.poll, token)
-                    .unwrap_or_else(|_| self.reset_connection(&self.poll, token));
+                    .unwrap_or_else(|_| self.reset_connection(token));
             }
         }
 
         if ready.is_readable() {
             if token == LISTENER {
-                self.accept_connection(&self.poll)
+                self.accept_connection()
                     .unwrap_or_else(|error| scoped_warn!("unable to accept connection: {}", error));
             } else {
-                self.readable(&self.poll, token)
+                self.readable(token)
                     // Only reregister the connection with the event loop if no error occurs and
                     // the connection is *not* reset.
                     .and_then(|_| self.connections[token].reregister(&self.poll, token))
                     .unwrap_or_else(|error| {
                         scoped_warn!("{:?}: failed read: {}",
                                      self.connections[token], error);
-                        self.reset_connection(&self.poll, token);
+                        self.reset_connection(token);
                     });
             }
         }
     }
 
+    /// Handles the end of a turn of the event loop.
+    fn tick(&mut self) {
+        // The proposals received during the turn are appended to the log together.
+        let mut actions = Actions::new();
+        self.consensus.flush_proposals(&mut actions);
+        self.execute_actions(actions);
+    }
+
+    /// Handles a timeout which has fired.
     fn timeout(&mut self, timeout: ServerTimeout) {
         info!("{:?}", self);
         scoped_trace!("timeout: {:?}", &timeout);
@@ -580,7 +991,7 @@ impl<L, M> Handler for Server<L, M>
                                timeout);
                 let mut actions = Actions::new();
                 self.consensus.apply_timeout(consensus, &mut actions);
-                self.execute_actions(&self.poll, actions);
+                self.execute_actions(actions);
             }
 
             ServerTimeout::Reconnect(token) => {
@@ -588,7 +999,7 @@ impl<L, M> Handler for Server<L, M>
                                "{:?} missing timeout: {:?}",
                                self.connections[token],
                                timeout);
-                let local_addr = self.listener.local_addr();
+                let local_addr = self.transport.local_addr(&self.listener);
                 scoped_assert!(local_addr.is_ok(), "could not obtain listener address");
                 let id = match *self.connections[token].kind() {
                     ConnectionKind::Peer(id) => id,
@@ -596,27 +1007,28 @@ impl<L, M> Handler for Server<L, M>
                 };
                 let addr = *self.connections[token].addr();
                 self.connections[token]
-                    .reconnect_peer(self.id, &local_addr.unwrap())
+                    .reconnect_peer(&self.transport, self.id, &local_addr.unwrap())
                     .and_then(|_| self.connections[token].register(&self.poll, token))
                     .map(|_| {
                         let mut actions = Actions::new();
                         self.consensus.peer_connection_reset(id, addr, &mut actions);
-                        self.execute_actions(&self.poll, actions);
+                        self.execute_actions(actions);
                     })
                     .unwrap_or_else(|error| {
                         scoped_warn!("unable to reconnect connection {:?}: {}",
                                      self.connections[token],
                                      error);
-                        self.reset_connection(&self.poll, token);
+                        self.reset_connection(token);
                     });
             }
         }
     }
 }
 
-impl<L, M> fmt::Debug for Server<L, M>
+impl<L, M, T> fmt::Debug for Server<L, M, T>
     where L: Log,
-          M: StateMachine
+          M: StateMachine,
+          T: Transport
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "Server({})", self.id)
@@ -628,23 +1040,27 @@ mod tests {
 
     extern crate env_logger;
 
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::io::{self, Read, Write};
     use std::net::{SocketAddr, TcpListener, TcpStream};
     use std::str::FromStr;
+    use std::time::Duration;
 
     use capnp::message::ReaderOptions;
     use capnp::serialize;
     use mio::EventLoop;
 
+    use AsyncClient;
+    use Client;
     use ClientId;
     use Result;
     use ServerId;
     use messages;
     use messages_capnp::connection_preamble;
     use consensus::Actions;
-    use state_machine::NullStateMachine;
+    use state_machine::{ChannelStateMachine, NullStateMachine, StateMachine};
     use persistent_log::MemLog;
+    use transport::ChannelTransport;
     use super::*;
 
     type TestServer = Server<MemLog, NullStateMachine>;
@@ -687,7 +1103,9 @@ mod tests {
     }
 
     /// Returns true if the server has an open connection with the peer.
-    fn peer_connected(server: &TestServer, peer: ServerId) -> bool {
+    fn peer_connected<T>(server: &Server<MemLog, NullStateMachine, T>, peer: ServerId) -> bool
+        where T: Transport
+    {
         let token = server.peer_tokens[&peer];
         server.reconnection_timeouts.get(&token).is_none()
     }
@@ -967,4 +1385,111 @@ mod tests {
 
         assert_eq!(peer_id, read_server_preamble(&mut in_stream));
     }
+
+    /// Tests that a Server connects to its peers over the transport it is built with, without
+    /// binding any ports.
+    #[test]
+    fn test_channel_transport() {
+        setup_test!("test_channel_transport");
+        let transport = ChannelTransport::new();
+        let peer_id = ServerId::from(1);
+        let peer_addr = SocketAddr::from_str("127.0.0.1:9001").unwrap();
+        let peer_listener = transport.listen(&peer_addr).unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_addr);
+        let mut server = Server::new(ServerId::from(0),
+                                     SocketAddr::from_str("127.0.0.1:9000").unwrap(),
+                                     MemLog::new(),
+                                     NullStateMachine)
+                             .with_peers(peers)
+                             .with_transport(transport.clone())
+                             .finalize()
+                             .unwrap();
+        server.start_loop().unwrap();
+
+        // Accept the server's connection, and check that the server sends a valid preamble.
+        let (mut stream, _) = transport.accept(&peer_listener).unwrap().unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut stream));
+        assert!(peer_connected(&server, peer_id));
+    }
+
+    /// Spawns a server for each of the state machines, all in one cluster on the transport.
+    /// Returns the addresses of the servers, and their handles.
+    fn spawn_cluster<M>(transport: &ChannelTransport,
+                        state_machines: Vec<M>)
+                        -> (HashSet<SocketAddr>, Vec<ServerHandle>)
+        where M: StateMachine
+    {
+        let addrs: HashMap<ServerId, SocketAddr> =
+            (0..state_machines.len() as u64)
+                .map(|id| {
+                    let addr = SocketAddr::from_str(&format!("127.0.0.1:{}", 9100 + id)).unwrap();
+                    (ServerId::from(id), addr)
+                })
+                .collect();
+        let handles = state_machines.into_iter()
+                                    .enumerate()
+                                    .map(|(id, state_machine)| {
+                                        let id = ServerId::from(id as u64);
+                                        let mut peers = addrs.clone();
+                                        let addr = peers.remove(&id).unwrap();
+                                        Server::new(id, addr, MemLog::new(), state_machine)
+                                            .with_peers(peers)
+                                            .with_transport(transport.clone())
+                                            .spawn()
+                                            .unwrap()
+                                    })
+                                    .collect();
+        (addrs.values().cloned().collect(), handles)
+    }
+
+    /// Tests that three servers sharing a `ChannelTransport` elect a leader, and that a client on
+    /// the same transport gets its proposal committed.
+    #[test]
+    fn test_channel_cluster() {
+        setup_test!("test_channel_cluster");
+        let transport = ChannelTransport::new();
+        let (cluster, handles) = spawn_cluster(&transport,
+                                               (0..3).map(|_| NullStateMachine).collect());
+
+        // The client retries until a leader has been elected.
+        let mut client = Client::builder(cluster).with_transport(transport).finalize();
+        assert_eq!(Vec::<u8>::new(), client.propose(b"foo").unwrap());
+
+        for handle in handles {
+            handle.shutdown().unwrap();
+        }
+    }
+
+    /// Tests that proposals sent with an `AsyncClient`, alone or pipelined, are applied by every
+    /// server in the cluster.
+    #[test]
+    fn test_async_client_cluster() {
+        setup_test!("test_async_client_cluster");
+        let transport = ChannelTransport::new();
+        let (state_machines, applied): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| ChannelStateMachine::new()).unzip();
+        let (cluster, handles) = spawn_cluster(&transport, state_machines);
+
+        let client = AsyncClient::builder(cluster).with_transport(transport).finalize_async();
+        // The first proposal is sent with no other proposal in flight.
+        assert_eq!(Vec::<u8>::new(), client.propose(b"foo").wait().unwrap());
+        let responses = vec![client.propose(b"bar"), client.propose(b"baz")];
+        for response in responses {
+            assert_eq!(Vec::<u8>::new(), response.wait().unwrap());
+        }
+
+        for applied in applied {
+            for &command in &[b"foo", b"bar", b"baz"] {
+                assert_eq!(command.to_vec(),
+                           applied.recv_timeout(Duration::from_secs(10)).unwrap());
+            }
+        }
+
+        drop(client);
+        for handle in handles {
+            handle.shutdown().unwrap();
+        }
+    }
 }
@@ -0,0 +1,334 @@
+//! Client sessions.
+//!
+//! A client may retry a proposal which was in fact committed, for instance when its connection
+//! to the leader fails before the response arrives. To apply every proposal exactly once, the
+//! client tags its proposals with its ID and a sequence number, and every server keeps a table of
+//! the results of the proposals each client may not have received yet. A duplicate is answered
+//! from the table instead of being applied to the state machine again. The table is part of the
+//! replicated state: it is updated as entries are applied, and stored in snapshots.
+//!
+//! A session expires once its client has not proposed anything for a while, as measured by the
+//! timestamps the leader records in the entries. The leader records the expiry in the entries
+//! too, so that every server expires a session at the same entry even if their settings differ.
+//! A client's first proposal opens its session; any other proposal from a client without a live
+//! session is rejected as `Applied::Expired` and not applied, since it may be a retry of a
+//! proposal which was applied before the session expired. The client then starts a new session.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use capnp::message::{Builder, ReaderOptions};
+use capnp::serialize;
+
+use {ClientId, Result};
+use messages_capnp::{session_entry, snapshot};
+
+/// A proposal made by a client with a session, as stored in the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionEntry {
+    /// The proposing client.
+    pub client: ClientId,
+    /// The client's sequence number for the proposal.
+    pub sequence: u64,
+    /// Every sequence number below this one has been answered.
+    pub acknowledged: u64,
+    /// The time on the leader's consensus clock when it received the proposal, in milliseconds.
+    pub timestamp: u64,
+    /// The proposed entry.
+    pub data: Vec<u8>,
+    /// How long sessions last without proposals as of this entry, in milliseconds.
+    pub expiry: u64,
+}
+
+impl SessionEntry {
+    /// Serializes the proposal, as the data of a session entry.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = Builder::new_default();
+        {
+            let mut entry = message.init_root::<session_entry::Builder>();
+            entry.set_client(self.client.as_bytes());
+            entry.set_sequence(self.sequence);
+            entry.set_acknowledged(self.acknowledged);
+            entry.set_timestamp(self.timestamp);
+            entry.set_data(&self.data);
+            entry.set_expiry(self.expiry);
+        }
+        let mut bytes = Vec::new();
+        serialize::write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    /// Deserializes a proposal from the data of a session entry.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<SessionEntry> {
+        let message = try!(serialize::read_message(&mut bytes, ReaderOptions::new()));
+        let entry = try!(message.get_root::<session_entry::Reader>());
+        Ok(SessionEntry {
+            client: try!(ClientId::from_bytes(try!(entry.get_client()))),
+            sequence: entry.get_sequence(),
+            acknowledged: entry.get_acknowledged(),
+            timestamp: entry.get_timestamp(),
+            data: try!(entry.get_data()).to_vec(),
+            expiry: entry.get_expiry(),
+        })
+    }
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn timestamp() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    millis(elapsed)
+}
+
+/// Returns the duration in whole milliseconds.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// The outcome of applying a session entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Applied {
+    /// The proposal has been applied, now or before, with this result.
+    Result(Vec<u8>),
+    /// The proposal was applied and answered long ago, and its result discarded.
+    Discarded,
+    /// The client had no live session, so the proposal was not applied.
+    Expired,
+}
+
+/// The applied proposals of a client.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Session {
+    /// Every sequence number below this one has been answered, and its result discarded.
+    acknowledged: u64,
+    /// The timestamp of the client's latest proposal.
+    last_active: u64,
+    /// The results of the applied proposals which may not have been answered, by sequence
+    /// number.
+    responses: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Session {
+    /// Returns whether the session had expired by the time of the entry.
+    fn expired(&self, entry: &SessionEntry) -> bool {
+        entry.timestamp.saturating_sub(self.last_active) > entry.expiry
+    }
+}
+
+/// The session table: the applied proposals of every client with a live session.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sessions {
+    sessions: HashMap<ClientId, Session>,
+    /// The timestamp of the entry which last swept the table for expired sessions.
+    swept: u64,
+}
+
+impl Sessions {
+    /// Creates an empty session table.
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    /// Returns the number of live sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Applies a committed session entry. The entry's data is passed to `apply` unless the
+    /// proposal was applied before, in which case the cached result is returned instead, or the
+    /// client has no live session.
+    ///
+    /// Sessions which have been idle for longer than the entry's expiry as of its timestamp have
+    /// expired. They are dropped from the table once per expiry period, as measured by the
+    /// entries, rather than on every entry.
+    pub fn apply<F>(&mut self, entry: &SessionEntry, apply: F) -> Applied
+        where F: FnOnce(&[u8]) -> Vec<u8>
+    {
+        if entry.timestamp.saturating_sub(self.swept) > entry.expiry {
+            self.sessions.retain(|_, session| !session.expired(entry));
+            self.swept = entry.timestamp;
+        }
+
+        let live = self.sessions
+                       .get(&entry.client)
+                       .map_or(false, |session| !session.expired(entry));
+        if !live {
+            self.sessions.remove(&entry.client);
+            if entry.sequence != 1 {
+                return Applied::Expired;
+            }
+        }
+        let session = self.sessions.entry(entry.client).or_insert_with(Session::default);
+        session.last_active = cmp::max(session.last_active, entry.timestamp);
+        if entry.acknowledged > session.acknowledged {
+            session.acknowledged = entry.acknowledged;
+            session.responses = session.responses.split_off(&entry.acknowledged);
+        }
+        if entry.sequence < session.acknowledged {
+            return Applied::Discarded;
+        }
+        if let Some(result) = session.responses.get(&entry.sequence) {
+            return Applied::Result(result.clone());
+        }
+        let result = apply(&entry.data);
+        session.responses.insert(entry.sequence, result.clone());
+        Applied::Result(result)
+    }
+
+    /// Writes the session table into a snapshot. Sessions are written in client ID order, so
+    /// that every server with the same table writes the same bytes.
+    pub fn write(&self, mut snapshot: snapshot::Builder) {
+        snapshot.set_sessions_swept(self.swept);
+        let mut list = snapshot.init_sessions(self.sessions.len() as u32);
+        let mut sessions: Vec<(&ClientId, &Session)> = self.sessions.iter().collect();
+        sessions.sort_by(|&(a, _), &(b, _)| a.as_bytes().cmp(b.as_bytes()));
+        for (n, (client, session)) in sessions.into_iter().enumerate() {
+            let mut slot = list.borrow().get(n as u32);
+            slot.set_client(client.as_bytes());
+            slot.set_acknowledged(session.acknowledged);
+            slot.set_last_active(session.last_active);
+            let mut responses = slot.init_responses(session.responses.len() as u32);
+            for (m, (&sequence, result)) in session.responses.iter().enumerate() {
+                let mut response = responses.borrow().get(m as u32);
+                response.set_sequence(sequence);
+                response.set_result(result);
+            }
+        }
+    }
+
+    /// Reads a session table from a snapshot.
+    pub fn read(snapshot: snapshot::Reader) -> Result<Sessions> {
+        let mut sessions = HashMap::new();
+        for slot in try!(snapshot.get_sessions()).iter() {
+            let mut responses = BTreeMap::new();
+            for response in try!(slot.get_responses()).iter() {
+                responses.insert(response.get_sequence(), try!(response.get_result()).to_vec());
+            }
+            let session = Session {
+                acknowledged: slot.get_acknowledged(),
+                last_active: slot.get_last_active(),
+                responses: responses,
+            };
+            sessions.insert(try!(ClientId::from_bytes(try!(slot.get_client()))), session);
+        }
+        Ok(Sessions {
+            sessions: sessions,
+            swept: snapshot.get_sessions_swept(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use capnp::message::Builder;
+    use capnp::serialize;
+
+    use ClientId;
+    use messages_capnp::snapshot;
+    use session::{Applied, SessionEntry, Sessions};
+
+    fn entry(client: ClientId, sequence: u64, acknowledged: u64, timestamp: u64) -> SessionEntry {
+        SessionEntry {
+            client: client,
+            sequence: sequence,
+            acknowledged: acknowledged,
+            timestamp: timestamp,
+            data: b"foo".to_vec(),
+            expiry: 1000,
+        }
+    }
+
+    fn write(sessions: &Sessions) -> Vec<u8> {
+        let mut message = Builder::new_default();
+        sessions.write(message.init_root::<snapshot::Builder>());
+        let mut bytes = Vec::new();
+        serialize::write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    /// Tests that session entries survive encoding.
+    #[test]
+    fn test_entry_round_trip() {
+        let entry = entry(ClientId::new(), 2, 1, 1000);
+        assert_eq!(entry, SessionEntry::from_bytes(&entry.to_bytes()).unwrap());
+    }
+
+    /// Tests that a duplicate proposal is answered from the session table, and that results are
+    /// discarded once acknowledged or expired.
+    #[test]
+    fn test_deduplication() {
+        let client = ClientId::new();
+        let mut sessions = Sessions::new();
+        let mut applied = 0;
+
+        let result = sessions.apply(&entry(client, 1, 1, 0), |_| {
+            applied += 1;
+            b"bar".to_vec()
+        });
+        assert_eq!(Applied::Result(b"bar".to_vec()), result);
+        let result = sessions.apply(&entry(client, 1, 1, 10), |_| {
+            applied += 1;
+            b"baz".to_vec()
+        });
+        assert_eq!(Applied::Result(b"bar".to_vec()), result);
+        assert_eq!(1, applied);
+
+        // Once the client has moved on, the result is forgotten.
+        sessions.apply(&entry(client, 2, 2, 20), |_| Vec::new());
+        assert_eq!(Applied::Discarded, sessions.apply(&entry(client, 1, 1, 30), |_| Vec::new()));
+
+        // Another client's proposal expires the idle session.
+        sessions.apply(&entry(ClientId::new(), 1, 1, 1031), |_| Vec::new());
+        assert_eq!(1, sessions.len());
+    }
+
+    /// Tests that a proposal from a client whose session has expired, or which never had one, is
+    /// not applied unless it opens a new session.
+    #[test]
+    fn test_expired_session() {
+        let client = ClientId::new();
+        let mut sessions = Sessions::new();
+        sessions.apply(&entry(client, 1, 1, 0), |_| Vec::new());
+
+        // A retry after the session expired may have been applied before.
+        let result = sessions.apply(&entry(client, 2, 1, 2000), |_| panic!("applied"));
+        assert_eq!(Applied::Expired, result);
+        let result = sessions.apply(&entry(ClientId::new(), 2, 1, 2000), |_| panic!("applied"));
+        assert_eq!(Applied::Expired, result);
+        assert_eq!(0, sessions.len());
+
+        // The client's first proposal in a new session opens it.
+        let result = sessions.apply(&entry(ClientId::new(), 1, 1, 2000), |_| b"bar".to_vec());
+        assert_eq!(Applied::Result(b"bar".to_vec()), result);
+        assert_eq!(1, sessions.len());
+    }
+
+    /// Tests that the leader's expiry recorded in an entry decides which sessions expire.
+    #[test]
+    fn test_entry_expiry() {
+        let client = ClientId::new();
+        let mut sessions = Sessions::new();
+        sessions.apply(&entry(client, 1, 1, 0), |_| Vec::new());
+
+        let mut later = entry(ClientId::new(), 1, 1, 5000);
+        later.expiry = 10000;
+        sessions.apply(&later, |_| Vec::new());
+        assert_eq!(2, sessions.len());
+    }
+
+    /// Tests that equal session tables are written identically, whatever order the sessions
+    /// were created in.
+    #[test]
+    fn test_write_order() {
+        let clients: Vec<ClientId> = (0..16).map(|_| ClientId::new()).collect();
+        let mut forward = Sessions::new();
+        for &client in &clients {
+            forward.apply(&entry(client, 1, 1, 0), |_| Vec::new());
+        }
+        let mut backward = Sessions::new();
+        for &client in clients.iter().rev() {
+            backward.apply(&entry(client, 1, 1, 0), |_| Vec::new());
+        }
+        assert_eq!(write(&forward), write(&backward));
+    }
+}
@@ -0,0 +1,654 @@
+//! A deterministic simulation of a Raft cluster, for testing.
+//!
+//! A `Simulation` runs a cluster of `Consensus` instances on virtual time, over a simulated
+//! network which can drop, delay, duplicate and reorder messages, and partition the cluster.
+//! Every random choice, the election timeouts included, is drawn from a single generator seeded
+//! by the test, so a seed which exposes a bug reproduces it exactly. The safety properties of
+//! Raft are checked after every step, and a violation panics with the seed.
+//!
+//! The module is built for the crate's own tests, and for other crates with the `simulator`
+//! feature enabled.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, btree_map};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use capnp::message::{Allocator, Builder, HeapAllocator, Reader, ReaderOptions};
+use capnp::serialize::{self, OwnedSegments};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use {ClientId, EntryKind, LogIndex, ServerId, Term, messages};
+use consensus::{Actions, Consensus, ConsensusConfiguration, ConsensusTimeout,
+                TimeoutConfiguration};
+use messages_capnp::client_request;
+use persistent_log::{Log, MemLog};
+use state_machine::NullStateMachine;
+
+type Peer = Consensus<MemLog, NullStateMachine>;
+
+/// The faults injected by the simulated network.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    /// The probability that a message is lost.
+    pub drop: f64,
+    /// The probability that a message is delivered twice.
+    pub duplicate: f64,
+    /// The minimum delay of a message, in milliseconds. Must be at least 1.
+    pub min_delay_ms: u64,
+    /// The maximum delay of a message, in milliseconds. Messages overtake each other when their
+    /// delays differ.
+    pub max_delay_ms: u64,
+}
+
+impl Faults {
+    /// A reliable network, which delivers every message in order after a millisecond.
+    pub fn none() -> Faults {
+        Faults {
+            drop: 0.0,
+            duplicate: 0.0,
+            min_delay_ms: 1,
+            max_delay_ms: 1,
+        }
+    }
+}
+
+/// An event scheduled on the virtual clock.
+enum Event {
+    /// A message arrives at a peer.
+    Deliver {
+        from: ServerId,
+        to: ServerId,
+        message: Rc<Builder<HeapAllocator>>,
+    },
+    /// A timeout of a peer expires.
+    Timeout(ServerId, ConsensusTimeout),
+    /// A client sends a request with the given ID to a peer.
+    Request(ServerId, u64, Request),
+}
+
+/// A client request.
+#[derive(Clone, Copy)]
+enum Request {
+    /// A proposal, whose entry is the request ID.
+    Proposal,
+    /// Adds the server to the configuration as a voting member.
+    AddServer(ServerId),
+    /// Removes the server from the configuration.
+    RemoveServer(ServerId),
+}
+
+/// A simulated cluster. See the module documentation.
+pub struct Simulation {
+    seed: u64,
+    rng: XorShiftRng,
+    /// The instant corresponding to time 0 on the virtual clock.
+    epoch: Instant,
+    /// The virtual time, in milliseconds.
+    now: u64,
+    /// The pending events, keyed by the time they happen at and the order they were scheduled
+    /// in.
+    events: BTreeMap<(u64, u64), Event>,
+    /// The number of events scheduled so far.
+    scheduled: u64,
+    peers: BTreeMap<ServerId, Peer>,
+    addrs: HashMap<ServerId, SocketAddr>,
+    /// The peers each peer is connected to: the peers of the latest configuration it knows of.
+    connections: HashMap<ServerId, HashSet<ServerId>>,
+    /// The keys of the pending timeouts of each peer.
+    timeouts: HashMap<ServerId, HashMap<ConsensusTimeout, (u64, u64)>>,
+    timeout_config: TimeoutConfiguration,
+    faults: Faults,
+    /// The side of the partition each peer is on. Peers missing from the map are on a side of
+    /// their own together.
+    partition: HashMap<ServerId, usize>,
+    client: ClientId,
+    /// The number of requests made so far.
+    requests: u64,
+    /// The leader of each term seen so far.
+    leaders: HashMap<Term, ServerId>,
+    /// The committed entries seen so far, by index. Entries which a peer compacted before they
+    /// could be seen are missing.
+    committed: BTreeMap<LogIndex, (Term, EntryKind, Vec<u8>)>,
+}
+
+impl Simulation {
+    /// Creates a cluster of `size` peers, in which every random choice is drawn from `seed`. The
+    /// leaders check for a quorum every 150 milliseconds.
+    pub fn new(size: u64, seed: u64) -> Simulation {
+        let config = ConsensusConfiguration {
+            check_quorum: Some(Duration::from_millis(150)),
+            ..ConsensusConfiguration::default()
+        };
+        Simulation::with_config(size, seed, config)
+    }
+
+    /// Creates a cluster of `size` peers with the given configuration, in which every random
+    /// choice is drawn from `seed`.
+    pub fn with_config(size: u64, seed: u64, config: ConsensusConfiguration) -> Simulation {
+        let mut rng = XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e37_79b9,
+                                              0x7f4a_7c15]);
+        let mut client = [0; 16];
+        rng.fill_bytes(&mut client);
+
+        let addrs: HashMap<ServerId, SocketAddr> =
+            (0..size)
+                .map(|id| {
+                    let addr = SocketAddr::from_str(&format!("127.0.0.1:{}", 9000 + id)).unwrap();
+                    (ServerId(id), addr)
+                })
+                .collect();
+        let peers = addrs.iter()
+                         .map(|(&id, &addr)| {
+                             let mut peers = addrs.clone();
+                             peers.remove(&id);
+                             let peer = Consensus::new(id,
+                                                       addr,
+                                                       Some(peers),
+                                                       MemLog::new(),
+                                                       NullStateMachine,
+                                                       config.clone())
+                                            .unwrap();
+                             (id, peer)
+                         })
+                         .collect::<BTreeMap<_, _>>();
+
+        let mut simulation = Simulation {
+            seed: seed,
+            rng: rng,
+            epoch: Instant::now(),
+            now: 0,
+            events: BTreeMap::new(),
+            scheduled: 0,
+            timeouts: peers.keys().map(|&id| (id, HashMap::new())).collect(),
+            connections: peers.iter()
+                              .map(|(&id, peer)| (id, peer.peers().keys().cloned().collect()))
+                              .collect(),
+            peers: peers,
+            addrs: addrs,
+            timeout_config: TimeoutConfiguration {
+                election_min_ms: 150,
+                election_max_ms: 300,
+                heartbeat_ms: 50,
+            },
+            faults: Faults::none(),
+            partition: HashMap::new(),
+            client: ClientId::from_bytes(&client).unwrap(),
+            requests: 0,
+            leaders: HashMap::new(),
+            committed: BTreeMap::new(),
+        };
+        let ids = simulation.ids();
+        for id in ids {
+            let actions = {
+                let peer = simulation.peers.get_mut(&id).unwrap();
+                peer.set_time(simulation.epoch);
+                peer.init()
+            };
+            simulation.execute(id, actions);
+        }
+        simulation
+    }
+
+    /// Returns the IDs of the peers, in order.
+    pub fn ids(&self) -> Vec<ServerId> {
+        self.peers.keys().cloned().collect()
+    }
+
+    /// Returns the index of the latest entry the peer knows to be committed.
+    pub fn peer_commit_index(&self, id: ServerId) -> LogIndex {
+        self.peers[&id].commit_index()
+    }
+
+    /// Returns the members of the latest configuration the peer knows of, in order.
+    pub fn configuration(&self, id: ServerId) -> Vec<ServerId> {
+        let peer = &self.peers[&id];
+        let mut members = peer.peers().keys().cloned().collect::<Vec<_>>();
+        if peer.is_voter() {
+            members.push(id);
+        }
+        members.sort();
+        members
+    }
+
+    /// Returns the leader in the latest term, if there is one.
+    pub fn leader(&self) -> Option<ServerId> {
+        self.peers
+            .iter()
+            .filter(|&(_, peer)| peer.is_leader())
+            .max_by_key(|&(_, peer)| peer.log().current_term().unwrap())
+            .map(|(&id, _)| id)
+    }
+
+    /// Returns the committed entries seen so far, by index. Entries which a peer compacted into a
+    /// snapshot in the same step as it committed them are missing.
+    pub fn committed(&self) -> &BTreeMap<LogIndex, (Term, EntryKind, Vec<u8>)> {
+        &self.committed
+    }
+
+    /// Returns the index of the latest entry any peer has committed.
+    pub fn commit_index(&self) -> LogIndex {
+        self.peers.values().map(|peer| peer.commit_index()).max().unwrap_or(LogIndex(0))
+    }
+
+    /// Returns the generator the simulation draws from, for tests to make their own choices.
+    pub fn rng(&mut self) -> &mut XorShiftRng {
+        &mut self.rng
+    }
+
+    /// Sets the faults injected by the network from now on.
+    pub fn set_faults(&mut self, faults: Faults) {
+        assert!(faults.min_delay_ms > 0 && faults.min_delay_ms <= faults.max_delay_ms);
+        self.faults = faults;
+    }
+
+    /// Partitions the cluster into the given groups. Messages between groups, including those
+    /// already in flight, are lost.
+    pub fn partition(&mut self, groups: &[&[ServerId]]) {
+        self.partition.clear();
+        for (side, group) in groups.iter().enumerate() {
+            for &id in group.iter() {
+                self.partition.insert(id, side);
+            }
+        }
+    }
+
+    /// Heals any partition.
+    pub fn heal(&mut self) {
+        self.partition.clear();
+    }
+
+    /// Has a client send a proposal, to the leader if there is one or else to a random peer.
+    pub fn propose(&mut self) {
+        self.request(Request::Proposal);
+    }
+
+    /// Has a client ask for the server to be added to the configuration as a voting member, in
+    /// the same way as it sends proposals.
+    pub fn add_server(&mut self, id: ServerId) {
+        self.request(Request::AddServer(id));
+    }
+
+    /// Has a client ask for the server to be removed from the configuration, in the same way as
+    /// it sends proposals.
+    pub fn remove_server(&mut self, id: ServerId) {
+        self.request(Request::RemoveServer(id));
+    }
+
+    /// Sends a client request to the leader if there is one, or else to a random peer.
+    fn request(&mut self, request: Request) {
+        let to = match self.leader() {
+            Some(leader) => leader,
+            None => {
+                let ids = self.ids();
+                ids[self.rng.gen_range(0, ids.len())]
+            }
+        };
+        self.requests += 1;
+        let (now, id) = (self.now, self.requests);
+        self.schedule(now, Event::Request(to, id, request));
+    }
+
+    /// Runs the simulation for the given number of milliseconds of virtual time.
+    pub fn run_for(&mut self, millis: u64) {
+        let end = self.now + millis;
+        while self.events.keys().next().map_or(false, |&(at, _)| at <= end) {
+            self.step();
+        }
+        self.now = end;
+    }
+
+    /// Handles the next event, and checks the safety properties.
+    fn step(&mut self) {
+        let key = *self.events.keys().next().unwrap();
+        let event = self.events.remove(&key).unwrap();
+        self.now = key.0;
+        let now = self.epoch + Duration::from_millis(self.now);
+
+        let mut actions = Actions::new();
+        let id = match event {
+            Event::Deliver { from, to, message } => {
+                if !self.connected(from, to) {
+                    return;
+                }
+                let peer = self.peers.get_mut(&to).unwrap();
+                peer.set_time(now);
+                peer.apply_peer_message(from, &into_reader(&*message), &mut actions).unwrap();
+                to
+            }
+            Event::Timeout(id, timeout) => {
+                self.timeouts.get_mut(&id).unwrap().remove(&timeout);
+                let peer = self.peers.get_mut(&id).unwrap();
+                peer.set_time(now);
+                peer.apply_timeout(timeout, &mut actions);
+                id
+            }
+            Event::Request(to, id, request) => {
+                let mut message = match request {
+                    Request::Proposal => messages::proposal_request(format!("{}", id).as_bytes()),
+                    Request::AddServer(server) => {
+                        messages::add_server_request(server, &self.addrs[&server], false)
+                    }
+                    Request::RemoveServer(server) => messages::remove_server_request(server),
+                };
+                message.get_root::<client_request::Builder>().unwrap().set_id(id);
+                let peer = self.peers.get_mut(&to).unwrap();
+                peer.set_time(now);
+                peer.apply_client_message(self.client, &into_reader(&message), &mut actions)
+                    .unwrap();
+                to
+            }
+        };
+        // The event loop turn ends, so batched proposals are appended.
+        self.peers.get_mut(&id).unwrap().flush_proposals(&mut actions);
+        self.execute(id, actions);
+        self.check_invariants();
+    }
+
+    /// Carries out the actions of a peer: schedules its timeouts, and sends its messages over the
+    /// network. Responses to the client are discarded.
+    fn execute(&mut self, id: ServerId, actions: Actions) {
+        let Actions { mut peer_messages, clear_timeouts, mut timeouts, peers, log_error, .. } =
+            actions;
+        assert!(log_error.is_none(), "seed {}: log failure on {:?}", self.seed, id);
+
+        if clear_timeouts {
+            for (_, key) in self.timeouts.get_mut(&id).unwrap().drain() {
+                self.events.remove(&key);
+            }
+        }
+        // The consensus module orders timeouts and messages to different peers by hash map
+        // iteration, so they are sorted for the random choices to be made in a fixed order.
+        timeouts.sort_by_key(timeout_order);
+        for timeout in timeouts {
+            let at = self.now + timeout.duration_ms(&self.timeout_config, &mut self.rng);
+            let key = self.schedule(at, Event::Timeout(id, timeout));
+            if let Some(replaced) = self.timeouts.get_mut(&id).unwrap().insert(timeout, key) {
+                self.events.remove(&replaced);
+            }
+        }
+        peer_messages.sort_by_key(|&(to, _)| to);
+        for (to, message) in peer_messages {
+            if self.rng.gen::<f64>() < self.faults.drop {
+                continue;
+            }
+            let copies = if self.rng.gen::<f64>() < self.faults.duplicate { 2 } else { 1 };
+            for _ in 0..copies {
+                let delay = self.rng
+                                .gen_range(self.faults.min_delay_ms, self.faults.max_delay_ms + 1);
+                let at = self.now + delay;
+                self.schedule(at,
+                              Event::Deliver {
+                                  from: id,
+                                  to: to,
+                                  message: message.clone(),
+                              });
+            }
+        }
+
+        // A `Server` connects to the peers which join the configuration, and resets its
+        // connection to them once connected.
+        if let Some(peers) = peers {
+            let mut joined = peers.iter()
+                                  .filter(|&(peer, _)| !self.connections[&id].contains(peer))
+                                  .map(|(&peer, &addr)| (peer, addr))
+                                  .collect::<Vec<_>>();
+            joined.sort_by_key(|&(peer, _)| peer);
+            self.connections.insert(id, peers.keys().cloned().collect());
+            for (peer, addr) in joined {
+                let mut actions = Actions::new();
+                self.peers.get_mut(&id).unwrap().peer_connection_reset(peer, addr, &mut actions);
+                self.execute(id, actions);
+            }
+        }
+    }
+
+    /// Schedules an event, and returns its key.
+    fn schedule(&mut self, at: u64, event: Event) -> (u64, u64) {
+        let key = (at, self.scheduled);
+        self.scheduled += 1;
+        self.events.insert(key, event);
+        key
+    }
+
+    /// Returns whether the network carries messages between the peers.
+    fn connected(&self, from: ServerId, to: ServerId) -> bool {
+        self.partition.get(&from) == self.partition.get(&to)
+    }
+
+    /// Checks election safety, log matching and state machine safety.
+    fn check_invariants(&mut self) {
+        // Election safety: at most one leader is elected in a term.
+        for (&id, peer) in &self.peers {
+            if peer.is_leader() {
+                let term = peer.log().current_term().unwrap();
+                let leader = *self.leaders.entry(term).or_insert(id);
+                assert!(leader == id,
+                        "seed {}: {:?} and {:?} are both leaders of {:?}",
+                        self.seed,
+                        leader,
+                        id,
+                        term);
+            }
+        }
+
+        // Log matching: if two logs hold an entry with the same index and term, they are
+        // identical up to that index. Only the entries which neither peer has compacted are
+        // compared.
+        let logs = self.peers
+                       .values()
+                       .map(|peer| {
+                           let log = peer.log();
+                           let first_log_index = log.first_log_index().unwrap();
+                           let latest_log_index = log.latest_log_index().unwrap();
+                           (first_log_index,
+                            log.entries(first_log_index, latest_log_index + 1).unwrap())
+                       })
+                       .collect::<Vec<_>>();
+        let ids = self.ids();
+        for a in 0..logs.len() {
+            for b in a + 1..logs.len() {
+                let (first_a, ref entries_a) = logs[a];
+                let (first_b, ref entries_b) = logs[b];
+                let lo = cmp::max(first_a, first_b).as_u64();
+                let hi = cmp::min(first_a + entries_a.len() as u64,
+                                  first_b + entries_b.len() as u64)
+                             .as_u64();
+                let entry_a = |index: u64| &entries_a[(index - first_a.as_u64()) as usize];
+                let entry_b = |index: u64| &entries_b[(index - first_b.as_u64()) as usize];
+                if let Some(last) = (lo..hi).rev().find(|&n| entry_a(n).0 == entry_b(n).0) {
+                    assert!((lo..last + 1).all(|n| entry_a(n) == entry_b(n)),
+                            "seed {}: the logs of {:?} and {:?} match at index {} but differ \
+                             before it",
+                            self.seed,
+                            ids[a],
+                            ids[b],
+                            last);
+                }
+            }
+        }
+
+        // State machine safety: no two peers commit, and so apply, different entries at an
+        // index.
+        for (&(first_log_index, ref log), peer) in logs.iter().zip(self.peers.values()) {
+            let commit_index = peer.commit_index().as_u64();
+            for (index, entry) in (first_log_index.as_u64()..commit_index + 1).zip(log) {
+                match self.committed.entry(LogIndex(index)) {
+                    btree_map::Entry::Occupied(committed) => {
+                        assert!(committed.get() == entry,
+                                "seed {}: {:?} committed an entry at index {} which differs \
+                                 from another peer's",
+                                self.seed,
+                                peer.id(),
+                                index);
+                    }
+                    btree_map::Entry::Vacant(committed) => {
+                        committed.insert(entry.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns a key which orders timeouts.
+fn timeout_order(timeout: &ConsensusTimeout) -> (u8, ServerId) {
+    match *timeout {
+        ConsensusTimeout::Election => (0, ServerId(0)),
+        ConsensusTimeout::Heartbeat(peer) => (1, peer),
+        ConsensusTimeout::LeadershipTransfer => (2, ServerId(0)),
+        ConsensusTimeout::CheckQuorum => (3, ServerId(0)),
+    }
+}
+
+fn into_reader<A>(message: &Builder<A>) -> Reader<OwnedSegments>
+    where A: Allocator
+{
+    let mut bytes = Vec::new();
+    serialize::write_message(&mut bytes, message).unwrap();
+    serialize::read_message(&mut &bytes[..], ReaderOptions::new()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use rand::Rng;
+
+    use EntryKind;
+    use consensus::ConsensusConfiguration;
+    use super::{Faults, Simulation};
+
+    /// Runs a cluster through a series of partitions on a lossy network, then heals the network
+    /// and lets the cluster recover. With `changes`, servers are also removed from the
+    /// configuration and added back meanwhile, and every server is added back before the end.
+    fn run_faulty(mut simulation: Simulation, changes: bool) -> Simulation {
+        simulation.set_faults(Faults {
+            drop: 0.1,
+            duplicate: 0.1,
+            min_delay_ms: 1,
+            max_delay_ms: 40,
+        });
+        let ids = simulation.ids();
+        for round in 0..40 {
+            if round % 10 == 0 {
+                let split = simulation.rng().gen_range(0, ids.len());
+                match simulation.rng().gen_range(0, 3) {
+                    0 => simulation.heal(),
+                    _ => simulation.partition(&[&ids[..split], &ids[split..]]),
+                }
+            }
+            if changes && round % 10 == 5 {
+                let id = ids[simulation.rng().gen_range(0, ids.len())];
+                // At least three voters are kept, so that the cluster can recover.
+                let members = simulation.leader().map_or(0, |leader| {
+                    simulation.configuration(leader).len()
+                });
+                if members > 3 && simulation.rng().gen() {
+                    simulation.remove_server(id);
+                } else {
+                    simulation.add_server(id);
+                }
+            }
+            simulation.propose();
+            simulation.run_for(100);
+        }
+
+        simulation.heal();
+        simulation.set_faults(Faults::none());
+        simulation.run_for(2000);
+        if changes {
+            for &id in &ids {
+                simulation.add_server(id);
+                simulation.run_for(500);
+            }
+        }
+        simulation.propose();
+        simulation.run_for(500);
+        simulation
+    }
+
+    /// Checks that the cluster has a leader whose configuration holds every server, and that
+    /// every server has caught up with it.
+    fn check_recovered(simulation: &Simulation, seed: u64) {
+        let leader = simulation.leader();
+        assert!(leader.is_some(), "seed {}: no leader after healing", seed);
+        assert_eq!(simulation.ids(),
+                   simulation.configuration(leader.unwrap()),
+                   "seed {}: servers missing from the configuration",
+                   seed);
+        let commit_index = simulation.commit_index();
+        for id in simulation.ids() {
+            assert_eq!(commit_index,
+                       simulation.peer_commit_index(id),
+                       "seed {}: {:?} did not catch up",
+                       seed,
+                       id);
+        }
+    }
+
+    /// Tests that every proposal commits on a reliable network.
+    #[test]
+    fn test_reliable_network() {
+        let mut simulation = Simulation::new(3, 1);
+        simulation.run_for(1000);
+        assert!(simulation.leader().is_some());
+        for _ in 0..20 {
+            simulation.propose();
+            simulation.run_for(50);
+        }
+        let proposals = simulation.committed()
+                                  .values()
+                                  .filter(|&&(_, kind, _)| kind == EntryKind::Normal)
+                                  .count();
+        assert_eq!(20, proposals);
+    }
+
+    /// Tests that the safety properties hold under network faults, and that the cluster
+    /// recovers once they stop.
+    #[test]
+    fn test_network_faults() {
+        for seed in 0..20 {
+            let simulation = run_faulty(Simulation::new(5, seed), false);
+            check_recovered(&simulation, seed);
+        }
+    }
+
+    /// Tests that the safety properties hold under network faults while the peers compact their
+    /// logs, so that lagging peers are sent snapshots.
+    #[test]
+    fn test_compaction_faults() {
+        let config = ConsensusConfiguration {
+            check_quorum: Some(Duration::from_millis(150)),
+            snapshot_threshold: Some(8),
+            ..ConsensusConfiguration::default()
+        };
+        for seed in 0..20 {
+            let simulation = run_faulty(Simulation::with_config(5, seed, config.clone()), false);
+            check_recovered(&simulation, seed);
+        }
+    }
+
+    /// Tests that the safety properties hold under network faults while servers are removed from
+    /// the configuration and added back, and that every server rejoins once the faults stop.
+    #[test]
+    fn test_membership_faults() {
+        for seed in 0..20 {
+            let simulation = run_faulty(Simulation::new(5, seed), true);
+            check_recovered(&simulation, seed);
+        }
+    }
+
+    /// Tests that a seed reproduces a run exactly.
+    #[test]
+    fn test_reproducible() {
+        let first = run_faulty(Simulation::new(5, 7), true);
+        let second = run_faulty(Simulation::new(5, 7), true);
+        assert_eq!(first.committed(), second.committed());
+        assert_eq!(first.leader(), second.leader());
+        assert_eq!(first.scheduled, second.scheduled);
+    }
+}
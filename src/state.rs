@@ -1,8 +1,12 @@
+use std::{cmp, mem};
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
-use ClientId;
+use consensus::RequestId;
+use EntryKind;
 use LogIndex;
 use ServerId;
+use Term;
 
 /// Consensus modules can be in one of three state:
 ///
@@ -19,13 +23,69 @@ pub enum ConsensusState {
     Leader,
 }
 
+/// A client query waiting for the leader to confirm its leadership, or for a follower to apply
+/// the read index fetched from the leader.
+#[derive(Clone, Debug)]
+pub struct PendingQuery {
+    /// The client request which carried the query.
+    pub request: RequestId,
+    /// The leader's commit index when the query was received, or the no-op entry of its term if
+    /// that had yet to commit. The query is answered once it is applied.
+    pub read_index: LogIndex,
+    /// The read sequence number which a majority must acknowledge before the query is answered.
+    /// Unused by followers.
+    pub sequence: u64,
+    /// The query itself.
+    pub query: Vec<u8>,
+}
+
+/// A leadership transfer in progress.
+#[derive(Clone, Debug)]
+pub struct LeadershipTransfer {
+    /// The voter which should take over leadership.
+    pub target: ServerId,
+    /// The client request for the transfer, if any, which has not been answered yet.
+    pub request: Option<RequestId>,
+    /// Whether the target has caught up and been told to start an election.
+    pub timeout_now_sent: bool,
+}
+
 /// The state associated with a Raft consensus module in the `Leader` state.
 #[derive(Clone, Debug)]
 pub struct LeaderState {
     next_index: HashMap<ServerId, LogIndex>,
     match_index: HashMap<ServerId, LogIndex>,
+    /// Peers which replicate the log without voting.
+    learners: HashSet<ServerId>,
+    /// The sequence number of the latest round of leadership confirmation for reads.
+    read_sequence: u64,
+    /// The latest read sequence number acknowledged by each follower.
+    read_acks: HashMap<ServerId, u64>,
+    /// When each unacknowledged round of leadership confirmation was started, if leader leases
+    /// are enabled.
+    round_starts: VecDeque<(u64, Instant)>,
+    /// The instant until which the leader may answer queries without confirming its leadership.
+    lease_expiry: Option<Instant>,
+    /// Whether the lease may no longer be used in this term.
+    lease_revoked: bool,
+    /// When each follower last responded to the leader.
+    last_response: HashMap<ServerId, Instant>,
+    /// The previous log index and the latest entry index of each AppendEntries request in flight
+    /// to each follower, oldest first.
+    in_flight: HashMap<ServerId, VecDeque<(LogIndex, LogIndex)>>,
+    /// Proposals received since the last log append, which are appended together.
+    batch: Vec<(RequestId, EntryKind, Vec<u8>)>,
+    /// The total size of the batched proposals.
+    batch_bytes: usize,
     /// Stores in-flight client proposals.
-    pub proposals: VecDeque<(ClientId, LogIndex)>,
+    pub proposals: VecDeque<(RequestId, LogIndex)>,
+    /// Stores client queries waiting to be answered, in the order they were received.
+    pub queries: VecDeque<PendingQuery>,
+    /// The leadership transfer in progress, during which no new entries are accepted.
+    pub transfer: Option<LeadershipTransfer>,
+    /// The index of the no-op entry which begins the leader's term. Until it commits, the
+    /// leader does not know the commit index.
+    pub term_start: LogIndex,
 }
 
 impl LeaderState {
@@ -39,16 +99,33 @@ impl LeaderState {
     pub fn new(latest_log_index: LogIndex, peers: &HashSet<ServerId>) -> LeaderState {
         let next_index = peers.iter().cloned().map(|peer| (peer, latest_log_index + 1)).collect();
         let match_index = peers.iter().cloned().map(|peer| (peer, LogIndex::from(0))).collect();
+        let read_acks = peers.iter().cloned().map(|peer| (peer, 0)).collect();
+        let now = Instant::now();
+        let last_response = peers.iter().cloned().map(|peer| (peer, now)).collect();
+        let in_flight = peers.iter().cloned().map(|peer| (peer, VecDeque::new())).collect();
 
         LeaderState {
             next_index: next_index,
             match_index: match_index,
+            learners: HashSet::new(),
+            read_sequence: 0,
+            read_acks: read_acks,
+            round_starts: VecDeque::new(),
+            lease_expiry: None,
+            lease_revoked: false,
+            last_response: last_response,
+            in_flight: in_flight,
+            batch: Vec::new(),
+            batch_bytes: 0,
             proposals: VecDeque::new(),
+            queries: VecDeque::new(),
+            transfer: None,
+            term_start: latest_log_index + 1,
         }
     }
 
     /// Returns the next log entry index of the follower.
-    pub fn next_index(&mut self, follower: &ServerId) -> LogIndex {
+    pub fn next_index(&self, follower: &ServerId) -> LogIndex {
         self.next_index[follower]
     }
 
@@ -57,27 +134,230 @@ impl LeaderState {
         self.next_index.insert(follower, index);
     }
 
+    /// Returns the index of the highest log entry known to be replicated on the follower.
+    pub fn match_index(&self, follower: &ServerId) -> LogIndex {
+        self.match_index[follower]
+    }
+
     /// Sets the index of the highest log entry known to be replicated on the
-    /// follower.
+    /// follower. Responses may be reordered or duplicated, so the index never moves backwards.
     pub fn set_match_index(&mut self, follower: ServerId, index: LogIndex) {
-        self.match_index.insert(follower, index);
+        if let Some(match_index) = self.match_index.get_mut(&follower) {
+            *match_index = cmp::max(*match_index, index);
+        }
     }
 
-    /// Counts the number of followers containing the given log index.
+    /// Starts tracking a peer which joined the cluster configuration.
+    pub fn add_peer(&mut self, peer: ServerId, latest_log_index: LogIndex, now: Instant) {
+        self.next_index.insert(peer, latest_log_index + 1);
+        self.match_index.insert(peer, LogIndex::from(0));
+        self.read_acks.insert(peer, 0);
+        self.last_response.insert(peer, now);
+        self.in_flight.insert(peer, VecDeque::new());
+    }
+
+    /// Stops tracking a peer which left the cluster configuration.
+    pub fn remove_peer(&mut self, peer: &ServerId) {
+        self.next_index.remove(peer);
+        self.match_index.remove(peer);
+        self.read_acks.remove(peer);
+        self.last_response.remove(peer);
+        self.in_flight.remove(peer);
+        self.learners.remove(peer);
+    }
+
+    /// Sets whether the peer is a learner. Learners are replicated to, but are not counted by
+    /// `count_match_indexes`.
+    pub fn set_learner(&mut self, peer: ServerId, learner: bool) {
+        if learner {
+            self.learners.insert(peer);
+        } else {
+            self.learners.remove(&peer);
+        }
+    }
+
+    /// Counts the number of voting followers containing the given log index.
     pub fn count_match_indexes(&self, index: LogIndex) -> usize {
         // +1 for self.
-        self.match_index.values().filter(|&&i| i >= index).count() + 1
+        self.match_index
+            .iter()
+            .filter(|&(peer, &i)| i >= index && !self.learners.contains(peer))
+            .count() + 1
+    }
+
+    /// Returns the sequence number of the latest round of leadership confirmation.
+    pub fn read_sequence(&self) -> u64 {
+        self.read_sequence
+    }
+
+    /// Starts a new round of leadership confirmation, and returns its sequence number.
+    pub fn start_read_round(&mut self) -> u64 {
+        self.read_sequence += 1;
+        self.read_sequence
+    }
+
+    /// Records that the follower has acknowledged the read sequence number.
+    pub fn ack_read(&mut self, follower: ServerId, sequence: u64) {
+        if let Some(acked) = self.read_acks.get_mut(&follower) {
+            *acked = cmp::max(*acked, sequence);
+        }
+    }
+
+    /// Counts the number of voting followers which have acknowledged the read sequence number.
+    pub fn count_read_acks(&self, sequence: u64) -> usize {
+        // +1 for self.
+        self.read_acks
+            .iter()
+            .filter(|&(peer, &acked)| acked >= sequence && !self.learners.contains(peer))
+            .count() + 1
     }
 
-    /// Reinitializes the state following an election.
-    pub fn reinitialize(&mut self, latest_log_index: LogIndex) {
+    /// Records when a round of leadership confirmation was started, so that the lease can be
+    /// renewed once a majority acknowledges it. Rounds too old to extend the lease are dropped.
+    pub fn record_round_start(&mut self, sequence: u64, now: Instant, lease: Duration) {
+        while self.round_starts.front().map_or(false, |&(_, start)| start + lease <= now) {
+            self.round_starts.pop_front();
+        }
+        self.round_starts.push_back((sequence, now));
+    }
+
+    /// Renews the lease from the latest round acknowledged by a majority of voters. The lease
+    /// runs from the start of the round, since the followers may have acknowledged it at any
+    /// point after.
+    pub fn renew_lease(&mut self, majority: usize, include_self: bool, lease: Duration) {
+        let mut acks: Vec<u64> = self.read_acks
+                                     .iter()
+                                     .filter(|&(peer, _)| !self.learners.contains(peer))
+                                     .map(|(_, &acked)| acked)
+                                     .collect();
+        if include_self {
+            acks.push(self.read_sequence);
+        }
+        if acks.len() < majority {
+            return;
+        }
+        acks.sort_by(|a, b| b.cmp(a));
+        let acked = acks[majority - 1];
+        while let Some(&(sequence, start)) = self.round_starts.front() {
+            if sequence > acked {
+                break;
+            }
+            self.round_starts.pop_front();
+            let expiry = start + lease;
+            self.lease_expiry = Some(self.lease_expiry.map_or(expiry, |e| cmp::max(e, expiry)));
+        }
+    }
+
+    /// Returns whether the leader holds the lease at the given instant.
+    pub fn holds_lease(&self, now: Instant) -> bool {
+        !self.lease_revoked && self.lease_expiry.map_or(false, |expiry| now < expiry)
+    }
+
+    /// Gives up the lease for the rest of the term, once another server may be elected without
+    /// waiting for it to expire.
+    pub fn revoke_lease(&mut self) {
+        self.lease_revoked = true;
+    }
+
+    /// Records that the follower responded to the leader.
+    pub fn record_response(&mut self, peer: ServerId, now: Instant) {
+        self.last_response.insert(peer, now);
+    }
+
+    /// Counts the number of voting followers which have responded within `period` of `now`.
+    pub fn count_active(&self, now: Instant, period: Duration) -> usize {
+        // +1 for self.
+        self.last_response
+            .iter()
+            .filter(|&(peer, &last)| {
+                !self.learners.contains(peer) && now.duration_since(last) < period
+            })
+            .count() + 1
+    }
+
+    /// Returns the number of AppendEntries requests carrying entries in flight to the follower.
+    pub fn in_flight(&self, follower: &ServerId) -> usize {
+        self.in_flight[follower].len()
+    }
+
+    /// Records that the entries following `prev_log_index` up to `index` were sent to the
+    /// follower.
+    pub fn send_entries(&mut self, follower: ServerId, prev_log_index: LogIndex, index: LogIndex) {
+        self.in_flight.get_mut(&follower).unwrap().push_back((prev_log_index, index));
+    }
+
+    /// Returns whether a request with the previous log index `prev_log_index` is in flight to the
+    /// follower.
+    pub fn is_in_flight(&self, follower: &ServerId, prev_log_index: LogIndex) -> bool {
+        self.in_flight[follower].iter().any(|&(prev, _)| prev == prev_log_index)
+    }
+
+    /// Records that the follower holds the entries up to `index`, completing the requests which
+    /// carried them.
+    pub fn ack_entries(&mut self, follower: ServerId, index: LogIndex) {
+        let in_flight = self.in_flight.get_mut(&follower).unwrap();
+        while in_flight.front().map_or(false, |&(_, sent)| sent <= index) {
+            in_flight.pop_front();
+        }
+    }
+
+    /// Forgets the requests in flight to the follower, after they were rejected or lost.
+    pub fn clear_in_flight(&mut self, follower: ServerId) {
+        self.in_flight.get_mut(&follower).unwrap().clear();
+    }
+
+    /// Adds a proposal to the batch, returning the number of proposals and the total number of
+    /// bytes batched.
+    pub fn batch_proposal(&mut self,
+                          request: RequestId,
+                          kind: EntryKind,
+                          entry: Vec<u8>)
+                          -> (usize, usize) {
+        self.batch_bytes += entry.len();
+        self.batch.push((request, kind, entry));
+        (self.batch.len(), self.batch_bytes)
+    }
+
+    /// Returns whether any proposals are batched.
+    pub fn has_batch(&self) -> bool {
+        !self.batch.is_empty()
+    }
+
+    /// Removes and returns the batched proposals.
+    pub fn take_batch(&mut self) -> Vec<(RequestId, EntryKind, Vec<u8>)> {
+        self.batch_bytes = 0;
+        mem::replace(&mut self.batch, Vec::new())
+    }
+
+    /// Reinitializes the state following an election won at time `now`.
+    pub fn reinitialize(&mut self, latest_log_index: LogIndex, now: Instant) {
         for next_index in self.next_index.values_mut() {
             *next_index = latest_log_index + 1;
         }
         for match_index in self.match_index.values_mut() {
             *match_index = LogIndex::from(0);
         }
+        // The read sequence keeps increasing across terms, so acknowledgements from a previous
+        // term can never confirm a new read.
+        for acked in self.read_acks.values_mut() {
+            *acked = 0;
+        }
+        self.round_starts.clear();
+        self.lease_expiry = None;
+        self.lease_revoked = false;
+        // Followers are given a full period to respond to the new leader.
+        for last in self.last_response.values_mut() {
+            *last = now;
+        }
+        for in_flight in self.in_flight.values_mut() {
+            in_flight.clear();
+        }
+        self.batch.clear();
+        self.batch_bytes = 0;
         self.proposals.clear();
+        self.queries.clear();
+        self.transfer = None;
+        self.term_start = latest_log_index + 1;
     }
 }
 
@@ -85,12 +365,17 @@ impl LeaderState {
 #[derive(Clone, Debug)]
 pub struct CandidateState {
     granted_votes: HashSet<ServerId>,
+    /// Whether the election was started at the request of the leader.
+    pub leadership_transfer: bool,
 }
 
 impl CandidateState {
     /// Creates a new `CandidateState`.
     pub fn new() -> CandidateState {
-        CandidateState { granted_votes: HashSet::new() }
+        CandidateState {
+            granted_votes: HashSet::new(),
+            leadership_transfer: false,
+        }
     }
 
     /// Records a vote from `voter`.
@@ -106,6 +391,7 @@ impl CandidateState {
     /// Clears the vote count.
     pub fn clear(&mut self) {
         self.granted_votes.clear();
+        self.leadership_transfer = false;
     }
 
     /// Returns whether the peer has voted in the current election.
@@ -126,6 +412,24 @@ pub struct FollowerState {
     /// otherwise left untouched.
     /// See see ktoso/akka-raft#66.
     pub min_index: LogIndex,
+    /// A snapshot which is being received from the leader in chunks: the index and term of the
+    /// last entry it covers, and the bytes received so far. It is reset on set_leader().
+    pub snapshot: Option<(LogIndex, Term, Vec<u8>)>,
+    /// When the follower last heard from the leader. While leader leases are enabled, votes are
+    /// refused for the lease duration after contact.
+    pub leader_contact: Option<Instant>,
+    /// Whether the follower has heard from the leader since its last election timeout. Pre-votes
+    /// are refused while the leader is active.
+    pub leader_active: bool,
+    /// Whether the follower is collecting pre-votes. The votes are tallied in the
+    /// `CandidateState`.
+    pub pre_candidate: bool,
+    /// The sequence number of the latest read index requested from the leader.
+    pub read_sequence: u64,
+    /// Bounded-staleness queries waiting for the leader's read index, by read sequence number.
+    pub read_requests: HashMap<u64, (RequestId, Vec<u8>)>,
+    /// Bounded-staleness queries waiting for the read index to be applied.
+    pub queries: Vec<PendingQuery>,
 }
 
 impl FollowerState {
@@ -134,6 +438,13 @@ impl FollowerState {
         FollowerState {
             leader: None,
             min_index: LogIndex(0),
+            snapshot: None,
+            leader_contact: None,
+            leader_active: false,
+            pre_candidate: false,
+            read_sequence: 0,
+            read_requests: HashMap::new(),
+            queries: Vec::new(),
         }
     }
 
@@ -141,12 +452,33 @@ impl FollowerState {
     pub fn set_leader(&mut self, leader: ServerId) {
         self.leader = Some(leader);
         self.min_index = LogIndex(0);
+        self.snapshot = None;
+        self.pre_candidate = false;
+    }
+
+    /// Queues a bounded-staleness query until the leader returns its read index, and returns the
+    /// read sequence number to request it with.
+    pub fn request_read_index(&mut self, request: RequestId, query: Vec<u8>) -> u64 {
+        self.read_sequence += 1;
+        self.read_requests.insert(self.read_sequence, (request, query));
+        self.read_sequence
+    }
+
+    /// Removes every pending query, returning the client requests which carried them.
+    pub fn drain_queries(&mut self) -> Vec<RequestId> {
+        let mut requests: Vec<RequestId> = self.read_requests
+                                               .drain()
+                                               .map(|(_, (request, _))| request)
+                                               .collect();
+        requests.extend(self.queries.drain(..).map(|query| query.request));
+        requests
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
+    use std::time::{Duration, Instant};
 
     use {LogIndex, ServerId};
     use state::LeaderState;
@@ -178,5 +510,92 @@ mod tests {
         leader_state.set_match_index(ServerId(1), LogIndex(1));
         leader_state.set_match_index(ServerId(2), LogIndex(1));
         assert_eq!(3, leader_state.count_match_indexes(LogIndex(1)));
+
+        // A reordered response does not move the match index backwards.
+        leader_state.set_match_index(ServerId(1), LogIndex(0));
+        assert_eq!(LogIndex(1), leader_state.match_index(&ServerId(1)));
+
+        // Removed peers no longer count towards the match.
+        leader_state.remove_peer(&ServerId(2));
+        assert_eq!(2, leader_state.count_match_indexes(LogIndex(1)));
+        leader_state.add_peer(ServerId(4), LogIndex(1), Instant::now());
+        assert_eq!(2, leader_state.count_match_indexes(LogIndex(1)));
+
+        // Learners are not counted until they are promoted.
+        leader_state.set_learner(ServerId(4), true);
+        leader_state.set_match_index(ServerId(4), LogIndex(1));
+        assert_eq!(2, leader_state.count_match_indexes(LogIndex(1)));
+        leader_state.set_learner(ServerId(4), false);
+        assert_eq!(3, leader_state.count_match_indexes(LogIndex(1)));
+    }
+
+    /// Tests that reads are only confirmed by acknowledgements of the current round or later.
+    #[test]
+    fn test_count_read_acks() {
+        let mut peers = HashSet::new();
+        peers.insert(ServerId(1));
+        peers.insert(ServerId(2));
+        let mut leader_state = LeaderState::new(LogIndex(0), &peers);
+
+        let first = leader_state.start_read_round();
+        leader_state.ack_read(ServerId(1), first);
+        assert_eq!(2, leader_state.count_read_acks(first));
+
+        let second = leader_state.start_read_round();
+        assert_eq!(1, leader_state.count_read_acks(second));
+        leader_state.ack_read(ServerId(2), second);
+        leader_state.ack_read(ServerId(2), first);
+        assert_eq!(2, leader_state.count_read_acks(second));
+        assert_eq!(3, leader_state.count_read_acks(first));
+    }
+
+    /// Tests that the lease runs from the start of the latest round acknowledged by a majority.
+    #[test]
+    fn test_renew_lease() {
+        let mut peers = HashSet::new();
+        peers.insert(ServerId(1));
+        peers.insert(ServerId(2));
+        let mut leader_state = LeaderState::new(LogIndex(0), &peers);
+        let lease = Duration::from_millis(100);
+        let start = Instant::now();
+
+        let first = leader_state.start_read_round();
+        leader_state.record_round_start(first, start, lease);
+        let second = leader_state.start_read_round();
+        leader_state.record_round_start(second, start + Duration::from_millis(50), lease);
+        leader_state.renew_lease(2, true, lease);
+        assert!(!leader_state.holds_lease(start));
+
+        leader_state.ack_read(ServerId(1), first);
+        leader_state.renew_lease(2, true, lease);
+        assert!(leader_state.holds_lease(start + Duration::from_millis(99)));
+        assert!(!leader_state.holds_lease(start + Duration::from_millis(100)));
+
+        leader_state.ack_read(ServerId(2), second);
+        leader_state.renew_lease(2, true, lease);
+        assert!(leader_state.holds_lease(start + Duration::from_millis(149)));
+    }
+
+    /// Tests that requests in flight are completed by acknowledgements of their entries.
+    #[test]
+    fn test_in_flight() {
+        let mut peers = HashSet::new();
+        peers.insert(ServerId(1));
+        let mut leader_state = LeaderState::new(LogIndex(0), &peers);
+
+        leader_state.send_entries(ServerId(1), LogIndex(0), LogIndex(2));
+        leader_state.send_entries(ServerId(1), LogIndex(2), LogIndex(5));
+        leader_state.send_entries(ServerId(1), LogIndex(5), LogIndex(7));
+        assert_eq!(3, leader_state.in_flight(&ServerId(1)));
+        assert!(leader_state.is_in_flight(&ServerId(1), LogIndex(2)));
+        assert!(!leader_state.is_in_flight(&ServerId(1), LogIndex(3)));
+
+        leader_state.ack_entries(ServerId(1), LogIndex(1));
+        assert_eq!(3, leader_state.in_flight(&ServerId(1)));
+        leader_state.ack_entries(ServerId(1), LogIndex(5));
+        assert_eq!(1, leader_state.in_flight(&ServerId(1)));
+        assert!(!leader_state.is_in_flight(&ServerId(1), LogIndex(2)));
+        leader_state.clear_in_flight(ServerId(1));
+        assert_eq!(0, leader_state.in_flight(&ServerId(1)));
     }
 }
@@ -32,9 +32,10 @@ pub trait StateMachine: Debug + Send + 'static {
     /// Returns an application-specific result value.
     fn query(&self, query: &[u8]) -> Vec<u8>;
 
-    /// Take a snapshot of the state machine.
+    /// Take a snapshot of the state machine. The snapshot must capture the effects of every
+    /// command applied so far, since the log entries it covers are discarded afterwards.
     fn snapshot(&self) -> Vec<u8>;
 
-    /// Restore a snapshot of the state machine.
+    /// Restore a snapshot of the state machine, replacing its current state.
     fn restore_snapshot(&mut self, snapshot: Vec<u8>) -> ();
 }
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+
+use transport::{Transport, unspecified_addr};
+
+/// Connects servers within a single process over channels, without any sockets. Clones of a
+/// `ChannelTransport` share the same set of listeners, so servers given clones of one transport
+/// can reach each other. Addresses are only names; nothing binds to them.
+#[derive(Clone, Default)]
+pub struct ChannelTransport {
+    /// The listeners, by address, along with their readiness.
+    listeners: Arc<Mutex<HashMap<SocketAddr, (mpsc::Sender<ChannelStream>, SetReadiness)>>>,
+}
+
+impl ChannelTransport {
+    /// Creates a transport with no listeners.
+    pub fn new() -> ChannelTransport {
+        ChannelTransport::default()
+    }
+}
+
+impl Transport for ChannelTransport {
+    type Stream = ChannelStream;
+    type Listener = ChannelListener;
+
+    /// Starts listening at the provided address. Replaces any previous listener at the address,
+    /// so that a server can be restarted.
+    fn listen(&self, addr: &SocketAddr) -> io::Result<ChannelListener> {
+        let (sender, incoming) = mpsc::channel();
+        let (registration, readiness) = Registration::new2();
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.insert(*addr, (sender, readiness.clone()));
+        Ok(ChannelListener {
+            addr: *addr,
+            incoming: incoming,
+            registration: registration,
+            readiness: readiness,
+        })
+    }
+
+    fn local_addr(&self, listener: &ChannelListener) -> io::Result<SocketAddr> {
+        Ok(listener.addr)
+    }
+
+    fn accept(&self,
+              listener: &ChannelListener)
+              -> io::Result<Option<(ChannelStream, SocketAddr)>> {
+        match listener.incoming.try_recv() {
+            Ok(stream) => return Ok(Some((stream, unspecified_addr()))),
+            Err(mpsc::TryRecvError::Disconnected) => return Ok(None),
+            Err(mpsc::TryRecvError::Empty) => (),
+        }
+        try!(listener.readiness.set_readiness(Ready::empty()));
+        // A connection may have arrived before the readiness was cleared.
+        match listener.incoming.try_recv() {
+            Ok(stream) => {
+                try!(listener.readiness.set_readiness(Ready::readable()));
+                Ok(Some((stream, unspecified_addr())))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<ChannelStream> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let (local, remote) = ChannelStream::pair();
+        let delivered = match listeners.get(addr) {
+            Some(&(ref sender, ref readiness)) => {
+                sender.send(remote).is_ok() && readiness.set_readiness(Ready::readable()).is_ok()
+            }
+            None => false,
+        };
+        if !delivered {
+            // The listener was dropped, so the entry is stale.
+            listeners.remove(addr);
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                      format!("no listener at {}", addr)));
+        }
+        Ok(local)
+    }
+}
+
+/// Listens for connections over a `ChannelTransport`.
+pub struct ChannelListener {
+    addr: SocketAddr,
+    incoming: mpsc::Receiver<ChannelStream>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+impl Evented for ChannelListener {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+/// One end of a connection over a `ChannelTransport`. Each write is sent to the other end as a
+/// chunk of bytes.
+pub struct ChannelStream {
+    /// Sends to the other end. Taken when the stream is dropped.
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    /// The rest of the chunk being read.
+    chunk: Cursor<Vec<u8>>,
+    registration: Registration,
+    readiness: SetReadiness,
+    /// The readiness of the other end.
+    remote_readiness: SetReadiness,
+}
+
+impl ChannelStream {
+    /// Creates the two ends of a connection.
+    fn pair() -> (ChannelStream, ChannelStream) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        let (a_registration, a_readiness) = Registration::new2();
+        let (b_registration, b_readiness) = Registration::new2();
+        // The streams never fill up, so they are always writable.
+        let _ = a_readiness.set_readiness(Ready::writable());
+        let _ = b_readiness.set_readiness(Ready::writable());
+        let a = ChannelStream {
+            sender: Some(a_sender),
+            receiver: a_receiver,
+            chunk: Cursor::new(Vec::new()),
+            registration: a_registration,
+            readiness: a_readiness.clone(),
+            remote_readiness: b_readiness.clone(),
+        };
+        let b = ChannelStream {
+            sender: Some(b_sender),
+            receiver: b_receiver,
+            chunk: Cursor::new(Vec::new()),
+            registration: b_registration,
+            readiness: b_readiness,
+            remote_readiness: a_readiness,
+        };
+        (a, b)
+    }
+}
+
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = try!(self.chunk.read(buf));
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.receiver.try_recv() {
+                Ok(chunk) => {
+                    self.chunk = Cursor::new(chunk);
+                    continue;
+                }
+                // The other end was dropped.
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(0),
+                Err(mpsc::TryRecvError::Empty) => (),
+            }
+            try!(self.readiness.set_readiness(Ready::writable()));
+            // A chunk may have arrived before the readiness was cleared.
+            match self.receiver.try_recv() {
+                Ok(chunk) => {
+                    try!(self.readiness.set_readiness(Ready::readable() | Ready::writable()));
+                    self.chunk = Cursor::new(chunk);
+                }
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(0),
+                Err(mpsc::TryRecvError::Empty) => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data available"));
+                }
+            }
+        }
+    }
+}
+
+impl Write for ChannelStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sent = self.sender.as_ref().map_or(false, |sender| sender.send(buf.to_vec()).is_ok());
+        if !sent {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+        }
+        try!(self.remote_readiness.set_readiness(Ready::readable() | Ready::writable()));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ChannelStream {
+    fn drop(&mut self) {
+        // Close the channel first, so that the other end reads the end of the stream once it is
+        // woken.
+        self.sender.take();
+        let _ = self.remote_readiness.set_readiness(Ready::readable() | Ready::writable());
+    }
+}
+
+impl Evented for ChannelStream {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use transport::Transport;
+    use super::*;
+
+    #[test]
+    fn test_stream() {
+        let transport = ChannelTransport::new();
+        let addr = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        let listener = transport.listen(&addr).unwrap();
+        assert!(transport.accept(&listener).unwrap().is_none());
+
+        let mut client = transport.connect(&addr).unwrap();
+        let (mut server, _) = transport.accept(&listener).unwrap().unwrap();
+
+        let mut buf = [0; 8];
+        assert_eq!(ErrorKind::WouldBlock, server.read(&mut buf).unwrap_err().kind());
+        client.write_all(b"foo").unwrap();
+        client.write_all(b"bar").unwrap();
+        assert_eq!(3, server.read(&mut buf).unwrap());
+        assert_eq!(3, server.read(&mut buf[3..]).unwrap());
+        assert_eq!(b"foobar", &buf[..6]);
+
+        // The end of the stream is read once the other end is dropped.
+        drop(client);
+        assert_eq!(0, server.read(&mut buf).unwrap());
+        assert_eq!(ErrorKind::BrokenPipe, server.write(b"baz").unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        let transport = ChannelTransport::new();
+        let addr = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        assert_eq!(ErrorKind::ConnectionRefused,
+                   transport.connect(&addr).unwrap_err().kind());
+
+        drop(transport.listen(&addr).unwrap());
+        assert_eq!(ErrorKind::ConnectionRefused,
+                   transport.connect(&addr).unwrap_err().kind());
+    }
+}
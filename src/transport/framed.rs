@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+use capnp::message::{Allocator, Builder, Reader, ReaderOptions};
+use capnp::serialize::{self, OwnedSegments};
+
+use {Error, RaftError, Result};
+
+/// The most segments a frame may have, as in `capnp::serialize::read_message`.
+const MAX_SEGMENTS: usize = 512;
+
+/// Serializes a message into a frame, to be sent with `Framed::send_frame`.
+pub fn frame<A>(message: &Builder<A>) -> Vec<u8>
+    where A: Allocator
+{
+    let mut frame = Vec::new();
+    // Writing to a `Vec` cannot fail.
+    serialize::write_message(&mut frame, message).unwrap();
+    frame
+}
+
+/// Returns whether an operation on a stream failed only because the stream is not ready. A TCP
+/// stream is not writable before its connection completes.
+fn would_block(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::NotConnected => true,
+        _ => false,
+    }
+}
+
+/// Cap'n Proto messages framed over a stream opened by a `Transport`.
+///
+/// Every transport carries messages in the standard Cap'n Proto stream framing: a segment table
+/// giving the number and the length of the segments, followed by the segments. The stream does
+/// not block, so messages are queued until the stream accepts them, and received bytes are kept
+/// until a whole frame has arrived.
+pub struct Framed<S> {
+    stream: S,
+    options: ReaderOptions,
+    /// The frames waiting to be written, in order.
+    outgoing: VecDeque<Vec<u8>>,
+    /// How much of the first outgoing frame has been written.
+    written: usize,
+    /// The bytes received which do not make up a whole frame yet.
+    incoming: Vec<u8>,
+}
+
+impl<S> Framed<S>
+    where S: Read + Write
+{
+    /// Frames messages over the stream.
+    pub fn new(stream: S) -> Framed<S> {
+        Framed {
+            stream: stream,
+            options: ReaderOptions::new(),
+            outgoing: VecDeque::new(),
+            written: 0,
+            incoming: Vec::new(),
+        }
+    }
+
+    /// Returns the stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Queues a message, and writes as much of the queue as the stream accepts.
+    pub fn send<A>(&mut self, message: &Builder<A>) -> io::Result<()>
+        where A: Allocator
+    {
+        self.send_frame(frame(message))
+    }
+
+    /// Queues a frame made with `frame`, and writes as much of the queue as the stream accepts.
+    pub fn send_frame(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        self.outgoing.push_back(frame);
+        self.flush()
+    }
+
+    /// Writes as much of the queue as the stream accepts.
+    pub fn flush(&mut self) -> io::Result<()> {
+        loop {
+            let done = match self.outgoing.front() {
+                Some(frame) => {
+                    match self.stream.write(&frame[self.written..]) {
+                        Ok(0) => {
+                            return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                      "connection closed"))
+                        }
+                        Ok(written) => {
+                            self.written += written;
+                            self.written == frame.len()
+                        }
+                        Err(ref error) if would_block(error) => return Ok(()),
+                        Err(error) => return Err(error),
+                    }
+                }
+                None => return Ok(()),
+            };
+            if done {
+                self.outgoing.pop_front();
+                self.written = 0;
+            }
+        }
+    }
+
+    /// Returns the number of queued bytes the stream has not accepted yet.
+    pub fn queued(&self) -> usize {
+        self.outgoing.iter().map(|frame| frame.len()).sum::<usize>() - self.written
+    }
+
+    /// Discards the queued frames, except for a frame which is partly written: the rest of it
+    /// must follow for the stream to stay framed.
+    pub fn clear(&mut self) {
+        let partial = if self.written > 0 { 1 } else { 0 };
+        self.outgoing.truncate(partial);
+    }
+
+    /// Receives a message, or returns `None` if no whole message has arrived yet. The stream
+    /// is read until it would block, so that edge-triggered readiness is not missed, unless a
+    /// message is returned; call again until `None` is returned.
+    ///
+    /// Fails with `UnexpectedEof` once the stream is closed.
+    pub fn receive(&mut self) -> Result<Option<Reader<OwnedSegments>>> {
+        let mut buf = [0; 4096];
+        loop {
+            if let Some(len) = try!(frame_len(&self.incoming, &self.options)) {
+                let message = try!(serialize::read_message(&mut &self.incoming[..len],
+                                                           self.options));
+                self.incoming.drain(..len);
+                return Ok(Some(message));
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                        "connection closed")))
+                }
+                Ok(read) => self.incoming.extend_from_slice(&buf[..read]),
+                Err(ref error) if would_block(error) => return Ok(None),
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+}
+
+/// Returns the length of the frame at the start of `bytes`, or `None` if the bytes do not hold a
+/// whole frame yet. Fails if the frame is larger than the reader options allow, rather than
+/// waiting for it.
+fn frame_len(bytes: &[u8], options: &ReaderOptions) -> Result<Option<usize>> {
+    if bytes.len() < 4 {
+        return Ok(None);
+    }
+    let segments = LittleEndian::read_u32(&bytes[..4]) as usize + 1;
+    if segments > MAX_SEGMENTS {
+        return Err(Error::Raft(RaftError::MalformedMessage));
+    }
+    // The segment table is padded to a whole number of words.
+    let table = (4 + segments * 4 + 7) / 8 * 8;
+    if bytes.len() < table {
+        return Ok(None);
+    }
+    let words = (0..segments).map(|n| {
+        LittleEndian::read_u32(&bytes[4 + n * 4..8 + n * 4]) as u64
+    }).sum::<u64>();
+    if words > options.traversal_limit_in_words {
+        return Err(Error::Raft(RaftError::MalformedMessage));
+    }
+    let len = table + words as usize * 8;
+    Ok(if bytes.len() >= len { Some(len) } else { None })
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use ClientId;
+    use messages;
+    use messages_capnp::connection_preamble;
+    use transport::{ChannelTransport, Transport};
+    use super::*;
+
+    /// Tests that messages survive framing, even when their bytes arrive piecemeal.
+    #[test]
+    fn test_round_trip() {
+        let transport = ChannelTransport::new();
+        let addr = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        let listener = transport.listen(&addr).unwrap();
+        let mut client = Framed::new(transport.connect(&addr).unwrap());
+        let (stream, _) = transport.accept(&listener).unwrap().unwrap();
+        let mut server = Framed::new(stream);
+        assert!(server.receive().unwrap().is_none());
+
+        let ids = [ClientId::new(), ClientId::new()];
+        for &id in &ids {
+            let frame = frame(&*messages::client_connection_preamble(id));
+            let (head, tail) = frame.split_at(frame.len() / 2);
+            client.send_frame(head.to_vec()).unwrap();
+            assert!(server.receive().unwrap().is_none());
+            client.send_frame(tail.to_vec()).unwrap();
+        }
+        assert_eq!(0, client.queued());
+
+        for &id in &ids {
+            let message = server.receive().unwrap().unwrap();
+            let preamble = message.get_root::<connection_preamble::Reader>().unwrap();
+            match preamble.get_id().which().unwrap() {
+                connection_preamble::id::Which::Client(Ok(bytes)) => {
+                    assert_eq!(id, ClientId::from_bytes(bytes).unwrap());
+                }
+                _ => panic!("unexpected preamble"),
+            }
+        }
+        assert!(server.receive().unwrap().is_none());
+
+        drop(client);
+        assert!(server.receive().is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! The means by which servers connect to each other, and clients to servers.
+//!
+//! A `Transport` opens byte streams between servers: it binds a listener to the server's address,
+//! accepts incoming connections on it, and connects to the addresses of peers. Servers and clients
+//! send and receive messages over the streams with `Framed`, which frames them the same way
+//! whatever the transport, so a transport only has to carry bytes. Streams and listeners must be
+//! `mio` event sources so that the server's event loop, and the clients, can wait on them.
+//!
+//! `TcpTransport` is used unless `ServerBuilder::with_transport` or
+//! `ClientBuilder::with_transport` says otherwise.
+//! `ChannelTransport` connects servers within a single process, which is useful for tests and for
+//! embedding several servers in one application. On Unix, `UnixTransport` connects servers and
+//! clients on the same host over Unix domain sockets.
+
+mod channel;
+mod framed;
+mod tcp;
+#[cfg(unix)]
+mod unix;
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+use mio::Evented;
+
+pub use transport::channel::{ChannelListener, ChannelStream, ChannelTransport};
+pub use transport::framed::{Framed, frame};
+pub use transport::tcp::TcpTransport;
+#[cfg(unix)]
+pub use transport::unix::{UnixSocketListener, UnixTransport};
+
+/// A means of opening byte streams between servers, and between clients and servers. Messages
+/// are sent over the streams with `Framed`.
+///
+/// Servers are always identified by a `SocketAddr`, which transports other than TCP may treat as
+/// a name.
+pub trait Transport: Send + 'static {
+    /// A connected byte stream. Reads and writes must not block, and fail with `WouldBlock`
+    /// instead.
+    type Stream: Read + Write + Evented;
+    /// A listener for incoming connections.
+    type Listener: Evented;
+
+    /// Starts listening for connections at the provided address.
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Self::Listener>;
+
+    /// Returns the address the listener listens at.
+    fn local_addr(&self, listener: &Self::Listener) -> io::Result<SocketAddr>;
+
+    /// Accepts an incoming connection, returning the stream along with the remote address, or
+    /// `None` if no connection is pending. The remote address is informational only; it is
+    /// unspecified if the transport has no notion of one.
+    fn accept(&self, listener: &Self::Listener) -> io::Result<Option<(Self::Stream, SocketAddr)>>;
+
+    /// Connects to the server listening at the provided address. The connection may complete
+    /// asynchronously, in which case the stream becomes writable once it is established.
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Self::Stream>;
+}
+
+/// The remote address of connections accepted by transports without a notion of one.
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
@@ -0,0 +1,35 @@
+use std::io;
+use std::net::SocketAddr;
+
+use mio::tcp::{TcpListener, TcpStream};
+
+use transport::Transport;
+
+/// Connects servers over TCP. This is the default transport.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<TcpListener> {
+        TcpListener::bind(addr)
+    }
+
+    fn local_addr(&self, listener: &TcpListener) -> io::Result<SocketAddr> {
+        listener.local_addr()
+    }
+
+    fn accept(&self, listener: &TcpListener) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        match listener.accept() {
+            Ok(accepted) => Ok(Some(accepted)),
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(addr)
+    }
+}
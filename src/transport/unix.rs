@@ -0,0 +1,162 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio_uds::{UnixListener, UnixStream};
+
+use transport::{Transport, unspecified_addr};
+
+/// Connects servers and clients on the same host over Unix domain sockets. Each address names a
+/// socket file in a shared directory, so servers keep identifying each other by `SocketAddr`.
+#[derive(Clone, Debug)]
+pub struct UnixTransport {
+    dir: PathBuf,
+}
+
+impl UnixTransport {
+    /// Creates a transport which keeps its socket files in the provided directory.
+    pub fn new<P>(dir: P) -> UnixTransport
+        where P: Into<PathBuf>
+    {
+        UnixTransport { dir: dir.into() }
+    }
+
+    /// Returns the path of the socket file for the provided address.
+    pub fn path(&self, addr: &SocketAddr) -> PathBuf {
+        self.dir.join(format!("{}.sock", addr))
+    }
+}
+
+impl Transport for UnixTransport {
+    type Stream = UnixStream;
+    type Listener = UnixSocketListener;
+
+    /// Starts listening at the socket file for the provided address, replacing any file left
+    /// behind by a previous listener.
+    fn listen(&self, addr: &SocketAddr) -> io::Result<UnixSocketListener> {
+        let path = self.path(addr);
+        if let Err(error) = fs::remove_file(&path) {
+            if error.kind() != io::ErrorKind::NotFound {
+                return Err(error);
+            }
+        }
+        Ok(UnixSocketListener {
+            listener: try!(UnixListener::bind(&path)),
+            addr: *addr,
+        })
+    }
+
+    fn local_addr(&self, listener: &UnixSocketListener) -> io::Result<SocketAddr> {
+        Ok(listener.addr)
+    }
+
+    fn accept(&self,
+              listener: &UnixSocketListener)
+              -> io::Result<Option<(UnixStream, SocketAddr)>> {
+        let accepted = try!(listener.listener.accept());
+        Ok(accepted.map(|(stream, _)| (stream, unspecified_addr())))
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<UnixStream> {
+        UnixStream::connect(self.path(addr))
+    }
+}
+
+/// Listens for connections over a `UnixTransport`.
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    /// The address the socket file is named after.
+    addr: SocketAddr,
+}
+
+impl Evented for UnixSocketListener {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.listener.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.listener.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.listener.deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+
+    use ClientId;
+    use messages;
+    use messages_capnp::connection_preamble;
+    use transport::{Framed, Transport};
+    use super::*;
+
+    /// Tests that a connection accepted over a Unix socket carries framed messages.
+    #[test]
+    fn test_round_trip() {
+        let dir = Path::new("/tmp/raft-unix-transport");
+        remove_dir_all(&dir).unwrap_or(());
+        create_dir_all(&dir).unwrap();
+        let transport = UnixTransport::new(dir);
+        let addr = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        let listener = transport.listen(&addr).unwrap();
+        assert_eq!(addr, transport.local_addr(&listener).unwrap());
+        assert!(transport.path(&addr).exists());
+
+        let poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(16);
+        let timeout = Some(Duration::from_secs(5));
+        poll.register(&listener, Token(0), Ready::readable(), PollOpt::edge()).unwrap();
+        let mut client = Framed::new(transport.connect(&addr).unwrap());
+        let mut accepted = transport.accept(&listener).unwrap();
+        while accepted.is_none() {
+            poll.poll(&mut events, timeout).unwrap();
+            accepted = transport.accept(&listener).unwrap();
+        }
+        let (stream, _) = accepted.unwrap();
+
+        let id = ClientId::new();
+        client.send(&*messages::client_connection_preamble(id)).unwrap();
+        assert_eq!(0, client.queued());
+
+        poll.register(&stream, Token(1), Ready::readable(), PollOpt::edge()).unwrap();
+        let mut server = Framed::new(stream);
+        let mut received = server.receive().unwrap();
+        while received.is_none() {
+            poll.poll(&mut events, timeout).unwrap();
+            received = server.receive().unwrap();
+        }
+        let message = received.unwrap();
+        let preamble = message.get_root::<connection_preamble::Reader>().unwrap();
+        match preamble.get_id().which().unwrap() {
+            connection_preamble::id::Which::Client(Ok(bytes)) => {
+                assert_eq!(id, ClientId::from_bytes(bytes).unwrap());
+            }
+            _ => panic!("unexpected preamble"),
+        }
+
+        drop(client);
+        remove_dir_all(&dir).unwrap();
+    }
+}